@@ -0,0 +1,94 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::session::RequestState;
+
+// Options for `request_batch`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchOptions {
+    pub concurrency: Option<usize>, // Sub-requests in flight at once (default: all)
+    pub fail_fast: bool,            // Cancel the rest of the batch on the first failure
+}
+
+// Aggregate state of a batch, derived from its sub-requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchState {
+    Running,   // Some sub-requests haven't finished yet
+    Complete,  // Every sub-request completed
+    Failed,    // At least one sub-request errored or timed out
+    Cancelled, // Sub-requests were cancelled, none failed
+}
+
+// A group of requests started together
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub request_ids: Vec<String>,
+}
+
+impl Batch {
+    pub fn new(batch_id: &str, count: usize) -> Self {
+        Self {
+            request_ids: (0..count).map(|index| format!("{}:{}", batch_id, index + 1)).collect(),
+        }
+    }
+
+    // Gather semantics: the batch is done once every sub-request is
+    pub fn state(states: &[RequestState]) -> BatchState {
+        if states.iter().any(|state| !state.is_terminal()) {
+            BatchState::Running
+        } else if states.iter().any(|state| matches!(state, RequestState::Error | RequestState::Timeout)) {
+            BatchState::Failed
+        } else if states.iter().any(|state| matches!(state, RequestState::Cancelled | RequestState::Idle)) {
+            BatchState::Cancelled
+        } else {
+            BatchState::Complete
+        }
+    }
+}
+
+impl FromLua for BatchOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(BatchOptions::default()),
+            LuaValue::Table(table) => {
+                let concurrency: Option<usize> = table.get("concurrency")?;
+                if concurrency == Some(0) {
+                    return Err(LuaError::RuntimeError("Batch concurrency must be at least 1".to_string()));
+                }
+
+                Ok(BatchOptions {
+                    concurrency,
+                    fail_fast: table.get::<Option<bool>>("fail_fast")?.unwrap_or(false),
+                })
+            }
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "BatchOptions".to_string(),
+                message: Some("Expected a table".to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_ids() {
+        let batch = Batch::new("b", 2);
+        assert_eq!(batch.request_ids, vec!["b:1".to_string(), "b:2".to_string()]);
+    }
+
+    #[test]
+    fn test_batch_state() {
+        use RequestState::*;
+
+        assert_eq!(Batch::state(&[Complete, Receiving]), BatchState::Running);
+        assert_eq!(Batch::state(&[Complete, Complete]), BatchState::Complete);
+        assert_eq!(Batch::state(&[Complete, Error, Cancelled]), BatchState::Failed);
+        assert_eq!(Batch::state(&[Timeout, Complete]), BatchState::Failed);
+        assert_eq!(Batch::state(&[Complete, Cancelled]), BatchState::Cancelled);
+        assert_eq!(Batch::state(&[]), BatchState::Complete);
+    }
+}
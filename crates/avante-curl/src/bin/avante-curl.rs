@@ -0,0 +1,7 @@
+// Command-line runner for reproducing provider requests without Neovim.
+// See `avante-curl --help`.
+
+fn main() {
+    let code = avante_curl::cli::main(std::env::args().skip(1).collect());
+    std::process::exit(code);
+}
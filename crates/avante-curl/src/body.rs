@@ -0,0 +1,509 @@
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use mlua::prelude::*;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::io::{self, Write};
+use tokio::io::AsyncReadExt;
+
+use crate::error::AvanteCurlError;
+use crate::RequestBody;
+
+// Read size for bodies streamed from disk
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// A piece of a multi-part body; parts are sent back to back
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyPart {
+    Text(#[serde(serialize_with = "serialize_text")] Bytes),
+    File(String), // Streamed from disk
+}
+
+impl BodyPart {
+    pub fn size(&self) -> io::Result<u64> {
+        match self {
+            BodyPart::Text(text) => Ok(text.len() as u64),
+            BodyPart::File(path) => Ok(std::fs::metadata(path)?.len()),
+        }
+    }
+}
+
+// Request-body compression, announced with Content-Encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(name: Option<&str>) -> Result<Option<Self>, AvanteCurlError> {
+        match name {
+            None | Some("none") => Ok(None),
+            Some("gzip") => Ok(Some(Compression::Gzip)),
+            Some("zstd") => Ok(Some(Compression::Zstd)),
+            Some(other) => Err(AvanteCurlError::InvalidConfig(format!("Unsupported compression: {}", other))),
+        }
+    }
+
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn encoder(&self) -> io::Result<Encoder> {
+        Ok(match self {
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+        })
+    }
+}
+
+// Incremental compressor; output is taken after every chunk
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+// Encodings offered in Accept-Encoding when responses are decoded by `Decoder`
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br, zstd";
+
+// Incremental response decompressor picked from Content-Encoding. Decoding
+// here rather than in reqwest lets the caller count the bytes on the wire.
+pub enum Decoder {
+    Identity, // Not encoded, or an encoding we pass through as is
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Deflate(flate2::write::ZlibDecoder<Vec<u8>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Decoder {
+    pub fn for_encoding(content_encoding: Option<&str>) -> io::Result<Self> {
+        let encoding = content_encoding.map(|encoding| encoding.trim().to_ascii_lowercase());
+        Ok(match encoding.as_deref() {
+            Some("gzip" | "x-gzip") => Decoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            Some("deflate") => Decoder::Deflate(flate2::write::ZlibDecoder::new(Vec::new())),
+            Some("br") => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096))),
+            Some("zstd") => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+            _ => Decoder::Identity,
+        })
+    }
+
+    pub fn is_identity(&self) -> bool {
+        matches!(self, Decoder::Identity)
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Decoder::Identity => return Ok(chunk.to_vec()),
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    // Flush what's left; fails if the body ended mid-stream
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Identity => Ok(Vec::new()),
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(decoder) => decoder.finish(),
+            Decoder::Brotli(mut decoder) => {
+                decoder.close()?;
+                decoder.into_inner().map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream"))
+            }
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+// A request body ready to send. Raw and JSON bodies stay in memory (so they
+// can be replayed on redirects); files and multi-part bodies are streamed.
+pub enum OutgoingBody {
+    Bytes(Bytes),
+    Stream {
+        stream: BoxStream<'static, io::Result<Bytes>>,
+        length: Option<u64>, // Sent as Content-Length when known, otherwise chunked
+    },
+}
+
+impl OutgoingBody {
+    // In-memory bodies and parts are shared with the request, not copied
+    pub fn build(body: &RequestBody, compression: Option<Compression>) -> Result<Self, AvanteCurlError> {
+        let body = match body {
+            RequestBody::Raw(bytes) | RequestBody::Json(bytes) => OutgoingBody::Bytes(bytes.clone()),
+            RequestBody::File(path) => OutgoingBody::Stream {
+                length: Some(std::fs::metadata(path)?.len()),
+                stream: file_stream(path.clone()),
+            },
+            RequestBody::Parts(parts) => {
+                let mut length = 0;
+                for part in parts {
+                    length += part.size()?;
+                }
+                OutgoingBody::Stream {
+                    length: Some(length),
+                    stream: stream::iter(parts.clone()).flat_map(part_stream).boxed(),
+                }
+            }
+        };
+
+        match compression {
+            None => Ok(body),
+            Some(compression) => body.compress(compression),
+        }
+    }
+
+    // Content-Length to send, if known up front
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            OutgoingBody::Bytes(bytes) => Some(bytes.len() as u64),
+            OutgoingBody::Stream { length, .. } => *length,
+        }
+    }
+
+    fn compress(self, compression: Compression) -> Result<Self, AvanteCurlError> {
+        let mut encoder = compression.encoder()?;
+        match self {
+            OutgoingBody::Bytes(bytes) => {
+                let mut compressed = encoder.compress(&bytes)?.to_vec();
+                compressed.extend_from_slice(&encoder.finish()?);
+                Ok(OutgoingBody::Bytes(Bytes::from(compressed)))
+            }
+            OutgoingBody::Stream { stream, .. } => {
+                let stream = stream::unfold((stream, Some(encoder)), |(mut stream, encoder)| async move {
+                    let mut encoder = encoder?;
+                    match stream.next().await {
+                        Some(Ok(chunk)) => {
+                            let compressed = encoder.compress(&chunk);
+                            Some((compressed, (stream, Some(encoder))))
+                        }
+                        Some(Err(e)) => Some((Err(e), (stream, None))),
+                        None => Some((encoder.finish(), (stream, None))),
+                    }
+                })
+                // The encoder often holds output back; don't send empty chunks
+                .filter(|chunk| futures_util::future::ready(!matches!(chunk, Ok(bytes) if bytes.is_empty())))
+                .boxed();
+                Ok(OutgoingBody::Stream { stream, length: None })
+            }
+        }
+    }
+
+    pub fn into_reqwest(self) -> reqwest::Body {
+        match self {
+            OutgoingBody::Bytes(bytes) => reqwest::Body::from(bytes),
+            OutgoingBody::Stream { stream, .. } => reqwest::Body::wrap_stream(stream),
+        }
+    }
+
+    pub fn into_hyper(self) -> hyper::Body {
+        match self {
+            OutgoingBody::Bytes(bytes) => hyper::Body::from(bytes),
+            OutgoingBody::Stream { stream, .. } => hyper::Body::wrap_stream(stream),
+        }
+    }
+}
+
+fn part_stream(part: BodyPart) -> BoxStream<'static, io::Result<Bytes>> {
+    match part {
+        BodyPart::Text(text) => stream::once(futures_util::future::ready(Ok(text))).boxed(),
+        BodyPart::File(path) => file_stream(path),
+    }
+}
+
+// Read a file in chunks as the request goes out
+fn file_stream(path: String) -> BoxStream<'static, io::Result<Bytes>> {
+    stream::try_unfold(None, move |file: Option<tokio::fs::File>| {
+        let path = path.clone();
+        async move {
+            let mut file = match file {
+                Some(file) => file,
+                None => tokio::fs::File::open(&path).await?,
+            };
+            let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+            let read = file.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            buf.truncate(read);
+            Ok(Some((Bytes::from(buf), Some(file))))
+        }
+    })
+    .boxed()
+}
+
+impl RequestBody {
+    // Encode a JSON body once, up front
+    pub fn json(value: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        Ok(RequestBody::Json(Bytes::from(serde_json::to_vec(value)?)))
+    }
+
+    // A JSON body that is already encoded, checked but kept as is
+    fn encoded_json(encoded: Bytes) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice::<serde::de::IgnoredAny>(&encoded)?;
+        Ok(RequestBody::Json(encoded))
+    }
+}
+
+// In-memory bodies serialize as the text they were given as
+pub fn serialize_text<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(bytes))
+}
+
+// `{ Raw = "..." }`, `{ Json = table | "<encoded>" }`, `{ File = path }` or
+// `{ Parts = { "text", { file = path }, { text = "..." }, ... } }`
+impl FromLua for RequestBody {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::String(raw) => return Ok(RequestBody::Raw(Bytes::copy_from_slice(&raw.as_bytes()))),
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: "LuaValue",
+                    to: "RequestBody".to_string(),
+                    message: Some("Expected a string or a table".to_string()),
+                })
+            }
+        };
+
+        if let Some(raw) = table.get::<Option<LuaString>>("Raw")? {
+            return Ok(RequestBody::Raw(Bytes::copy_from_slice(&raw.as_bytes())));
+        }
+        if let Some(path) = table.get::<Option<String>>("File")? {
+            return Ok(RequestBody::File(path));
+        }
+        match table.get::<LuaValue>("Json")? {
+            LuaValue::Nil => {}
+            LuaValue::String(encoded) => {
+                return RequestBody::encoded_json(Bytes::copy_from_slice(&encoded.as_bytes()))
+                    .map_err(|e| LuaError::RuntimeError(format!("Invalid JSON body: {}", e)));
+            }
+            value => {
+                let json: serde_json::Value = lua.from_value(value)?;
+                return RequestBody::json(&json).map_err(LuaError::external);
+            }
+        }
+        if let Some(parts) = table.get::<Option<Vec<LuaValue>>>("Parts")? {
+            return parts.into_iter().map(body_part).collect::<LuaResult<_>>().map(RequestBody::Parts);
+        }
+
+        Err(LuaError::RuntimeError("Body needs one of Raw, Json, File or Parts".to_string()))
+    }
+}
+
+// The same shapes from JSON, e.g. options for the command-line runner
+impl<'de> Deserialize<'de> for RequestBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        enum Tagged {
+            Raw(String),
+            Json(serde_json::Value),
+            File(String),
+            Parts(Vec<BodyPart>),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Shape {
+            Raw(String),
+            Tagged(Tagged),
+        }
+
+        Ok(match Shape::deserialize(deserializer)? {
+            Shape::Raw(raw) | Shape::Tagged(Tagged::Raw(raw)) => RequestBody::Raw(Bytes::from(raw)),
+            Shape::Tagged(Tagged::Json(serde_json::Value::String(encoded))) => RequestBody::encoded_json(Bytes::from(encoded))
+                .map_err(|e| D::Error::custom(format!("Invalid JSON body: {}", e)))?,
+            Shape::Tagged(Tagged::Json(json)) => RequestBody::json(&json).map_err(D::Error::custom)?,
+            Shape::Tagged(Tagged::File(path)) => RequestBody::File(path),
+            Shape::Tagged(Tagged::Parts(parts)) => RequestBody::Parts(parts),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for BodyPart {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        enum Tagged {
+            Text(String),
+            File(String),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Shape {
+            Text(String),
+            Tagged(Tagged),
+        }
+
+        Ok(match Shape::deserialize(deserializer)? {
+            Shape::Text(text) | Shape::Tagged(Tagged::Text(text)) => BodyPart::Text(Bytes::from(text)),
+            Shape::Tagged(Tagged::File(path)) => BodyPart::File(path),
+        })
+    }
+}
+
+fn body_part(value: LuaValue) -> LuaResult<BodyPart> {
+    match value {
+        LuaValue::String(text) => Ok(BodyPart::Text(Bytes::copy_from_slice(&text.as_bytes()))),
+        LuaValue::Table(table) => {
+            if let Some(path) = table.get::<Option<String>>("file")? {
+                return Ok(BodyPart::File(path));
+            }
+            Ok(BodyPart::Text(Bytes::copy_from_slice(&table.get::<LuaString>("text")?.as_bytes())))
+        }
+        _ => Err(LuaError::RuntimeError("Body parts must be strings or { file = path } tables".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    async fn collect(body: OutgoingBody) -> Vec<u8> {
+        match body {
+            OutgoingBody::Bytes(bytes) => bytes.to_vec(),
+            OutgoingBody::Stream { mut stream, .. } => {
+                let mut out = Vec::new();
+                while let Some(chunk) = stream.next().await {
+                    out.extend_from_slice(&chunk.unwrap());
+                }
+                out
+            }
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    #[test]
+    fn test_parts_are_streamed_in_order() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let image = "x".repeat(FILE_CHUNK_SIZE + 10);
+        file.write_all(image.as_bytes()).unwrap();
+        let path = file.path().to_string_lossy().to_string();
+
+        let body = RequestBody::Parts(vec![
+            BodyPart::Text("{\"image\":\"".into()),
+            BodyPart::File(path),
+            BodyPart::Text("\"}".into()),
+        ]);
+        let outgoing = OutgoingBody::build(&body, None).unwrap();
+        assert_eq!(outgoing.length(), Some(image.len() as u64 + 12));
+
+        let sent = runtime().block_on(collect(outgoing));
+        assert_eq!(String::from_utf8(sent).unwrap(), format!("{{\"image\":\"{}\"}}", image));
+    }
+
+    #[test]
+    fn test_in_memory_body_is_shared() {
+        let body = RequestBody::json(&serde_json::json!({ "messages": ["x".repeat(1024)] })).unwrap();
+        let RequestBody::Json(encoded) = &body else { unreachable!() };
+
+        // Every attempt sends the same buffer
+        for _ in 0..2 {
+            match OutgoingBody::build(&body, None).unwrap() {
+                OutgoingBody::Bytes(bytes) => assert_eq!(bytes.as_ptr(), encoded.as_ptr()),
+                OutgoingBody::Stream { .. } => panic!("expected an in-memory body"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let body = RequestBody::Parts(vec![BodyPart::File("/nonexistent/avante-body".to_string())]);
+        assert!(OutgoingBody::build(&body, None).is_err());
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let text = "The quick brown fox. ".repeat(500);
+        let rt = runtime();
+
+        // In memory
+        let gzip = OutgoingBody::build(&RequestBody::Raw(text.clone().into()), Some(Compression::Gzip)).unwrap();
+        let compressed = rt.block_on(collect(gzip));
+        assert!(compressed.len() < text.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        // Streamed
+        let part = BodyPart::Text(text.clone().into());
+        let parts = RequestBody::Parts(vec![part.clone(), part]);
+        let zstd = OutgoingBody::build(&parts, Some(Compression::Zstd)).unwrap();
+        assert_eq!(zstd.length(), None);
+        let compressed = rt.block_on(collect(zstd));
+        assert_eq!(zstd::stream::decode_all(&compressed[..]).unwrap(), format!("{}{}", text, text).into_bytes());
+    }
+
+    #[test]
+    fn test_decoder_in_chunks() {
+        let text = "The quick brown fox. ".repeat(500);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(text.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::stream::encode_all(text.as_bytes(), 0).unwrap();
+
+        for (encoding, wire) in [("gzip", gzip.clone()), ("zstd", zstd), ("identity", text.clone().into_bytes())] {
+            let mut decoder = Decoder::for_encoding(Some(encoding)).unwrap();
+            let mut decoded = Vec::new();
+            for chunk in wire.chunks(100) {
+                decoded.extend(decoder.decode(chunk).unwrap());
+            }
+            decoded.extend(decoder.finish().unwrap());
+            assert_eq!(String::from_utf8(decoded).unwrap(), text, "{}", encoding);
+        }
+
+        // A body cut short can't be finished
+        let mut decoder = Decoder::for_encoding(Some("GZIP")).unwrap();
+        decoder.decode(&gzip[..gzip.len() / 2]).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::response::ResponseHeaders;
+
+const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+// Headers of a 304 that must not replace the stored ones
+const KEEP_ON_REVALIDATE: &[&str] = &["content-length", "content-encoding", "transfer-encoding"];
+
+// Request headers that identify the caller. They're always part of the key,
+// so one user's response is never served to another, Vary or not.
+const CREDENTIAL_HEADERS: &[&str] =
+    &["authorization", "proxy-authorization", "cookie", "x-api-key", "api-key", "x-goog-api-key"];
+
+// Session-level cache configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheOptions {
+    pub dir: String,
+    pub max_bytes: Option<u64>, // Evict least recently used entries past this size (default 100 MiB)
+}
+
+// Per-request `cache` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    Default, // Serve fresh entries, revalidate stale ones
+    Force,   // Serve any stored entry, however old
+    Bypass,  // Neither read nor write the cache
+}
+
+impl CacheMode {
+    pub fn parse(mode: Option<&str>) -> Result<Self, String> {
+        match mode {
+            None | Some("default") => Ok(CacheMode::Default),
+            Some("force") => Ok(CacheMode::Force),
+            Some("bypass") => Ok(CacheMode::Bypass),
+            Some(other) => Err(format!("Unsupported cache mode: {}", other)),
+        }
+    }
+}
+
+// Metadata of a stored response; the body lives next to it in `<key>.body`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub key: String,
+    pub primary: String,                      // Hash of method, URL and credentials
+    pub url: String,
+    pub status: u16,
+    pub headers: ResponseHeaders,
+    pub http_version: String,
+    pub vary: Vec<(String, Option<String>)>,  // Request header values the response varies on
+    pub stored_at: u64,
+    pub expires_at: Option<u64>,              // None: always revalidate
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at > now)
+    }
+
+    // If-None-Match / If-Modified-Since for revalidating this entry
+    pub fn conditional_headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = self.headers.get("etag") {
+            headers.push(("If-None-Match".to_string(), etag.to_string()));
+        }
+        if let Some(last_modified) = self.headers.get("last-modified") {
+            headers.push(("If-Modified-Since".to_string(), last_modified.to_string()));
+        }
+        headers
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    size: u64,
+    last_access: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<String, IndexEntry>,
+    vary: HashMap<String, Vec<String>>, // Primary key -> header names of the last stored variant
+    total: u64,
+}
+
+// What Cache-Control / Expires allow for a response
+#[derive(Debug, Clone, PartialEq)]
+struct Freshness {
+    store: bool,
+    expires_at: Option<u64>,
+}
+
+// Disk cache shared by the requests of a session. Entries are keyed by
+// method, URL, credential headers and the request headers named in the
+// response's Vary. The index lock is never held across file IO; async code
+// goes through `run` to keep the IO off the runtime's workers.
+pub struct HttpCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+}
+
+impl HttpCache {
+    // Open (or create) the cache directory and index what's already there
+    pub fn open(options: &CacheOptions) -> io::Result<Self> {
+        let dir = PathBuf::from(&options.dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut index = CacheIndex::default();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            let meta: CachedResponse = match fs::read(&path).ok().and_then(|raw| serde_json::from_slice(&raw).ok()) {
+                Some(meta) => meta,
+                None => {
+                    // Unreadable entries (e.g. from an older format) are dropped
+                    let _ = fs::remove_file(&path);
+                    let _ = fs::remove_file(path.with_extension("body"));
+                    continue;
+                }
+            };
+
+            let body = match fs::metadata(path.with_extension("body")) {
+                Ok(body) => body,
+                Err(_) => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            let last_access = body.modified().map(unix_seconds).unwrap_or(0);
+            let size = body.len() + fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+            index
+                .vary
+                .insert(meta.primary.clone(), meta.vary.iter().map(|(name, _)| name.clone()).collect());
+            index.entries.insert(meta.key, IndexEntry { size, last_access });
+            index.total += size;
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes: options.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            index: Mutex::new(index),
+        })
+    }
+
+    // Run blocking cache work on tokio's blocking pool
+    pub async fn run<T: Send + 'static>(self: &Arc<Self>, f: impl FnOnce(&HttpCache) -> T + Send + 'static) -> T {
+        let cache = self.clone();
+        match tokio::task::spawn_blocking(move || f(&cache)).await {
+            Ok(value) => value,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    // Stored response and body for a request, if any
+    pub fn lookup(
+        &self,
+        method: &str,
+        url: &str,
+        request_headers: &HashMap<String, String>,
+    ) -> Option<(CachedResponse, String)> {
+        let primary = primary_key(method, url, request_headers);
+        let (key, vary) = {
+            let index = self.index.lock().unwrap();
+            let names = index.vary.get(&primary).cloned().unwrap_or_default();
+            let vary = vary_values(&names, request_headers);
+            let key = variant_key(&primary, &vary);
+            index.entries.get(&key)?;
+            (key, vary)
+        };
+
+        let meta: CachedResponse = fs::read(self.meta_path(&key))
+            .ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())?;
+        let body = fs::read_to_string(self.body_path(&key)).ok()?;
+        if meta.vary != vary {
+            return None;
+        }
+
+        // Bump the entry in the LRU order, also across restarts
+        let now = timestamp_now();
+        if let Some(entry) = self.index.lock().unwrap().entries.get_mut(&key) {
+            entry.last_access = now;
+        }
+        if let Ok(file) = fs::File::options().write(true).open(self.body_path(&key)) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        Some((meta, body))
+    }
+
+    // Store a response if its status and Cache-Control allow it.
+    // Returns whether it was stored.
+    #[allow(clippy::too_many_arguments)]
+    pub fn store(
+        &self,
+        method: &str,
+        url: &str,
+        request_headers: &HashMap<String, String>,
+        status: u16,
+        headers: &ResponseHeaders,
+        http_version: &str,
+        body: &str,
+    ) -> io::Result<bool> {
+        if !method.eq_ignore_ascii_case("GET") || status != 200 {
+            return Ok(false);
+        }
+
+        let now = timestamp_now();
+        let freshness = freshness(headers, now);
+        let has_validator = headers.get("etag").is_some() || headers.get("last-modified").is_some();
+        if !freshness.store || (freshness.expires_at.is_none() && !has_validator) {
+            return Ok(false);
+        }
+
+        let names: Vec<String> = headers
+            .get_all("vary")
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.iter().any(|name| name == "*") {
+            return Ok(false);
+        }
+
+        let primary = primary_key(method, url, request_headers);
+        let vary = vary_values(&names, request_headers);
+        let key = variant_key(&primary, &vary);
+        let meta = CachedResponse {
+            key: key.clone(),
+            primary: primary.clone(),
+            url: url.to_string(),
+            status,
+            headers: headers.clone(),
+            http_version: http_version.to_string(),
+            vary,
+            stored_at: now,
+            expires_at: freshness.expires_at,
+        };
+
+        let meta_json = serde_json::to_vec(&meta)?;
+        let size = (meta_json.len() + body.len()) as u64;
+        if size > self.max_bytes {
+            return Ok(false);
+        }
+
+        self.write_file(&self.body_path(&key), body.as_bytes())?;
+        self.write_file(&self.meta_path(&key), &meta_json)?;
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            if let Some(previous) = index.entries.insert(key, IndexEntry { size, last_access: now }) {
+                index.total -= previous.size;
+            }
+            index.total += size;
+            index.vary.insert(primary, names);
+            self.evict(&mut index)
+        };
+        for key in evicted {
+            let _ = fs::remove_file(self.meta_path(&key));
+            let _ = fs::remove_file(self.body_path(&key));
+        }
+        Ok(true)
+    }
+
+    // Apply the headers of a 304 to a stored entry and extend its lifetime
+    pub fn refresh(&self, entry: &CachedResponse, not_modified: &ResponseHeaders) -> io::Result<CachedResponse> {
+        let mut headers = ResponseHeaders::new();
+        for (name, value) in entry.headers.iter() {
+            let replaced = not_modified.get(name).is_some() && !KEEP_ON_REVALIDATE.contains(&name);
+            if !replaced {
+                headers.push(name, value);
+            }
+        }
+        for (name, value) in not_modified.iter() {
+            if !KEEP_ON_REVALIDATE.contains(&name) {
+                headers.push(name, value);
+            }
+        }
+
+        let now = timestamp_now();
+        let mut refreshed = entry.clone();
+        refreshed.expires_at = freshness(&headers, now).expires_at;
+        refreshed.headers = headers;
+        refreshed.stored_at = now;
+
+        self.write_file(&self.meta_path(&entry.key), &serde_json::to_vec(&refreshed)?)?;
+        Ok(refreshed)
+    }
+
+    // Drop least recently used entries from the index until the cache fits.
+    // Returns their keys; the files are removed once the lock is released.
+    fn evict(&self, index: &mut CacheIndex) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while index.total > self.max_bytes {
+            let oldest = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, entry)| (key.clone(), entry.size));
+            let (key, size) = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            index.entries.remove(&key);
+            index.total -= size;
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    // Write through a temporary file, so concurrent readers never see half a file
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        io::Write::write_all(&mut file, contents)?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn meta_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn body_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.body", key))
+    }
+}
+
+fn primary_key(method: &str, url: &str, request_headers: &HashMap<String, String>) -> String {
+    let mut input = format!("{} {}", method.to_ascii_uppercase(), url);
+    let credentials: Vec<String> = CREDENTIAL_HEADERS.iter().map(|name| name.to_string()).collect();
+    for (name, value) in vary_values(&credentials, request_headers) {
+        if let Some(value) = value {
+            input.push('\n');
+            input.push_str(&name);
+            input.push(':');
+            input.push_str(&value);
+        }
+    }
+    format!("{:x}", Sha256::digest(input))
+}
+
+fn variant_key(primary: &str, vary: &[(String, Option<String>)]) -> String {
+    let mut input = primary.to_string();
+    for (name, value) in vary {
+        input.push('\n');
+        input.push_str(name);
+        input.push(':');
+        input.push_str(value.as_deref().unwrap_or(""));
+    }
+    format!("{:x}", Sha256::digest(input))
+}
+
+fn vary_values(names: &[String], request_headers: &HashMap<String, String>) -> Vec<(String, Option<String>)> {
+    names
+        .iter()
+        .map(|name| {
+            let value = request_headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone());
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+// Private-cache reading of Cache-Control, falling back to Expires
+fn freshness(headers: &ResponseHeaders, now: u64) -> Freshness {
+    let directives: Vec<(String, Option<String>)> = headers
+        .get_all("cache-control")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|directive| {
+            let mut parts = directive.trim().splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = parts.next().map(|value| value.trim().trim_matches('"').to_string());
+            (name, value)
+        })
+        .collect();
+    let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+
+    if has("no-store") {
+        return Freshness { store: false, expires_at: None };
+    }
+    if has("no-cache") {
+        return Freshness { store: true, expires_at: None };
+    }
+
+    let age: u64 = headers.get("age").and_then(|age| age.trim().parse().ok()).unwrap_or(0);
+    let max_age = directives
+        .iter()
+        .find(|(directive, _)| directive == "max-age")
+        .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok());
+
+    let expires_at = match max_age {
+        Some(max_age) => Some((now + max_age).saturating_sub(age)),
+        None => headers
+            .get("expires")
+            .and_then(|expires| httpdate::parse_http_date(expires.trim()).ok())
+            .map(unix_seconds),
+    };
+
+    Freshness { store: true, expires_at }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs()
+}
+
+pub fn timestamp_now() -> u64 {
+    unix_seconds(SystemTime::now())
+}
+
+impl FromLua for CacheOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::String(dir) => Ok(CacheOptions {
+                dir: dir.to_str()?.to_string(),
+                max_bytes: None,
+            }),
+            LuaValue::Table(table) => Ok(CacheOptions {
+                dir: table.get("dir")?,
+                max_bytes: table.get("max_bytes")?,
+            }),
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "CacheOptions".to_string(),
+                message: Some("Expected a directory or a table".to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> ResponseHeaders {
+        let mut headers = ResponseHeaders::new();
+        for (name, value) in pairs {
+            headers.push(name, value);
+        }
+        headers
+    }
+
+    fn open(dir: &tempfile::TempDir, max_bytes: Option<u64>) -> HttpCache {
+        HttpCache::open(&CacheOptions {
+            dir: dir.path().to_string_lossy().to_string(),
+            max_bytes,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_freshness() {
+        let now = 1_000;
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "public, max-age=60")]), now),
+            Freshness { store: true, expires_at: Some(1_060) }
+        );
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "max-age=60"), ("age", "20")]), now),
+            Freshness { store: true, expires_at: Some(1_040) }
+        );
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "no-store")]), now),
+            Freshness { store: false, expires_at: None }
+        );
+        assert_eq!(
+            freshness(&headers(&[("cache-control", "no-cache, max-age=60")]), now),
+            Freshness { store: true, expires_at: None }
+        );
+        assert_eq!(
+            freshness(&headers(&[("expires", "Thu, 01 Jan 1970 00:16:40 GMT")]), now).expires_at,
+            Some(1_000)
+        );
+    }
+
+    #[test]
+    fn test_store_and_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(&dir, None);
+        let url = "https://api.example.com/v1/models";
+        let response = headers(&[("cache-control", "max-age=600"), ("etag", "\"v1\""), ("vary", "Authorization")]);
+
+        let alice = HashMap::from([("authorization".to_string(), "Bearer alice".to_string())]);
+        let bob = HashMap::from([("Authorization".to_string(), "Bearer bob".to_string())]);
+
+        assert!(cache.store("GET", url, &alice, 200, &response, "HTTP/1.1", "{\"data\":[]}").unwrap());
+        assert!(!cache.store("POST", url, &alice, 200, &response, "HTTP/1.1", "{}").unwrap());
+
+        let (entry, body) = cache.lookup("GET", url, &alice).unwrap();
+        assert_eq!(body, "{\"data\":[]}");
+        assert!(entry.is_fresh(timestamp_now()));
+        assert_eq!(entry.conditional_headers(), vec![("If-None-Match".to_string(), "\"v1\"".to_string())]);
+
+        // Different Vary header value, different entry
+        assert!(cache.lookup("GET", url, &bob).is_none());
+
+        // Entries survive reopening the cache
+        let reopened = open(&dir, None);
+        assert!(reopened.lookup("GET", url, &alice).is_some());
+    }
+
+    #[test]
+    fn test_credentials_are_part_of_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(&dir, None);
+        let url = "https://api.example.com/v1/models";
+        let response = headers(&[("cache-control", "max-age=600")]);
+
+        let alice = HashMap::from([("x-api-key".to_string(), "alice".to_string())]);
+        let bob = HashMap::from([("X-Api-Key".to_string(), "bob".to_string())]);
+        assert!(cache.store("GET", url, &alice, 200, &response, "HTTP/1.1", "alice's").unwrap());
+
+        // No Vary, but another caller's response still isn't served
+        assert!(cache.lookup("GET", url, &bob).is_none());
+        assert!(cache.lookup("GET", url, &HashMap::new()).is_none());
+        assert_eq!(cache.lookup("GET", url, &alice).unwrap().1, "alice's");
+    }
+
+    #[test]
+    fn test_not_storable() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(&dir, None);
+        let none = HashMap::new();
+
+        let no_store = headers(&[("cache-control", "no-store"), ("etag", "\"x\"")]);
+        assert!(!cache.store("GET", "http://a/", &none, 200, &no_store, "HTTP/1.1", "x").unwrap());
+
+        // Nothing to reuse it with
+        let no_validator = headers(&[("content-type", "text/plain")]);
+        assert!(!cache.store("GET", "http://a/", &none, 200, &no_validator, "HTTP/1.1", "x").unwrap());
+
+        let vary_all = headers(&[("cache-control", "max-age=60"), ("vary", "*")]);
+        assert!(!cache.store("GET", "http://a/", &none, 200, &vary_all, "HTTP/1.1", "x").unwrap());
+
+        let not_ok = headers(&[("cache-control", "max-age=60")]);
+        assert!(!cache.store("GET", "http://a/", &none, 404, &not_ok, "HTTP/1.1", "x").unwrap());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = open(&dir, None);
+        let none = HashMap::new();
+        let response = headers(&[("cache-control", "no-cache"), ("etag", "\"v1\""), ("content-length", "4")]);
+        cache.store("GET", "http://a/", &none, 200, &response, "HTTP/1.1", "body").unwrap();
+
+        let (entry, _) = cache.lookup("GET", "http://a/", &none).unwrap();
+        assert!(!entry.is_fresh(timestamp_now()));
+
+        let not_modified = headers(&[("cache-control", "max-age=60"), ("content-length", "0")]);
+        let refreshed = cache.refresh(&entry, &not_modified).unwrap();
+        assert!(refreshed.is_fresh(timestamp_now()));
+        assert_eq!(refreshed.headers.get("content-length"), Some("4"));
+        assert_eq!(refreshed.headers.get("etag"), Some("\"v1\""));
+
+        let (entry, body) = cache.lookup("GET", "http://a/", &none).unwrap();
+        assert_eq!(entry, refreshed);
+        assert_eq!(body, "body");
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let none = HashMap::new();
+        let response = headers(&[("cache-control", "max-age=60")]);
+
+        // Room for roughly two entries
+        let probe = open(&dir, None);
+        probe.store("GET", "http://probe/", &none, 200, &response, "HTTP/1.1", &"x".repeat(1000)).unwrap();
+        let entry_size = probe.index.lock().unwrap().total;
+        fs::remove_dir_all(dir.path()).unwrap();
+
+        let cache = open(&dir, Some(entry_size * 2 + entry_size / 2));
+        let body = "x".repeat(1000);
+        cache.store("GET", "http://a/", &none, 200, &response, "HTTP/1.1", &body).unwrap();
+        cache.store("GET", "http://b/", &none, 200, &response, "HTTP/1.1", &body).unwrap();
+
+        // Make "a" the most recently used, then push over the limit
+        cache.index.lock().unwrap().entries.values_mut().for_each(|entry| entry.last_access = 1);
+        assert!(cache.lookup("GET", "http://a/", &none).is_some());
+        cache.store("GET", "http://c/", &none, 200, &response, "HTTP/1.1", &body).unwrap();
+
+        assert!(cache.lookup("GET", "http://a/", &none).is_some());
+        assert!(cache.lookup("GET", "http://b/", &none).is_none());
+        assert!(cache.lookup("GET", "http://c/", &none).is_some());
+    }
+}
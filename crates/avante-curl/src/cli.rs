@@ -0,0 +1,415 @@
+// Standalone runner behind the `avante-curl` binary: runs one request through
+// the same request path as the Lua module, framing and retries included, and
+// prints what happens as JSON lines, for reproducing provider issues outside
+// Neovim.
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::error::ErrorInfo;
+use crate::framing::{Frame, Framing};
+use crate::middleware::Middleware;
+use crate::session::{RequestInfo, Session};
+use crate::{run_scheduled, AuthInfo, RequestBody, RequestOptions, SessionOptions};
+
+const REQUEST_ID: &str = "cli";
+
+const USAGE: &str = "\
+Usage: avante-curl [OPTIONS] [URL]
+
+Runs one request and prints framed events as JSON lines.
+
+Request:
+  --options <FILE>         Request options as the Lua client passes them, as JSON
+                           ('-' for stdin); flags below override them
+  -X, --request <METHOD>   HTTP method
+  -H, --header <H: V>      Add a header (repeatable)
+  -d, --data <DATA>        Request body; @FILE sends a file
+      --json <JSON>        JSON request body, sets Content-Type
+      --compress <ALGO>    Compress the request body: gzip or zstd
+  -u, --user <USER:PASS>   Basic auth
+  -m, --max-time <SECS>    Request timeout
+  -k, --insecure           Skip TLS verification
+  -x, --proxy <URL>        Proxy for every scheme
+  -L, --location           Follow redirects
+      --compressed         Accept compressed responses
+      --http1.1 | --http2  Force the HTTP version
+      --unix-socket <PATH> Connect through a Unix domain socket
+      --resolve <H:P:ADDR> Pin a host to an address (repeatable)
+
+Output:
+      --framing <MODE>     auto (from Content-Type), sse, ndjson or raw [default: auto]
+      --retries <N>        Retry retryable failures up to N times [default: 0]
+      --redact <NAME>      Also mask this header or query parameter (repeatable)
+                           Credentials and cookies are always masked
+  -h, --help               Print this help
+";
+
+// Command line, parsed
+#[derive(Debug, Default)]
+struct Args {
+    options: RequestOptions,
+    redact: Vec<String>,
+}
+
+// Entry point of the binary; returns the process exit code
+pub fn main(args: Vec<String>) -> i32 {
+    let args = match parse_args(args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(message) => {
+            eprintln!("avante-curl: {}\n\n{}", message, USAGE);
+            return 2;
+        }
+    };
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("avante-curl: failed to start runtime: {}", e);
+            return 1;
+        }
+    };
+
+    match runtime.block_on(run(args)) {
+        Ok(()) => 0,
+        Err(error) => {
+            emit(json!({ "type": "error", "error": error }));
+            1
+        }
+    }
+}
+
+// Run the request, printing the request, the response head and every framed
+// event of each attempt, and a line for every retry
+async fn run(args: Args) -> Result<(), ErrorInfo> {
+    let session = Arc::new(Session::with_options(SessionOptions {
+        middleware: Some(vec![
+            Middleware::Usage,
+            Middleware::Redact {
+                headers: args.redact.clone(),
+                query: args.redact.clone(),
+            },
+        ]),
+        ..Default::default()
+    }));
+
+    session
+        .init_request(REQUEST_ID)
+        .map_err(|message| ErrorInfo::new("session", &message))?;
+
+    let task = {
+        let session = session.clone();
+        let options = args.options;
+        tokio::spawn(async move { run_scheduled(&session, REQUEST_ID, options).await })
+    };
+
+    let mut retries = 0;
+    let mut sent_printed = false;
+    let mut head_printed = false;
+    loop {
+        let step = session
+            .wait_until(|| {
+                let info = session.get_response(REQUEST_ID);
+                if info.retry.as_ref().is_some_and(|retry| retry.attempt > retries) {
+                    return Some(Step::Retry(Box::new(info)));
+                }
+                // The head goes before the events of its body
+                if !head_printed && info.status.is_some() {
+                    return Some(Step::Head(Box::new(info)));
+                }
+                // Events are all queued by the time the request ends
+                if let Some(frame) = session.pop_event(REQUEST_ID) {
+                    return Some(Step::Event(frame));
+                }
+                info.state.is_terminal().then(|| Step::Done(Box::new(info)))
+            })
+            .await;
+
+        match step {
+            Step::Retry(info) => {
+                if let Some(retry) = info.retry {
+                    emit(json!({ "type": "retry", "attempt": retry.attempt, "delay": retry.delay, "error": retry.error }));
+                    retries = retry.attempt;
+                }
+                sent_printed = false;
+                head_printed = false;
+            }
+            Step::Head(info) => {
+                print_sent(&info, &mut sent_printed);
+                let headers = info.headers.as_ref().map(|headers| session.middleware().redact_response_headers(headers));
+                emit(json!({
+                    "type": "response",
+                    "status": info.status,
+                    "headers": headers,
+                    "url": info.meta.as_ref().map(|meta| &meta.url),
+                    "http_version": info.meta.as_ref().map(|meta| &meta.http_version),
+                    "cache": info.cache,
+                }));
+                head_printed = true;
+            }
+            Step::Event(frame) => emit(json!({ "type": "event", "event": frame })),
+            Step::Done(info) => {
+                print_sent(&info, &mut sent_printed);
+                let _ = task.await;
+                return match info.error {
+                    Some(error) => Err(error),
+                    None => {
+                        emit(json!({ "type": "done", "state": info.state, "usage": info.token_usage, "truncated": info.truncated }));
+                        Ok(())
+                    }
+                };
+            }
+        }
+    }
+}
+
+enum Step {
+    Retry(Box<RequestInfo>),
+    Head(Box<RequestInfo>),
+    Event(Frame),
+    Done(Box<RequestInfo>),
+}
+
+fn print_sent(info: &RequestInfo, printed: &mut bool) {
+    if let (Some(sent), false) = (&info.sent, *printed) {
+        emit(json!({ "type": "request", "method": sent.method, "url": sent.url, "headers": sent.headers }));
+        *printed = true;
+    }
+}
+
+fn emit(line: serde_json::Value) {
+    println!("{}", line);
+}
+
+fn parse_args(args: Vec<String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args::default();
+    let mut url = None;
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut resolve = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--options" => {
+                let path = value(&arg)?;
+                let raw = if path == "-" {
+                    let mut raw = String::new();
+                    std::io::stdin().read_to_string(&mut raw).map_err(|e| e.to_string())?;
+                    raw
+                } else {
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?
+                };
+                parsed.options = parse_options(&raw)?;
+            }
+            "-X" | "--request" => parsed.options.method = Some(value(&arg)?.to_ascii_uppercase()),
+            "-H" | "--header" => {
+                let header = value(&arg)?;
+                let (name, header_value) = header
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid header '{}', expected 'Name: value'", header))?;
+                headers.insert(name.trim().to_string(), header_value.trim().to_string());
+            }
+            "-d" | "--data" => {
+                let data = value(&arg)?;
+                parsed.options.body = Some(match data.strip_prefix('@') {
+                    Some(path) => RequestBody::File(path.to_string()),
+                    None => RequestBody::Raw(data.into()),
+                });
+            }
+            "--json" => {
+                let data = value(&arg)?;
+                let json = serde_json::from_str(&data).map_err(|e| format!("invalid --json: {}", e))?;
+                parsed.options.body = Some(RequestBody::json(&json).map_err(|e| e.to_string())?);
+            }
+            "--compress" => parsed.options.compress = Some(value(&arg)?),
+            "-u" | "--user" => {
+                let user = value(&arg)?;
+                let (username, password) = user.split_once(':').unwrap_or((user.as_str(), ""));
+                parsed.options.auth = Some(AuthInfo {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                });
+            }
+            "-m" | "--max-time" => {
+                let secs = value(&arg)?;
+                parsed.options.timeout = Some(secs.parse().map_err(|_| format!("invalid --max-time '{}'", secs))?);
+            }
+            "-k" | "--insecure" => parsed.options.insecure = Some(true),
+            "-x" | "--proxy" => {
+                parsed.options.proxy = Some(crate::proxy::ProxyOptions {
+                    all: Some(value(&arg)?),
+                    ..Default::default()
+                })
+            }
+            "-L" | "--location" => parsed.options.follow_redirects = Some(true),
+            "--compressed" => parsed.options.compressed = Some(true),
+            "--http1.1" => parsed.options.http_version = Some("1.1".to_string()),
+            "--http2" => parsed.options.http_version = Some("2".to_string()),
+            "--unix-socket" => parsed.options.unix_socket = Some(value(&arg)?),
+            "--resolve" => resolve.push(value(&arg)?),
+            "--framing" => {
+                let framing = value(&arg)?;
+                Framing::parse(&framing)?;
+                parsed.options.framing = Some(framing);
+            }
+            "--retries" => {
+                let retries = value(&arg)?;
+                parsed.options.retries = Some(retries.parse().map_err(|_| format!("invalid --retries '{}'", retries))?);
+            }
+            "--redact" => parsed.redact.push(value(&arg)?.to_ascii_lowercase()),
+            flag if flag.starts_with('-') && flag.len() > 1 => return Err(format!("unknown option '{}'", flag)),
+            _ => {
+                if url.replace(arg.clone()).is_some() {
+                    return Err("only one URL may be given".to_string());
+                }
+            }
+        }
+    }
+
+    if let Some(url) = url {
+        parsed.options.url = url;
+    }
+    if parsed.options.url.is_empty() {
+        return Err("no URL given".to_string());
+    }
+    if !headers.is_empty() {
+        parsed.options.headers.get_or_insert_with(HashMap::new).extend(headers);
+    }
+    if !resolve.is_empty() {
+        parsed.options.resolve.get_or_insert_with(Vec::new).extend(resolve);
+    }
+    if matches!(parsed.options.body, Some(RequestBody::Json(_))) {
+        let headers = parsed.options.headers.get_or_insert_with(HashMap::new);
+        if !headers.keys().any(|name| name.eq_ignore_ascii_case("content-type")) {
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+        }
+    }
+    if parsed.options.method.is_none() && parsed.options.body.is_some() {
+        parsed.options.method = Some("POST".to_string());
+    }
+    // Always print events; what they are is up to --framing
+    parsed.options.framing.get_or_insert_with(|| "auto".to_string());
+
+    Ok(Some(parsed))
+}
+
+// Options in the shape the Lua client sends, either the `_options` table
+// itself or the table wrapping it
+fn parse_options(raw: &str) -> Result<RequestOptions, String> {
+    let mut value: serde_json::Value = serde_json::from_str(raw).map_err(|e| format!("invalid --options: {}", e))?;
+    if let Some(options) = value.get_mut("_options") {
+        value = options.take();
+    }
+    serde_json::from_value(value).map_err(|e| format!("invalid --options: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::BodyPart;
+
+    fn args(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse_curl_flags() {
+        let parsed = args(&[
+            "https://api.anthropic.com/v1/messages",
+            "-H",
+            "x-api-key: secret",
+            "--json",
+            "{\"stream\":true}",
+            "--framing",
+            "sse",
+            "--retries",
+            "2",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(parsed.options.url, "https://api.anthropic.com/v1/messages");
+        assert_eq!(parsed.options.method.as_deref(), Some("POST"));
+        let headers = parsed.options.headers.unwrap();
+        assert_eq!(headers["x-api-key"], "secret");
+        assert_eq!(headers["Content-Type"], "application/json");
+        assert!(matches!(parsed.options.body, Some(RequestBody::Json(_))));
+        assert_eq!(parsed.options.framing.as_deref(), Some("sse"));
+        assert_eq!(parsed.options.retries, Some(2));
+    }
+
+    #[test]
+    fn test_lua_shaped_options() {
+        // What vim.json.encode makes of the table build_request passes to the backend
+        let options = parse_options(
+            r#"{
+                "_options": {
+                    "url": "https://api.openai.com/v1/chat/completions",
+                    "method": "POST",
+                    "headers": {"Authorization": "Bearer sk"},
+                    "query": [],
+                    "timeout": 60,
+                    "insecure": false,
+                    "proxy": "http://127.0.0.1:8080",
+                    "resolve": "api.openai.com:443:127.0.0.1",
+                    "body": {"Json": "{\"model\":\"gpt-4o\",\"stream\":true}"}
+                },
+                "_callbacks": {}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(options.url, "https://api.openai.com/v1/chat/completions");
+        assert_eq!(options.headers.unwrap()["Authorization"], "Bearer sk");
+        assert_eq!(options.query, Some(HashMap::new()));
+        assert_eq!(options.proxy.unwrap().all.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(options.resolve, Some(vec!["api.openai.com:443:127.0.0.1".to_string()]));
+        match options.body {
+            Some(RequestBody::Json(json)) => assert_eq!(&json[..], br#"{"model":"gpt-4o","stream":true}"#),
+            other => panic!("unexpected body {:?}", other),
+        }
+
+        let options = parse_options(
+            r#"{
+                "url": "http://localhost/",
+                "proxy": {"https": "socks5h://127.0.0.1:1080", "no_proxy": "localhost, .internal"},
+                "body": {"Parts": ["head", {"file": "/tmp/body.bin"}, {"text": "tail"}]}
+            }"#,
+        )
+        .unwrap();
+        let proxy = options.proxy.unwrap();
+        assert_eq!(proxy.no_proxy, vec!["localhost".to_string(), ".internal".to_string()]);
+        match options.body {
+            Some(RequestBody::Parts(parts)) => assert_eq!(
+                parts,
+                vec![
+                    BodyPart::Text("head".into()),
+                    BodyPart::File("/tmp/body.bin".to_string()),
+                    BodyPart::Text("tail".into()),
+                ]
+            ),
+            other => panic!("unexpected body {:?}", other),
+        }
+
+        assert!(matches!(parse_options(r#"{"url": "http://a", "body": "plain"}"#).unwrap().body, Some(RequestBody::Raw(_))));
+        assert!(parse_options(r#"{"url": "http://a", "body": {"Json": "{not json"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(args(&["--help"]).unwrap().is_none());
+        assert!(args(&[]).is_err());
+        assert!(args(&["http://a", "-H"]).is_err());
+        assert!(args(&["http://a", "--bogus"]).is_err());
+        assert!(args(&["http://a", "-H", "no colon"]).is_err());
+        assert!(args(&["http://a", "--framing", "xml"]).is_err());
+    }
+}
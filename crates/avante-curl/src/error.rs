@@ -1,3 +1,5 @@
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
 use std::io;
 use thiserror::Error;
 
@@ -5,25 +7,44 @@ use thiserror::Error;
 pub enum AvanteCurlError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
-    
+
     #[error("I/O error: {0}")]
     IoError(#[from] io::Error),
-    
+
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::Error),
-    
+
     #[error("Request was cancelled")]
     Cancelled,
-    
+
     #[error("Request timed out")]
     Timeout,
-    
+
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
-    
+
     #[error("Session error: {0}")]
     SessionError(String),
-    
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("Token error: {0}")]
+    Token(String),
+
+    #[error("Request body is {size} bytes, over the {limit} byte limit")]
+    BodyTooLarge { size: u64, limit: u64 },
+
+    #[error("Response exceeded the {limit} byte limit")]
+    ResponseTooLarge { limit: u64 },
+
+    #[error("HTTP status {status}")]
+    HttpStatus {
+        status: u16,
+        body: String,
+        retry_after: Option<f64>,
+    },
+
     #[error("{0}")]
     Other(String),
 }
@@ -33,3 +54,281 @@ impl From<anyhow::Error> for AvanteCurlError {
         AvanteCurlError::Other(err.to_string())
     }
 }
+
+impl AvanteCurlError {
+    // Build an error for a non-success HTTP response
+    pub fn from_status(status: u16, headers: &HeaderMap, body: &str) -> Self {
+        AvanteCurlError::HttpStatus {
+            status,
+            body: body.to_string(),
+            retry_after: parse_retry_after(headers),
+        }
+    }
+
+    // Machine-readable error category
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AvanteCurlError::HttpError(e) => reqwest_error_kind(e),
+            AvanteCurlError::IoError(_) => "io",
+            AvanteCurlError::JsonError(_) => "json",
+            AvanteCurlError::Cancelled => "cancelled",
+            AvanteCurlError::Timeout => "timeout",
+            AvanteCurlError::InvalidConfig(_) => "invalid_config",
+            AvanteCurlError::SessionError(_) => "session",
+            AvanteCurlError::Tls(_) => "tls",
+            AvanteCurlError::Token(_) => "token",
+            AvanteCurlError::BodyTooLarge { .. } => "body_too_large",
+            AvanteCurlError::ResponseTooLarge { .. } => "response_too_large",
+            AvanteCurlError::HttpStatus { status: 429, .. } => "rate_limited",
+            AvanteCurlError::HttpStatus { status: 401 | 403, .. } => "auth",
+            AvanteCurlError::HttpStatus { .. } => "http_status",
+            AvanteCurlError::Other(_) => "other",
+        }
+    }
+
+    // HTTP status code, if the error came from a response
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            AvanteCurlError::HttpStatus { status, .. } => Some(*status),
+            AvanteCurlError::HttpError(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+
+    // Whether retrying the same request may succeed
+    pub fn retryable(&self) -> bool {
+        match self {
+            AvanteCurlError::HttpError(e) => reqwest_error_retryable(e),
+            AvanteCurlError::Timeout => true,
+            AvanteCurlError::HttpStatus { status, .. } => is_retryable_status(*status),
+            _ => false,
+        }
+    }
+}
+
+// Convert any error coming out of the request pipeline into structured form
+pub fn error_info(err: &anyhow::Error) -> ErrorInfo {
+    if let Some(e) = err.downcast_ref::<AvanteCurlError>() {
+        return ErrorInfo::from(e);
+    }
+    if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        let mut info = ErrorInfo::new(reqwest_error_kind(e), &format!("HTTP error: {}", e));
+        info.status = e.status().map(|s| s.as_u16());
+        info.retryable = reqwest_error_retryable(e);
+        return info;
+    }
+
+    ErrorInfo::new("other", &err.to_string())
+}
+
+fn reqwest_error_kind(e: &reqwest::Error) -> &'static str {
+    if e.is_timeout() {
+        "timeout"
+    } else if e.is_connect() {
+        "connect"
+    } else if e.is_decode() || e.is_body() {
+        "body"
+    } else if e.is_builder() {
+        "invalid_config"
+    } else {
+        "http"
+    }
+}
+
+fn reqwest_error_retryable(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect()
+}
+
+// Error information handed to Lua
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    pub kind: String,
+    pub message: String,
+    pub status: Option<u16>,
+    pub retryable: bool,
+    pub retry_after: Option<f64>, // Seconds, from Retry-After / retry-after-ms
+    pub body: Option<serde_json::Value>, // Error body, parsed as JSON where possible
+    pub provider: Option<ProviderError>,
+    pub line: Option<usize>,   // Position of a JSON parse error in the body
+    pub column: Option<usize>,
+}
+
+// Provider-specific error details extracted from the response body
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProviderError {
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    pub code: Option<String>,
+    pub message: Option<String>,
+}
+
+impl ErrorInfo {
+    pub fn new(kind: &str, message: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            status: None,
+            retryable: false,
+            retry_after: None,
+            body: None,
+            provider: None,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl From<&AvanteCurlError> for ErrorInfo {
+    fn from(err: &AvanteCurlError) -> Self {
+        let mut info = ErrorInfo {
+            kind: err.kind().to_string(),
+            message: err.to_string(),
+            status: err.status(),
+            retryable: err.retryable(),
+            retry_after: None,
+            body: None,
+            provider: None,
+            line: None,
+            column: None,
+        };
+
+        if let AvanteCurlError::JsonError(e) = err {
+            info.line = Some(e.line());
+            info.column = Some(e.column());
+        }
+
+        if let AvanteCurlError::HttpStatus { status, body, retry_after } = err {
+            info.retry_after = *retry_after;
+            info.body = Some(
+                serde_json::from_str(body)
+                    .unwrap_or_else(|_| serde_json::Value::String(body.clone())),
+            );
+            info.provider = info.body.as_ref().and_then(parse_provider_error);
+
+            let detail = info.provider.as_ref().and_then(|p| p.message.clone());
+            info.message = match (detail, info.retry_after) {
+                (Some(detail), Some(secs)) => {
+                    format!("HTTP {}: {} (retry in {}s)", status, detail, secs.ceil())
+                }
+                (Some(detail), None) => format!("HTTP {}: {}", status, detail),
+                (None, Some(secs)) => format!("HTTP {} (retry in {}s)", status, secs.ceil()),
+                (None, None) => format!("HTTP {}", status),
+            };
+        }
+
+        info
+    }
+}
+
+// Extract `{type, code, message}` from the error shapes used by common providers:
+//   OpenAI:    {"error": {"message", "type", "code"}}
+//   Anthropic: {"type": "error", "error": {"type", "message"}}
+//   Gemini:    {"error": {"code", "message", "status"}}
+fn parse_provider_error(body: &serde_json::Value) -> Option<ProviderError> {
+    let error = body.get("error")?;
+
+    // Some gateways return {"error": "message"}
+    if let Some(message) = error.as_str() {
+        return Some(ProviderError {
+            error_type: None,
+            code: None,
+            message: Some(message.to_string()),
+        });
+    }
+
+    let as_string = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+
+    Some(ProviderError {
+        error_type: error
+            .get("type")
+            .or_else(|| error.get("status"))
+            .and_then(as_string),
+        code: error.get("code").and_then(as_string),
+        message: error.get("message").and_then(as_string),
+    })
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 408 | 409 | 425 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+// Parse Retry-After (seconds only; HTTP dates are ignored) and the
+// millisecond variant some providers send
+fn parse_retry_after(headers: &HeaderMap) -> Option<f64> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(ms / 1000.0);
+    }
+
+    header("retry-after").and_then(|v| v.trim().parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_openai_rate_limit() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("12"));
+        let body = r#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#;
+
+        let info = ErrorInfo::from(&AvanteCurlError::from_status(429, &headers, body));
+        assert_eq!(info.kind, "rate_limited");
+        assert_eq!(info.status, Some(429));
+        assert!(info.retryable);
+        assert_eq!(info.retry_after, Some(12.0));
+        assert_eq!(info.message, "HTTP 429: Rate limit reached (retry in 12s)");
+
+        let provider = info.provider.unwrap();
+        assert_eq!(provider.error_type.as_deref(), Some("requests"));
+        assert_eq!(provider.code.as_deref(), Some("rate_limit_exceeded"));
+    }
+
+    #[test]
+    fn test_anthropic_overloaded() {
+        let body = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+
+        let info = ErrorInfo::from(&AvanteCurlError::from_status(529, &HeaderMap::new(), body));
+        assert_eq!(info.kind, "http_status");
+        assert!(info.retryable);
+        assert_eq!(info.provider.unwrap().error_type.as_deref(), Some("overloaded_error"));
+    }
+
+    #[test]
+    fn test_non_json_body() {
+        let info = ErrorInfo::from(&AvanteCurlError::from_status(400, &HeaderMap::new(), "bad request"));
+        assert_eq!(info.kind, "http_status");
+        assert!(!info.retryable);
+        assert_eq!(info.body, Some(serde_json::Value::String("bad request".to_string())));
+        assert!(info.provider.is_none());
+    }
+
+    #[test]
+    fn test_plain_errors() {
+        let info = ErrorInfo::from(&AvanteCurlError::Cancelled);
+        assert_eq!(info.kind, "cancelled");
+        assert_eq!(info.message, "Request was cancelled");
+        assert!(!info.retryable);
+
+        let info = error_info(&anyhow::anyhow!("boom"));
+        assert_eq!(info.kind, "other");
+        assert_eq!(info.message, "boom");
+    }
+
+    #[test]
+    fn test_json_parse_error_position() {
+        let err = serde_json::from_str::<serde_json::Value>("{\n  \"a\": tru }").unwrap_err();
+        let info = ErrorInfo::from(&AvanteCurlError::from(err));
+        assert_eq!(info.kind, "json");
+        assert_eq!(info.line, Some(2));
+        assert!(info.column.is_some());
+        assert!(!info.retryable);
+    }
+}
@@ -0,0 +1,239 @@
+use serde::Serialize;
+
+// How a streamed body is split into events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Sse,    // text/event-stream
+    Ndjson, // One JSON document per line
+    Raw,    // Chunks as received
+}
+
+impl Framing {
+    pub fn parse(name: &str) -> Result<Option<Self>, String> {
+        match name {
+            "auto" => Ok(None),
+            "sse" => Ok(Some(Framing::Sse)),
+            "ndjson" | "jsonl" => Ok(Some(Framing::Ndjson)),
+            "raw" => Ok(Some(Framing::Raw)),
+            other => Err(format!("Unsupported framing: {}", other)),
+        }
+    }
+
+    // Pick the framing from a response Content-Type
+    pub fn detect(content_type: Option<&str>) -> Self {
+        let mime = content_type
+            .and_then(|value| value.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        match mime.as_str() {
+            "text/event-stream" => Framing::Sse,
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" | "application/x-jsonlines" => {
+                Framing::Ndjson
+            }
+            _ => Framing::Raw,
+        }
+    }
+}
+
+// One framed event
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    Sse {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        event: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        data: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        json: Option<serde_json::Value>, // `data` decoded, when it is JSON
+    },
+    Json {
+        value: serde_json::Value,
+    },
+    Line {
+        line: String, // NDJSON line that isn't valid JSON
+    },
+    Chunk {
+        data: String,
+    },
+}
+
+// Incremental framer; chunks may split events (and lines) anywhere
+#[derive(Debug)]
+pub struct Framer {
+    framing: Framing,
+    buffer: String,
+    event: Option<String>,
+    id: Option<String>,
+    data: Vec<String>,
+}
+
+impl Framer {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            buffer: String::new(),
+            event: None,
+            id: None,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<Frame> {
+        if self.framing == Framing::Raw {
+            return vec![Frame::Chunk { data: text.to_string() }];
+        }
+
+        self.buffer.push_str(text);
+        let mut frames = Vec::new();
+        while let Some(end) = self.buffer.find('\n') {
+            let line: String = self.buffer.drain(..=end).collect();
+            let line = line.trim_end_matches(['\n', '\r']);
+            self.line(line, &mut frames);
+        }
+        frames
+    }
+
+    // Flush whatever is left once the body ends
+    pub fn finish(&mut self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        if self.framing == Framing::Raw {
+            return frames;
+        }
+
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = rest.trim_end_matches('\r');
+        if !rest.is_empty() {
+            self.line(rest, &mut frames);
+        }
+        if self.framing == Framing::Sse {
+            self.dispatch(&mut frames);
+        }
+        frames
+    }
+
+    fn line(&mut self, line: &str, frames: &mut Vec<Frame>) {
+        match self.framing {
+            Framing::Sse => self.sse_line(line, frames),
+            Framing::Ndjson => {
+                if line.trim().is_empty() {
+                    return;
+                }
+                frames.push(match serde_json::from_str(line) {
+                    Ok(value) => Frame::Json { value },
+                    Err(_) => Frame::Line { line: line.to_string() },
+                });
+            }
+            Framing::Raw => {}
+        }
+    }
+
+    fn sse_line(&mut self, line: &str, frames: &mut Vec<Frame>) {
+        if line.is_empty() {
+            self.dispatch(frames);
+            return;
+        }
+        if line.starts_with(':') {
+            return; // Comment / keepalive
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            _ => {} // "retry" and unknown fields
+        }
+    }
+
+    fn dispatch(&mut self, frames: &mut Vec<Frame>) {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return;
+        }
+
+        let data = std::mem::take(&mut self.data).join("\n");
+        frames.push(Frame::Sse {
+            event,
+            id: self.id.clone(), // The last event id carries over, as in EventSource
+            json: serde_json::from_str(&data).ok(),
+            data,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Framing::detect(Some("text/event-stream; charset=utf-8")), Framing::Sse);
+        assert_eq!(Framing::detect(Some("application/x-ndjson")), Framing::Ndjson);
+        assert_eq!(Framing::detect(Some("application/json")), Framing::Raw);
+        assert_eq!(Framing::detect(None), Framing::Raw);
+    }
+
+    #[test]
+    fn test_sse_split_across_chunks() {
+        let mut framer = Framer::new(Framing::Sse);
+        let mut frames = framer.push(": ping\r\nevent: message_start\r\ndata: {\"a\":");
+        assert!(frames.is_empty());
+        frames.extend(framer.push("1}\r\n\r\ndata: [DONE]\n"));
+        frames.extend(framer.finish());
+
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Sse {
+                    event: Some("message_start".to_string()),
+                    id: None,
+                    data: "{\"a\":1}".to_string(),
+                    json: Some(serde_json::json!({ "a": 1 })),
+                },
+                Frame::Sse {
+                    event: None,
+                    id: None,
+                    data: "[DONE]".to_string(),
+                    json: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sse_multiline_data() {
+        let mut framer = Framer::new(Framing::Sse);
+        let frames = framer.push("id: 7\ndata: first\ndata:second\n\n");
+        assert_eq!(
+            frames,
+            vec![Frame::Sse {
+                event: None,
+                id: Some("7".to_string()),
+                data: "first\nsecond".to_string(),
+                json: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let mut framer = Framer::new(Framing::Ndjson);
+        let mut frames = framer.push("{\"done\":false}\n\n{\"do");
+        frames.extend(framer.push("ne\":true}\nnot json"));
+        frames.extend(framer.finish());
+
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Json { value: serde_json::json!({ "done": false }) },
+                Frame::Json { value: serde_json::json!({ "done": true }) },
+                Frame::Line { line: "not json".to_string() },
+            ]
+        );
+    }
+}
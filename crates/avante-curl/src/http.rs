@@ -1,51 +1,70 @@
+use crate::body::{Compression, OutgoingBody};
 use crate::error::AvanteCurlError;
-use crate::session::Session;
-use crate::util::file;
+use crate::tls;
+use crate::util::net;
 use crate::RequestOptions;
 use anyhow::Result;
-use futures_util::stream::StreamExt;
 use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
-    Client, Method, RequestBuilder, Response, Url,
-};
-use std::{
-    path::Path,
-    sync::{atomic::AtomicBool, Arc},
-    time::Duration,
+    header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    Client, Method, Response, Url,
 };
+use std::{cell::RefCell, time::Duration};
+
+const MAX_REDIRECTS: usize = 10;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    // Hops followed by the request being sent on the current task. Clients
+    // are shared between requests, so the chain can't live on the client.
+    static REDIRECTS: RefCell<Vec<String>>;
+}
+
+// URLs visited while following redirects, attached to the final response
+#[derive(Debug, Clone, Default)]
+pub struct RedirectChain(pub Vec<String>);
 
 pub struct HttpClient {
     client: Client,
+    timeout: Duration, // Also applied to requests that bypass reqwest
 }
 
 impl HttpClient {
     pub fn new() -> Result<Self> {
         let client = Client::builder()
-            .timeout(Duration::from_secs(60))
+            .timeout(DEFAULT_TIMEOUT)
+            .redirect(Self::redirect_policy(true))
             .build()
             .map_err(AvanteCurlError::HttpError)?;
 
-        Ok(Self { client })
+        Ok(Self { client, timeout: DEFAULT_TIMEOUT })
+    }
+
+    // Redirect policy that records every hop so it can be reported back
+    fn redirect_policy(follow: bool) -> reqwest::redirect::Policy {
+        if !follow {
+            return reqwest::redirect::Policy::none();
+        }
+
+        reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            let _ = REDIRECTS.try_with(|redirects| redirects.borrow_mut().push(attempt.url().to_string()));
+            attempt.follow()
+        })
     }
 
     pub fn new_from_options(options: &RequestOptions) -> Result<Self> {
         let mut builder = Client::builder();
 
         // Set timeout
-        if let Some(timeout) = options.timeout {
-            builder = builder.timeout(Duration::from_secs(timeout));
-        } else {
-            builder = builder.timeout(Duration::from_secs(60));
-        }
+        let timeout = options.timeout.map(Duration::from_secs).unwrap_or(DEFAULT_TIMEOUT);
+        builder = builder.timeout(timeout);
 
         // Set redirect policy
-        if let Some(follow) = options.follow_redirects {
-            builder = if follow {
-                builder.redirect(reqwest::redirect::Policy::limited(10))
-            } else {
-                builder.redirect(reqwest::redirect::Policy::none())
-            };
-        }
+        let follow = options.follow_redirects.unwrap_or(true);
+        builder = builder.redirect(Self::redirect_policy(follow));
 
         // Set TLS verification
         if let Some(insecure) = options.insecure {
@@ -66,11 +85,16 @@ impl HttpClient {
             builder = builder.brotli(true);
         }
 
+        // Configure CA bundles, client identity, TLS version and pinning
+        if let Some(tls) = &options.tls {
+            let insecure = options.insecure.unwrap_or(false);
+            let http1_only = matches!(options.http_version.as_deref(), Some("1.0" | "1.1"));
+            builder = tls.apply(builder, insecure, http1_only)?;
+        }
+
         // Configure proxy if specified
         if let Some(proxy) = &options.proxy {
-            let proxy = reqwest::Proxy::all(proxy)
-                .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid proxy: {}", e)))?;
-            builder = builder.proxy(proxy);
+            builder = builder.proxy(proxy.to_reqwest()?);
         }
 
         // Configure HTTP version
@@ -83,10 +107,18 @@ impl HttpClient {
             }
         }
 
+        // Pin hostnames to specific addresses (curl's --resolve)
+        if let Some(entries) = &options.resolve {
+            for entry in entries {
+                let (host, addrs) = net::parse_resolve(entry).map_err(AvanteCurlError::InvalidConfig)?;
+                builder = builder.resolve_to_addrs(&host, &addrs);
+            }
+        }
+
         let client = builder.build()
             .map_err(|e| AvanteCurlError::HttpError(e))?;
 
-        Ok(Self { client })
+        Ok(Self { client, timeout })
     }
 
     pub async fn send_request(&self, options: RequestOptions) -> Result<Response> {
@@ -130,19 +162,6 @@ impl HttpClient {
             builder = builder.query(query);
         }
 
-        // Add request body
-        if let Some(body) = &options.body {
-            builder = match body {
-                crate::RequestBody::Raw(raw) => builder.body(raw.clone()),
-                crate::RequestBody::Json(json) => builder.json(json),
-                crate::RequestBody::File(path) => {
-                    let content = file::read_file(path)
-                        .map_err(|e| AvanteCurlError::IoError(e))?;
-                    builder.body(content)
-                }
-            };
-        }
-
         // Add form data
         if let Some(form) = &options.form {
             builder = builder.form(form);
@@ -190,108 +209,125 @@ impl HttpClient {
             }
         }
 
-        // Send the request
-        let response = builder.send().await.map_err(|e| {
-            REQUEST_MANAGER.set_error(request_id, &e.to_string());
-            AvanteCurlError::HttpError(e)
-        })?;
-
-        // Update request state to Receiving
-        REQUEST_MANAGER.set_response(request_id, response.status().as_u16(), HashMap::new(), "");
-        Ok(response)
-    }
+        // Add the request body; files and parts are streamed, not read up front
+        let mut body = None;
+        if let Some(request_body) = &options.body {
+            let compression = Compression::parse(options.compress.as_deref())?;
+            let outgoing = OutgoingBody::build(request_body, compression)?;
+            let has_header = |name: &str| {
+                options
+                    .headers
+                    .as_ref()
+                    .is_some_and(|headers| headers.keys().any(|key| key.eq_ignore_ascii_case(name)))
+            };
 
-    // Send a request with streaming response, passing chunks to the session
-    pub async fn send_stream_request(
-        &self,
-        options: RequestOptions,
-        session: Arc<Session>,
-        request_id: String,
-        cancel_flag: Arc<AtomicBool>,
-    ) -> Result<()> {
-        let response = self.send_request(options).await?;
-
-        // Process response headers
-        let mut headers_map = std::collections::HashMap::new();
-        for (key, value) in response.headers() {
-            if let Ok(val_str) = value.to_str() {
-                headers_map.insert(key.as_str().to_string(), val_str.to_string());
+            if matches!(request_body, crate::RequestBody::Json(_)) && !has_header("content-type") {
+                builder = builder.header(CONTENT_TYPE, "application/json");
             }
+            if let Some(compression) = compression {
+                builder = builder.header(CONTENT_ENCODING, compression.content_encoding());
+            }
+            if let (OutgoingBody::Stream { .. }, Some(length)) = (&outgoing, outgoing.length()) {
+                builder = builder.header(CONTENT_LENGTH, length);
+            }
+            body = Some(outgoing);
+        }
+
+        // Talk HTTP over a Unix domain socket instead of TCP
+        if let Some(socket_path) = &options.unix_socket {
+            let request = builder.build().map_err(AvanteCurlError::HttpError)?;
+            return self.send_unix_request(socket_path, request, body).await;
         }
 
-        // Set initial response with headers
-        let status = response.status().as_u16();
-        session.set_response(&request_id, status, headers_map.clone(), "");
-
-        // Create stream processor
-        let content_type = response
-            .headers()
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("");
-
-        let is_sse = content_type.contains("text/event-stream");
-        let mut body = response.bytes_stream();
-        let mut accumulated_data = String::new();
-        let mut buffer = Vec::new();
-
-        while let Some(chunk_result) = body.next().await {
-            // Check for cancellation
-            if session.should_cancel(&request_id) {
-                return Err(AvanteCurlError::Cancelled.into());
+        if let Some(body) = body {
+            builder = builder.body(body.into_reqwest());
+        }
+
+        // Send the request
+        let (result, redirects) = REDIRECTS
+            .scope(RefCell::new(Vec::new()), async {
+                let result = builder.send().await;
+                (result, REDIRECTS.with(RefCell::take))
+            })
+            .await;
+        let mut response = result.map_err(|e| {
+            if tls::is_handshake_error(&e) {
+                AvanteCurlError::Tls(tls::error_chain(&e))
+            } else {
+                AvanteCurlError::HttpError(e)
             }
+        })?;
+        response.extensions_mut().insert(RedirectChain(redirects));
 
-            let chunk = chunk_result?;
+        Ok(response)
+    }
 
-            if is_sse {
-                // Process SSE data
-                buffer.extend_from_slice(&chunk);
+    // Send a request over a Unix domain socket (curl's --unix-socket).
+    // The URL still provides the path and Host header. Redirects are not
+    // followed and bodies must be in memory.
+    #[cfg(unix)]
+    async fn send_unix_request(
+        &self,
+        socket_path: &str,
+        request: reqwest::Request,
+        body: Option<OutgoingBody>,
+    ) -> Result<Response> {
+        use reqwest::ResponseBuilderExt;
+
+        let stream = tokio::net::UnixStream::connect(socket_path)
+            .await
+            .map_err(AvanteCurlError::IoError)?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        let url = request.url().clone();
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
 
-                // Process complete lines
-                let mut start_idx = 0;
-                for i in 0..buffer.len() {
-                    if i + 1 < buffer.len() && buffer[i] == b'\n' && buffer[i+1] == b'\n' {
-                        // Found a complete SSE message
-                        if let Ok(data) = String::from_utf8(buffer[start_idx..i].to_vec()) {
-                            session.handle_stream_event(&request_id, &data);
-                        }
-                        start_idx = i + 2;
-                    }
-                    else if buffer[i] == b'\n' && start_idx < i {
-                        // Found a complete line
-                        if let Ok(line) = String::from_utf8(buffer[start_idx..i].to_vec()) {
-                            let line = line.trim();
-                            if line.starts_with("data:") {
-                                let data = line[5..].trim();
-                                session.handle_stream_event(&request_id, data);
-                            }
-                        }
-                        start_idx = i + 1;
-                    }
-                }
+        let mut builder = hyper::Request::builder()
+            .method(request.method().clone())
+            .uri(path_and_query);
+        for (name, value) in request.headers() {
+            builder = builder.header(name, value);
+        }
+        if !request.headers().contains_key(reqwest::header::HOST) {
+            builder = builder.header(reqwest::header::HOST, url.host_str().unwrap_or("localhost"));
+        }
 
-                // Keep remaining data
-                if start_idx < buffer.len() {
-                    buffer = buffer[start_idx..].to_vec();
-                } else {
-                    buffer.clear();
-                }
-            } else {
-                // Regular response - accumulate data
-                if let Ok(chunk_str) = String::from_utf8(chunk.to_vec()) {
-                    accumulated_data.push_str(&chunk_str);
-                    session.handle_stream_event(&request_id, &chunk_str);
-                }
-            }
+        // Form bodies were built by reqwest and are always in memory
+        let body = match (body, request.body().and_then(|body| body.as_bytes())) {
+            (Some(body), _) => body.into_hyper(),
+            (None, Some(bytes)) => hyper::Body::from(bytes.to_vec()),
+            (None, None) => hyper::Body::empty(),
+        };
+        let response = tokio::time::timeout(self.timeout, sender.send_request(builder.body(body)?))
+            .await
+            .map_err(|_| AvanteCurlError::Timeout)??;
+
+        // Hand back a regular reqwest::Response so callers don't need to care
+        let (parts, body) = response.into_parts();
+        let mut builder = hyper::Response::builder()
+            .status(parts.status)
+            .version(parts.version)
+            .url(url);
+        for (name, value) in &parts.headers {
+            builder = builder.header(name, value);
         }
 
-        // Complete the request with final data
-        session.set_response(&request_id, status, headers_map, &accumulated_data);
-        session.set_completed(&request_id);
+        Ok(Response::from(builder.body(reqwest::Body::wrap_stream(body))?))
+    }
 
-        Ok(())
+    #[cfg(not(unix))]
+    async fn send_unix_request(
+        &self,
+        _socket_path: &str,
+        _request: reqwest::Request,
+        _body: Option<OutgoingBody>,
+    ) -> Result<Response> {
+        Err(AvanteCurlError::InvalidConfig("Unix sockets are not supported on this platform".to_string()).into())
     }
 }
-
-
-
@@ -84,7 +84,7 @@ mod httpbin_tests {
             let options = RequestOptions {
                 url: "https://httpbin.org/post".to_string(),
                 method: Some("POST".to_string()),
-                body: Some(RequestBody::Json(json_data.clone())),
+                body: Some(RequestBody::json(&json_data).unwrap()),
                 ..Default::default()
             };
 
@@ -148,7 +148,7 @@ mod httpbin_tests {
             let options = RequestOptions {
                 url: "https://httpbin.org/put".to_string(),
                 method: Some("PUT".to_string()),
-                body: Some(RequestBody::Json(json_data)),
+                body: Some(RequestBody::json(&json_data).unwrap()),
                 ..Default::default()
             };
 
@@ -265,6 +265,13 @@ mod httpbin_tests {
 
             // Should follow redirects and eventually get 200
             assert_eq!(response.status().as_u16(), 200);
+
+            // The redirect chain and final URL are reported
+            let meta = crate::response::ResponseMeta::from_response(&response);
+            assert_eq!(meta.url, "https://httpbin.org/get");
+            assert_eq!(meta.redirects.len(), 2);
+            assert_eq!(meta.redirects.last().unwrap(), "https://httpbin.org/get");
+            assert!(meta.http_version.starts_with("HTTP/"));
         });
     }
 
@@ -365,7 +372,7 @@ mod httpbin_tests {
             let options = RequestOptions {
                 url: "https://httpbin.org/post".to_string(),
                 method: Some("POST".to_string()),
-                body: Some(RequestBody::Raw(raw_data.to_string())),
+                body: Some(RequestBody::Raw(raw_data.to_string().into())),
                 ..Default::default()
             };
 
@@ -1,49 +1,148 @@
 use anyhow::Result;
+use bytes::Bytes;
 use dashmap::DashMap;
 use mlua::{prelude::*, Lua};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
 use uuid::Uuid;
 
+mod batch;
+mod body;
+mod cache;
+pub mod cli;
 mod error;
+mod framing;
 mod http;
 mod httpbin_tests;
+mod local_tests;
+mod middleware;
+mod notify;
+mod proxy;
+mod response;
+mod scheduler;
 mod session;
+mod tls;
+mod token;
+mod usage;
 mod util;
+mod ws;
 
+use batch::{Batch, BatchOptions};
+use body::{BodyPart, Decoder};
+use cache::{CacheMode, CacheOptions, CachedResponse, HttpCache};
+use error::{error_info, AvanteCurlError, ErrorInfo};
+use framing::{Framer, Framing};
 use http::HttpClient;
-use session::{RequestManager, Session};
-
-// Global state management
-static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
-    tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(4)
-        .thread_name("avante-curl-worker")
-        .enable_all()
-        .build()
-        .expect("Failed to create tokio runtime")
-});
+use middleware::{Middleware, UsageCollector};
+use response::{JsonBody, ResponseHeaders, ResponseMeta};
+use proxy::ProxyOptions;
+use session::{RequestInfo, RequestState, RetryInfo, Session, StreamMessage};
+use tls::TlsOptions;
+use token::{TokenProvider, TokenProviderOptions};
+use usage::{ModelPrice, TokenUsage, UsageFormat};
+use ws::WsOptions;
+
+// Global state management. The runtime starts on first use and can be shut
+// down (and later restarted) with `shutdown`.
+static RUNTIME: Lazy<Mutex<Option<Runtime>>> = Lazy::new(|| Mutex::new(None));
+
+static RUNTIME_CONFIG: Lazy<Mutex<RuntimeConfig>> = Lazy::new(|| Mutex::new(RuntimeConfig::default()));
+
+// Settings for the shared runtime, applied when it starts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RuntimeConfig {
+    worker_threads: usize,
+    cleanup_interval: u64, // Seconds between janitor runs
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: 4,
+            cleanup_interval: 60,
+        }
+    }
+}
+
+impl FromLua for RuntimeConfig {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        let defaults = RuntimeConfig::default();
+        match value {
+            LuaValue::Nil => Ok(defaults),
+            LuaValue::Table(table) => {
+                let config = RuntimeConfig {
+                    worker_threads: table.get::<Option<usize>>("worker_threads")?.unwrap_or(defaults.worker_threads),
+                    cleanup_interval: table.get::<Option<u64>>("cleanup_interval")?.unwrap_or(defaults.cleanup_interval),
+                };
+                if config.worker_threads == 0 || config.cleanup_interval == 0 {
+                    return Err(LuaError::RuntimeError(
+                        "worker_threads and cleanup_interval must be at least 1".to_string(),
+                    ));
+                }
+                Ok(config)
+            }
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "RuntimeConfig".to_string(),
+                message: Some("Expected a table".to_string()),
+            }),
+        }
+    }
+}
+
+// Handle to the shared runtime, starting it (and its janitor) if needed
+fn runtime() -> tokio::runtime::Handle {
+    let mut runtime = RUNTIME.lock().unwrap();
+    runtime
+        .get_or_insert_with(|| {
+            let config = RUNTIME_CONFIG.lock().unwrap().clone();
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(config.worker_threads)
+                .thread_name("avante-curl-worker")
+                .enable_all()
+                .build()
+                .expect("Failed to create tokio runtime");
+            runtime.spawn(janitor(Duration::from_secs(config.cleanup_interval)));
+            runtime
+        })
+        .handle()
+        .clone()
+}
+
+// Periodically drop idle and acknowledged requests, even if nobody polls
+async fn janitor(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for session in SESSIONS.iter() {
+            session.cleanup();
+        }
+    }
+}
 
 static SESSIONS: Lazy<DashMap<String, Arc<Session>>> = Lazy::new(|| {
     DashMap::new()
 });
 
-pub fn request_manager_status() -> String {
-    let sessions_count = SESSIONS.len();
-    format!("Active sessions: {}\n", sessions_count)
-}
-
-// Request types
-#[derive(Debug, Serialize, Deserialize)]
+// Request types. From JSON, they take the same shapes as the `_options` table
+// the Lua client builds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct RequestOptions {
     url: String,
     method: Option<String>,
+    #[serde(deserialize_with = "string_map")]
     headers: Option<HashMap<String, String>>,
     body: Option<RequestBody>,
+    #[serde(deserialize_with = "string_map")]
     query: Option<HashMap<String, String>>,
+    #[serde(deserialize_with = "string_map")]
     form: Option<HashMap<String, String>>,
     auth: Option<AuthInfo>,
     timeout: Option<u64>,
@@ -51,14 +150,146 @@ struct RequestOptions {
     output: Option<String>,
     follow_redirects: Option<bool>,
     insecure: Option<bool>,
-    proxy: Option<String>,
+    proxy: Option<ProxyOptions>,
     compressed: Option<bool>,
     raw: Option<Vec<String>>,
     http_version: Option<String>,
+    unix_socket: Option<String>,  // Path of a Unix domain socket to connect through
+    #[serde(deserialize_with = "string_list")]
+    resolve: Option<Vec<String>>, // curl-style HOST:PORT:ADDR overrides
+    tls: Option<TlsOptions>,
+    token_provider: Option<String>, // Name of a session token provider to authenticate with
+    response_type: Option<String>,  // "text" (default) or "json" to decode the body on the worker thread
+    json_null: Option<String>,      // How JSON null reaches Lua: "vim.NIL" (default) or "nil"
+    max_response_bytes: Option<u64>, // Stop reading the body after this many bytes
+    max_buffered_bytes: Option<u64>, // Keep at most this much of the body in memory
+    on_limit: Option<String>,        // "error" (default) or "truncate" when a limit is hit
+    cache: Option<String>,           // "default", "force" or "bypass" with a session cache
+    priority: Option<i32>,           // Higher starts first when the session is at max_concurrent (default 0)
+    usage_format: Option<String>,    // "auto", "openai", "anthropic" or "gemini" to account token usage
+    model: Option<String>,           // Model to price usage with; defaults to the JSON body's "model"
+    compress: Option<String>,        // "gzip" or "zstd" to compress the request body
+    framing: Option<String>,         // "auto", "sse", "ndjson" or "raw" to split the body into events
+    retries: Option<u32>,            // Retry retryable failures up to this many times
+}
+
+impl RequestOptions {
+    // Fill in anything the request didn't set from the session defaults
+    fn apply_session_defaults(&mut self, defaults: &SessionOptions) {
+        if self.unix_socket.is_none() {
+            self.unix_socket = defaults.unix_socket.clone();
+        }
+        if self.proxy.is_none() {
+            self.proxy = defaults.proxy.clone();
+        }
+        if let Some(resolve) = &defaults.resolve {
+            // Request-level entries take precedence, so they go last
+            let mut merged = resolve.clone();
+            merged.extend(self.resolve.take().unwrap_or_default());
+            self.resolve = Some(merged);
+        }
+        if let Some(tls_defaults) = &defaults.tls {
+            let mut tls = self.tls.take().unwrap_or_default();
+            tls.merge_defaults(tls_defaults);
+            self.tls = Some(tls);
+        }
+    }
+}
+
+// Defaults shared by every request of a session
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SessionOptions {
+    unix_socket: Option<String>,
+    resolve: Option<Vec<String>>,
+    tls: Option<TlsOptions>,
+    proxy: Option<ProxyOptions>,
+    middleware: Option<Vec<Middleware>>,
+    cache: Option<CacheOptions>,
+    max_concurrent: Option<usize>, // Requests in flight at once; the rest wait by priority
+    preempt: Option<bool>,         // Let higher-priority requests bump lower ones at the limit
+    pricing: Option<HashMap<String, ModelPrice>>, // Per-model prices for usage cost estimates
+}
+
+impl FromLua for SessionOptions {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(SessionOptions::default()),
+            LuaValue::Table(table) => {
+                let mut options = SessionOptions::default();
+                for pair in table.pairs::<String, LuaValue>() {
+                    let (key, value) = pair?;
+                    match key.as_str() {
+                        "unix_socket" => options.unix_socket = Some(value.to_string().unwrap_or_default()),
+                        "resolve" => options.resolve = Some(lua_string_list(value)?),
+                        "tls" => options.tls = Some(TlsOptions::from_lua(value, lua)?),
+                        "proxy" => options.proxy = Some(ProxyOptions::from_lua(value, lua)?),
+                        "middleware" => options.middleware = Some(Vec::<Middleware>::from_lua(value, lua)?),
+                        "cache" => options.cache = Some(CacheOptions::from_lua(value, lua)?),
+                        "max_concurrent" => {
+                            let max_concurrent = usize::from_lua(value, lua)?;
+                            if max_concurrent == 0 {
+                                return Err(LuaError::RuntimeError("max_concurrent must be at least 1".to_string()));
+                            }
+                            options.max_concurrent = Some(max_concurrent);
+                        }
+                        "preempt" => options.preempt = Some(bool::from_lua(value, lua)?),
+                        "pricing" => options.pricing = Some(HashMap::<String, ModelPrice>::from_lua(value, lua)?),
+                        _ => {}
+                    }
+                }
+                Ok(options)
+            }
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "SessionOptions".to_string(),
+                message: Some("Expected a table".to_string()),
+            }),
+        }
+    }
+}
+
+// Accept either a single string or a list of strings
+fn lua_string_list(value: LuaValue) -> LuaResult<Vec<String>> {
+    match value {
+        LuaValue::String(s) => Ok(vec![s.to_str()?.to_string()]),
+        LuaValue::Table(t) => t.sequence_values::<String>().collect(),
+        _ => Err(LuaError::RuntimeError("Expected a string or a list of strings".to_string())),
+    }
+}
+
+// `lua_string_list` for JSON
+fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Option::<StringList>::deserialize(deserializer)?.map(|list| match list {
+        StringList::One(s) => vec![s],
+        StringList::Many(list) => list,
+    }))
+}
+
+// A string map from JSON. Lua encodes an empty table as `[]`, so that's
+// accepted as an empty map.
+fn string_map<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<HashMap<String, String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringMap {
+        Map(HashMap<String, String>),
+        Empty([(); 0]),
+    }
+
+    Ok(Option::<StringMap>::deserialize(deserializer)?.map(|map| match map {
+        StringMap::Map(map) => map,
+        StringMap::Empty(_) => HashMap::new(),
+    }))
 }
 
 impl FromLua for RequestOptions {
-    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
         if let LuaValue::Table(table) = value {
             let mut options = RequestOptions::default();
             for pair in table.pairs::<String, LuaValue>() {
@@ -76,6 +307,24 @@ impl FromLua for RequestOptions {
                             );
                         }
                     }
+                    "unix_socket" => options.unix_socket = Some(value.to_string().unwrap_or_default()),
+                    "resolve" => options.resolve = Some(lua_string_list(value)?),
+                    "tls" => options.tls = Some(TlsOptions::from_lua(value, lua)?),
+                    "proxy" => options.proxy = Some(ProxyOptions::from_lua(value, lua)?),
+                    "token_provider" => options.token_provider = Some(value.to_string().unwrap_or_default()),
+                    "response_type" => options.response_type = Some(value.to_string().unwrap_or_default()),
+                    "json_null" => options.json_null = Some(value.to_string().unwrap_or_default()),
+                    "max_response_bytes" => options.max_response_bytes = Some(u64::from_lua(value, lua)?),
+                    "max_buffered_bytes" => options.max_buffered_bytes = Some(u64::from_lua(value, lua)?),
+                    "on_limit" => options.on_limit = Some(value.to_string().unwrap_or_default()),
+                    "cache" => options.cache = Some(value.to_string().unwrap_or_default()),
+                    "priority" => options.priority = Some(i32::from_lua(value, lua)?),
+                    "usage_format" => options.usage_format = Some(value.to_string().unwrap_or_default()),
+                    "model" => options.model = Some(value.to_string().unwrap_or_default()),
+                    "body" => options.body = Some(RequestBody::from_lua(value, lua)?),
+                    "compress" => options.compress = Some(value.to_string().unwrap_or_default()),
+                    "framing" => options.framing = Some(value.to_string().unwrap_or_default()),
+                    "retries" => options.retries = Some(u32::from_lua(value, lua)?),
                     // Handle other fields similarly...
                     _ => {}
                 }
@@ -110,33 +359,42 @@ impl Default for RequestOptions {
             compressed: None,
             raw: None,
             http_version: None,
+            unix_socket: None,
+            resolve: None,
+            tls: None,
+            token_provider: None,
+            response_type: None,
+            json_null: None,
+            max_response_bytes: None,
+            max_buffered_bytes: None,
+            on_limit: None,
+            cache: None,
+            priority: None,
+            usage_format: None,
+            model: None,
+            compress: None,
+            framing: None,
+            retries: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// In-memory bodies are refcounted, so every attempt shares them. Deserialized
+// like the Lua tables, see body.rs.
+#[derive(Debug, Clone, Serialize)]
 enum RequestBody {
-    Raw(String),
-    Json(serde_json::Value),
-    File(String),
+    Raw(#[serde(serialize_with = "body::serialize_text")] Bytes),
+    Json(#[serde(serialize_with = "body::serialize_text")] Bytes), // Encoded once, see `RequestBody::json`
+    File(String),          // Streamed from disk
+    Parts(Vec<BodyPart>),  // Sent back to back, without concatenating
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct AuthInfo {
     username: String,
     password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ResponseInfo {
-    request_id: String,
-    status: Option<u16>,
-    headers: Option<HashMap<String, String>>,
-    body: Option<String>,
-    error: Option<String>,
-    completed: bool,
-}
-
 // Lua module functions
 #[mlua::lua_module]
 fn avante_curl(lua: &Lua) -> LuaResult<LuaTable> {
@@ -146,6 +404,8 @@ fn avante_curl(lua: &Lua) -> LuaResult<LuaTable> {
     // Register functions
     exports.set("create_session", lua.create_function(create_session)?)?;
     exports.set("destroy_session", lua.create_function(destroy_session)?)?;
+    exports.set("configure", lua.create_function(configure)?)?;
+    exports.set("shutdown", lua.create_function(shutdown)?)?;
     exports.set("request", lua.create_function(request)?)?;
     exports.set("get", lua.create_function(get)?)?;
     exports.set("post", lua.create_function(post)?)?;
@@ -154,15 +414,44 @@ fn avante_curl(lua: &Lua) -> LuaResult<LuaTable> {
     exports.set("head", lua.create_function(head)?)?;
     exports.set("patch", lua.create_function(patch)?)?;
     exports.set("get_status", lua.create_function(get_status)?)?;
+    exports.set("take_messages", lua.create_function(take_messages)?)?;
+    exports.set("take_events", lua.create_function(take_events)?)?;
     exports.set("cancel_request", lua.create_function(cancel_request)?)?;
+    exports.set("acknowledge_request", lua.create_function(acknowledge_request)?)?;
+    exports.set("usage", lua.create_function(usage)?)?;
+    exports.set("request_batch", lua.create_function(request_batch)?)?;
+    exports.set("get_batch_status", lua.create_function(get_batch_status)?)?;
+    exports.set("cancel_batch", lua.create_function(cancel_batch)?)?;
+    exports.set("event_fd", lua.create_function(event_fd)?)?;
+    exports.set("drain_events", lua.create_function(drain_events)?)?;
+    exports.set("register_token_provider", lua.create_function(register_token_provider)?)?;
+    exports.set("invalidate_token", lua.create_function(invalidate_token)?)?;
+    exports.set("ws_connect", lua.create_function(ws_connect)?)?;
+    exports.set("ws_send", lua.create_function(ws_send)?)?;
+    exports.set("ws_close", lua.create_function(ws_close)?)?;
+    exports.set("await_request", lua.create_async_function(await_request)?)?;
+    exports.set("next_chunk", lua.create_async_function(next_chunk)?)?;
+    // Yielded by the async functions while they wait; resume the coroutine on the next event
+    exports.set("poll_pending", Lua::poll_pending())?;
 
     Ok(exports)
 }
 
 // Create a new session
-fn create_session(_: &Lua, _: ()) -> LuaResult<String> {
+fn create_session(_: &Lua, options: SessionOptions) -> LuaResult<String> {
     let session_id = Uuid::new_v4().to_string();
-    let session = Arc::new(Session::new());
+    let cache = match &options.cache {
+        Some(cache_options) => Some(HttpCache::open(cache_options).map_err(|e| {
+            LuaError::RuntimeError(format!("Failed to open cache '{}': {}", cache_options.dir, e))
+        })?),
+        None => None,
+    };
+
+    let mut session = Session::with_options(options);
+    if let Some(cache) = cache {
+        session = session.with_cache(cache);
+    }
+    let session = Arc::new(session);
     SESSIONS.insert(session_id.clone(), session);
     Ok(session_id)
 }
@@ -172,28 +461,222 @@ fn destroy_session(_: &Lua, session_id: String) -> LuaResult<bool> {
     Ok(SESSIONS.remove(&session_id).is_some())
 }
 
+// Set the worker thread count and janitor interval. Takes effect the next
+// time the runtime starts, i.e. before the first request or after `shutdown`.
+fn configure(_: &Lua, config: RuntimeConfig) -> LuaResult<bool> {
+    *RUNTIME_CONFIG.lock().unwrap() = config;
+    Ok(RUNTIME.lock().unwrap().is_none())
+}
+
+// Cancel everything in flight, drop all sessions and stop the runtime, waiting
+// at most `timeout_ms` for tasks to wind down. Meant for VimLeavePre.
+fn shutdown(_: &Lua, timeout_ms: Option<u64>) -> LuaResult<bool> {
+    for session in SESSIONS.iter() {
+        session.cancel_all();
+    }
+    SESSIONS.clear();
+
+    let runtime = RUNTIME.lock().unwrap().take();
+    match runtime {
+        Some(runtime) => {
+            runtime.shutdown_timeout(Duration::from_millis(timeout_ms.unwrap_or(1000)));
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
 // Make a request with given options
 fn request(_: &Lua, (session_id, request_id, options): (String, String, LuaTable)) -> LuaResult<String> {
-    let req_options: RequestOptions = options
+    start_request(&session_id, &request_id, options, false)?;
+    Ok(request_id)
+}
+
+// Register the request with the session and run it on the runtime
+fn start_request(
+    session_id: &str,
+    request_id: &str,
+    options: LuaTable,
+    queue_chunks: bool,
+) -> LuaResult<Arc<Session>> {
+    let mut req_options: RequestOptions = options
         .get("_options")
         .map_err(|_| LuaError::RuntimeError("Invalid options".to_string()))?;
 
     // Get the session
+    let session = SESSIONS
+        .get(session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?
+        .clone();
+
+    req_options.apply_session_defaults(session.options());
+
+    session.init_request(request_id).map_err(LuaError::RuntimeError)?;
+    if queue_chunks {
+        session.queue_chunks(request_id);
+    }
+
+    let task_session = session.clone();
+    let cloned_id = request_id.to_string();
+
+    runtime().spawn(async move {
+        run_scheduled(&task_session, &cloned_id, req_options).await;
+    });
+
+    Ok(session)
+}
+
+// Start several requests at once and return the batch id. Sub-request ids
+// are "<batch_id>:<n>", in the order given.
+fn request_batch(
+    _: &Lua,
+    (session_id, requests, options): (String, Vec<LuaTable>, BatchOptions),
+) -> LuaResult<String> {
     let session = SESSIONS
         .get(&session_id)
         .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?
         .clone();
 
-    let cloned_id = request_id.clone();
+    let mut batch_options = Vec::with_capacity(requests.len());
+    for request in requests {
+        let mut req_options: RequestOptions = request
+            .get("_options")
+            .map_err(|_| LuaError::RuntimeError("Invalid options".to_string()))?;
+        req_options.apply_session_defaults(session.options());
+        batch_options.push(req_options);
+    }
+
+    let batch_id = Uuid::new_v4().to_string();
+    let batch = Batch::new(&batch_id, batch_options.len());
+    for request_id in &batch.request_ids {
+        session.init_request(request_id).map_err(LuaError::RuntimeError)?;
+    }
+    let batch = session.add_batch(&batch_id, batch);
+
+    spawn_batch(session, &batch_id, &batch, batch_options, options);
+    Ok(batch_id)
+}
 
-    RUNTIME.spawn(async move {
-        if let Err(e) = execute_request(&session, &cloned_id, req_options).await {
-            session.set_error(&cloned_id, &e.to_string());
+// Run the sub-requests of a batch, at most `concurrency` at a time
+fn spawn_batch(
+    session: Arc<Session>,
+    batch_id: &str,
+    batch: &Batch,
+    requests: Vec<RequestOptions>,
+    options: BatchOptions,
+) {
+    let permits = Arc::new(Semaphore::new(options.concurrency.unwrap_or(requests.len()).max(1)));
+    let request_ids = batch.request_ids.clone();
+    let batch_id = batch_id.to_string();
+    let fail_fast = options.fail_fast;
+
+    // Hand out the slots from one task so sub-requests start in batch order
+    runtime().spawn(async move {
+        for (request_id, req_options) in request_ids.into_iter().zip(requests) {
+            let permit = match permits.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+
+            // Cancelled while waiting for a slot
+            if session.should_cancel(&request_id) {
+                continue;
+            }
+
+            let session = session.clone();
+            let batch_id = batch_id.clone();
+            runtime().spawn(async move {
+                let _permit = permit;
+                if !run_scheduled(&session, &request_id, req_options).await && fail_fast {
+                    session.cancel_batch(&batch_id);
+                }
+            });
         }
-        session.set_completed(&cloned_id);
     });
+}
+
+// Status of every sub-request of a batch plus the aggregate state. A finished
+// batch is reported once; after that its id is unknown.
+fn get_batch_status(lua: &Lua, (session_id, batch_id): (String, String)) -> LuaResult<LuaTable> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+    let infos = session
+        .collect_batch(&batch_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Batch not found: {}", batch_id)))?;
+
+    let states: Vec<RequestState> = infos.iter().map(|info| info.state).collect();
+
+    let table = lua.create_table()?;
+    table.set("id", batch_id)?;
+    table.set("state", lua.to_value(&Batch::state(&states))?)?;
+    table.set("total", states.len())?;
+    table.set("finished", states.iter().filter(|state| state.is_terminal()).count())?;
+
+    let requests = lua.create_table()?;
+    for info in &infos {
+        let entry = response_table(lua, info)?;
+        set_body(lua, &entry, info)?;
+        requests.push(entry)?;
+    }
+    table.set("requests", requests)?;
+
+    Ok(table)
+}
+
+// Cancel every unfinished sub-request of a batch
+fn cancel_batch(_: &Lua, (session_id, batch_id): (String, String)) -> LuaResult<bool> {
+    let session = match SESSIONS.get(&session_id) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
 
-    Ok(request_id.clone())
+    Ok(session.cancel_batch(&batch_id))
+}
+
+// Start a request and yield the calling coroutine until its headers arrive.
+// Returns the response (without body) or nil and the error.
+async fn await_request(lua: Lua, (session_id, options): (String, LuaTable)) -> LuaResult<(LuaValue, LuaValue)> {
+    let request_id = match options.get::<Option<String>>("id")? {
+        Some(id) => id,
+        None => Uuid::new_v4().to_string(),
+    };
+    let session = start_request(&session_id, &request_id, options, true)?;
+
+    let info = session
+        .wait_until(|| {
+            let info = session.get_response(&request_id);
+            (info.status.is_some() || info.state.is_terminal()).then_some(info)
+        })
+        .await;
+
+    match (&info.status, &info.error) {
+        (None, Some(error)) => Ok((LuaValue::Nil, lua.to_value(error)?)),
+        _ => Ok((LuaValue::Table(response_table(&lua, &info)?), LuaValue::Nil)),
+    }
+}
+
+// Yield until the next chunk of a request started with `await_request`.
+// Returns nil once the response is finished; check `get_status` for errors.
+async fn next_chunk(lua: Lua, (session_id, request_id): (String, String)) -> LuaResult<Option<LuaString>> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?
+        .clone();
+
+    let chunk = session
+        .wait_until(|| match session.pop_message(&request_id) {
+            Some(message) => Some(Some(message)),
+            None if session.get_response(&request_id).state.is_terminal() => Some(None),
+            None => None,
+        })
+        .await;
+
+    match chunk {
+        Some(StreamMessage::Text(text)) => Ok(Some(lua.create_string(&text)?)),
+        Some(StreamMessage::Binary(data)) => Ok(Some(lua.create_string(&data)?)),
+        Some(StreamMessage::Close { .. }) | None => Ok(None),
+    }
 }
 
 // Convenience function for GET requests
@@ -209,8 +692,7 @@ fn get(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>)) -
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
-    Ok(request_id)
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Convenience function for POST requests
@@ -226,7 +708,7 @@ fn post(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>))
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Convenience function for PUT requests
@@ -242,7 +724,7 @@ fn put(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>)) -
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Convenience function for DELETE requests
@@ -258,7 +740,7 @@ fn delete(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>)
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Convenience function for HEAD requests
@@ -274,7 +756,7 @@ fn head(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>))
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Convenience function for PATCH requests
@@ -290,16 +772,11 @@ fn patch(lua: &Lua, (session_id, url, opts): (String, String, Option<LuaTable>))
     // Generate a unique request ID
     let request_id = format!("{}", Uuid::new_v4());
 
-    request(lua, (session_id, request_id, opts_table))?
+    request(lua, (session_id, request_id, opts_table))
 }
 
 // Get status of a request
 fn get_status(lua: &Lua, (session_id, request_id): (String, String)) -> LuaResult<LuaTable> {
-    // print all the sessions
-    SESSIONS.iter().for_each(|session| {
-        println!("Session ID: {} - {:?}", session.key(), session.value());
-    });
-
     // Check if the session exists
     if !SESSIONS.contains_key(&session_id) {
         return Err(LuaError::RuntimeError(format!(
@@ -315,45 +792,137 @@ fn get_status(lua: &Lua, (session_id, request_id): (String, String)) -> LuaResul
 
     let response_info = session.get_response(&request_id);
 
-    let response = match serde_json::to_string(&response_info) {
-        Ok(json) => json,
-        Err(e) => {
-            return Err(LuaError::RuntimeError(format!(
-                "Failed to serialize response info: {}",
-                e
-            )))
+    let table = response_table(lua, &response_info)?;
+    set_body(lua, &table, &response_info)?;
+
+    // Messages received on a WebSocket are left queued; `take_messages` consumes them
+    let pending = session.message_count(&request_id);
+    if pending > 0 {
+        table.set("pending_messages", pending)?;
+    }
+    // Likewise for events framed from the body; `take_events` consumes them
+    let pending = session.event_count(&request_id);
+    if pending > 0 {
+        table.set("pending_events", pending)?;
+    }
+
+    Ok(table)
+}
+
+// Drain the events framed from the body of a request with `framing`:
+// `{ type = "sse", event, id, data, json }`, `{ type = "json", value }`,
+// `{ type = "line", line }` or `{ type = "chunk", data }`
+fn take_events(lua: &Lua, (session_id, request_id): (String, String)) -> LuaResult<LuaTable> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+
+    let list = lua.create_table()?;
+    for frame in session.take_events(&request_id) {
+        let value = serde_json::to_value(&frame).map_err(LuaError::external)?;
+        // JSON payloads decode like `response_type = "json"`, nulls as vim.NIL
+        list.push(JsonBody { value, null_as_nil: false }.to_lua(lua)?)?;
+    }
+
+    Ok(list)
+}
+
+// Drain the messages received on a WebSocket since the last call
+fn take_messages(lua: &Lua, (session_id, request_id): (String, String)) -> LuaResult<LuaTable> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+
+    let list = lua.create_table()?;
+    for message in session.take_messages(&request_id) {
+        let entry = lua.create_table()?;
+        match message {
+            StreamMessage::Text(text) => {
+                entry.set("type", "text")?;
+                entry.set("data", text)?;
+            }
+            StreamMessage::Binary(data) => {
+                entry.set("type", "binary")?;
+                entry.set("data", lua.create_string(&data)?)?;
+            }
+            StreamMessage::Close { code, reason } => {
+                entry.set("type", "close")?;
+                entry.set("code", code)?;
+                entry.set("reason", reason)?;
+            }
         }
-    };
+        list.push(entry)?;
+    }
 
-    // Convert the ResponseInfo to a Lua table directly
+    Ok(list)
+}
+
+// Request state, status, headers, connection details and error as a Lua table
+fn response_table(lua: &Lua, response_info: &RequestInfo) -> LuaResult<LuaTable> {
     let table = lua.create_table()?;
+    table.set("id", response_info.request_id.clone())?;
+    table.set("state", lua.to_value(&response_info.state)?)?;
+    table.set("truncated", response_info.truncated)?;
+    table.set("received_bytes", response_info.received_bytes)?;
 
-    // Add fields from ResponseInfo to the table
     if let Some(status) = response_info.status {
         table.set("status", status)?;
     }
 
     if let Some(headers) = &response_info.headers {
-        let headers_table = lua.create_table()?;
-        for (k, v) in headers {
-            headers_table.set(k.clone(), v.clone())?;
-        }
-        table.set("headers", headers_table)?;
+        table.set("headers", headers.to_lua_table(lua)?)?;
+        table.set("header_list", headers.to_lua_list(lua)?)?;
     }
 
-    if let Some(body) = &response_info.body {
-        table.set("body", body.clone())?;
+    if let Some(meta) = &response_info.meta {
+        table.set("url", meta.url.clone())?;
+        table.set("redirects", meta.redirects.clone())?;
+        table.set("http_version", meta.http_version.clone())?;
+        if let Some(remote_addr) = &meta.remote_addr {
+            table.set("remote_addr", remote_addr.clone())?;
+        }
     }
 
     if let Some(error) = &response_info.error {
-        table.set("error", error.clone())?;
+        table.set("error", lua.to_value(error)?)?;
     }
 
-    table.set("completed", response_info.completed)?;
+    if let Some(sent) = &response_info.sent {
+        table.set("sent", lua.to_value(sent)?)?;
+    }
+
+    if let Some(usage) = &response_info.usage {
+        table.set("usage", lua.to_value(usage)?)?;
+    }
+
+    if let Some(token_usage) = &response_info.token_usage {
+        table.set("token_usage", lua.to_value(token_usage)?)?;
+    }
+
+    if let Some(cache) = &response_info.cache {
+        table.set("cache", cache.clone())?;
+    }
+
+    if response_info.restarts > 0 {
+        table.set("restarts", response_info.restarts)?;
+    }
+
+    if let Some(retry) = &response_info.retry {
+        table.set("retry", lua.to_value(retry)?)?;
+    }
 
     Ok(table)
 }
 
+// Decoded JSON goes to `json` and the text body is skipped; otherwise `body`
+fn set_body(lua: &Lua, table: &LuaTable, response_info: &RequestInfo) -> LuaResult<()> {
+    match (&response_info.json, &response_info.body) {
+        (Some(json), _) => table.set("json", json.to_lua(lua)?),
+        (None, Some(body)) => table.set("body", body.clone()),
+        (None, None) => Ok(()),
+    }
+}
+
 // Cancel an in-progress request
 fn cancel_request(_: &Lua, (session_id, request_id): (String, String)) -> LuaResult<bool> {
     let session = match SESSIONS.get(&session_id) {
@@ -365,37 +934,560 @@ fn cancel_request(_: &Lua, (session_id, request_id): (String, String)) -> LuaRes
     Ok(true)
 }
 
-// Execute the request asynchronously
+// Tell the backend a finished request was handled, so it can be dropped
+fn acknowledge_request(_: &Lua, (session_id, request_id): (String, String)) -> LuaResult<bool> {
+    match SESSIONS.get(&session_id) {
+        Some(session) => Ok(session.acknowledge_request(&request_id)),
+        None => Ok(false),
+    }
+}
+
+// Token usage of the session so far, overall and per model
+fn usage(lua: &Lua, session_id: String) -> LuaResult<LuaValue> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+    lua.to_value(&session.usage())
+}
+
+// Get a file descriptor that becomes readable when the session has new events.
+// Returns nil when event notification is unavailable (e.g. on Windows), in
+// which case callers should fall back to polling.
+fn event_fd(_: &Lua, session_id: String) -> LuaResult<Option<i64>> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+
+    #[cfg(unix)]
+    {
+        Ok(session.event_notifier().map(|notifier| i64::from(notifier.fd())))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = session;
+        Ok(None)
+    }
+}
+
+// Acknowledge a wakeup from the event fd. Returns true if events were pending.
+fn drain_events(_: &Lua, session_id: String) -> LuaResult<bool> {
+    let session = match SESSIONS.get(&session_id) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+
+    Ok(session.event_notifier().map(|notifier| notifier.drain()).unwrap_or(false))
+}
+
+// Register a named token provider; requests opt in with `token_provider = name`
+fn register_token_provider(
+    _: &Lua,
+    (session_id, name, options): (String, String, TokenProviderOptions),
+) -> LuaResult<bool> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?;
+
+    session.register_token_provider(&name, TokenProvider::new(options));
+    Ok(true)
+}
+
+// Drop a cached token so the next request fetches a fresh one
+fn invalidate_token(_: &Lua, (session_id, name): (String, String)) -> LuaResult<bool> {
+    let provider = match SESSIONS.get(&session_id).and_then(|s| s.token_provider(&name)) {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+
+    // Don't block the editor if a refresh is in flight
+    runtime().spawn(async move { provider.invalidate(None).await });
+    Ok(true)
+}
+
+// Open a WebSocket connection. Incoming messages are read with `take_messages`.
+fn ws_connect(_: &Lua, (session_id, url, options): (String, String, WsOptions)) -> LuaResult<String> {
+    let session = SESSIONS
+        .get(&session_id)
+        .ok_or_else(|| LuaError::RuntimeError(format!("Session not found: {}", session_id)))?
+        .clone();
+
+    let connection_id = Uuid::new_v4().to_string();
+    session.init_request(&connection_id).map_err(LuaError::RuntimeError)?;
+    session.set_long_lived(&connection_id);
+
+    let (handle, commands) = ws::channel();
+    session.add_websocket(&connection_id, handle);
+
+    let cloned_id = connection_id.clone();
+    runtime().spawn(async move {
+        if let Err(e) = ws::run(session.clone(), cloned_id.clone(), url, options, commands).await {
+            session.set_error(&cloned_id, ErrorInfo::from(&e));
+        }
+        session.remove_websocket(&cloned_id);
+        session.set_completed(&cloned_id);
+    });
+
+    Ok(connection_id)
+}
+
+// Send a text (default) or binary message on an open WebSocket
+fn ws_send(
+    _: &Lua,
+    (session_id, connection_id, data, binary): (String, String, LuaString, Option<bool>),
+) -> LuaResult<bool> {
+    let session = match SESSIONS.get(&session_id) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+
+    let sent = if binary.unwrap_or(false) {
+        let bytes = data.as_bytes().to_vec();
+        session.with_websocket(&connection_id, |ws| ws.send_binary(bytes))
+    } else {
+        let text = data.to_str()?.to_string();
+        session.with_websocket(&connection_id, |ws| ws.send_text(text))
+    };
+
+    Ok(sent.unwrap_or(false))
+}
+
+// Start the closing handshake with an optional close code (default 1000) and reason
+fn ws_close(
+    _: &Lua,
+    (session_id, connection_id, code, reason): (String, String, Option<u16>, Option<String>),
+) -> LuaResult<bool> {
+    let session = match SESSIONS.get(&session_id) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+
+    let closed = session.with_websocket(&connection_id, |ws| {
+        ws.close(code.unwrap_or(1000), reason.unwrap_or_default())
+    });
+    Ok(closed.unwrap_or(false))
+}
+
+// Run a request once the session scheduler gives it a slot and mark it
+// complete. Retryable failures go back to the queue after a backoff, up to
+// `retries` times. Returns false if the request failed.
+async fn run_scheduled(session: &Session, request_id: &str, options: RequestOptions) -> bool {
+    let retries = options.retries.unwrap_or(0);
+    let mut attempt = 0;
+    let error = loop {
+        let error = match run_attempt(session, request_id, &options).await {
+            Ok(()) => break None,
+            Err(e) => error_info(&e),
+        };
+        if !error.retryable || attempt >= retries || session.should_cancel(request_id) {
+            break Some(error);
+        }
+
+        attempt += 1;
+        let delay = retry_delay(&error, attempt);
+        if !session.retry(request_id, RetryInfo { attempt, delay, error: error.clone() }) {
+            break Some(error);
+        }
+        // A cancel ends the backoff early; the scheduler then turns it away
+        let cancelled = session.wait_until(|| session.should_cancel(request_id).then_some(()));
+        let _ = tokio::time::timeout(Duration::from_secs_f64(delay), cancelled).await;
+    };
+
+    let failed = error.is_some();
+    if let Some(error) = error {
+        session.set_error(request_id, error);
+    }
+    session.set_completed(request_id);
+    !failed
+}
+
+// Seconds to wait before retry `attempt`: what the server asked for, or
+// exponential backoff from one second, capped at 32
+fn retry_delay(error: &ErrorInfo, attempt: u32) -> f64 {
+    error.retry_after.unwrap_or_else(|| f64::from(1u32 << (attempt - 1).min(5)))
+}
+
+// One attempt, from waiting for a slot to the end of the response. A
+// preempted request starts over from the queue, unless its response has
+// already started.
+async fn run_attempt(session: &Session, request_id: &str, options: &RequestOptions) -> Result<(), anyhow::Error> {
+    let priority = options.priority.unwrap_or(0);
+    'attempts: loop {
+        // None: cancelled while queued
+        let mut slot = match session.scheduler().acquire(request_id, priority).await {
+            Some(slot) if !session.should_cancel(request_id) => slot,
+            _ => return Ok(()),
+        };
+
+        let execution = execute_request(session, request_id, options.clone());
+        tokio::pin!(execution);
+        let mut preemptible = true;
+        loop {
+            tokio::select! {
+                result = &mut execution => return result,
+                _ = slot.preempted(), if preemptible => {
+                    if session.should_cancel(request_id) {
+                        return Ok(());
+                    }
+                    if session.requeue(request_id) {
+                        continue 'attempts;
+                    }
+                    // The response got here first; finish it
+                    preemptible = false;
+                }
+            }
+        }
+    }
+}
+
 async fn execute_request(
     session: &Session,
     request_id: &str,
     options: RequestOptions,
 ) -> Result<(), anyhow::Error> {
-    let client = HttpClient::new_from_options(&options)?;
-    let response = client.send_request(options).await?;
-
-    // Process response headers
-    let mut headers_map = HashMap::new();
-    // let var_name =  response.headers();
-    // if var_name.is_some() {
-    for (key, value) in response.headers().iter() {
-        if let Ok(val_str) = value.to_str() {
-            headers_map.insert(key.as_str().to_string(), val_str.to_string());
+    let decode_json = match options.response_type.as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(AvanteCurlError::InvalidConfig(format!("Unsupported response_type: {}", other)).into())
         }
+    };
+    let null_as_nil = match options.json_null.as_deref() {
+        None | Some("vim.NIL") => false,
+        Some("nil") => true,
+        Some(other) => return Err(AvanteCurlError::InvalidConfig(format!("Unsupported json_null: {}", other)).into()),
+    };
+
+    let usage_format = UsageFormat::parse(options.usage_format.as_deref()).map_err(AvanteCurlError::InvalidConfig)?;
+    // Some(None): pick the framing from the response Content-Type
+    let framing = options.framing.as_deref().map(Framing::parse).transpose().map_err(AvanteCurlError::InvalidConfig)?;
+
+    // Only GETs go through the session cache
+    let cache_mode = CacheMode::parse(options.cache.as_deref()).map_err(AvanteCurlError::InvalidConfig)?;
+    let is_get = options.method.as_deref().unwrap_or("GET").eq_ignore_ascii_case("GET");
+    let cache = session.cache().filter(|_| is_get && cache_mode != CacheMode::Bypass).cloned();
+    if session.cache().is_some() && cache.is_none() {
+        session.set_cache_status(request_id, "bypass");
+    }
+
+    let provider = match &options.token_provider {
+        Some(name) => Some(session.token_provider(name).ok_or_else(|| {
+            AvanteCurlError::InvalidConfig(format!("Unknown token provider: {}", name))
+        })?),
+        None => None,
+    };
+
+    // With a token provider, a 401 refreshes the token and retries once
+    let mut attempt = 0;
+    let (response, cache_key, stale) = loop {
+        let mut attempt_options = options.clone();
+        session.middleware().before_request(&mut attempt_options)?;
+        let token = match &provider {
+            Some(provider) => Some(provider.inject(&mut attempt_options, session.options()).await?),
+            None => None,
+        };
+
+        // Serve fresh (or forced) entries, otherwise revalidate what's stored
+        let mut cache_key = None;
+        let mut stale = None;
+        if let Some(cache) = &cache {
+            let url = middleware::add_query(&attempt_options.url, &attempt_options.query.clone().unwrap_or_default())?;
+            let request_headers = attempt_options.headers.clone().unwrap_or_default();
+            let found = {
+                let (url, request_headers) = (url.clone(), request_headers.clone());
+                cache.run(move |cache| cache.lookup("GET", &url, &request_headers)).await
+            };
+            if let Some((entry, body)) = found {
+                if cache_mode == CacheMode::Force || entry.is_fresh(cache::timestamp_now()) {
+                    let framer = framer_for(framing, &entry.headers);
+                    return serve_cached(session, request_id, &entry, body, "hit", framer, decode_json, null_as_nil);
+                }
+                attempt_options
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .extend(entry.conditional_headers());
+                stale = Some((entry, body));
+            }
+            cache_key = Some((url, request_headers));
+        }
+
+        // Responses are decoded in read_body rather than by reqwest
+        if attempt_options.compressed.unwrap_or(true) {
+            let headers = attempt_options.headers.get_or_insert_with(HashMap::new);
+            if !headers.keys().any(|name| name.eq_ignore_ascii_case("accept-encoding")) {
+                headers.insert("Accept-Encoding".to_string(), body::ACCEPT_ENCODING.to_string());
+            }
+        }
+        attempt_options.compressed = Some(false);
+
+        session.set_sent(request_id, session.middleware().sent_request(&attempt_options));
+
+        let response = HttpClient::new_from_options(&attempt_options)?.send_request(attempt_options).await?;
+
+        if let (Some(provider), 401, 0) = (&provider, response.status().as_u16(), attempt) {
+            provider.invalidate(token.as_deref()).await;
+            attempt += 1;
+            continue;
+        }
+
+        break (response, cache_key, stale);
+    };
+
+    // Lua can see the response from here on, so a restart would repeat it
+    session.scheduler().pin(request_id);
+
+    if let (Some(cache), Some((entry, body)), 304) = (&cache, stale, response.status().as_u16()) {
+        // A failed metadata write still leaves a valid body to serve
+        let not_modified = ResponseHeaders::from_header_map(response.headers());
+        let refreshed = {
+            let stored = entry.clone();
+            cache.run(move |cache| cache.refresh(&stored, &not_modified)).await.unwrap_or(entry)
+        };
+        let framer = framer_for(framing, &refreshed.headers);
+        return serve_cached(session, request_id, &refreshed, body, "revalidated", framer, decode_json, null_as_nil);
     }
-    // }
 
-    // Process response body
+    // Publish the headers before the body so waiters can start on them
+    let headers_map = ResponseHeaders::from_header_map(response.headers());
+    session.set_response_meta(request_id, ResponseMeta::from_response(&response));
+
     let status = response.status().as_u16();
-    let body = response.text().await?;
+    let response_headers = response.headers().clone();
+    let http_version = format!("{:?}", response.version());
+    session.set_response(request_id, status, headers_map.clone(), "");
 
-  println!("request_id: {} status: {} body: {}", request_id, status, body);
+    let decoder = match options.compressed.unwrap_or(true) {
+        true => Decoder::for_encoding(headers_map.get("content-encoding"))?,
+        false => Decoder::Identity,
+    };
+    let decoded = !decoder.is_identity();
+    let framer = framer_for(framing, &headers_map);
+
+    // The usage middleware alone captures with format detection
+    let usage_format = usage_format.or_else(|| session.middleware().captures_usage().then_some(UsageFormat::Auto));
+    let mut usage = usage_format.map(|_| UsageCollector::default());
+    let (body, truncated) = read_body(session, request_id, response, decoder, framer, usage.as_mut(), &options).await?;
+
+    // Cache write failures only cost a future hit
+    if let (Some(cache), Some((url, request_headers))) = (&cache, cache_key) {
+        session.set_cache_status(request_id, "miss");
+        if !truncated {
+            // The stored body is the decoded one
+            let mut stored_headers = headers_map.clone();
+            if decoded {
+                stored_headers.remove("content-encoding");
+                stored_headers.remove("content-length");
+            }
+            let body = body.clone();
+            let _ = cache
+                .run(move |cache| {
+                    cache.store("GET", &url, &request_headers, status, &stored_headers, &http_version, &body)
+                })
+                .await;
+        }
+    }
 
-    session.set_response(request_id, status, headers_map, &body);
+    if let (Some(format), Some(usage)) = (usage_format, usage) {
+        if let Some(usage) = usage.finish(&body) {
+            let tokens = TokenUsage::from_provider(format, &usage);
+            session.record_usage(request_id, usage, tokens, request_model(&options));
+        }
+    }
+
+    if status >= 400 {
+        return Err(AvanteCurlError::from_status(status, &response_headers, &body).into());
+    }
+
+    // A parse failure leaves the raw body in place and reports where it broke
+    if decode_json {
+        let value: serde_json::Value = serde_json::from_str(&body).map_err(AvanteCurlError::from)?;
+        session.set_json(request_id, JsonBody { value, null_as_nil });
+    }
 
     Ok(())
 }
 
+// Model named by the request, for pricing: the `model` option, the JSON
+// body's "model", or Gemini's ".../models/<model>:<method>" URL
+fn request_model(options: &RequestOptions) -> Option<String> {
+    let from_body = || match &options.body {
+        Some(RequestBody::Json(body) | RequestBody::Raw(body)) => serde_json::from_slice::<serde_json::Value>(body)
+            .ok()?
+            .get("model")?
+            .as_str()
+            .map(str::to_string),
+        _ => None,
+    };
+    let from_url = || {
+        let (_, rest) = options.url.split_once("/models/")?;
+        let model = rest.split([':', '?', '/']).next()?;
+        (!model.is_empty()).then(|| model.to_string())
+    };
+    options.model.clone().or_else(from_body).or_else(from_url)
+}
+
+// Framer for a request with `framing`, detecting it from the response if needed
+fn framer_for(framing: Option<Option<Framing>>, headers: &ResponseHeaders) -> Option<Framer> {
+    framing.map(|framing| Framer::new(framing.unwrap_or_else(|| Framing::detect(headers.get("content-type")))))
+}
+
+// Complete a request from a cache entry, as if it had just been received
+#[allow(clippy::too_many_arguments)]
+fn serve_cached(
+    session: &Session,
+    request_id: &str,
+    entry: &CachedResponse,
+    body: String,
+    cache_status: &str,
+    framer: Option<Framer>,
+    decode_json: bool,
+    null_as_nil: bool,
+) -> Result<(), anyhow::Error> {
+    session.set_cache_status(request_id, cache_status);
+    session.set_response_meta(
+        request_id,
+        ResponseMeta {
+            url: entry.url.clone(),
+            redirects: Vec::new(),
+            http_version: entry.http_version.clone(),
+            remote_addr: None,
+        },
+    );
+    session.set_response(request_id, entry.status, entry.headers.clone(), "");
+    session.handle_stream_event(request_id, &body);
+    if let Some(mut framer) = framer {
+        let mut frames = framer.push(&body);
+        frames.extend(framer.finish());
+        session.push_events(request_id, frames);
+    }
+
+    if decode_json {
+        let value: serde_json::Value = serde_json::from_str(&body).map_err(AvanteCurlError::from)?;
+        session.set_json(request_id, JsonBody { value, null_as_nil });
+    }
+
+    Ok(())
+}
+
+// Stream the body in chunks, decompressing it and holding back incomplete
+// UTF-8 sequences, and enforce the size limits. Returns the buffered body and
+// whether it was cut short.
+//  - max_response_bytes caps what is read off the wire, before decompression
+//  - max_buffered_bytes caps what is kept in memory; chunks still reach
+//    on_chunk / next_chunk after buffering stops
+// With on_limit = "truncate" the request completes with `truncated` set,
+// otherwise it fails with a "response_too_large" error. With a framer, the
+// events of every chunk are queued as well, and with a usage collector every
+// chunk is scanned for token usage.
+async fn read_body(
+    session: &Session,
+    request_id: &str,
+    mut response: reqwest::Response,
+    mut decoder: Decoder,
+    mut framer: Option<Framer>,
+    mut usage: Option<&mut UsageCollector>,
+    options: &RequestOptions,
+) -> Result<(String, bool), anyhow::Error> {
+    let truncate = match options.on_limit.as_deref() {
+        None | Some("error") => false,
+        Some("truncate") => true,
+        Some(other) => return Err(AvanteCurlError::InvalidConfig(format!("Unsupported on_limit: {}", other)).into()),
+    };
+
+    let mut body = String::new();
+    let mut pending: Vec<u8> = Vec::new();
+    let mut received: u64 = 0;
+    let mut buffering = true;
+    let mut truncated = false;
+    let mut at_limit = false;
+
+    while let Some(mut bytes) = response.chunk().await? {
+        if session.should_cancel(request_id) {
+            return Err(AvanteCurlError::Cancelled.into());
+        }
+
+        if let Some(limit) = options.max_response_bytes {
+            if received + bytes.len() as u64 > limit {
+                if !truncate {
+                    return Err(AvanteCurlError::ResponseTooLarge { limit }.into());
+                }
+                bytes.truncate((limit - received) as usize);
+                at_limit = true;
+            }
+        }
+        received += bytes.len() as u64;
+        session.add_received_bytes(request_id, bytes.len() as u64);
+
+        pending.extend_from_slice(&decoder.decode(&bytes)?);
+        let text = util::text::take_utf8(&mut pending);
+        if !text.is_empty() {
+            if let Some(limit) = options.max_buffered_bytes {
+                if buffering && (body.len() + text.len()) as u64 > limit {
+                    if !truncate {
+                        return Err(AvanteCurlError::ResponseTooLarge { limit }.into());
+                    }
+                    buffering = false;
+                    truncated = true;
+                    session.set_truncated(request_id);
+                }
+            }
+
+            deliver(session, request_id, &text, buffering, framer.as_mut(), usage.as_deref_mut());
+            if buffering {
+                body.push_str(&text);
+            }
+        }
+
+        if at_limit {
+            // Whatever is left is a sequence cut in half by the limit
+            pending.clear();
+            truncated = true;
+            session.set_truncated(request_id);
+            break;
+        }
+    }
+
+    // A compressed stream cut by the limit can't be completed
+    if !at_limit {
+        pending.extend_from_slice(&decoder.finish()?);
+    }
+
+    if !pending.is_empty() {
+        let text = String::from_utf8_lossy(&pending).into_owned();
+        deliver(session, request_id, &text, buffering, framer.as_mut(), usage);
+        if buffering {
+            body.push_str(&text);
+        }
+    }
+    if let Some(framer) = framer.as_mut() {
+        session.push_events(request_id, framer.finish());
+    }
+
+    Ok((body, truncated))
+}
+
+// Hand a decoded piece of the body to the session, its events to the queue
+// and its text to the usage collector
+fn deliver(
+    session: &Session,
+    request_id: &str,
+    text: &str,
+    buffered: bool,
+    framer: Option<&mut Framer>,
+    usage: Option<&mut UsageCollector>,
+) {
+    if buffered {
+        session.handle_stream_event(request_id, text);
+    } else {
+        session.handle_stream_event_unbuffered(request_id, text);
+    }
+    if let Some(framer) = framer {
+        session.push_events(request_id, framer.push(text));
+    }
+    if let Some(usage) = usage {
+        usage.push(text);
+    }
+}
 
 
 
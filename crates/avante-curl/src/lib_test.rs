@@ -2,10 +2,10 @@
 mod tests {
     use crate::{
         http::HttpClient,
+        response::ResponseHeaders,
         session::Session,
         RequestBody, RequestOptions,
     };
-    use std::collections::HashMap;
     use std::sync::Arc;
 
     #[tokio::test]
@@ -48,13 +48,14 @@ mod tests {
         assert!(session.should_cancel(request_id));
         
         // Test setting response
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type".to_string(), "application/json".to_string());
+        let mut headers = ResponseHeaders::new();
+        headers.push("Content-Type", "application/json");
         session.set_response(request_id, 200, headers, "Test body");
         
         let response = session.get_response(request_id);
         assert_eq!(response.status, Some(200));
         assert_eq!(response.body, Some("Test body".to_string()));
+        assert_eq!(response.headers.unwrap().get("content-type"), Some("application/json"));
         
         // Test completion
         session.set_completed(request_id);
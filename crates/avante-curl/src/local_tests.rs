@@ -0,0 +1,958 @@
+#[cfg(test)]
+mod local_tests {
+    use crate::{http::HttpClient, util::{net, text}, RequestOptions};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::runtime::Runtime;
+
+    // Helper function to create a tokio runtime for tests
+    fn get_runtime() -> Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("test-worker")
+            .enable_all()
+            .build()
+            .expect("Failed to create test runtime")
+    }
+
+    // Read one HTTP request head and answer with a JSON body that echoes
+    // the request line and Host header
+    async fn serve_one<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let request_line = request.lines().next().unwrap_or_default();
+        let host = request
+            .lines()
+            .find_map(|line| line.strip_prefix("host: ").or_else(|| line.strip_prefix("Host: ")))
+            .unwrap_or_default();
+
+        let body = serde_json::json!({ "request_line": request_line, "host": host }).to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.shutdown().await.ok();
+    }
+
+    // Answer one request with a fixed body
+    async fn serve_body(mut stream: tokio::net::TcpStream, body: &[u8]) {
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        stream.shutdown().await.ok();
+    }
+
+    #[test]
+    fn test_parse_resolve() {
+        let (host, addrs) = net::parse_resolve("example.com:443:127.0.0.1").unwrap();
+        assert_eq!(host, "example.com");
+        assert_eq!(addrs, vec!["127.0.0.1:443".parse().unwrap()]);
+
+        let (_, addrs) = net::parse_resolve("example.com:8080:[::1],10.0.0.1").unwrap();
+        assert_eq!(addrs, vec!["[::1]:8080".parse().unwrap(), "10.0.0.1:8080".parse().unwrap()]);
+
+        assert!(net::parse_resolve("example.com:443").is_err());
+        assert!(net::parse_resolve("example.com:http:127.0.0.1").is_err());
+        assert!(net::parse_resolve("example.com:443:not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_take_utf8() {
+        // "é" is 0xC3 0xA9, split across two chunks
+        let mut buf = b"caf\xC3".to_vec();
+        assert_eq!(text::take_utf8(&mut buf), "caf");
+        assert_eq!(buf, vec![0xC3]);
+
+        buf.extend_from_slice(b"\xA9!");
+        assert_eq!(text::take_utf8(&mut buf), "é!");
+        assert!(buf.is_empty());
+
+        let mut buf = b"a\xFFb".to_vec();
+        assert_eq!(text::take_utf8(&mut buf), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_chunks_are_queued() {
+        use crate::session::{Session, StreamMessage};
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                for chunk in [&b"data: caf\xC3"[..], &b"\xA9\n\n"[..]] {
+                    let framed = [format!("{:x}\r\n", chunk.len()).as_bytes(), chunk, b"\r\n"].concat();
+                    stream.write_all(&framed).await.unwrap();
+                    stream.flush().await.unwrap();
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                stream.write_all(b"0\r\n\r\n").await.unwrap();
+            });
+
+            let session = Session::new();
+            session.init_request("chunks").unwrap();
+            session.queue_chunks("chunks");
+
+            let options = RequestOptions {
+                url: format!("http://127.0.0.1:{}/stream", port),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "chunks", options).await.unwrap();
+
+            // Resolves right away once the headers are in
+            let status = session.wait_until(|| session.get_response("chunks").status).await;
+            assert_eq!(status, 200);
+
+            let mut chunks = Vec::new();
+            while let Some(StreamMessage::Text(chunk)) = session.pop_message("chunks") {
+                chunks.push(chunk);
+            }
+            assert_eq!(chunks.concat(), "data: café\n\n");
+            assert_eq!(session.get_response("chunks").body.as_deref(), Some("data: café\n\n"));
+        });
+    }
+
+    #[test]
+    fn test_resolve_pins_host() {
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_one(stream).await;
+            });
+
+            let options = RequestOptions {
+                url: format!("http://internal.invalid:{}/models", port),
+                method: Some("GET".to_string()),
+                resolve: Some(vec![format!("internal.invalid:{}:127.0.0.1", port)]),
+                ..Default::default()
+            };
+
+            let client = HttpClient::new_from_options(&options).unwrap();
+            let response = client.send_request(options).await.unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+
+            let json: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(json["request_line"], "GET /models HTTP/1.1");
+            assert_eq!(json["host"], format!("internal.invalid:{}", port));
+        });
+    }
+
+    #[test]
+    fn test_pinned_pubkey_checked_in_handshake() {
+        use crate::tls::{spki_sha256, TlsOptions};
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let rt = get_runtime();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let mut ca = tempfile::NamedTempFile::new().unwrap();
+        ca.write_all(cert.serialize_pem().unwrap().as_bytes()).unwrap();
+
+        rt.block_on(async {
+            let config = rustls::ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![rustls::Certificate(der.clone())],
+                    rustls::PrivateKey(cert.serialize_private_key_der()),
+                )
+                .unwrap();
+            let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let served = Arc::new(AtomicUsize::new(0));
+            {
+                let served = served.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let (stream, _) = listener.accept().await.unwrap();
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            served.fetch_add(1, Ordering::SeqCst);
+                            serve_one(stream).await;
+                        }
+                    }
+                });
+            }
+
+            let request = |pin: String| RequestOptions {
+                url: format!("https://localhost:{}/models", port),
+                resolve: Some(vec![format!("localhost:{}:127.0.0.1", port)]),
+                tls: Some(TlsOptions {
+                    ca_certs: Some(vec![ca.path().to_string_lossy().to_string()]),
+                    pinned_pubkeys: Some(vec![pin]),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            let pin = format!("sha256//{}", spki_sha256(&der).unwrap());
+            let options = request(pin);
+            let client = HttpClient::new_from_options(&options).unwrap();
+            let response = client.send_request(options).await.unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            assert_eq!(served.load(Ordering::SeqCst), 1);
+
+            // A mismatch aborts the handshake; the server never sees a request
+            let options = request("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string());
+            let client = HttpClient::new_from_options(&options).unwrap();
+            let err = client.send_request(options).await.unwrap_err();
+            let info = crate::error::error_info(&err);
+            assert_eq!(info.kind, "tls");
+            assert!(!info.retryable);
+            assert_eq!(served.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let dir = tempfile::tempdir().unwrap();
+            let socket_path = dir.path().join("avante.sock");
+            let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_one(stream).await;
+            });
+
+            let options = RequestOptions {
+                url: "http://localhost/v1/query?top_k=3".to_string(),
+                method: Some("POST".to_string()),
+                unix_socket: Some(socket_path.to_string_lossy().to_string()),
+                body: Some(crate::RequestBody::Raw("{}".into())),
+                ..Default::default()
+            };
+
+            let client = HttpClient::new_from_options(&options).unwrap();
+            let response = client.send_request(options).await.unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            assert_eq!(response.url().as_str(), "http://localhost/v1/query?top_k=3");
+
+            let json: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(json["request_line"], "POST /v1/query?top_k=3 HTTP/1.1");
+            assert_eq!(json["host"], "localhost");
+        });
+    }
+
+    #[test]
+    fn test_redirects_tracked_per_request() {
+        use crate::response::ResponseMeta;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(async move {
+                        let mut buf = [0u8; 1024];
+                        let n = stream.read(&mut buf).await.unwrap();
+                        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                        let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+                        let next = match path.as_str() {
+                            "/a" => Some("/b"),
+                            "/x" => Some("/y"),
+                            "/y" => Some("/z"),
+                            _ => None,
+                        };
+                        let response = match next {
+                            Some(next) => format!(
+                                "HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                                next
+                            ),
+                            None => "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+                        };
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                        stream.shutdown().await.ok();
+                    });
+                }
+            });
+
+            let base = format!("http://127.0.0.1:{}", port);
+            let get = |path: &str| RequestOptions { url: format!("{}{}", base, path), ..Default::default() };
+            let client = HttpClient::new_from_options(&get("/")).unwrap();
+
+            // Both requests share the client and run at the same time
+            let (short, long) = tokio::join!(client.send_request(get("/a")), client.send_request(get("/x")));
+            let short = ResponseMeta::from_response(&short.unwrap());
+            let long = ResponseMeta::from_response(&long.unwrap());
+
+            assert_eq!(short.redirects, vec![format!("{}/b", base)]);
+            assert_eq!(long.redirects, vec![format!("{}/y", base), format!("{}/z", base)]);
+            assert_eq!(long.url, format!("{}/z", base));
+        });
+    }
+
+    #[test]
+    fn test_websocket_echo() {
+        use crate::session::{Session, StreamMessage};
+        use crate::ws::{self, WsOptions};
+        use futures_util::{SinkExt, StreamExt};
+        use std::sync::Arc;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(message)) = ws.next().await {
+                    if message.is_text() || message.is_binary() {
+                        ws.send(message).await.unwrap();
+                    }
+                }
+            });
+
+            let session = Arc::new(Session::new());
+            session.init_request("ws").unwrap();
+            let (handle, commands) = ws::channel();
+
+            let task = tokio::spawn(ws::run(
+                session.clone(),
+                "ws".to_string(),
+                format!("ws://127.0.0.1:{}/realtime", port),
+                WsOptions::default(),
+                commands,
+            ));
+
+            assert!(handle.send_text("hello".to_string()));
+            assert!(handle.send_binary(vec![0, 1, 2]));
+            assert!(handle.close(4000, "bye".to_string()));
+            task.await.unwrap().unwrap();
+
+            assert_eq!(
+                session.take_messages("ws"),
+                vec![
+                    StreamMessage::Text("hello".to_string()),
+                    StreamMessage::Binary(vec![0, 1, 2]),
+                    StreamMessage::Close { code: 4000, reason: "bye".to_string() },
+                ]
+            );
+            assert_eq!(session.get_response("ws").status, Some(101));
+        });
+    }
+
+    #[test]
+    fn test_json_response_type() {
+        use crate::session::Session;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                serve_one(stream).await;
+            });
+
+            let session = Session::new();
+            session.init_request("json").unwrap();
+
+            let options = RequestOptions {
+                url: format!("http://127.0.0.1:{}/models", port),
+                response_type: Some("json".to_string()),
+                json_null: Some("nil".to_string()),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "json", options).await.unwrap();
+
+            let json = session.get_response("json").json.unwrap();
+            assert_eq!(json.value["request_line"], "GET /models HTTP/1.1");
+            assert!(json.null_as_nil);
+
+            let options = RequestOptions {
+                url: "http://127.0.0.1:1/".to_string(),
+                response_type: Some("xml".to_string()),
+                ..Default::default()
+            };
+            let err = crate::execute_request(&session, "json", options).await.unwrap_err();
+            assert_eq!(crate::error::error_info(&err).kind, "invalid_config");
+        });
+    }
+
+    #[test]
+    fn test_response_limits() {
+        use crate::session::{Session, StreamMessage};
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    serve_body(stream, &[b'x'; 100]).await;
+                }
+            });
+            let url = format!("http://127.0.0.1:{}/large", port);
+            let session = Session::new();
+
+            // Truncate what's read off the wire
+            session.init_request("truncate").unwrap();
+            let options = RequestOptions {
+                url: url.clone(),
+                max_response_bytes: Some(10),
+                on_limit: Some("truncate".to_string()),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "truncate", options).await.unwrap();
+            let info = session.get_response("truncate");
+            assert_eq!(info.body.as_deref(), Some("x".repeat(10).as_str()));
+            assert!(info.truncated);
+
+            // Fail instead
+            session.init_request("error").unwrap();
+            let options = RequestOptions {
+                url: url.clone(),
+                max_response_bytes: Some(10),
+                ..Default::default()
+            };
+            let err = crate::execute_request(&session, "error", options).await.unwrap_err();
+            assert_eq!(crate::error::error_info(&err).kind, "response_too_large");
+
+            // Stop buffering but keep delivering chunks
+            session.init_request("buffered").unwrap();
+            session.queue_chunks("buffered");
+            let options = RequestOptions {
+                url,
+                max_buffered_bytes: Some(0),
+                on_limit: Some("truncate".to_string()),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "buffered", options).await.unwrap();
+            let info = session.get_response("buffered");
+            assert_eq!(info.body.as_deref(), Some(""));
+            assert!(info.truncated);
+
+            let delivered: usize = session
+                .take_messages("buffered")
+                .into_iter()
+                .map(|message| match message {
+                    StreamMessage::Text(text) => text.len(),
+                    _ => 0,
+                })
+                .sum();
+            assert_eq!(delivered, 100);
+        });
+    }
+
+    #[test]
+    fn test_compressed_response_counts_wire_bytes() {
+        use crate::session::Session;
+        use std::io::Write;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let text = "x".repeat(5000);
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            let gzip = encoder.finish().unwrap();
+            let wire_len = gzip.len() as u64;
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let read = stream.read(&mut buf).await.unwrap();
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    gzip.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&gzip).await.unwrap();
+                stream.shutdown().await.ok();
+                String::from_utf8_lossy(&buf[..read]).to_lowercase()
+            });
+
+            // The limit is the compressed size, well below the decoded one
+            let session = Session::new();
+            session.init_request("gzip").unwrap();
+            let options = RequestOptions {
+                url: format!("http://127.0.0.1:{}/", port),
+                max_response_bytes: Some(wire_len),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "gzip", options).await.unwrap();
+
+            let request = server.await.unwrap();
+            assert!(request.contains("accept-encoding: gzip, deflate, br, zstd"));
+
+            let info = session.get_response("gzip");
+            assert_eq!(info.body.as_deref(), Some(text.as_str()));
+            assert!(!info.truncated);
+            assert_eq!(info.received_bytes, wire_len);
+            let content_length = info.headers.unwrap().get("content-length").unwrap().parse::<u64>().unwrap();
+            assert_eq!(content_length, wire_len);
+        });
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        use crate::session::{RequestState, Session};
+
+        let session = Session::new();
+        for request_id in ["done", "running", "queued"] {
+            session.init_request(request_id).unwrap();
+        }
+        session.set_completed("done");
+        session.handle_stream_event("running", "partial");
+
+        session.cancel_all();
+
+        assert_eq!(session.get_response("done").state, RequestState::Complete);
+        assert_eq!(session.get_response("running").state, RequestState::Cancelled);
+        assert_eq!(session.get_response("queued").state, RequestState::Cancelled);
+        assert!(session.should_cancel("running"));
+    }
+
+    #[test]
+    fn test_batch_fail_fast() {
+        use crate::batch::{Batch, BatchOptions, BatchState};
+        use crate::session::{RequestState, Session};
+        use std::sync::Arc;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            // Grab a free port and close it again so connecting fails
+            let port = {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                listener.local_addr().unwrap().port()
+            };
+
+            let session = Arc::new(Session::new());
+            let batch = Batch::new("batch", 2);
+            for request_id in &batch.request_ids {
+                session.init_request(request_id).unwrap();
+            }
+            let batch = session.add_batch("batch", batch);
+
+            let requests = (0..2)
+                .map(|_| RequestOptions {
+                    url: format!("http://127.0.0.1:{}/", port),
+                    ..Default::default()
+                })
+                .collect();
+            let options = BatchOptions { concurrency: Some(1), fail_fast: true };
+            crate::spawn_batch(session.clone(), "batch", &batch, requests, options);
+
+            let states = session
+                .wait_until(|| {
+                    let states: Vec<RequestState> =
+                        batch.request_ids.iter().map(|id| session.get_response(id).state).collect();
+                    (Batch::state(&states) != BatchState::Running).then_some(states)
+                })
+                .await;
+
+            // The second request never started
+            assert_eq!(states, vec![RequestState::Error, RequestState::Cancelled]);
+            assert_eq!(Batch::state(&states), BatchState::Failed);
+            assert_eq!(session.get_response("batch:1").error.unwrap().kind, "connect");
+        });
+    }
+
+    #[test]
+    fn test_finished_batch_is_removed() {
+        use crate::batch::{Batch, BatchOptions};
+        use crate::session::Session;
+        use std::sync::Arc;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let port = {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                listener.local_addr().unwrap().port()
+            };
+
+            let session = Arc::new(Session::new());
+            let batch = Batch::new("batch", 2);
+            for request_id in &batch.request_ids {
+                session.init_request(request_id).unwrap();
+            }
+            let batch = session.add_batch("batch", batch);
+
+            // Nothing has run yet, so the batch stays around
+            assert_eq!(session.collect_batch("batch").unwrap().len(), 2);
+            assert!(session.batch("batch").is_some());
+
+            let requests = (0..2)
+                .map(|_| RequestOptions {
+                    url: format!("http://127.0.0.1:{}/", port),
+                    ..Default::default()
+                })
+                .collect();
+            crate::spawn_batch(session.clone(), "batch", &batch, requests, BatchOptions::default());
+
+            session
+                .wait_until(|| {
+                    let finished = batch.request_ids.iter().all(|id| session.get_response(id).state.is_terminal());
+                    finished.then_some(())
+                })
+                .await;
+
+            // The finished result is handed out once
+            assert_eq!(session.collect_batch("batch").unwrap().len(), 2);
+            assert!(session.batch("batch").is_none());
+            assert!(session.collect_batch("batch").is_none());
+        });
+    }
+
+    #[test]
+    fn test_cache_revalidation() {
+        use crate::cache::{CacheOptions, HttpCache};
+        use crate::session::Session;
+
+        let rt = get_runtime();
+        let dir = tempfile::tempdir().unwrap();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn(async move {
+                let mut requests = Vec::new();
+                for response in [
+                    "HTTP/1.1 200 OK\r\ncache-control: no-cache\r\netag: \"v1\"\r\ncontent-length: 6\r\nconnection: close\r\n\r\ncached",
+                    "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\n",
+                ] {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    requests.push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.ok();
+                }
+                requests
+            });
+
+            let cache = HttpCache::open(&CacheOptions {
+                dir: dir.path().to_string_lossy().to_string(),
+                max_bytes: None,
+            })
+            .unwrap();
+            let session = Session::new().with_cache(cache);
+            let url = format!("http://127.0.0.1:{}/models", port);
+            let get = |cache: Option<&str>| RequestOptions {
+                url: url.clone(),
+                cache: cache.map(str::to_string),
+                ..Default::default()
+            };
+
+            for id in ["miss", "revalidated", "hit", "force", "bypass"] {
+                session.init_request(id).unwrap();
+            }
+
+            crate::execute_request(&session, "miss", get(None)).await.unwrap();
+            assert_eq!(session.get_response("miss").cache.as_deref(), Some("miss"));
+
+            // no-cache: stored, but revalidated with the ETag before use
+            crate::execute_request(&session, "revalidated", get(None)).await.unwrap();
+            let revalidated = session.get_response("revalidated");
+            assert_eq!(revalidated.cache.as_deref(), Some("revalidated"));
+            assert_eq!(revalidated.status, Some(200));
+            assert_eq!(revalidated.body.as_deref(), Some("cached"));
+
+            let requests = server.await.unwrap();
+            assert!(!requests[0].contains("if-none-match"));
+            assert!(requests[1].contains("if-none-match: \"v1\""));
+
+            // The 304 made the entry fresh for a minute; the server is gone now
+            crate::execute_request(&session, "hit", get(None)).await.unwrap();
+            assert_eq!(session.get_response("hit").body.as_deref(), Some("cached"));
+            crate::execute_request(&session, "force", get(Some("force"))).await.unwrap();
+            assert_eq!(session.get_response("force").cache.as_deref(), Some("hit"));
+
+            assert!(crate::execute_request(&session, "bypass", get(Some("bypass"))).await.is_err());
+            assert_eq!(session.get_response("bypass").cache.as_deref(), Some("bypass"));
+        });
+    }
+
+    #[test]
+    fn test_retry_then_framed_events() {
+        use crate::framing::Frame;
+        use crate::session::{RequestState, Session};
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                for response in [
+                    "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 4\r\nconnection: close\r\n\r\nslow",
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\ndata: {\"n\":1}\n\nevent: done\ndata: [DONE]\n\n",
+                ] {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await.unwrap();
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.ok();
+                }
+            });
+
+            let session = Session::new();
+            session.init_request("sse").unwrap();
+            let options = RequestOptions {
+                url: format!("http://127.0.0.1:{}/", port),
+                framing: Some("auto".to_string()),
+                retries: Some(1),
+                ..Default::default()
+            };
+            assert!(crate::run_scheduled(&session, "sse", options).await);
+
+            // Only the successful attempt is left
+            let info = session.get_response("sse");
+            assert_eq!(info.state, RequestState::Complete);
+            assert_eq!(info.status, Some(200));
+            let retry = info.retry.unwrap();
+            assert_eq!((retry.attempt, retry.delay, retry.error.status), (1, 0.0, Some(429)));
+
+            let events = session.take_events("sse");
+            assert_eq!(
+                events,
+                vec![
+                    Frame::Sse {
+                        event: None,
+                        id: None,
+                        data: "{\"n\":1}".to_string(),
+                        json: Some(serde_json::json!({ "n": 1 })),
+                    },
+                    Frame::Sse {
+                        event: Some("done".to_string()),
+                        id: None,
+                        data: "[DONE]".to_string(),
+                        json: None,
+                    },
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_preempt_and_requeue() {
+        use crate::session::{RequestState, Session};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                // The first connection never gets an answer
+                let mut stalled = None;
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    if stalled.is_none() {
+                        stalled = Some(stream);
+                    } else {
+                        tokio::spawn(serve_body(stream, b"done"));
+                    }
+                }
+            });
+
+            let session = Arc::new(Session::with_options(crate::SessionOptions {
+                max_concurrent: Some(1),
+                preempt: Some(true),
+                ..Default::default()
+            }));
+            let request = |priority| RequestOptions {
+                url: format!("http://127.0.0.1:{}/", port),
+                priority: Some(priority),
+                ..Default::default()
+            };
+
+            session.init_request("background").unwrap();
+            session.init_request("chat").unwrap();
+
+            let background = {
+                let session = session.clone();
+                let options = request(-1);
+                tokio::spawn(async move { crate::run_scheduled(&session, "background", options).await })
+            };
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(session.scheduler().counts(), (1, 0));
+
+            // The chat request bumps the stalled background one, which then runs again
+            assert!(crate::run_scheduled(&session, "chat", request(10)).await);
+            assert!(background.await.unwrap());
+
+            let chat = session.get_response("chat");
+            assert_eq!((chat.state, chat.restarts), (RequestState::Complete, 0));
+            let background = session.get_response("background");
+            assert_eq!((background.state, background.restarts), (RequestState::Complete, 1));
+            assert_eq!(background.body.as_deref(), Some("done"));
+        });
+    }
+
+    #[test]
+    fn test_usage_accounting() {
+        use crate::session::Session;
+        use crate::usage::ModelPrice;
+        use std::collections::HashMap;
+
+        let rt = get_runtime();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let stream = concat!(
+                    "event: message_start\n",
+                    "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":1000000,\"output_tokens\":1,\"cache_read_input_tokens\":1000000}}}\n\n",
+                    "event: message_delta\n",
+                    "data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":100000}}\n\n",
+                );
+                for _ in 0..3 {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    serve_body(socket, stream.as_bytes()).await;
+                }
+            });
+
+            let session = Session::with_options(crate::SessionOptions {
+                pricing: Some(HashMap::from([(
+                    "claude-3-5-sonnet".to_string(),
+                    ModelPrice { input: 3.0, output: 15.0, cache_read: Some(0.3), cache_write: None },
+                )])),
+                ..Default::default()
+            });
+            let request = RequestOptions {
+                url: format!("http://127.0.0.1:{}/v1/messages", port),
+                method: Some("POST".to_string()),
+                body: Some(crate::RequestBody::json(&serde_json::json!({ "model": "claude-3-5-sonnet-20241022" })).unwrap()),
+                usage_format: Some("anthropic".to_string()),
+                ..Default::default()
+            };
+
+            for id in ["first", "second"] {
+                session.init_request(id).unwrap();
+                crate::execute_request(&session, id, request.clone()).await.unwrap();
+            }
+
+            let usage = session.get_response("first").token_usage.unwrap();
+            assert_eq!(usage.tokens.output_tokens, 100_000);
+            assert_eq!(usage.tokens.cache_read_tokens, 1_000_000);
+            assert!((usage.cost.unwrap() - 4.8).abs() < 1e-9);
+
+            let totals = session.usage();
+            assert_eq!(totals.total.requests, 2);
+            assert_eq!(totals.total.tokens.input_tokens, 2_000_000);
+            assert!((totals.models["claude-3-5-sonnet-20241022"].cost.unwrap() - 9.6).abs() < 1e-9);
+
+            // The final message_delta is counted even though the body isn't buffered
+            session.init_request("unbuffered").unwrap();
+            let options = RequestOptions {
+                max_buffered_bytes: Some(0),
+                on_limit: Some("truncate".to_string()),
+                ..request
+            };
+            crate::execute_request(&session, "unbuffered", options).await.unwrap();
+            let info = session.get_response("unbuffered");
+            assert!(info.truncated);
+            assert_eq!(info.token_usage.unwrap().tokens.output_tokens, 100_000);
+        });
+    }
+
+    #[test]
+    fn test_streamed_body() {
+        use crate::body::BodyPart;
+        use crate::session::Session;
+        use std::io::Write;
+
+        let rt = get_runtime();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[b'a'; 200_000]).unwrap();
+        let path = file.path().to_string_lossy().to_string();
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 16 * 1024];
+                let head_end = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                    if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&received[..head_end]).to_lowercase();
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .unwrap()
+                    .trim()
+                    .parse()
+                    .unwrap();
+                while received.len() < head_end + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                (head, received[head_end..].to_vec())
+            });
+
+            let session = Session::new();
+            session.init_request("upload").unwrap();
+            let options = RequestOptions {
+                url: format!("http://127.0.0.1:{}/upload", port),
+                method: Some("POST".to_string()),
+                body: Some(crate::RequestBody::Parts(vec![
+                    BodyPart::Text("<".into()),
+                    BodyPart::File(path),
+                    BodyPart::Text(">".into()),
+                ])),
+                ..Default::default()
+            };
+            crate::execute_request(&session, "upload", options).await.unwrap();
+
+            let (head, body) = server.await.unwrap();
+            assert!(head.contains("content-length: 200002"));
+            assert!(!head.contains("transfer-encoding"));
+            assert_eq!(body.len(), 200_002);
+            assert_eq!((body[0], body[1], body[200_001]), (b'<', b'a', b'>'));
+        });
+    }
+}
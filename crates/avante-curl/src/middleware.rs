@@ -0,0 +1,479 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::AvanteCurlError;
+use crate::response::ResponseHeaders;
+use crate::{RequestBody, RequestOptions};
+
+// Header values that are always masked in the recorded request
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "api-key",
+    "x-goog-api-key",
+    "cookie",
+];
+
+// Also masked in a shown response: cookies the server sets. Credentials some
+// servers echo back are covered by the request list.
+const SENSITIVE_RESPONSE_HEADERS: &[&str] = &["set-cookie", "set-cookie2"];
+
+const REDACTED: &str = "[REDACTED]";
+
+// A single step of the session's middleware chain, configured from Lua as
+// `{ type = "headers" | "rewrite" | "body_limit" | "usage" | "redact", ... }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Middleware {
+    // Add headers the request doesn't set itself (or replace them with `override`)
+    Headers {
+        headers: HashMap<String, String>,
+        replace: bool,
+    },
+    // Swap a URL prefix and add query parameters, e.g. for Azure deployments:
+    // https://api.openai.com/v1 -> https://res.openai.azure.com/openai/deployments/gpt-4o
+    Rewrite {
+        from: String,
+        to: String,
+        query: HashMap<String, String>,
+    },
+    // Refuse to send request bodies larger than this
+    BodyLimit { max_bytes: u64 },
+    // Keep the `usage` object reported by the provider
+    Usage,
+    // Extra headers and query parameters to mask in the recorded request
+    Redact {
+        headers: Vec<String>,
+        query: Vec<String>,
+    },
+}
+
+// The request as it went out after the middleware chain, with secrets masked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SentRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+// Ordered middlewares of a session
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MiddlewareChain {
+    middlewares: Vec<Middleware>,
+}
+
+impl MiddlewareChain {
+    pub fn new(middlewares: Vec<Middleware>) -> Self {
+        Self { middlewares }
+    }
+
+    // Run the request-side middlewares, in order
+    pub fn before_request(&self, options: &mut RequestOptions) -> Result<(), AvanteCurlError> {
+        for middleware in &self.middlewares {
+            match middleware {
+                Middleware::Headers { headers, replace } => {
+                    let request_headers = options.headers.get_or_insert_with(HashMap::new);
+                    for (name, value) in headers {
+                        let existing = request_headers.keys().find(|key| key.eq_ignore_ascii_case(name)).cloned();
+                        match existing {
+                            Some(key) if *replace => {
+                                request_headers.insert(key, value.clone());
+                            }
+                            Some(_) => {}
+                            None => {
+                                request_headers.insert(name.clone(), value.clone());
+                            }
+                        }
+                    }
+                }
+                Middleware::Rewrite { from, to, query } => {
+                    if let Some(rest) = options.url.strip_prefix(from.as_str()) {
+                        options.url = format!("{}{}", to, rest);
+                        options.url = add_query(&options.url, query)?;
+                    }
+                }
+                Middleware::BodyLimit { max_bytes } => {
+                    let size = body_size(options.body.as_ref())?;
+                    if size > *max_bytes {
+                        return Err(AvanteCurlError::BodyTooLarge { size, limit: *max_bytes });
+                    }
+                }
+                Middleware::Usage | Middleware::Redact { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Whether usage should be captured from responses
+    pub fn captures_usage(&self) -> bool {
+        self.middlewares.iter().any(|middleware| matches!(middleware, Middleware::Usage))
+    }
+
+    // Header names (lowercase) and query parameters to mask: the built-in
+    // lists plus those of the redact middlewares
+    fn redacted_names(&self) -> (Vec<String>, Vec<String>) {
+        let mut redact_headers: Vec<String> = SENSITIVE_HEADERS.iter().map(|name| name.to_string()).collect();
+        let mut redact_query: Vec<String> = vec!["key".to_string(), "api_key".to_string()];
+        for middleware in &self.middlewares {
+            if let Middleware::Redact { headers, query } = middleware {
+                redact_headers.extend(headers.iter().map(|name| name.to_ascii_lowercase()));
+                redact_query.extend(query.iter().cloned());
+            }
+        }
+        (redact_headers, redact_query)
+    }
+
+    // Record what's about to be sent, masking credentials
+    pub fn sent_request(&self, options: &RequestOptions) -> SentRequest {
+        let (redact_headers, redact_query) = self.redacted_names();
+
+        let mut headers: Vec<(String, String)> = options
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                let value = if redact_headers.contains(&name.to_ascii_lowercase()) {
+                    REDACTED.to_string()
+                } else {
+                    value.clone()
+                };
+                (name.clone(), value)
+            })
+            .collect();
+        headers.sort();
+
+        SentRequest {
+            method: options.method.clone().unwrap_or_else(|| "GET".to_string()),
+            url: redact_url(&options.url, &redact_query),
+            headers,
+        }
+    }
+
+    // Response headers with the same names masked, plus cookies, for showing
+    // a response outside the plugin
+    pub fn redact_response_headers(&self, headers: &ResponseHeaders) -> ResponseHeaders {
+        let (mut redact_headers, _) = self.redacted_names();
+        redact_headers.extend(SENSITIVE_RESPONSE_HEADERS.iter().map(|name| name.to_string()));
+
+        let mut redacted = ResponseHeaders::new();
+        for (name, value) in headers.iter() {
+            let value = if redact_headers.contains(&name.to_ascii_lowercase()) { REDACTED } else { value };
+            redacted.push(name, value);
+        }
+        redacted
+    }
+}
+
+// Append query parameters the URL doesn't already have
+pub fn add_query(url: &str, query: &HashMap<String, String>) -> Result<String, AvanteCurlError> {
+    if query.is_empty() {
+        return Ok(url.to_string());
+    }
+
+    let mut parsed = url::Url::parse(url)
+        .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid URL '{}': {}", url, e)))?;
+    let existing: Vec<String> = parsed.query_pairs().map(|(key, _)| key.into_owned()).collect();
+
+    let mut keys: Vec<&String> = query.keys().filter(|key| !existing.contains(key)).collect();
+    keys.sort();
+    if !keys.is_empty() {
+        let mut pairs = parsed.query_pairs_mut();
+        for key in keys {
+            pairs.append_pair(key, &query[key]);
+        }
+    }
+
+    Ok(parsed.to_string())
+}
+
+fn redact_url(url: &str, names: &[String]) -> String {
+    let mut parsed = match url::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    if parsed.query().is_none() {
+        return url.to_string();
+    }
+
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(key, value)| {
+            let value = if names.iter().any(|name| *name == key) { REDACTED.to_string() } else { value.into_owned() };
+            (key.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+fn body_size(body: Option<&RequestBody>) -> Result<u64, AvanteCurlError> {
+    Ok(match body {
+        None => 0,
+        Some(RequestBody::Raw(bytes) | RequestBody::Json(bytes)) => bytes.len() as u64,
+        Some(RequestBody::File(path)) => std::fs::metadata(path)?.len(),
+        Some(RequestBody::Parts(parts)) => parts.iter().map(|part| part.size()).sum::<std::io::Result<u64>>()?,
+    })
+}
+
+// Longest SSE line looked at for usage; longer lines are skipped, not buffered
+const MAX_USAGE_LINE: usize = 64 * 1024;
+
+// Collects the token usage object while the body streams in, so it's counted
+// even when the body isn't buffered (max_buffered_bytes):
+//   OpenAI / Anthropic: {"usage": {...}}, Anthropic streams: {"message": {"usage": {...}}}
+//   Gemini: {"usageMetadata": {...}}
+// SSE `data:` lines are read as they complete; a plain JSON body is read
+// whole at the end. Later events (e.g. Anthropic's message_delta) update
+// earlier fields.
+#[derive(Debug, Default)]
+pub struct UsageCollector {
+    usage: serde_json::Map<String, serde_json::Value>,
+    line: String,
+    skipping: bool, // Rest of an over-long line
+}
+
+impl UsageCollector {
+    pub fn push(&mut self, text: &str) {
+        let mut rest = text;
+        while let Some(end) = rest.find('\n') {
+            if !self.skipping {
+                self.line.push_str(&rest[..end]);
+                let line = std::mem::take(&mut self.line);
+                self.push_line(&line);
+            }
+            self.skipping = false;
+            rest = &rest[end + 1..];
+        }
+
+        if !self.skipping {
+            self.line.push_str(rest);
+            if self.line.len() > MAX_USAGE_LINE {
+                self.line.clear();
+                self.skipping = true;
+            }
+        }
+    }
+
+    // Usage found so far; `body` is the buffered body, read when the
+    // response wasn't an SSE stream
+    pub fn finish(mut self, body: &str) -> Option<serde_json::Value> {
+        let line = std::mem::take(&mut self.line);
+        self.push_line(&line);
+
+        if self.usage.is_empty() {
+            if let Ok(value) = serde_json::from_str::<serde_json::Value>(body) {
+                self.merge(&value);
+            }
+        }
+
+        (!self.usage.is_empty()).then_some(serde_json::Value::Object(self.usage))
+    }
+
+    fn push_line(&mut self, line: &str) {
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => return,
+        };
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(data) {
+            self.merge(&value);
+        }
+    }
+
+    fn merge(&mut self, value: &serde_json::Value) {
+        let found = value
+            .get("usage")
+            .or_else(|| value.get("usageMetadata"))
+            .or_else(|| value.get("message").and_then(|message| message.get("usage")));
+        if let Some(serde_json::Value::Object(fields)) = found {
+            for (key, value) in fields {
+                self.usage.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+impl FromLua for Middleware {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: "LuaValue",
+                    to: "Middleware".to_string(),
+                    message: Some("Expected a table".to_string()),
+                })
+            }
+        };
+
+        let kind: String = table.get("type")?;
+        match kind.as_str() {
+            "headers" => Ok(Middleware::Headers {
+                headers: table.get("headers")?,
+                replace: table.get::<Option<bool>>("override")?.unwrap_or(false),
+            }),
+            "rewrite" => Ok(Middleware::Rewrite {
+                from: table.get("from")?,
+                to: table.get("to")?,
+                query: table.get::<Option<HashMap<String, String>>>("query")?.unwrap_or_default(),
+            }),
+            "body_limit" => Ok(Middleware::BodyLimit { max_bytes: table.get("max_bytes")? }),
+            "usage" => Ok(Middleware::Usage),
+            "redact" => Ok(Middleware::Redact {
+                headers: table.get::<Option<Vec<String>>>("headers")?.unwrap_or_default(),
+                query: table.get::<Option<Vec<String>>>("query")?.unwrap_or_default(),
+            }),
+            other => Err(LuaError::RuntimeError(format!("Unknown middleware type: {}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(url: &str) -> RequestOptions {
+        RequestOptions {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_headers_and_rewrite() {
+        let chain = MiddlewareChain::new(vec![
+            Middleware::Headers {
+                headers: HashMap::from([
+                    ("User-Agent".to_string(), "avante".to_string()),
+                    ("X-Trace".to_string(), "1".to_string()),
+                ]),
+                replace: false,
+            },
+            Middleware::Rewrite {
+                from: "https://api.openai.com/v1".to_string(),
+                to: "https://res.openai.azure.com/openai/deployments/gpt-4o".to_string(),
+                query: HashMap::from([("api-version".to_string(), "2024-06-01".to_string())]),
+            },
+        ]);
+
+        let mut request = options("https://api.openai.com/v1/chat/completions");
+        request.headers = Some(HashMap::from([("user-agent".to_string(), "custom".to_string())]));
+        chain.before_request(&mut request).unwrap();
+
+        assert_eq!(
+            request.url,
+            "https://res.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-06-01"
+        );
+        let headers = request.headers.unwrap();
+        assert_eq!(headers["user-agent"], "custom");
+        assert_eq!(headers["X-Trace"], "1");
+
+        // Other hosts are left alone
+        let mut request = options("https://api.anthropic.com/v1/messages");
+        chain.before_request(&mut request).unwrap();
+        assert_eq!(request.url, "https://api.anthropic.com/v1/messages");
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let chain = MiddlewareChain::new(vec![Middleware::BodyLimit { max_bytes: 4 }]);
+
+        let mut request = options("http://localhost/");
+        request.body = Some(RequestBody::Raw("1234".into()));
+        assert!(chain.before_request(&mut request).is_ok());
+
+        request.body = Some(RequestBody::Raw("12345".into()));
+        let err = chain.before_request(&mut request).unwrap_err();
+        assert_eq!(err.kind(), "body_too_large");
+    }
+
+    #[test]
+    fn test_sent_request_is_redacted() {
+        let chain = MiddlewareChain::new(vec![Middleware::Redact {
+            headers: vec!["X-Session".to_string()],
+            query: vec!["token".to_string()],
+        }]);
+
+        let mut request = options("https://example.com/v1?key=secret&token=t&model=m");
+        request.headers = Some(HashMap::from([
+            ("Authorization".to_string(), "Bearer sk".to_string()),
+            ("x-session".to_string(), "abc".to_string()),
+            ("Accept".to_string(), "application/json".to_string()),
+        ]));
+
+        let sent = chain.sent_request(&request);
+        assert_eq!(sent.method, "GET");
+        assert_eq!(sent.url, "https://example.com/v1?key=%5BREDACTED%5D&token=%5BREDACTED%5D&model=m");
+        assert_eq!(
+            sent.headers,
+            vec![
+                ("Accept".to_string(), "application/json".to_string()),
+                ("Authorization".to_string(), REDACTED.to_string()),
+                ("x-session".to_string(), REDACTED.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_response_headers_are_redacted() {
+        let chain = MiddlewareChain::new(vec![Middleware::Redact {
+            headers: vec!["X-Session".to_string()],
+            query: Vec::new(),
+        }]);
+
+        let mut headers = ResponseHeaders::new();
+        headers.push("Set-Cookie", "a=1");
+        headers.push("set-cookie", "b=2");
+        headers.push("x-api-key", "sk-echoed");
+        headers.push("x-session", "abc");
+        headers.push("content-type", "text/event-stream");
+
+        let redacted = chain.redact_response_headers(&headers);
+        assert_eq!(redacted.get_all("set-cookie"), vec![REDACTED, REDACTED]);
+        assert_eq!(redacted.get("x-api-key"), Some(REDACTED));
+        assert_eq!(redacted.get("x-session"), Some(REDACTED));
+        assert_eq!(redacted.get("content-type"), Some("text/event-stream"));
+    }
+
+    #[test]
+    fn test_usage_collector() {
+        let capture = |chunks: &[&str]| {
+            let mut collector = UsageCollector::default();
+            for chunk in chunks {
+                collector.push(chunk);
+            }
+            collector.finish(&chunks.concat())
+        };
+
+        let openai = r#"{"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":5}}"#;
+        assert_eq!(capture(&[openai]).unwrap()["prompt_tokens"], 10);
+
+        // Lines split across chunks
+        let anthropic_stream = [
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":25,",
+            "\"output_tokens\":1}}}\n\nevent: message_delta\n",
+            "data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":15}}\n\n",
+        ];
+        let usage = capture(&anthropic_stream).unwrap();
+        assert_eq!(usage["input_tokens"], 25);
+        assert_eq!(usage["output_tokens"], 15);
+
+        // Still counted when the body wasn't kept
+        let mut collector = UsageCollector::default();
+        for chunk in anthropic_stream {
+            collector.push(chunk);
+        }
+        assert_eq!(collector.finish("").unwrap()["output_tokens"], 15);
+
+        // Over-long lines are skipped without losing the next one
+        let long = format!("data: {}\n", "x".repeat(MAX_USAGE_LINE * 2));
+        let usage = capture(&[&long, "data: {\"usage\":{\"output_tokens\":2}}"]).unwrap();
+        assert_eq!(usage["output_tokens"], 2);
+
+        let gemini = r#"{"usageMetadata":{"promptTokenCount":3,"totalTokenCount":9}}"#;
+        assert_eq!(capture(&[gemini]).unwrap()["totalTokenCount"], 9);
+
+        assert_eq!(capture(&["plain text"]), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+// Wakes the Lua event loop whenever a session has new events queued.
+//
+// Backed by a non-blocking socket pair: the worker threads write a single byte
+// to one end, Lua watches the other end with `uv.new_poll` and calls
+// `drain_events` once it has woken up. Only one byte is written between two
+// drains, so a burst of chunks results in a single wakeup.
+pub struct EventNotifier {
+    #[cfg(unix)]
+    reader: Mutex<UnixStream>,
+    #[cfg(unix)]
+    writer: Mutex<UnixStream>,
+    pending: AtomicBool,
+}
+
+impl EventNotifier {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+
+        Ok(Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            pending: AtomicBool::new(false),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "event notification is only available on unix",
+        ))
+    }
+
+    // File descriptor that becomes readable when events are pending
+    #[cfg(unix)]
+    pub fn fd(&self) -> RawFd {
+        self.reader.lock().unwrap().as_raw_fd()
+    }
+
+    // Signal that new events are available, coalescing repeated signals
+    pub fn notify(&self) {
+        if self.pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        #[cfg(unix)]
+        {
+            let mut writer = self.writer.lock().unwrap();
+            match writer.write(&[1]) {
+                Ok(_) => {}
+                // The buffer is full, so the reader is already readable
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => self.pending.store(false, Ordering::SeqCst),
+            }
+        }
+    }
+
+    // Consume pending wakeups so the fd stops being readable.
+    // Returns true if there were events pending.
+    //
+    // The fd is emptied before `pending` is cleared: a signal that lands
+    // while reading then either finds `pending` still set (and is covered by
+    // the caller looking at the queue after this returns) or writes a fresh
+    // byte once it's cleared. Clearing first would let a signal set `pending`
+    // and have its byte swallowed here, after which no further byte is ever
+    // written.
+    pub fn drain(&self) -> bool {
+        #[cfg(unix)]
+        {
+            let mut reader = self.reader.lock().unwrap();
+            let mut buf = [0u8; 64];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => continue,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+
+        self.pending.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventNotifier;
+
+    #[test]
+    fn test_notify_and_drain() {
+        let notifier = EventNotifier::new().unwrap();
+        assert!(!notifier.drain());
+
+        notifier.notify();
+        notifier.notify();
+        notifier.notify();
+        assert!(notifier.drain());
+        assert!(!notifier.drain());
+
+        notifier.notify();
+        assert!(notifier.drain());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fd_is_readable_after_notify() {
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let notifier = EventNotifier::new().unwrap();
+        notifier.notify();
+
+        // Borrow the fd without taking ownership of it
+        let stream = unsafe { UnixStream::from_raw_fd(notifier.fd()) };
+        let mut stream = std::mem::ManuallyDrop::new(stream);
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_racing_drain() {
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+        use std::os::unix::net::UnixStream;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let notifier = Arc::new(EventNotifier::new().unwrap());
+        notifier.notify();
+
+        // Hold the reader so the drain stalls partway, then signal
+        let reader = notifier.reader.lock().unwrap();
+        let drain = {
+            let notifier = notifier.clone();
+            std::thread::spawn(move || notifier.drain())
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        notifier.notify();
+        drop(reader);
+        assert!(drain.join().unwrap());
+
+        // The fd must still work for the next signal
+        notifier.notify();
+        let stream = unsafe { UnixStream::from_raw_fd(notifier.fd()) };
+        let mut stream = std::mem::ManuallyDrop::new(stream);
+        assert!(matches!(stream.read(&mut [0u8; 1]), Ok(1)), "wakeup lost");
+    }
+}
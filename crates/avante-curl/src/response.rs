@@ -0,0 +1,197 @@
+use mlua::prelude::*;
+use reqwest::{header::HeaderMap, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::http::RedirectChain;
+
+// Response headers as a list of (name, value) pairs.
+// Repeated headers (set-cookie, link, www-authenticate, ...) keep every value.
+// The values of one header stay in received order, but `HeaderMap` groups them
+// by name, so different headers aren't interleaved as they were on the wire.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResponseHeaders(Vec<(String, String)>);
+
+impl ResponseHeaders {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn from_header_map(headers: &HeaderMap) -> Self {
+        let entries = headers
+            .iter()
+            .map(|(name, value)| (name.as_str().to_string(), header_value_to_string(value.as_bytes())))
+            .collect();
+        Self(entries)
+    }
+
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_ascii_lowercase(), value.to_string()));
+    }
+
+    // Drop every value of a header, case-insensitive
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    // First value for a header, case-insensitive
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // All values for a header in received order, case-insensitive
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Lua representation: `headers[name] = value`, repeated values comma-joined.
+    // `to_lua_list` keeps them apart.
+    pub fn to_lua_table(&self, lua: &Lua) -> LuaResult<LuaTable> {
+        let table = lua.create_table()?;
+        for (name, value) in self.iter() {
+            match table.get::<Option<String>>(name)? {
+                Some(joined) => table.set(name, format!("{joined}, {value}"))?,
+                None => table.set(name, value)?,
+            }
+        }
+        Ok(table)
+    }
+
+    // Lua representation: `{ { name, value }, ... }`, grouped by name
+    pub fn to_lua_list(&self, lua: &Lua) -> LuaResult<LuaTable> {
+        let list = lua.create_table_with_capacity(self.len(), 0)?;
+        for (name, value) in self.iter() {
+            list.push(lua.create_sequence_from([name, value])?)?;
+        }
+        Ok(list)
+    }
+}
+
+// Header values are bytes; decode non-UTF-8 values as ISO-8859-1 (obs-text)
+// instead of dropping them
+fn header_value_to_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+// Transport-level details about how a response was obtained
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResponseMeta {
+    pub url: String,             // Final URL after redirects
+    pub redirects: Vec<String>,  // URLs visited while following redirects, in order
+    pub http_version: String,    // e.g. "HTTP/1.1", "HTTP/2.0"
+    pub remote_addr: Option<String>,
+}
+
+impl ResponseMeta {
+    // Final URL, redirect chain, HTTP version and remote address of a response
+    pub fn from_response(response: &Response) -> Self {
+        let redirects = response.extensions().get::<RedirectChain>().map(|chain| chain.0.clone());
+        Self {
+            url: response.url().to_string(),
+            redirects: redirects.unwrap_or_default(),
+            http_version: format!("{:?}", response.version()),
+            remote_addr: response.remote_addr().map(|addr| addr.to_string()),
+        }
+    }
+}
+
+// Body decoded on the worker thread for `response_type = "json"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonBody {
+    pub value: serde_json::Value,
+    pub null_as_nil: bool, // Decode JSON null as nil instead of vim.NIL
+}
+
+impl JsonBody {
+    // Object keys holding null disappear with `null_as_nil`, and arrays get holes
+    pub fn to_lua(&self, lua: &Lua) -> LuaResult<LuaValue> {
+        if self.null_as_nil {
+            let options = LuaSerializeOptions::new().serialize_none_to_null(false).serialize_unit_to_null(false);
+            return lua.to_value_with(&self.value, options);
+        }
+
+        // Neovim's vim.NIL is a userdata of its own, not mlua's light userdata null
+        let null = match lua.globals().get::<Option<LuaTable>>("vim")? {
+            Some(vim) => match vim.get::<LuaValue>("NIL")? {
+                LuaValue::Nil => lua.null(),
+                nil => nil,
+            },
+            None => lua.null(),
+        };
+        json_to_lua(lua, &self.value, &null)
+    }
+}
+
+fn json_to_lua(lua: &Lua, value: &serde_json::Value, null: &LuaValue) -> LuaResult<LuaValue> {
+    match value {
+        serde_json::Value::Null => Ok(null.clone()),
+        serde_json::Value::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(json_to_lua(lua, item, null)?)?;
+            }
+            table.set_metatable(Some(lua.array_metatable()));
+            Ok(LuaValue::Table(table))
+        }
+        serde_json::Value::Object(map) => {
+            let table = lua.create_table_with_capacity(0, map.len())?;
+            for (key, item) in map {
+                table.raw_set(key.as_str(), json_to_lua(lua, item, null)?)?;
+            }
+            Ok(LuaValue::Table(table))
+        }
+        other => lua.to_value(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    #[test]
+    fn test_multi_value_headers() {
+        let mut map = HeaderMap::new();
+        map.append("set-cookie", HeaderValue::from_static("a=1"));
+        map.append("content-type", HeaderValue::from_static("text/plain"));
+        map.append("set-cookie", HeaderValue::from_static("b=2"));
+
+        let headers = ResponseHeaders::from_header_map(&map);
+        assert_eq!(headers.get_all("Set-Cookie"), vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("missing"), None);
+    }
+
+    #[test]
+    fn test_non_utf8_header_value() {
+        let mut map = HeaderMap::new();
+        map.insert(
+            HeaderName::from_static("x-name"),
+            HeaderValue::from_bytes(b"caf\xe9").unwrap(),
+        );
+
+        let headers = ResponseHeaders::from_header_map(&map);
+        assert_eq!(headers.get("x-name"), Some("café"));
+    }
+}
@@ -0,0 +1,293 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+// A request waiting for a slot. Higher priority first, then first come.
+struct Waiting {
+    priority: i32,
+    ticket: u64,
+    request_id: String,
+    grant: oneshot::Sender<Slot>,
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiting {}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.ticket.cmp(&self.ticket))
+    }
+}
+
+// A request holding a slot
+struct Running {
+    priority: i32,
+    request_id: String,
+    preempt: Option<oneshot::Sender<()>>, // Taken once chosen for preemption, or once pinned
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running: HashMap<u64, Running>,
+    queue: BinaryHeap<Waiting>,
+    next_ticket: u64,
+}
+
+// Hands out the session's request slots. Without a limit every request
+// starts right away; with one, waiting requests are started highest
+// priority first. With preemption, a request that finds every slot taken
+// bumps the lowest-priority running request below its own priority.
+pub struct Scheduler {
+    limit: Option<usize>,
+    preempt: bool,
+    state: Mutex<SchedulerState>,
+}
+
+// Permission to run; the slot is released when this is dropped
+pub struct Slot {
+    scheduler: Arc<Scheduler>,
+    ticket: u64,
+    preempted: oneshot::Receiver<()>,
+}
+
+impl Slot {
+    // Resolves once a higher-priority request claims this slot
+    pub async fn preempted(&mut self) {
+        if (&mut self.preempted).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler.release(self.ticket);
+    }
+}
+
+impl Scheduler {
+    pub fn new(limit: Option<usize>, preempt: bool) -> Self {
+        Self {
+            limit,
+            preempt,
+            state: Mutex::new(SchedulerState::default()),
+        }
+    }
+
+    // Wait for a slot. Returns None if the request was cancelled while queued.
+    pub async fn acquire(self: &Arc<Self>, request_id: &str, priority: i32) -> Option<Slot> {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+
+            if self.has_room(&state) {
+                return Some(self.start(&mut state, ticket, request_id, priority));
+            }
+
+            if self.preempt {
+                let victim = state
+                    .running
+                    .iter_mut()
+                    .filter(|(_, running)| running.priority < priority && running.preempt.is_some())
+                    // Lowest priority first, then the most recently started
+                    .min_by(|(a_ticket, a), (b_ticket, b)| a.priority.cmp(&b.priority).then(b_ticket.cmp(a_ticket)))
+                    .and_then(|(_, running)| running.preempt.take());
+                if let Some(victim) = victim {
+                    let _ = victim.send(());
+                }
+            }
+
+            let (grant, receiver) = oneshot::channel();
+            state.queue.push(Waiting {
+                priority,
+                ticket,
+                request_id: request_id.to_string(),
+                grant,
+            });
+            receiver
+        };
+
+        receiver.await.ok()
+    }
+
+    // Drop a queued request, making its `acquire` return None
+    pub fn cancel(&self, request_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.retain(|waiting| waiting.request_id != request_id);
+    }
+
+    // Keep a running request from being preempted, e.g. once its response
+    // has started reaching Lua and a restart would deliver it twice
+    pub fn pin(&self, request_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        for running in state.running.values_mut() {
+            if running.request_id == request_id {
+                running.preempt = None;
+            }
+        }
+    }
+
+    // Requests currently holding a slot and waiting for one
+    #[cfg(test)]
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running.len(), state.queue.len())
+    }
+
+    fn has_room(&self, state: &SchedulerState) -> bool {
+        match self.limit {
+            Some(limit) => state.running.len() < limit,
+            None => true,
+        }
+    }
+
+    fn start(self: &Arc<Self>, state: &mut SchedulerState, ticket: u64, request_id: &str, priority: i32) -> Slot {
+        let (preempt, preempted) = oneshot::channel();
+        let request_id = request_id.to_string();
+        state.running.insert(ticket, Running { priority, request_id, preempt: Some(preempt) });
+        Slot {
+            scheduler: self.clone(),
+            ticket,
+            preempted,
+        }
+    }
+
+    fn release(self: &Arc<Self>, ticket: u64) {
+        // A slot whose waiter went away comes back here and is released in
+        // turn, after the lock is dropped
+        let mut unclaimed = None;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running.remove(&ticket);
+
+            if self.has_room(&state) {
+                if let Some(waiting) = state.queue.pop() {
+                    let slot = self.start(&mut state, waiting.ticket, &waiting.request_id, waiting.priority);
+                    unclaimed = waiting.grant.send(slot).err();
+                }
+            }
+        }
+        drop(unclaimed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_priority_order() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let scheduler = Arc::new(Scheduler::new(Some(1), false));
+            let first = scheduler.acquire("first", 0).await.unwrap();
+
+            let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+            for (request_id, priority) in [("low", -1), ("normal", 0), ("high", 10), ("normal-2", 0)] {
+                let scheduler = scheduler.clone();
+                let order_tx = order_tx.clone();
+                tokio::spawn(async move {
+                    let _slot = scheduler.acquire(request_id, priority).await.unwrap();
+                    order_tx.send(request_id).unwrap();
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(scheduler.counts(), (1, 4));
+
+            drop(first);
+            let mut order = Vec::new();
+            for _ in 0..4 {
+                order.push(order_rx.recv().await.unwrap());
+            }
+            assert_eq!(order, vec!["high", "normal", "normal-2", "low"]);
+            assert_eq!(scheduler.counts(), (0, 0));
+        });
+    }
+
+    #[test]
+    fn test_cancel_queued() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let scheduler = Arc::new(Scheduler::new(Some(1), false));
+            let first = scheduler.acquire("first", 0).await.unwrap();
+
+            let waiter = {
+                let scheduler = scheduler.clone();
+                tokio::spawn(async move { scheduler.acquire("queued", 0).await.is_some() })
+            };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            scheduler.cancel("queued");
+            assert!(!waiter.await.unwrap());
+
+            drop(first);
+            assert_eq!(scheduler.counts(), (0, 0));
+        });
+    }
+
+    #[test]
+    fn test_preemption() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let scheduler = Arc::new(Scheduler::new(Some(2), true));
+            let mut background = scheduler.acquire("background", -1).await.unwrap();
+            let mut chat = scheduler.acquire("chat", 10).await.unwrap();
+
+            // Same priority as what's running: waits its turn
+            let waiter = {
+                let scheduler = scheduler.clone();
+                tokio::spawn(async move { scheduler.acquire("other-chat", 10).await.is_some() })
+            };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(tokio::time::timeout(Duration::from_millis(20), chat.preempted()).await.is_err());
+
+            // Higher than the background request: bumps it
+            let interactive = {
+                let scheduler = scheduler.clone();
+                tokio::spawn(async move { scheduler.acquire("interactive", 20).await.is_some() })
+            };
+            tokio::time::timeout(Duration::from_secs(1), background.preempted()).await.unwrap();
+            drop(background);
+
+            assert!(interactive.await.unwrap());
+            drop(chat);
+            assert!(waiter.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_pinned_request_is_not_preempted() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let scheduler = Arc::new(Scheduler::new(Some(2), true));
+            let mut streaming = scheduler.acquire("streaming", -1).await.unwrap();
+            let mut idle = scheduler.acquire("idle", 0).await.unwrap();
+            scheduler.pin("streaming");
+
+            // The lowest priority request is pinned, so the next one is bumped
+            let interactive = {
+                let scheduler = scheduler.clone();
+                tokio::spawn(async move { scheduler.acquire("interactive", 20).await.is_some() })
+            };
+            tokio::time::timeout(Duration::from_secs(1), idle.preempted()).await.unwrap();
+            assert!(tokio::time::timeout(Duration::from_millis(20), streaming.preempted()).await.is_err());
+            drop(idle);
+            assert!(interactive.await.unwrap());
+        });
+    }
+}
@@ -1,10 +1,25 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Poll, Waker};
 use core::fmt;
 
+use crate::batch::Batch;
+use crate::cache::HttpCache;
+use crate::error::ErrorInfo;
+use crate::framing::Frame;
+use crate::middleware::{MiddlewareChain, SentRequest};
+use crate::notify::EventNotifier;
+use crate::scheduler::Scheduler;
+use crate::response::{JsonBody, ResponseHeaders, ResponseMeta};
+use crate::token::TokenProvider;
+use crate::usage::{find_price, RequestUsage, SessionUsage, TokenUsage};
+use crate::ws::WsHandle;
+use crate::SessionOptions;
+
 // Request state enum to track current status
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RequestState {
@@ -35,25 +50,61 @@ impl fmt::Display for RequestState {
     }
 }
 
+impl RequestState {
+    // Whether the request has finished, one way or another
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, RequestState::Init | RequestState::Sending | RequestState::Receiving)
+    }
+}
+
 // Response information stored per request
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestInfo {
     pub request_id: String,
     pub state: RequestState,
     pub status: Option<u16>,
-    pub headers: Option<HashMap<String, String>>,
+    pub headers: Option<ResponseHeaders>,
+    pub meta: Option<ResponseMeta>,
     pub body: Option<String>,
-    pub error: Option<String>,
+    pub json: Option<JsonBody>, // Parsed body for `response_type = "json"`
+    pub error: Option<ErrorInfo>,
+    pub sent: Option<SentRequest>,          // Request as dispatched, after middleware
+    pub usage: Option<serde_json::Value>,   // Provider usage, with the usage middleware
+    pub token_usage: Option<RequestUsage>,  // `usage` normalized, with the cost when priced
+    pub truncated: bool,     // Body was cut short by max_response_bytes / max_buffered_bytes
+    pub received_bytes: u64, // Body bytes read off the wire, before decompression
+    pub cache: Option<String>,  // "hit", "revalidated", "miss" or "bypass" when the session has a cache
+    pub restarts: u32,       // Times the request was preempted and requeued
+    pub retry: Option<RetryInfo>, // Last retry after a retryable failure, with `retries`
+    pub long_lived: bool,    // Connections like WebSockets skip the stall timeout
     pub last_polled: u64,    // Timestamp of last poll
     pub created_at: u64,     // Timestamp of creation
     pub updated_at: u64,     // Timestamp of last update
 }
 
+// A failed attempt that is about to be retried. Everything the attempt
+// received is dropped from the request when this is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryInfo {
+    pub attempt: u32, // 1 for the first retry
+    pub delay: f64,   // Seconds before the next attempt starts
+    pub error: ErrorInfo,
+}
+
+// Discrete message received on a streaming connection (WebSocket, or HTTP
+// chunks when a request queues them for `next_chunk`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StreamMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Close { code: u16, reason: String },
+}
+
 // Callback handlers for request events
 pub struct CallbackHandlers {
     pub on_chunk: Option<Arc<Mutex<Box<dyn Fn(&str) + Send + 'static>>>>,
     pub on_complete: Option<Arc<Mutex<Box<dyn Fn(&RequestInfo) + Send + 'static>>>>,
-    pub on_error: Option<Arc<Mutex<Box<dyn Fn(&str) + Send + 'static>>>>,
+    pub on_error: Option<Arc<Mutex<Box<dyn Fn(&ErrorInfo) + Send + 'static>>>>,
 }
 
 // RequestManager keeps track of request states
@@ -61,29 +112,93 @@ pub struct RequestManager {
     requests: DashMap<String, Arc<RwLock<RequestInfo>>>,
     callbacks: DashMap<String, CallbackHandlers>,
     cancellations: DashMap<String, Arc<AtomicBool>>,
+    messages: DashMap<String, VecDeque<StreamMessage>>, // Queued until the next poll
+    events: DashMap<String, VecDeque<Frame>>, // Framed body events, for requests with `framing`
     idle_timeout: u64,       // Seconds after which an unpolled request is considered idle
     cleanup_interval: u64,   // Seconds between cleanup operations
     last_cleanup: Arc<AtomicU64>,  // Timestamp of last cleanup
+    notifier: Option<Arc<EventNotifier>>, // Wakes the Lua event loop on new events
+    waiters: Mutex<HashMap<u64, Waker>>, // One waker per pending `wait_until`, woken on every event
+    next_waiter: AtomicU64,
 }
 
 // Session class to handle requests for a specific client
 pub struct Session {
     request_manager: RequestManager,
+    options: SessionOptions, // Defaults applied to every request of the session
+    middleware: MiddlewareChain,
+    token_providers: DashMap<String, Arc<TokenProvider>>,
+    websockets: DashMap<String, WsHandle>,
+    batches: DashMap<String, Arc<Batch>>,
+    cache: Option<Arc<HttpCache>>,
+    scheduler: Arc<Scheduler>,
+    usage: Mutex<SessionUsage>, // Token usage of every request so far
 }
 
 impl Session {
     pub fn new() -> Self {
+        Self::with_options(SessionOptions::default())
+    }
+
+    pub fn with_options(options: SessionOptions) -> Self {
         Self {
             request_manager: RequestManager::new(),
+            middleware: MiddlewareChain::new(options.middleware.clone().unwrap_or_default()),
+            scheduler: Arc::new(Scheduler::new(options.max_concurrent, options.preempt.unwrap_or(false))),
+            usage: Mutex::new(SessionUsage::default()),
+            options,
+            token_providers: DashMap::new(),
+            websockets: DashMap::new(),
+            batches: DashMap::new(),
+            cache: None,
         }
     }
 
     pub fn with_config(idle_timeout: u64, cleanup_interval: u64) -> Self {
         Self {
             request_manager: RequestManager::with_config(idle_timeout, cleanup_interval),
+            options: SessionOptions::default(),
+            middleware: MiddlewareChain::default(),
+            scheduler: Arc::new(Scheduler::new(None, false)),
+            usage: Mutex::new(SessionUsage::default()),
+            token_providers: DashMap::new(),
+            websockets: DashMap::new(),
+            batches: DashMap::new(),
+            cache: None,
         }
     }
 
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    pub fn middleware(&self) -> &MiddlewareChain {
+        &self.middleware
+    }
+
+    // Use a disk cache for the session's GET requests
+    pub fn with_cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    pub fn cache(&self) -> Option<&Arc<HttpCache>> {
+        self.cache.as_ref()
+    }
+
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
+    // Register (or replace) a named token provider
+    pub fn register_token_provider(&self, name: &str, provider: TokenProvider) {
+        self.token_providers.insert(name.to_string(), Arc::new(provider));
+    }
+
+    pub fn token_provider(&self, name: &str) -> Option<Arc<TokenProvider>> {
+        self.token_providers.get(name).map(|p| p.value().clone())
+    }
+
     pub fn init_request(&self, request_id: &str) -> Result<Arc<AtomicBool>, String> {
         self.request_manager.init_request(request_id)
     }
@@ -96,8 +211,19 @@ impl Session {
                 state: RequestState::Error,
                 status: None,
                 headers: None,
+                meta: None,
                 body: None,
-                error: Some(format!("Request '{}' not found", request_id)),
+                json: None,
+                sent: None,
+                usage: None,
+                token_usage: None,
+                truncated: false,
+                received_bytes: 0,
+                cache: None,
+                restarts: 0,
+                retry: None,
+                error: Some(ErrorInfo::new("session", &format!("Request '{}' not found", request_id))),
+                long_lived: false,
                 last_polled: Self::timestamp_now(),
                 created_at: Self::timestamp_now(),
                 updated_at: Self::timestamp_now(),
@@ -105,15 +231,42 @@ impl Session {
         }
     }
 
-    pub fn set_response(&self, request_id: &str, status: u16, headers: HashMap<String, String>, body: &str) {
+    pub fn set_response(&self, request_id: &str, status: u16, headers: ResponseHeaders, body: &str) {
         self.request_manager.set_response(request_id, status, headers, body);
     }
 
+    pub fn set_response_meta(&self, request_id: &str, meta: ResponseMeta) {
+        self.request_manager.set_response_meta(request_id, meta);
+    }
+
+    pub fn set_json(&self, request_id: &str, json: JsonBody) {
+        self.request_manager.set_json(request_id, json);
+    }
+
+    pub fn set_sent(&self, request_id: &str, sent: SentRequest) {
+        self.request_manager.set_sent(request_id, sent);
+    }
+
+    // Store a request's usage and add it to the session totals
+    pub fn record_usage(&self, request_id: &str, raw: serde_json::Value, tokens: TokenUsage, model: Option<String>) {
+        let cost = match (&self.options.pricing, &model) {
+            (Some(pricing), Some(model)) => find_price(pricing, model).map(|price| price.cost(&tokens)),
+            _ => None,
+        };
+        let usage = RequestUsage { tokens, model, cost };
+        self.usage.lock().unwrap().add(&usage);
+        self.request_manager.set_usage(request_id, raw, usage);
+    }
+
+    pub fn usage(&self) -> SessionUsage {
+        self.usage.lock().unwrap().clone()
+    }
+
     pub fn set_completed(&self, request_id: &str) {
         self.request_manager.set_completed(request_id);
     }
 
-    pub fn set_error(&self, request_id: &str, error: &str) {
+    pub fn set_error(&self, request_id: &str, error: ErrorInfo) {
         self.request_manager.set_error(request_id, error);
     }
 
@@ -121,149 +274,165 @@ impl Session {
         self.request_manager.handle_chunk(request_id, data);
     }
 
+    pub fn handle_stream_event_unbuffered(&self, request_id: &str, data: &str) {
+        self.request_manager.handle_chunk_unbuffered(request_id, data);
+    }
+
+    pub fn set_truncated(&self, request_id: &str) {
+        self.request_manager.set_truncated(request_id);
+    }
+
+    pub fn add_received_bytes(&self, request_id: &str, count: u64) {
+        self.request_manager.add_received_bytes(request_id, count);
+    }
+
+    pub fn set_cache_status(&self, request_id: &str, status: &str) {
+        self.request_manager.set_cache_status(request_id, status);
+    }
+
+    pub fn requeue(&self, request_id: &str) -> bool {
+        self.request_manager.requeue(request_id)
+    }
+
+    pub fn retry(&self, request_id: &str, retry: RetryInfo) -> bool {
+        self.request_manager.retry(request_id, retry)
+    }
+
     pub fn cancel_request(&self, request_id: &str) {
         self.request_manager.cancel_request(request_id);
+        self.scheduler.cancel(request_id);
+        // Dropping the handle makes the connection task close the socket
+        self.websockets.remove(request_id);
     }
 
-    pub fn should_cancel(&self, request_id: &str) -> bool {
-        self.request_manager.should_cancel(request_id)
+    // Mark a finished request as handled so the next cleanup drops it
+    pub fn acknowledge_request(&self, request_id: &str) -> bool {
+        self.request_manager.acknowledge_request(request_id)
     }
 
-    pub fn set_callbacks(&self, request_id: &str,
-                         on_chunk: Option<Box<dyn Fn(&str) + Send + 'static>>,
-                         on_complete: Option<Box<dyn Fn(&RequestInfo) + Send + 'static>>,
-                         on_error: Option<Box<dyn Fn(&str) + Send + 'static>>) {
-        self.request_manager.set_callbacks(request_id, on_chunk, on_complete, on_error);
+    pub fn push_message(&self, request_id: &str, message: StreamMessage) {
+        self.request_manager.push_message(request_id, message);
     }
 
-    // Helper to get current timestamp
-    fn timestamp_now() -> u64 {
-        RequestManager::timestamp_now()
+    pub fn set_long_lived(&self, request_id: &str) {
+        self.request_manager.set_long_lived(request_id);
     }
-}
 
-impl RequestManager {
-    pub fn new() -> Self {
-        Self {
-            requests: DashMap::new(),
-            callbacks: DashMap::new(),
-            cancellations: DashMap::new(),
-            idle_timeout: 3600,       // Default: 1 hour
-            cleanup_interval: 300,    // Default: 5 minutes
-            last_cleanup: Arc::new(AtomicU64::new(Self::timestamp_now())),
-        }
+    pub fn take_messages(&self, request_id: &str) -> Vec<StreamMessage> {
+        self.request_manager.take_messages(request_id)
     }
 
-    pub fn init_request(&self, request_id: &str) -> Result<Arc<AtomicBool>, String> {
-        let now = Self::timestamp_now();
-        let cancel_flag = Arc::new(AtomicBool::new(false));
+    pub fn message_count(&self, request_id: &str) -> usize {
+        self.request_manager.message_count(request_id)
+    }
 
-        if let Some(existing) = self.requests.get(request_id) {
-            let mut req = existing.write().unwrap();
-            match req.state {
-                RequestState::Complete | RequestState::Error | RequestState::Timeout | RequestState::Cancelled | RequestState::Idle => {
-                    req.state = RequestState::Init;
-                    req.status = None;
-                    req.headers = None;
-                    req.body = None;
-                    req.error = None;
-                    req.last_polled = now;
-                    req.updated_at = now;
+    pub fn pop_message(&self, request_id: &str) -> Option<StreamMessage> {
+        self.request_manager.pop_message(request_id)
+    }
 
-                    self.cancellations.insert(request_id.to_string(), cancel_flag.clone());
+    pub fn push_events(&self, request_id: &str, frames: Vec<Frame>) {
+        self.request_manager.push_events(request_id, frames);
+    }
 
-                    Ok(cancel_flag)
-                },
-                _ => Err(format!("Request '{}' is already in progress with state: {}", request_id, req.state))
-            }
-        } else {
-            let request_info = RequestInfo {
-                request_id: request_id.to_string(),
-                state: RequestState::Init,
-                status: None,
-                headers: None,
-                body: None,
-                error: None,
-                last_polled: now,
-                created_at: now,
-                updated_at: now,
-            };
+    pub fn take_events(&self, request_id: &str) -> Vec<Frame> {
+        self.request_manager.take_events(request_id)
+    }
 
-            self.requests.insert(request_id.to_string(), Arc::new(RwLock::new(request_info)));
-            self.cancellations.insert(request_id.to_string(), cancel_flag.clone());
+    pub fn event_count(&self, request_id: &str) -> usize {
+        self.request_manager.event_count(request_id)
+    }
 
-            Ok(cancel_flag)
-        }
+    pub fn pop_event(&self, request_id: &str) -> Option<Frame> {
+        self.request_manager.pop_event(request_id)
     }
 
-    pub fn poll_request(&self, request_id: &str) -> Option<RequestInfo> {
-        let now = Self::timestamp_now();
-        self.try_cleanup(now);
+    pub fn queue_chunks(&self, request_id: &str) {
+        self.request_manager.queue_chunks(request_id);
+    }
 
-        if let Some(req_lock) = self.requests.get(request_id) {
-            let mut req = req_lock.write().unwrap();
-            req.last_polled = now;
+    pub fn wait_until<'a, T>(&'a self, ready: impl FnMut() -> Option<T> + 'a) -> impl Future<Output = T> + 'a {
+        self.request_manager.wait_until(ready)
+    }
 
-            if req.state == RequestState::Sending || req.state == RequestState::Receiving {
-                let time_since_update = now - req.updated_at;
-                if time_since_update > 30 {
-                    req.state = RequestState::Timeout;
-                    req.error = Some("Request timed out".to_string());
-                }
-            }
+    pub fn add_websocket(&self, connection_id: &str, handle: WsHandle) {
+        self.websockets.insert(connection_id.to_string(), handle);
+    }
 
-            return Some(req.clone());
+    pub fn remove_websocket(&self, connection_id: &str) -> Option<WsHandle> {
+        self.websockets.remove(connection_id).map(|(_, handle)| handle)
+    }
+
+    // Run a closure against an open connection
+    pub fn with_websocket<T>(&self, connection_id: &str, f: impl FnOnce(&WsHandle) -> T) -> Option<T> {
+        self.websockets.get(connection_id).map(|handle| f(handle.value()))
+    }
+
+    pub fn should_cancel(&self, request_id: &str) -> bool {
+        self.request_manager.should_cancel(request_id)
+    }
+
+    pub fn cleanup(&self) {
+        self.request_manager.cleanup();
+    }
+
+    // Cancel every unfinished request and close all WebSockets
+    pub fn cancel_all(&self) {
+        for request_id in self.request_manager.active_requests() {
+            self.cancel_request(&request_id);
         }
+        self.websockets.clear();
+    }
 
-        None
+    pub fn add_batch(&self, batch_id: &str, batch: Batch) -> Arc<Batch> {
+        let batch = Arc::new(batch);
+        self.batches.insert(batch_id.to_string(), batch.clone());
+        batch
     }
 
-    pub fn set_response(&self, request_id: &str, status: u16, headers: HashMap<String, String>, body: &str) {
-        if let Some(req_lock) = self.requests.get(request_id) {
-            let mut req = req_lock.write().unwrap();
-            req.status = Some(status);
-            req.headers = Some(headers);
-            req.body = Some(body.to_string());
-            req.updated_at = Self::timestamp_now();
+    pub fn batch(&self, batch_id: &str) -> Option<Arc<Batch>> {
+        self.batches.get(batch_id).map(|batch| batch.clone())
+    }
+
+    // Status of every sub-request of a batch. Once they've all finished the
+    // result has been handed out, so the batch is forgotten.
+    pub fn collect_batch(&self, batch_id: &str) -> Option<Vec<RequestInfo>> {
+        let batch = self.batch(batch_id)?;
+        let infos: Vec<RequestInfo> = batch.request_ids.iter().map(|id| self.get_response(id)).collect();
+        if infos.iter().all(|info| info.state.is_terminal()) {
+            self.batches.remove(batch_id);
         }
+        Some(infos)
     }
 
-    pub fn set_completed(&self, request_id: &str) {
-        let req_info = {
-            if let Some(req_lock) = self.requests.get(request_id) {
-                let mut req = req_lock.write().unwrap();
-                req.state = RequestState::Complete;
-                req.updated_at = Self::timestamp_now();
-                req.clone()
-            } else {
-                return;
-            }
+    // Cancel every sub-request of a batch that hasn't finished yet
+    pub fn cancel_batch(&self, batch_id: &str) -> bool {
+        let batch = match self.batch(batch_id) {
+            Some(batch) => batch,
+            None => return false,
         };
 
-        if let Some(callbacks) = self.callbacks.get(request_id) {
-            if let Some(on_complete) = &callbacks.on_complete {
-                if let Ok(handler) = on_complete.lock() {
-                    handler(&req_info);
-                }
+        for request_id in &batch.request_ids {
+            if !self.get_response(request_id).state.is_terminal() {
+                self.cancel_request(request_id);
             }
         }
+        true
     }
 
-    pub fn set_error(&self, request_id: &str, error: &str) {
-        if let Some(req_lock) = self.requests.get(request_id) {
-            let mut req = req_lock.write().unwrap();
-            req.state = RequestState::Error;
-            req.error = Some(error.to_string());
-            req.updated_at = Self::timestamp_now();
-        }
+    pub fn event_notifier(&self) -> Option<Arc<EventNotifier>> {
+        self.request_manager.event_notifier()
+    }
 
-        if let Some(callbacks) = self.callbacks.get(request_id) {
-            if let Some(on_error) = &callbacks.on_error {
-                if let Ok(handler) = on_error.lock() {
-                    handler(error);
-                }
-            }
-        }
+    pub fn set_callbacks(&self, request_id: &str,
+                         on_chunk: Option<Box<dyn Fn(&str) + Send + 'static>>,
+                         on_complete: Option<Box<dyn Fn(&RequestInfo) + Send + 'static>>,
+                         on_error: Option<Box<dyn Fn(&ErrorInfo) + Send + 'static>>) {
+        self.request_manager.set_callbacks(request_id, on_chunk, on_complete, on_error);
+    }
+
+    // Helper to get current timestamp
+    fn timestamp_now() -> u64 {
+        RequestManager::timestamp_now()
     }
 }
 
@@ -273,19 +442,47 @@ impl fmt::Debug for RequestManager {
             .field("requests", &self.requests)
             .field("idle_timeout", &self.idle_timeout)
             .field("cleanup_interval", &self.cleanup_interval)
+            .field("notifier", &self.notifier.is_some())
             .finish()
     }
 }
 
+// A `wait_until` future's slot in the waiter map. Polling again replaces the
+// waker instead of adding another one, and dropping the future removes it.
+struct Waiter<'a> {
+    id: u64,
+    waiters: &'a Mutex<HashMap<u64, Waker>>,
+}
+
+impl Waiter<'_> {
+    fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.get(&self.id).is_some_and(|current| current.will_wake(waker)) {
+            waiters.insert(self.id, waker.clone());
+        }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.waiters.lock().unwrap().remove(&self.id);
+    }
+}
+
 impl RequestManager {
     pub fn new() -> Self {
         Self {
             requests: DashMap::new(),
             callbacks: DashMap::new(),
             cancellations: DashMap::new(),
+            messages: DashMap::new(),
+            events: DashMap::new(),
             idle_timeout: 3600,       // Default: 1 hour
             cleanup_interval: 300,    // Default: 5 minutes
             last_cleanup: Arc::new(AtomicU64::new(Self::timestamp_now())),
+            notifier: EventNotifier::new().ok().map(Arc::new),
+            waiters: Mutex::new(HashMap::new()),
+            next_waiter: AtomicU64::new(0),
         }
     }
 
@@ -294,12 +491,48 @@ impl RequestManager {
             requests: DashMap::new(),
             callbacks: DashMap::new(),
             cancellations: DashMap::new(),
+            messages: DashMap::new(),
+            events: DashMap::new(),
             idle_timeout,
             cleanup_interval,
             last_cleanup: Arc::new(AtomicU64::new(Self::timestamp_now())),
+            notifier: EventNotifier::new().ok().map(Arc::new),
+            waiters: Mutex::new(HashMap::new()),
+            next_waiter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn event_notifier(&self) -> Option<Arc<EventNotifier>> {
+        self.notifier.clone()
+    }
+
+    // Wake up anyone waiting on the event fd or in `wait_until`
+    fn notify(&self) {
+        if let Some(notifier) = &self.notifier {
+            notifier.notify();
+        }
+
+        let waiters: Vec<Waker> = self.waiters.lock().unwrap().drain().map(|(_, waker)| waker).collect();
+        for waker in waiters {
+            waker.wake();
         }
     }
 
+    // Resolve once `ready` returns a value. It's re-checked whenever the
+    // future is polled, so it also works under drivers that don't wake, like
+    // a Lua coroutine resumed from the event loop.
+    pub fn wait_until<'a, T>(&'a self, mut ready: impl FnMut() -> Option<T> + 'a) -> impl Future<Output = T> + 'a {
+        let waiter = Waiter { id: self.next_waiter.fetch_add(1, Ordering::Relaxed), waiters: &self.waiters };
+        std::future::poll_fn(move |cx| {
+            // Register before checking so an event in between isn't missed
+            waiter.register(cx.waker());
+            match ready() {
+                Some(value) => Poll::Ready(value),
+                None => Poll::Pending,
+            }
+        })
+    }
+
     // Get current timestamp in seconds
     fn timestamp_now() -> u64 {
         let now = std::time::SystemTime::now()
@@ -327,8 +560,19 @@ impl RequestManager {
                     req.state = RequestState::Init;
                     req.status = None;
                     req.headers = None;
+                    req.meta = None;
                     req.body = None;
+                    req.json = None;
                     req.error = None;
+                    req.sent = None;
+                    req.usage = None;
+                    req.token_usage = None;
+                    req.truncated = false;
+                    req.received_bytes = 0;
+                    req.cache = None;
+                    req.restarts = 0;
+                    req.retry = None;
+                    req.long_lived = false;
                     req.last_polled = now;
                     req.updated_at = now;
 
@@ -349,8 +593,19 @@ impl RequestManager {
                 state: RequestState::Init,
                 status: None,
                 headers: None,
+                meta: None,
                 body: None,
+                json: None,
                 error: None,
+                sent: None,
+                usage: None,
+                token_usage: None,
+                truncated: false,
+                received_bytes: 0,
+                cache: None,
+                restarts: 0,
+                retry: None,
+                long_lived: false,
                 last_polled: now,
                 created_at: now,
                 updated_at: now,
@@ -367,7 +622,7 @@ impl RequestManager {
     pub fn set_callbacks(&self, request_id: &str,
                          on_chunk: Option<Box<dyn Fn(&str) + Send + 'static>>,
                          on_complete: Option<Box<dyn Fn(&RequestInfo) + Send + 'static>>,
-                         on_error: Option<Box<dyn Fn(&str) + Send + 'static>>) {
+                         on_error: Option<Box<dyn Fn(&ErrorInfo) + Send + 'static>>) {
 
         let handlers = CallbackHandlers {
             on_chunk: on_chunk.map(|h| Arc::new(Mutex::new(h))),
@@ -380,6 +635,16 @@ impl RequestManager {
 
     // Process a chunk of data from the response
     pub fn handle_chunk(&self, request_id: &str, data: &str) -> bool {
+        self.receive_chunk(request_id, data, true)
+    }
+
+    // Deliver a chunk without adding it to the stored body, once the body has
+    // hit its buffer limit
+    pub fn handle_chunk_unbuffered(&self, request_id: &str, data: &str) -> bool {
+        self.receive_chunk(request_id, data, false)
+    }
+
+    fn receive_chunk(&self, request_id: &str, data: &str, buffer: bool) -> bool {
         // Update request state
         if let Some(req_lock) = self.requests.get(request_id) {
             let mut req = req_lock.write().unwrap();
@@ -387,15 +652,24 @@ impl RequestManager {
             req.updated_at = Self::timestamp_now();
 
             // Append to body if it exists
-            if let Some(body) = &mut req.body {
-                body.push_str(data);
-            } else {
-                req.body = Some(data.to_string());
+            if buffer {
+                if let Some(body) = &mut req.body {
+                    body.push_str(data);
+                } else {
+                    req.body = Some(data.to_string());
+                }
             }
         } else {
             return false;
         }
 
+        // Queue the chunk if someone is iterating over them
+        if let Some(mut queue) = self.messages.get_mut(request_id) {
+            queue.push_back(StreamMessage::Text(data.to_string()));
+        }
+
+        self.notify();
+
         // Call the on_chunk callback if it exists
         if let Some(callbacks) = self.callbacks.get(request_id) {
             if let Some(on_chunk) = &callbacks.on_chunk {
@@ -410,7 +684,7 @@ impl RequestManager {
     }
 
     // Set the response for a request
-    pub fn set_response(&self, request_id: &str, status: u16, headers: HashMap<String, String>, body: &str) {
+    pub fn set_response(&self, request_id: &str, status: u16, headers: ResponseHeaders, body: &str) {
         if let Some(req_lock) = self.requests.get(request_id) {
             let mut req = req_lock.write().unwrap();
             req.status = Some(status);
@@ -418,13 +692,213 @@ impl RequestManager {
             req.body = Some(body.to_string());
             req.updated_at = Self::timestamp_now();
         }
+
+        self.notify();
+    }
+
+    // Record the final URL, redirect chain and connection details
+    pub fn set_response_meta(&self, request_id: &str, meta: ResponseMeta) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            let mut req = req_lock.write().unwrap();
+            req.meta = Some(meta);
+            req.updated_at = Self::timestamp_now();
+        }
+    }
+
+    // Flag a body that was cut short by a size limit
+    pub fn set_truncated(&self, request_id: &str) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            req_lock.write().unwrap().truncated = true;
+        }
+    }
+
+    pub fn add_received_bytes(&self, request_id: &str, count: u64) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            req_lock.write().unwrap().received_bytes += count;
+        }
     }
 
-    // Mark a request as complete and trigger callbacks
+    // Put a preempted request back to Init. Only requests still waiting for
+    // their response qualify: once it has reached Lua, or the request was
+    // cancelled, there's nothing to restart. Returns whether it was requeued.
+    pub fn requeue(&self, request_id: &str) -> bool {
+        {
+            let req_lock = match self.requests.get(request_id) {
+                Some(req_lock) => req_lock,
+                None => return false,
+            };
+            let mut req = req_lock.write().unwrap();
+            if !matches!(req.state, RequestState::Init | RequestState::Sending) || req.status.is_some() {
+                return false;
+            }
+            Self::clear_attempt(&mut req);
+            req.restarts += 1;
+        }
+        self.clear_queues(request_id);
+        self.notify();
+        true
+    }
+
+    // Put a failed request back to Init for another attempt, recording why.
+    // Returns false if the request was cancelled in the meantime.
+    pub fn retry(&self, request_id: &str, retry: RetryInfo) -> bool {
+        {
+            let req_lock = match self.requests.get(request_id) {
+                Some(req_lock) => req_lock,
+                None => return false,
+            };
+            let mut req = req_lock.write().unwrap();
+            if req.state == RequestState::Cancelled {
+                return false;
+            }
+            Self::clear_attempt(&mut req);
+            req.error = None;
+            req.retry = Some(retry);
+        }
+        self.clear_queues(request_id);
+        self.notify();
+        true
+    }
+
+    // Forget everything an attempt received
+    fn clear_attempt(req: &mut RequestInfo) {
+        req.state = RequestState::Init;
+        req.status = None;
+        req.headers = None;
+        req.meta = None;
+        req.body = None;
+        req.json = None;
+        req.sent = None;
+        req.usage = None;
+        req.token_usage = None;
+        req.truncated = false;
+        req.received_bytes = 0;
+        req.cache = None;
+        req.updated_at = Self::timestamp_now();
+    }
+
+    fn clear_queues(&self, request_id: &str) {
+        if let Some(mut queue) = self.messages.get_mut(request_id) {
+            queue.clear();
+        }
+        if let Some(mut queue) = self.events.get_mut(request_id) {
+            queue.clear();
+        }
+    }
+
+    // Record how the session cache handled the request
+    pub fn set_cache_status(&self, request_id: &str, status: &str) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            req_lock.write().unwrap().cache = Some(status.to_string());
+        }
+    }
+
+    // Store the decoded JSON body
+    pub fn set_json(&self, request_id: &str, json: JsonBody) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            let mut req = req_lock.write().unwrap();
+            req.json = Some(json);
+            req.updated_at = Self::timestamp_now();
+        }
+    }
+
+    // Record the request as it was dispatched
+    pub fn set_sent(&self, request_id: &str, sent: SentRequest) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            req_lock.write().unwrap().sent = Some(sent);
+        }
+    }
+
+    // Store the usage reported by the provider
+    pub fn set_usage(&self, request_id: &str, usage: serde_json::Value, token_usage: RequestUsage) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            let mut req = req_lock.write().unwrap();
+            req.usage = Some(usage);
+            req.token_usage = Some(token_usage);
+            req.updated_at = Self::timestamp_now();
+        }
+    }
+
+    // Exempt a request from the stall timeout
+    pub fn set_long_lived(&self, request_id: &str) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            req_lock.write().unwrap().long_lived = true;
+        }
+    }
+
+    // Queue a message for the next poll
+    pub fn push_message(&self, request_id: &str, message: StreamMessage) {
+        if let Some(req_lock) = self.requests.get(request_id) {
+            let mut req = req_lock.write().unwrap();
+            req.state = RequestState::Receiving;
+            req.updated_at = Self::timestamp_now();
+        } else {
+            return;
+        }
+
+        self.messages.entry(request_id.to_string()).or_default().push_back(message);
+        self.notify();
+    }
+
+    // Drain queued messages
+    pub fn take_messages(&self, request_id: &str) -> Vec<StreamMessage> {
+        match self.messages.get_mut(request_id) {
+            Some(mut queue) => queue.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Number of queued messages, without consuming them
+    pub fn message_count(&self, request_id: &str) -> usize {
+        self.messages.get(request_id).map_or(0, |queue| queue.len())
+    }
+
+    // Take the oldest queued message
+    pub fn pop_message(&self, request_id: &str) -> Option<StreamMessage> {
+        self.messages.get_mut(request_id)?.pop_front()
+    }
+
+    // Queue events framed from the body
+    pub fn push_events(&self, request_id: &str, frames: Vec<Frame>) {
+        if frames.is_empty() || !self.requests.contains_key(request_id) {
+            return;
+        }
+        self.events.entry(request_id.to_string()).or_default().extend(frames);
+        self.notify();
+    }
+
+    // Drain queued events
+    pub fn take_events(&self, request_id: &str) -> Vec<Frame> {
+        match self.events.get_mut(request_id) {
+            Some(mut queue) => queue.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Number of queued events, without consuming them
+    pub fn event_count(&self, request_id: &str) -> usize {
+        self.events.get(request_id).map_or(0, |queue| queue.len())
+    }
+
+    // Take the oldest queued event
+    pub fn pop_event(&self, request_id: &str) -> Option<Frame> {
+        self.events.get_mut(request_id)?.pop_front()
+    }
+
+    // Keep response chunks in the message queue as well as the body
+    pub fn queue_chunks(&self, request_id: &str) {
+        self.messages.entry(request_id.to_string()).or_default();
+    }
+
+    // Mark a request as complete and trigger callbacks.
+    // Requests that already ended in an error keep their terminal state.
     pub fn set_completed(&self, request_id: &str) {
         let req_info = {
             if let Some(req_lock) = self.requests.get(request_id) {
                 let mut req = req_lock.write().unwrap();
+                if matches!(req.state, RequestState::Error | RequestState::Timeout | RequestState::Cancelled) {
+                    return;
+                }
                 req.state = RequestState::Complete;
                 req.updated_at = Self::timestamp_now();
                 req.clone()
@@ -433,6 +907,8 @@ impl RequestManager {
             }
         };
 
+        self.notify();
+
         // Call the on_complete callback if it exists
         if let Some(callbacks) = self.callbacks.get(request_id) {
             if let Some(on_complete) = &callbacks.on_complete {
@@ -444,20 +920,26 @@ impl RequestManager {
     }
 
     // Set an error for a request and trigger callbacks
-    pub fn set_error(&self, request_id: &str, error: &str) {
+    pub fn set_error(&self, request_id: &str, error: ErrorInfo) {
         // Update request state
         if let Some(req_lock) = self.requests.get(request_id) {
             let mut req = req_lock.write().unwrap();
-            req.state = RequestState::Error;
-            req.error = Some(error.to_string());
+            req.state = match error.kind.as_str() {
+                "timeout" => RequestState::Timeout,
+                "cancelled" => RequestState::Cancelled,
+                _ => RequestState::Error,
+            };
+            req.error = Some(error.clone());
             req.updated_at = Self::timestamp_now();
         }
 
+        self.notify();
+
         // Call the on_error callback if it exists
         if let Some(callbacks) = self.callbacks.get(request_id) {
             if let Some(on_error) = &callbacks.on_error {
                 if let Ok(handler) = on_error.lock() {
-                    handler(error);
+                    handler(&error);
                 }
             }
         }
@@ -496,12 +978,15 @@ impl RequestManager {
             req.last_polled = now;
 
             // Check for timeouts
-            if req.state == RequestState::Sending || req.state == RequestState::Receiving {
-                let time_since_update = now - req.updated_at;
+            if !req.long_lived && (req.state == RequestState::Sending || req.state == RequestState::Receiving) {
+                let time_since_update = now.saturating_sub(req.updated_at);
                 // If no updates for 30 seconds, consider it a timeout
                 if time_since_update > 30 {
                     req.state = RequestState::Timeout;
-                    req.error = Some("Request timed out".to_string());
+                    req.error = Some(ErrorInfo {
+                        retryable: true,
+                        ..ErrorInfo::new("timeout", "Request timed out")
+                    });
                 }
             }
 
@@ -530,15 +1015,33 @@ impl RequestManager {
         if let Some(req_lock) = self.requests.get(request_id) {
             let mut req = req_lock.write().unwrap();
             req.state = RequestState::Cancelled;
-            req.error = Some("Request was cancelled".to_string());
+            req.error = Some(ErrorInfo::new("cancelled", "Request was cancelled"));
             req.updated_at = Self::timestamp_now();
         }
+
+        self.notify();
+    }
+
+    // Run the cleanup now, e.g. from the background janitor
+    pub fn cleanup(&self) {
+        let now = Self::timestamp_now();
+        self.last_cleanup.store(now, Ordering::SeqCst);
+        self.cleanup_idle_requests(now);
+    }
+
+    // Ids of requests that haven't finished yet
+    pub fn active_requests(&self) -> Vec<String> {
+        self.requests
+            .iter()
+            .filter(|entry| !entry.value().read().unwrap().state.is_terminal())
+            .map(|entry| entry.key().clone())
+            .collect()
     }
 
     // Try to run the cleanup procedure if enough time has passed
     fn try_cleanup(&self, now: u64) {
         let last_cleanup = self.last_cleanup.load(Ordering::Relaxed);
-        if now - last_cleanup > self.cleanup_interval {
+        if now.saturating_sub(last_cleanup) > self.cleanup_interval {
             if self.last_cleanup.compare_exchange(
                 last_cleanup,
                 now,
@@ -563,7 +1066,7 @@ impl RequestManager {
                 }
 
                 // Check if idle
-                let time_since_poll = now - req.last_polled;
+                let time_since_poll = now.saturating_sub(req.last_polled);
                 if time_since_poll > self.idle_timeout {
                     // Mark as idle if not already in a terminal state
                     if !matches!(req.state,
@@ -589,7 +1092,7 @@ impl RequestManager {
         // Mark idle requests
         for entry in self.requests.iter() {
             let mut req = entry.value().write().unwrap();
-            let time_since_poll = now - req.last_polled;
+            let time_since_poll = now.saturating_sub(req.last_polled);
 
             if time_since_poll > self.idle_timeout &&
                !matches!(req.state,
@@ -610,6 +1113,8 @@ impl RequestManager {
             self.requests.remove(&id);
             self.callbacks.remove(&id);
             self.cancellations.remove(&id);
+            self.messages.remove(&id);
+            self.events.remove(&id);
         }
     }
 }
@@ -617,3 +1122,61 @@ impl RequestManager {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestManager, RequestState, StreamMessage};
+    use crate::response::ResponseHeaders;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn test_wait_until_keeps_one_waker() {
+        let manager = RequestManager::new();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        {
+            let mut future = pin!(manager.wait_until(|| None::<()>));
+            for _ in 0..10 {
+                assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+            }
+            assert_eq!(manager.waiters.lock().unwrap().len(), 1);
+        }
+
+        // Dropping a pending future unregisters it
+        assert!(manager.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_message_count_does_not_drain() {
+        let manager = RequestManager::new();
+        manager.init_request("ws").unwrap();
+        manager.push_message("ws", StreamMessage::Text("hello".to_string()));
+
+        assert_eq!(manager.message_count("ws"), 1);
+        assert_eq!(manager.message_count("ws"), 1);
+        assert_eq!(manager.pop_message("ws"), Some(StreamMessage::Text("hello".to_string())));
+        assert_eq!(manager.message_count("ws"), 0);
+    }
+
+    #[test]
+    fn test_requeue_only_before_the_response() {
+        let manager = RequestManager::new();
+
+        manager.init_request("waiting").unwrap();
+        assert!(manager.requeue("waiting"));
+        assert_eq!(manager.poll_request("waiting").unwrap().restarts, 1);
+
+        manager.init_request("answered").unwrap();
+        manager.set_response("answered", 200, ResponseHeaders::new(), "");
+        assert!(!manager.requeue("answered"));
+        assert_eq!(manager.poll_request("answered").unwrap().status, Some(200));
+
+        manager.init_request("cancelled").unwrap();
+        manager.cancel_request("cancelled");
+        assert!(!manager.requeue("cancelled"));
+        assert_eq!(manager.poll_request("cancelled").unwrap().state, RequestState::Cancelled);
+    }
+}
@@ -0,0 +1,469 @@
+use base64::Engine;
+use mlua::prelude::*;
+use reqwest::{tls, Certificate, ClientBuilder, Identity};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{CertificateError, ClientConfig, RootCertStore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::error::AvanteCurlError;
+use crate::util::file;
+
+// TLS settings shared by sessions and requests
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TlsOptions {
+    pub ca_certs: Option<Vec<String>>,       // Extra root certificates (PEM files)
+    pub client_cert: Option<String>,         // Client identity, PEM certificate or PKCS#12 (.p12/.pfx)
+    pub client_key: Option<String>,          // PKCS#8 PEM key, when `client_cert` is PEM
+    pub client_cert_password: Option<String>, // Password of a PKCS#12 identity
+    pub min_version: Option<String>,         // "1.0", "1.1", "1.2" or "1.3"
+    pub pinned_pubkeys: Option<Vec<String>>, // curl-style "sha256//<base64>" SPKI pins
+}
+
+impl TlsOptions {
+    // Fill in anything unset from the session defaults. Root certificates and
+    // pins accumulate, everything else is overridden by the request.
+    pub fn merge_defaults(&mut self, defaults: &TlsOptions) {
+        fn extend(target: &mut Option<Vec<String>>, defaults: &Option<Vec<String>>) {
+            if let Some(defaults) = defaults {
+                let mut merged = defaults.clone();
+                merged.extend(target.take().unwrap_or_default());
+                *target = Some(merged);
+            }
+        }
+
+        extend(&mut self.ca_certs, &defaults.ca_certs);
+        extend(&mut self.pinned_pubkeys, &defaults.pinned_pubkeys);
+
+        if self.client_cert.is_none() {
+            self.client_cert = defaults.client_cert.clone();
+            self.client_key = defaults.client_key.clone();
+            self.client_cert_password = defaults.client_cert_password.clone();
+        }
+        if self.min_version.is_none() {
+            self.min_version = defaults.min_version.clone();
+        }
+    }
+
+    // Configure the reqwest client builder. Pinned clients run on rustls so
+    // the pins can be checked during the handshake, before anything is sent.
+    pub fn apply(
+        &self,
+        builder: ClientBuilder,
+        insecure: bool,
+        http1_only: bool,
+    ) -> Result<ClientBuilder, AvanteCurlError> {
+        match &self.pinned_pubkeys {
+            Some(pins) if !pins.is_empty() => {
+                let config = self.pinned_config(pins, insecure, http1_only)?;
+                Ok(builder.use_preconfigured_tls(config))
+            }
+            _ => self.apply_native(builder),
+        }
+    }
+
+    fn apply_native(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, AvanteCurlError> {
+        for path in self.ca_certs.iter().flatten() {
+            let pem = file::read_file_bytes(path)?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid CA certificate '{}': {}", path, e)))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        if let Some(identity) = self.load_identity()? {
+            builder = builder.identity(identity);
+        }
+
+        if let Some(version) = &self.min_version {
+            builder = builder.min_tls_version(parse_tls_version(version)?);
+        }
+
+        Ok(builder)
+    }
+
+    fn load_identity(&self) -> Result<Option<Identity>, AvanteCurlError> {
+        let cert_path = match &self.client_cert {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let cert = file::read_file_bytes(cert_path)?;
+        let invalid = |e: reqwest::Error| {
+            AvanteCurlError::InvalidConfig(format!("Invalid client certificate '{}': {}", cert_path, e))
+        };
+
+        let identity = if is_pkcs12(cert_path) {
+            let password = self.client_cert_password.as_deref().unwrap_or("");
+            Identity::from_pkcs12_der(&cert, password).map_err(invalid)?
+        } else {
+            // The key may live in its own file or be bundled with the certificate
+            let key = match &self.client_key {
+                Some(key_path) => file::read_file_bytes(key_path)?,
+                None => cert.clone(),
+            };
+            Identity::from_pkcs8_pem(&cert, &key).map_err(invalid)?
+        };
+
+        Ok(Some(identity))
+    }
+
+    // rustls configuration that verifies the chain as usual and then checks
+    // the server's public key against the pins
+    fn pinned_config(
+        &self,
+        pins: &[String],
+        insecure: bool,
+        http1_only: bool,
+    ) -> Result<ClientConfig, AvanteCurlError> {
+        let roots = if insecure { None } else { Some(self.root_store()?) };
+        let verifier = PinnedVerifier::new(pins, roots)?;
+
+        let versions: &[&rustls::SupportedProtocolVersion] = match self.min_version.as_deref() {
+            Some("1.3") => &[&rustls::version::TLS13],
+            // rustls has no TLS 1.0/1.1; 1.2 is the floor
+            Some("1.0" | "1.1" | "1.2") | None => rustls::DEFAULT_VERSIONS,
+            Some(version) => {
+                return Err(AvanteCurlError::InvalidConfig(format!("Unsupported TLS version: {}", version)))
+            }
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid TLS configuration: {}", e)))?
+            .with_custom_certificate_verifier(Arc::new(verifier));
+
+        let mut config = match self.load_pem_identity()? {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid client certificate: {}", e)))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        // reqwest leaves ALPN to preconfigured backends
+        config.alpn_protocols =
+            if http1_only { vec![b"http/1.1".to_vec()] } else { vec![b"h2".to_vec(), b"http/1.1".to_vec()] };
+
+        Ok(config)
+    }
+
+    // The platform's trust store plus any extra CA certificates
+    fn root_store(&self) -> Result<RootCertStore, AvanteCurlError> {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs().map_err(AvanteCurlError::IoError)?;
+        let native: Vec<Vec<u8>> = native.into_iter().map(|cert| cert.0).collect();
+        roots.add_parsable_certificates(&native);
+
+        for path in self.ca_certs.iter().flatten() {
+            for cert in read_pem_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid CA certificate '{}': {}", path, e)))?;
+            }
+        }
+
+        Ok(roots)
+    }
+
+    fn load_pem_identity(&self) -> Result<Option<PemIdentity>, AvanteCurlError> {
+        let cert_path = match &self.client_cert {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        if is_pkcs12(cert_path) {
+            return Err(AvanteCurlError::InvalidConfig(
+                "PKCS#12 client certificates can't be combined with pinned_pubkeys; use a PEM certificate and key"
+                    .to_string(),
+            ));
+        }
+
+        let certs = read_pem_certs(cert_path)?;
+        let key_path = self.client_key.as_deref().unwrap_or(cert_path);
+        let pem = file::read_file_bytes(key_path)?;
+        let key = rustls_pemfile::read_all(&mut pem.as_slice())
+            .map_err(AvanteCurlError::IoError)?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| AvanteCurlError::InvalidConfig(format!("No private key found in '{}'", key_path)))?;
+
+        Ok(Some((certs, key)))
+    }
+}
+
+// Certificate chain and private key for rustls client auth
+type PemIdentity = (Vec<rustls::Certificate>, rustls::PrivateKey);
+
+fn is_pkcs12(path: &str) -> bool {
+    path.ends_with(".p12") || path.ends_with(".pfx")
+}
+
+fn read_pem_certs(path: &str) -> Result<Vec<rustls::Certificate>, AvanteCurlError> {
+    let pem = file::read_file_bytes(path)?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).map_err(AvanteCurlError::IoError)?;
+    if certs.is_empty() {
+        return Err(AvanteCurlError::InvalidConfig(format!("No certificates found in '{}'", path)));
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+// Checks the end-entity certificate's SPKI against curl-style
+// "sha256//<base64>" pins, after the regular chain and hostname checks.
+// Without roots (insecure) only the pins are enforced, like curl.
+struct PinnedVerifier {
+    pins: Vec<String>,
+    webpki: Option<WebPkiVerifier>,
+}
+
+impl PinnedVerifier {
+    fn new(pins: &[String], roots: Option<RootCertStore>) -> Result<Self, AvanteCurlError> {
+        let pins = pins
+            .iter()
+            .map(|pin| {
+                pin.trim()
+                    .strip_prefix("sha256//")
+                    .map(str::to_string)
+                    .ok_or_else(|| AvanteCurlError::InvalidConfig(format!("Unsupported public key pin: {}", pin)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { pins, webpki: roots.map(|roots| WebPkiVerifier::new(roots, None)) })
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+
+        let fingerprint =
+            spki_sha256(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if self.pins.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(PinMismatch(fingerprint)))))
+        }
+    }
+}
+
+// The server's key didn't match any pin
+pub struct PinMismatch(String);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Public key sha256//{} doesn't match any pinned key", self.0)
+    }
+}
+
+impl fmt::Debug for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+// Whether a request failed during the TLS handshake (bad certificate, pin
+// mismatch, ...). rustls errors reach reqwest wrapped in (nested) io::Errors,
+// which don't report their payload as a source.
+pub fn is_handshake_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if err.is::<rustls::Error>() {
+            return true;
+        }
+        current = match err.downcast_ref::<std::io::Error>().and_then(std::io::Error::get_ref) {
+            Some(inner) => Some(inner),
+            None => err.source(),
+        };
+    }
+    false
+}
+
+// "outer: inner: ..." so the handshake failure's cause isn't lost
+pub fn error_chain(err: &(dyn std::error::Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut current = err.source();
+    while let Some(err) = current {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        current = err.source();
+    }
+    message
+}
+
+impl FromLua for TlsOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        if let LuaValue::Table(table) = value {
+            Ok(TlsOptions {
+                ca_certs: table.get("ca_certs")?,
+                client_cert: table.get("client_cert")?,
+                client_key: table.get("client_key")?,
+                client_cert_password: table.get("client_cert_password")?,
+                min_version: table.get("min_version")?,
+                pinned_pubkeys: table.get("pinned_pubkeys")?,
+            })
+        } else {
+            Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "TlsOptions".to_string(),
+                message: Some("Expected a table".to_string()),
+            })
+        }
+    }
+}
+
+fn parse_tls_version(version: &str) -> Result<tls::Version, AvanteCurlError> {
+    match version {
+        "1.0" => Ok(tls::Version::TLS_1_0),
+        "1.1" => Ok(tls::Version::TLS_1_1),
+        "1.2" => Ok(tls::Version::TLS_1_2),
+        "1.3" => Ok(tls::Version::TLS_1_3),
+        _ => Err(AvanteCurlError::InvalidConfig(format!("Unsupported TLS version: {}", version))),
+    }
+}
+
+// Base64 SHA-256 of the certificate's SubjectPublicKeyInfo, as used by
+// curl's --pinnedpubkey and HPKP
+pub fn spki_sha256(cert_der: &[u8]) -> Option<String> {
+    let spki = find_spki(cert_der)?;
+    Some(base64::engine::general_purpose::STANDARD.encode(Sha256::digest(spki)))
+}
+
+// Read one DER TLV, returning (tag, full element, contents, rest)
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first_len = *input.get(1)?;
+    let (len, header_len) = if first_len & 0x80 == 0 {
+        (first_len as usize, 2)
+    } else {
+        let count = (first_len & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let bytes = input.get(2..2 + count)?;
+        (bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize), 2 + count)
+    };
+
+    let end = header_len.checked_add(len)?;
+    let element = input.get(..end)?;
+    Some((tag, element, &element[header_len..], &input[end..]))
+}
+
+// Certificate  ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature,
+//                               issuer, validity, subject, subjectPublicKeyInfo, ... }
+fn find_spki(cert_der: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let (tag, _, certificate, _) = der_element(cert_der)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, _, tbs, _) = der_element(certificate)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    let mut rest = tbs;
+    if rest.first() == Some(&VERSION) {
+        rest = der_element(rest)?.3;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.3;
+    }
+
+    let (tag, spki, _, _) = der_element(rest)?;
+    (tag == SEQUENCE).then_some(spki)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal hand-built certificate: only the structure find_spki walks
+    fn fake_cert(spki: &[u8]) -> Vec<u8> {
+        fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+            let mut out = vec![tag, contents.len() as u8];
+            out.extend_from_slice(contents);
+            out
+        }
+
+        let mut tbs = Vec::new();
+        tbs.extend(tlv(0xa0, &tlv(0x02, &[2]))); // version
+        tbs.extend(tlv(0x02, &[1])); // serialNumber
+        tbs.extend(tlv(0x30, &[])); // signature
+        tbs.extend(tlv(0x30, &[])); // issuer
+        tbs.extend(tlv(0x30, &[])); // validity
+        tbs.extend(tlv(0x30, &[])); // subject
+        tbs.extend_from_slice(spki);
+
+        let mut cert = tlv(0x30, &tbs);
+        cert.extend(tlv(0x30, &[])); // signatureAlgorithm
+        tlv(0x30, &cert)
+    }
+
+    #[test]
+    fn test_find_spki() {
+        let spki = [0x30, 0x03, 0x01, 0x02, 0x03];
+        let cert = fake_cert(&spki);
+        assert_eq!(find_spki(&cert), Some(&spki[..]));
+
+        let expected = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(spki));
+        assert_eq!(spki_sha256(&cert), Some(expected));
+    }
+
+    #[test]
+    fn test_find_spki_rejects_garbage() {
+        assert_eq!(find_spki(&[]), None);
+        assert_eq!(find_spki(&[0x30, 0x05, 0x01]), None);
+        assert_eq!(find_spki(&[0x04, 0x00]), None);
+    }
+
+    #[test]
+    fn test_merge_defaults() {
+        let defaults = TlsOptions {
+            ca_certs: Some(vec!["/etc/corp-ca.pem".to_string()]),
+            client_cert: Some("/etc/client.p12".to_string()),
+            min_version: Some("1.2".to_string()),
+            ..Default::default()
+        };
+
+        let mut options = TlsOptions {
+            ca_certs: Some(vec!["/tmp/extra.pem".to_string()]),
+            min_version: Some("1.3".to_string()),
+            ..Default::default()
+        };
+        options.merge_defaults(&defaults);
+
+        assert_eq!(
+            options.ca_certs,
+            Some(vec!["/etc/corp-ca.pem".to_string(), "/tmp/extra.pem".to_string()])
+        );
+        assert_eq!(options.client_cert.as_deref(), Some("/etc/client.p12"));
+        assert_eq!(options.min_version.as_deref(), Some("1.3"));
+    }
+
+    #[test]
+    fn test_invalid_tls_version() {
+        assert!(parse_tls_version("1.2").is_ok());
+        assert!(parse_tls_version("2.0").is_err());
+    }
+}
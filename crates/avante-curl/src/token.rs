@@ -0,0 +1,326 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+use crate::error::AvanteCurlError;
+use crate::http::HttpClient;
+use crate::{RequestBody, RequestOptions, SessionOptions};
+
+const DEFAULT_TTL: u64 = 300; // Seconds, when the source doesn't say
+const DEFAULT_SKEW: u64 = 60; // Refresh this many seconds before expiry
+const DEFAULT_COMMAND_TIMEOUT: u64 = 30; // Seconds a token command may run
+
+// Where a bearer token comes from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TokenSource {
+    // Token endpoint returning JSON, e.g. the Copilot token exchange:
+    // GET https://api.github.com/copilot_internal/v2/token -> {token, expires_at}
+    Endpoint {
+        url: String,
+        method: Option<String>,
+        headers: Option<HashMap<String, String>>,
+        body: Option<String>,
+    },
+    // Local command printing the token, e.g. `gcloud auth print-access-token`.
+    // With `json`, stdout is parsed like an endpoint response.
+    Command { command: Vec<String>, json: bool },
+}
+
+// Token provider configuration, registered per session from Lua
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenProviderOptions {
+    pub source: TokenSource,
+    pub token_field: Option<String>, // JSON field holding the token (default: access_token, then token)
+    pub ttl: Option<u64>,            // Lifetime in seconds if the response has no expiry
+    pub skew: Option<u64>,           // Refresh this many seconds before expiry
+    pub header: Option<String>,      // Header to inject (default: Authorization)
+    pub prefix: Option<String>,      // Value prefix (default: "Bearer ")
+    pub timeout: Option<u64>,        // Seconds before a token command is killed
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: u64, // Unix seconds
+}
+
+// Caches a token until it expires and makes concurrent callers share a
+// single refresh: the cache lock is held while fetching, so requests that
+// arrive during a refresh wait for its result instead of fetching again.
+pub struct TokenProvider {
+    options: TokenProviderOptions,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl TokenProvider {
+    pub fn new(options: TokenProviderOptions) -> Self {
+        Self {
+            options,
+            cached: Mutex::new(None),
+        }
+    }
+
+    // Current token, fetching a new one if it's missing or about to expire
+    pub async fn token(&self, session_options: &SessionOptions) -> Result<String, AvanteCurlError> {
+        let mut cached = self.cached.lock().await;
+        let skew = self.options.skew.unwrap_or(DEFAULT_SKEW);
+
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > timestamp_now() + skew {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let token = self.fetch(session_options).await?;
+        let value = token.token.clone();
+        *cached = Some(token);
+        Ok(value)
+    }
+
+    // Drop the cached token, but only if it's the one that was rejected;
+    // when several requests get a 401 for the same token it is refreshed once
+    pub async fn invalidate(&self, rejected: Option<&str>) {
+        let mut cached = self.cached.lock().await;
+        let matches = match (cached.as_ref(), rejected) {
+            (Some(token), Some(rejected)) => token.token == rejected,
+            _ => true,
+        };
+        if matches {
+            *cached = None;
+        }
+    }
+
+    // Add the token header to a request, returning the token used
+    pub async fn inject(
+        &self,
+        options: &mut RequestOptions,
+        session_options: &SessionOptions,
+    ) -> Result<String, AvanteCurlError> {
+        let token = self.token(session_options).await?;
+        let header = self.options.header.clone().unwrap_or_else(|| "Authorization".to_string());
+        let prefix = self.options.prefix.as_deref().unwrap_or("Bearer ");
+
+        options
+            .headers
+            .get_or_insert_with(HashMap::new)
+            .insert(header, format!("{}{}", prefix, token));
+
+        Ok(token)
+    }
+
+    async fn fetch(&self, session_options: &SessionOptions) -> Result<CachedToken, AvanteCurlError> {
+        match &self.options.source {
+            TokenSource::Endpoint { url, method, headers, body } => {
+                let mut options = RequestOptions {
+                    url: url.clone(),
+                    method: method.clone(),
+                    headers: headers.clone(),
+                    body: body.clone().map(|body| RequestBody::Raw(body.into())),
+                    ..Default::default()
+                };
+                options.apply_session_defaults(session_options);
+
+                let client = HttpClient::new_from_options(&options)?;
+                let response = client.send_request(options).await?;
+                let status = response.status().as_u16();
+                let response_headers = response.headers().clone();
+                let body = response.text().await?;
+                if status >= 400 {
+                    return Err(AvanteCurlError::from_status(status, &response_headers, &body));
+                }
+
+                self.parse_json(&body)
+            }
+            TokenSource::Command { command, json } => {
+                let (program, args) = command
+                    .split_first()
+                    .ok_or_else(|| AvanteCurlError::InvalidConfig("Token command is empty".to_string()))?;
+
+                // The cache lock is held meanwhile, so a hung command must not
+                // block every request using this provider; timing out kills it
+                let timeout = self.options.timeout.unwrap_or(DEFAULT_COMMAND_TIMEOUT);
+                let output = tokio::process::Command::new(program)
+                    .args(args)
+                    .kill_on_drop(true)
+                    .output();
+                let output = tokio::time::timeout(Duration::from_secs(timeout), output)
+                    .await
+                    .map_err(|_| AvanteCurlError::Token(format!("Token command timed out after {}s", timeout)))??;
+                if !output.status.success() {
+                    return Err(AvanteCurlError::Other(format!(
+                        "Token command failed ({}): {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                if *json {
+                    self.parse_json(&stdout)
+                } else {
+                    self.with_ttl(stdout.trim().to_string(), None)
+                }
+            }
+        }
+    }
+
+    // Read the token and its expiry (expires_at or expires_in) from JSON
+    fn parse_json(&self, body: &str) -> Result<CachedToken, AvanteCurlError> {
+        let json: serde_json::Value = serde_json::from_str(body)?;
+
+        let token = match &self.options.token_field {
+            Some(field) => json.get(field),
+            None => json.get("access_token").or_else(|| json.get("token")),
+        }
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| AvanteCurlError::Other("Token response has no token field".to_string()))?;
+
+        let expires_at = json
+            .get("expires_at")
+            .and_then(|v| v.as_u64())
+            .or_else(|| json.get("expires_in").and_then(|v| v.as_u64()).map(|secs| timestamp_now() + secs));
+
+        self.with_ttl(token.to_string(), expires_at)
+    }
+
+    fn with_ttl(&self, token: String, expires_at: Option<u64>) -> Result<CachedToken, AvanteCurlError> {
+        if token.is_empty() {
+            return Err(AvanteCurlError::Other("Token provider returned an empty token".to_string()));
+        }
+
+        let expires_at = expires_at.unwrap_or_else(|| timestamp_now() + self.options.ttl.unwrap_or(DEFAULT_TTL));
+        Ok(CachedToken { token, expires_at })
+    }
+}
+
+fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
+
+impl FromLua for TokenProviderOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: "LuaValue",
+                    to: "TokenProviderOptions".to_string(),
+                    message: Some("Expected a table".to_string()),
+                })
+            }
+        };
+
+        let source = match (table.get::<Option<String>>("url")?, table.get::<LuaValue>("command")?) {
+            (Some(url), LuaValue::Nil) => TokenSource::Endpoint {
+                url,
+                method: table.get("method")?,
+                headers: table.get("headers")?,
+                body: table.get("body")?,
+            },
+            (None, LuaValue::String(command)) => TokenSource::Command {
+                command: command.to_str()?.split_whitespace().map(str::to_string).collect(),
+                json: table.get::<Option<bool>>("json")?.unwrap_or(false),
+            },
+            (None, LuaValue::Table(command)) => TokenSource::Command {
+                command: command.sequence_values::<String>().collect::<LuaResult<_>>()?,
+                json: table.get::<Option<bool>>("json")?.unwrap_or(false),
+            },
+            _ => {
+                return Err(LuaError::RuntimeError(
+                    "Token provider needs either `url` or `command`".to_string(),
+                ))
+            }
+        };
+
+        Ok(TokenProviderOptions {
+            source,
+            token_field: table.get("token_field")?,
+            ttl: table.get("ttl")?,
+            skew: table.get("skew")?,
+            header: table.get("header")?,
+            prefix: table.get("prefix")?,
+            timeout: table.get("timeout")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_provider(script: &str) -> TokenProvider {
+        TokenProvider::new(TokenProviderOptions {
+            source: TokenSource::Command {
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+                json: false,
+            },
+            token_field: None,
+            ttl: Some(3600),
+            skew: None,
+            header: None,
+            prefix: None,
+            timeout: None,
+        })
+    }
+
+    #[test]
+    fn test_parse_json_expiry() {
+        let provider = command_provider("true");
+        let now = timestamp_now();
+
+        let token = provider.parse_json(r#"{"token":"tid=abc","expires_at":4102444800}"#).unwrap();
+        assert_eq!(token.token, "tid=abc");
+        assert_eq!(token.expires_at, 4102444800);
+
+        let token = provider.parse_json(r#"{"access_token":"ya29","expires_in":100}"#).unwrap();
+        assert_eq!(token.token, "ya29");
+        assert!(token.expires_at >= now + 100);
+
+        let token = provider.parse_json(r#"{"access_token":"ya29"}"#).unwrap();
+        assert!(token.expires_at >= now + 3600);
+
+        assert!(provider.parse_json(r#"{"nope":1}"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_token_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let script = format!("echo x >> {0}; echo token-$(wc -l < {0} | tr -d ' ')", counter.display());
+        let provider = std::sync::Arc::new(command_provider(&script));
+        let session_options = SessionOptions::default();
+
+        // Concurrent callers share a single fetch
+        let (a, b) = tokio::join!(provider.token(&session_options), provider.token(&session_options));
+        assert_eq!(a.unwrap(), "token-1");
+        assert_eq!(b.unwrap(), "token-1");
+
+        // A stale rejection doesn't drop the current token
+        provider.invalidate(Some("token-0")).await;
+        assert_eq!(provider.token(&session_options).await.unwrap(), "token-1");
+
+        // Rejecting the current token refreshes it once
+        provider.invalidate(Some("token-1")).await;
+        assert_eq!(provider.token(&session_options).await.unwrap(), "token-2");
+
+        let mut options = RequestOptions::default();
+        provider.inject(&mut options, &session_options).await.unwrap();
+        assert_eq!(options.headers.unwrap()["Authorization"], "Bearer token-2");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_timeout() {
+        let mut provider = command_provider("sleep 5");
+        provider.options.timeout = Some(1);
+
+        let err = provider.token(&SessionOptions::default()).await.unwrap_err();
+        assert_eq!(err.kind(), "token");
+    }
+}
@@ -0,0 +1,269 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+// Shape of the usage object a provider reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageFormat {
+    Auto,      // Guess from the field names
+    OpenAi,    // prompt_tokens / completion_tokens (or the Responses API's input_tokens / output_tokens)
+    Anthropic, // input_tokens / output_tokens / cache_*_input_tokens
+    Gemini,    // usageMetadata: promptTokenCount / candidatesTokenCount
+}
+
+impl UsageFormat {
+    pub fn parse(format: Option<&str>) -> Result<Option<Self>, String> {
+        match format {
+            None => Ok(None),
+            Some("auto") => Ok(Some(UsageFormat::Auto)),
+            Some("openai") => Ok(Some(UsageFormat::OpenAi)),
+            Some("anthropic") => Ok(Some(UsageFormat::Anthropic)),
+            Some("gemini") => Ok(Some(UsageFormat::Gemini)),
+            Some(other) => Err(format!("Unsupported usage_format: {}", other)),
+        }
+    }
+
+    fn detect(usage: &Value) -> Self {
+        if usage.get("promptTokenCount").is_some() || usage.get("candidatesTokenCount").is_some() {
+            UsageFormat::Gemini
+        } else if usage.get("prompt_tokens").is_some() || usage.get("input_tokens_details").is_some() {
+            UsageFormat::OpenAi
+        } else {
+            UsageFormat::Anthropic
+        }
+    }
+}
+
+// Token counts in one convention for every provider. `input_tokens` excludes
+// cached input, which is counted in `cache_read_tokens` / `cache_write_tokens`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub cache_write_tokens: u64,
+}
+
+impl TokenUsage {
+    // Normalize a usage object as captured by `middleware::UsageCollector`
+    pub fn from_provider(format: UsageFormat, usage: &Value) -> Self {
+        let count = |path: &[&str]| {
+            path.iter()
+                .try_fold(usage, |value, key| value.get(key))
+                .and_then(Value::as_u64)
+                .unwrap_or(0)
+        };
+
+        let format = match format {
+            UsageFormat::Auto => UsageFormat::detect(usage),
+            format => format,
+        };
+        match format {
+            UsageFormat::OpenAi => {
+                // Chat Completions and Responses API field names; cached tokens are part of the prompt
+                let prompt = count(&["prompt_tokens"]).max(count(&["input_tokens"]));
+                let cached = count(&["prompt_tokens_details", "cached_tokens"])
+                    .max(count(&["input_tokens_details", "cached_tokens"]));
+                TokenUsage {
+                    input_tokens: prompt.saturating_sub(cached),
+                    output_tokens: count(&["completion_tokens"]).max(count(&["output_tokens"])),
+                    cache_read_tokens: cached,
+                    cache_write_tokens: 0,
+                }
+            }
+            UsageFormat::Gemini => {
+                let cached = count(&["cachedContentTokenCount"]);
+                TokenUsage {
+                    input_tokens: count(&["promptTokenCount"]).saturating_sub(cached),
+                    output_tokens: count(&["candidatesTokenCount"]) + count(&["thoughtsTokenCount"]),
+                    cache_read_tokens: cached,
+                    cache_write_tokens: 0,
+                }
+            }
+            UsageFormat::Anthropic | UsageFormat::Auto => TokenUsage {
+                input_tokens: count(&["input_tokens"]),
+                output_tokens: count(&["output_tokens"]),
+                cache_read_tokens: count(&["cache_read_input_tokens"]),
+                cache_write_tokens: count(&["cache_creation_input_tokens"]),
+            },
+        }
+    }
+
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_tokens += other.cache_read_tokens;
+        self.cache_write_tokens += other.cache_write_tokens;
+    }
+}
+
+// Prices of a model in currency units per million tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    pub cache_read: Option<f64>,  // Defaults to the input price
+    pub cache_write: Option<f64>, // Defaults to the input price
+}
+
+impl ModelPrice {
+    pub fn cost(&self, tokens: &TokenUsage) -> f64 {
+        let per_token = |price: f64, count: u64| price * count as f64 / 1_000_000.0;
+        per_token(self.input, tokens.input_tokens)
+            + per_token(self.output, tokens.output_tokens)
+            + per_token(self.cache_read.unwrap_or(self.input), tokens.cache_read_tokens)
+            + per_token(self.cache_write.unwrap_or(self.input), tokens.cache_write_tokens)
+    }
+}
+
+// Price of a model: an exact entry, otherwise the longest entry the model
+// name starts with ("claude-3-5-sonnet" covers "claude-3-5-sonnet-20241022")
+pub fn find_price<'a>(pricing: &'a HashMap<String, ModelPrice>, model: &str) -> Option<&'a ModelPrice> {
+    pricing.get(model).or_else(|| {
+        pricing
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    })
+}
+
+// Usage of a single request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestUsage {
+    #[serde(flatten)]
+    pub tokens: TokenUsage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>, // With a pricing entry for the model
+}
+
+// Usage accumulated over a group of requests
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    #[serde(flatten)]
+    pub tokens: TokenUsage,
+    pub requests: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>, // Sum over the requests that had a price
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: &RequestUsage) {
+        self.tokens.add(&usage.tokens);
+        self.requests += 1;
+        if let Some(cost) = usage.cost {
+            *self.cost.get_or_insert(0.0) += cost;
+        }
+    }
+}
+
+// Session-wide usage, overall and per model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionUsage {
+    #[serde(flatten)]
+    pub total: UsageTotals,
+    pub models: BTreeMap<String, UsageTotals>,
+}
+
+impl SessionUsage {
+    pub fn add(&mut self, usage: &RequestUsage) {
+        self.total.add(usage);
+        let model = usage.model.clone().unwrap_or_else(|| "unknown".to_string());
+        self.models.entry(model).or_default().add(usage);
+    }
+}
+
+impl FromLua for ModelPrice {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Table(table) => Ok(ModelPrice {
+                input: table.get::<Option<f64>>("input")?.unwrap_or(0.0),
+                output: table.get::<Option<f64>>("output")?.unwrap_or(0.0),
+                cache_read: table.get("cache_read")?,
+                cache_write: table.get("cache_write")?,
+            }),
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "ModelPrice".to_string(),
+                message: Some("Expected a table".to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize() {
+        let openai = json!({ "prompt_tokens": 100, "completion_tokens": 20, "prompt_tokens_details": { "cached_tokens": 60 } });
+        assert_eq!(
+            TokenUsage::from_provider(UsageFormat::Auto, &openai),
+            TokenUsage { input_tokens: 40, output_tokens: 20, cache_read_tokens: 60, cache_write_tokens: 0 }
+        );
+
+        let responses = json!({ "input_tokens": 50, "output_tokens": 5, "input_tokens_details": { "cached_tokens": 10 } });
+        assert_eq!(TokenUsage::from_provider(UsageFormat::Auto, &responses).input_tokens, 40);
+
+        // Merged message_start + message_delta usage
+        let anthropic = json!({
+            "input_tokens": 25,
+            "output_tokens": 15,
+            "cache_read_input_tokens": 1000,
+            "cache_creation_input_tokens": 200,
+        });
+        assert_eq!(
+            TokenUsage::from_provider(UsageFormat::Anthropic, &anthropic),
+            TokenUsage { input_tokens: 25, output_tokens: 15, cache_read_tokens: 1000, cache_write_tokens: 200 }
+        );
+
+        let gemini = json!({ "promptTokenCount": 30, "candidatesTokenCount": 7, "thoughtsTokenCount": 3, "cachedContentTokenCount": 10 });
+        assert_eq!(
+            TokenUsage::from_provider(UsageFormat::Auto, &gemini),
+            TokenUsage { input_tokens: 20, output_tokens: 10, cache_read_tokens: 10, cache_write_tokens: 0 }
+        );
+    }
+
+    #[test]
+    fn test_pricing() {
+        let pricing = HashMap::from([
+            ("claude-3-5".to_string(), ModelPrice { input: 1.0, output: 1.0, ..Default::default() }),
+            (
+                "claude-3-5-sonnet".to_string(),
+                ModelPrice { input: 3.0, output: 15.0, cache_read: Some(0.3), cache_write: Some(3.75) },
+            ),
+        ]);
+        let price = find_price(&pricing, "claude-3-5-sonnet-20241022").unwrap();
+        assert_eq!(price.input, 3.0);
+        assert!(find_price(&pricing, "gpt-4o").is_none());
+
+        let tokens = TokenUsage {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            cache_read_tokens: 1_000_000,
+            cache_write_tokens: 0,
+        };
+        assert!((price.cost(&tokens) - 4.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_session_totals() {
+        let mut usage = SessionUsage::default();
+        let tokens = TokenUsage { input_tokens: 10, output_tokens: 5, ..Default::default() };
+        usage.add(&RequestUsage { tokens, model: Some("gpt-4o".to_string()), cost: Some(0.5) });
+        usage.add(&RequestUsage { tokens, model: Some("gpt-4o".to_string()), cost: None });
+        usage.add(&RequestUsage { tokens, model: None, cost: Some(0.25) });
+
+        assert_eq!(usage.total.requests, 3);
+        assert_eq!(usage.total.tokens.input_tokens, 30);
+        assert_eq!(usage.total.cost, Some(0.75));
+        assert_eq!(usage.models["gpt-4o"].requests, 2);
+        assert_eq!(usage.models["gpt-4o"].cost, Some(0.5));
+        assert_eq!(usage.models["unknown"].tokens.output_tokens, 5);
+    }
+}
@@ -84,3 +84,52 @@ pub mod url {
         Ok(url.to_string())
     }
 }
+
+pub mod net {
+    use std::net::{IpAddr, SocketAddr};
+
+    // Parse a curl-style `--resolve` entry: HOST:PORT:ADDR[,ADDR]...
+    // IPv6 addresses may be wrapped in brackets, e.g. `example.com:443:[::1]`.
+    pub fn parse_resolve(entry: &str) -> Result<(String, Vec<SocketAddr>), String> {
+        let mut parts = entry.splitn(3, ':');
+        let host = parts.next().unwrap_or_default().trim();
+        let port = parts.next().unwrap_or_default().trim();
+        let addrs = parts.next().unwrap_or_default().trim();
+
+        if host.is_empty() || addrs.is_empty() {
+            return Err(format!("Invalid resolve entry '{}', expected HOST:PORT:ADDR", entry));
+        }
+
+        let port: u16 = port
+            .parse()
+            .map_err(|_| format!("Invalid port in resolve entry '{}'", entry))?;
+
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                let addr = addr.trim().trim_start_matches('[').trim_end_matches(']');
+                addr.parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .map_err(|_| format!("Invalid address '{}' in resolve entry '{}'", addr, entry))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((host.to_string(), addrs))
+    }
+}
+
+pub mod text {
+    // Take the decodable part of a byte buffer, leaving an incomplete UTF-8
+    // sequence at the end for the next chunk. Invalid bytes are replaced.
+    pub fn take_utf8(buf: &mut Vec<u8>) -> String {
+        let complete = match std::str::from_utf8(buf) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => buf.len(),
+        };
+
+        let rest = buf.split_off(complete);
+        let text = String::from_utf8_lossy(buf).into_owned();
+        *buf = rest;
+        text
+    }
+}
@@ -0,0 +1,175 @@
+use futures_util::{SinkExt, StreamExt};
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{HeaderName, HeaderValue},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+use crate::error::AvanteCurlError;
+use crate::response::ResponseHeaders;
+use crate::session::{Session, StreamMessage};
+
+const DEFAULT_PING_INTERVAL: u64 = 30; // Seconds
+
+// Options for `ws_connect`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WsOptions {
+    pub headers: Option<HashMap<String, String>>,
+    pub protocols: Option<Vec<String>>, // Sec-WebSocket-Protocol values
+    pub ping_interval: Option<u64>,     // Seconds between keepalive pings, 0 to disable
+    pub pong_timeout: Option<u64>,      // Seconds without a pong before giving up (default: 2 * ping_interval)
+}
+
+// Commands sent from Lua to a connection task
+#[derive(Debug)]
+pub enum WsCommand {
+    Send(Message),
+    Close(u16, String),
+}
+
+// Handle kept by the session for each open connection
+pub struct WsHandle {
+    commands: mpsc::UnboundedSender<WsCommand>,
+}
+
+impl WsHandle {
+    pub fn send_text(&self, text: String) -> bool {
+        self.commands.send(WsCommand::Send(Message::Text(text))).is_ok()
+    }
+
+    pub fn send_binary(&self, data: Vec<u8>) -> bool {
+        self.commands.send(WsCommand::Send(Message::Binary(data))).is_ok()
+    }
+
+    pub fn close(&self, code: u16, reason: String) -> bool {
+        self.commands.send(WsCommand::Close(code, reason)).is_ok()
+    }
+}
+
+// Open a connection and run it until it closes. Incoming messages are queued
+// on the session like HTTP chunks and picked up through `get_status`.
+pub async fn run(
+    session: Arc<Session>,
+    connection_id: String,
+    url: String,
+    options: WsOptions,
+    commands: mpsc::UnboundedReceiver<WsCommand>,
+) -> Result<(), AvanteCurlError> {
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid WebSocket URL: {}", e)))?;
+
+    for (key, value) in options.headers.iter().flatten() {
+        let name = HeaderName::from_bytes(key.as_bytes())
+            .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid header name: {}", e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid header value: {}", e)))?;
+        request.headers_mut().insert(name, value);
+    }
+    if let Some(protocols) = &options.protocols {
+        let value = HeaderValue::from_str(&protocols.join(", "))
+            .map_err(|e| AvanteCurlError::InvalidConfig(format!("Invalid protocols: {}", e)))?;
+        request.headers_mut().insert("sec-websocket-protocol", value);
+    }
+
+    let (stream, response) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(|e| AvanteCurlError::Other(format!("WebSocket connect failed: {}", e)))?;
+
+    let mut headers = ResponseHeaders::new();
+    for (name, value) in response.headers() {
+        headers.push(name.as_str(), &String::from_utf8_lossy(value.as_bytes()));
+    }
+    session.set_response(&connection_id, response.status().as_u16(), headers, "");
+
+    let (mut write, mut read) = stream.split();
+    let mut commands = commands;
+
+    let ping_secs = options.ping_interval.unwrap_or(DEFAULT_PING_INTERVAL);
+    let ping_interval = Duration::from_secs(ping_secs.max(1));
+    let pong_timeout = Duration::from_secs(options.pong_timeout.unwrap_or(ping_secs * 2).max(1));
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    let mut last_pong = Instant::now();
+
+    let send_error = |e: tokio_tungstenite::tungstenite::Error| AvanteCurlError::Other(format!("WebSocket send failed: {}", e));
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(WsCommand::Send(message)) => write.send(message).await.map_err(send_error)?,
+                Some(WsCommand::Close(code, reason)) => {
+                    let frame = CloseFrame { code: CloseCode::from(code), reason: reason.into() };
+                    write.send(Message::Close(Some(frame))).await.map_err(send_error)?;
+                }
+                // The session dropped the handle, e.g. on destroy_session
+                None => {
+                    let _ = write.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => session.push_message(&connection_id, StreamMessage::Text(text)),
+                Some(Ok(Message::Binary(data))) => session.push_message(&connection_id, StreamMessage::Binary(data)),
+                Some(Ok(Message::Pong(_))) => last_pong = Instant::now(),
+                // Pings are answered by tungstenite on the next write
+                Some(Ok(Message::Ping(_))) => write.flush().await.map_err(send_error)?,
+                Some(Ok(Message::Close(frame))) => {
+                    let (code, reason) = match frame {
+                        Some(frame) => (u16::from(frame.code), frame.reason.into_owned()),
+                        None => (u16::from(CloseCode::Status), String::new()),
+                    };
+                    session.push_message(&connection_id, StreamMessage::Close { code, reason });
+                    break;
+                }
+                Some(Ok(Message::Frame(_))) => {}
+                Some(Err(e)) => return Err(AvanteCurlError::Other(format!("WebSocket error: {}", e))),
+                None => break,
+            },
+            _ = ping.tick(), if ping_secs > 0 => {
+                if session.should_cancel(&connection_id) {
+                    let _ = write.send(Message::Close(None)).await;
+                    return Err(AvanteCurlError::Cancelled);
+                }
+                if last_pong.elapsed() > pong_timeout {
+                    return Err(AvanteCurlError::Timeout);
+                }
+                write.send(Message::Ping(Vec::new())).await.map_err(send_error)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Create the command channel for a new connection
+pub fn channel() -> (WsHandle, mpsc::UnboundedReceiver<WsCommand>) {
+    let (commands, receiver) = mpsc::unbounded_channel();
+    (WsHandle { commands }, receiver)
+}
+
+impl FromLua for WsOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => Ok(WsOptions::default()),
+            LuaValue::Table(table) => Ok(WsOptions {
+                headers: table.get("headers")?,
+                protocols: table.get("protocols")?,
+                ping_interval: table.get("ping_interval")?,
+                pong_timeout: table.get("pong_timeout")?,
+            }),
+            _ => Err(LuaError::FromLuaConversionError {
+                from: "LuaValue",
+                to: "WsOptions".to_string(),
+                message: Some("Expected a table".to_string()),
+            }),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+;; Capture called functions, field accesses and type references
+(call_expression
+  function: (identifier) @reference)
+
+(field_expression
+  field: (field_identifier) @reference)
+
+(_
+  type: (type_identifier) @reference)
+
+(struct_specifier
+  name: (type_identifier) @reference
+  !body)
+
+(union_specifier
+  name: (type_identifier) @reference
+  !body)
+
+(enum_specifier
+  name: (type_identifier) @reference
+  !body)
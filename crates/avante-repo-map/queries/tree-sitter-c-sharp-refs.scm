@@ -0,0 +1,29 @@
+;; Capture invoked methods, member accesses, classes used as a static scope,
+;; created objects, base types and type references
+(invocation_expression
+  function: (identifier) @reference)
+
+(member_access_expression
+  name: (identifier) @reference)
+
+(member_access_expression
+  expression: (identifier) @reference
+  (#match? @reference "^[A-Z]"))
+
+(_
+  type: (identifier) @reference)
+
+(_
+  returns: (identifier) @reference)
+
+(base_list
+  (identifier) @reference)
+
+(type_argument_list
+  (identifier) @reference)
+
+(generic_name
+  (identifier) @reference)
+
+(qualified_name
+  name: (identifier) @reference)
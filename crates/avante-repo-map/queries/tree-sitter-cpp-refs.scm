@@ -0,0 +1,37 @@
+;; Capture called functions, member accesses, base classes and type references
+(call_expression
+  function: (identifier) @reference)
+
+(call_expression
+  function: (qualified_identifier
+    name: (identifier) @reference))
+
+(template_function
+  name: (identifier) @reference)
+
+(field_expression
+  field: (field_identifier) @reference)
+
+(_
+  type: (type_identifier) @reference)
+
+(qualified_identifier
+  name: (type_identifier) @reference)
+
+(template_type
+  name: (type_identifier) @reference)
+
+(base_class_clause
+  (type_identifier) @reference)
+
+(struct_specifier
+  name: (type_identifier) @reference
+  !body)
+
+(class_specifier
+  name: (type_identifier) @reference
+  !body)
+
+(enum_specifier
+  name: (type_identifier) @reference
+  !body)
@@ -0,0 +1,17 @@
+;; Capture called functions, remote calls, and modules used in a remote call or
+;; by alias, import, require and use
+(call
+  target: (identifier) @reference
+  (#not-match? @reference "^(def|defp|defmacro|defmacrop|defguard|defguardp|defdelegate|defmodule|defprotocol|defimpl|defstruct|defexception|defoverridable|alias|import|require|use|if|unless|case|cond|with|for|fn|try|receive|quote|unquote|raise)$"))
+
+(dot
+  left: (alias) @reference)
+
+(dot
+  right: (identifier) @reference)
+
+(call
+  target: (identifier) @_directive
+  (arguments
+    (alias) @reference)
+  (#match? @_directive "^(alias|import|require|use)$"))
@@ -0,0 +1,35 @@
+;; Capture called functions, field and method accesses (package members
+;; included), and type references
+(call_expression
+  function: (identifier) @reference)
+
+(selector_expression
+  field: (field_identifier) @reference)
+
+(_
+  type: (type_identifier) @reference)
+
+(_
+  result: (type_identifier) @reference)
+
+(_
+  element: (type_identifier) @reference)
+
+(map_type
+  key: (type_identifier) @reference)
+
+(_
+  value: (type_identifier) @reference)
+
+(pointer_type
+  (type_identifier) @reference)
+
+(qualified_type
+  name: (type_identifier) @reference)
+
+(type_arguments
+  (type_elem
+    (type_identifier) @reference))
+
+(type_elem
+  (type_identifier) @reference)
@@ -0,0 +1,13 @@
+;; Capture invoked methods, field accesses, classes used as a static scope,
+;; and type references
+(method_invocation
+  name: (identifier) @reference)
+
+(method_invocation
+  object: (identifier) @reference
+  (#match? @reference "^[A-Z]"))
+
+(field_access
+  field: (identifier) @reference)
+
+(type_identifier) @reference
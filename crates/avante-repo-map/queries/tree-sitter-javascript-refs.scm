@@ -0,0 +1,22 @@
+;; Capture called functions and constructors, property accesses (method calls
+;; included), base classes, imported names and JSX components
+(call_expression
+  function: (identifier) @reference)
+
+(new_expression
+  constructor: (identifier) @reference)
+
+(member_expression
+  property: (property_identifier) @reference)
+
+(class_heritage
+  (identifier) @reference)
+
+(import_specifier
+  name: (identifier) @reference)
+
+(jsx_opening_element
+  name: (identifier) @reference)
+
+(jsx_self_closing_element
+  name: (identifier) @reference)
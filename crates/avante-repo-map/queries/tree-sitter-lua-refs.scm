@@ -0,0 +1,9 @@
+;; Capture called functions and methods, and field accesses
+(function_call
+  name: (identifier) @reference)
+
+(method_index_expression
+  method: (identifier) @reference)
+
+(dot_index_expression
+  field: (identifier) @reference)
@@ -0,0 +1,34 @@
+;; Capture called functions and methods, property accesses, created objects,
+;; base classes and interfaces, and type references
+(function_call_expression
+  function: (name) @reference)
+
+(member_call_expression
+  name: (name) @reference)
+
+(nullsafe_member_call_expression
+  name: (name) @reference)
+
+(scoped_call_expression
+  scope: (name) @reference)
+
+(scoped_call_expression
+  name: (name) @reference)
+
+(member_access_expression
+  name: (name) @reference)
+
+(object_creation_expression
+  (name) @reference)
+
+(named_type
+  (name) @reference)
+
+(base_clause
+  (name) @reference)
+
+(class_interface_clause
+  (name) @reference)
+
+(qualified_name
+  (name) @reference)
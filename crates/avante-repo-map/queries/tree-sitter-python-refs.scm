@@ -0,0 +1,33 @@
+;; Capture called functions, attribute accesses (method calls included),
+;; base classes, decorators, imported names and type hints
+(call
+  function: (identifier) @reference)
+
+(attribute
+  attribute: (identifier) @reference)
+
+(class_definition
+  superclasses: (argument_list
+    (identifier) @reference))
+
+(decorator
+  (identifier) @reference)
+
+(import_from_statement
+  name: (dotted_name
+    (identifier) @reference))
+
+(import_from_statement
+  name: (aliased_import
+    name: (dotted_name
+      (identifier) @reference)))
+
+(type
+  (identifier) @reference)
+
+(generic_type
+  (identifier) @reference)
+
+(type_parameter
+  (type
+    (identifier) @reference))
@@ -0,0 +1,19 @@
+;; Capture called methods, superclasses, and constants used as a receiver,
+;; argument or scope
+(call
+  method: (identifier) @reference)
+
+(call
+  receiver: (constant) @reference)
+
+(superclass
+  (constant) @reference)
+
+(argument_list
+  (constant) @reference)
+
+(scope_resolution
+  scope: (constant) @reference)
+
+(scope_resolution
+  name: (constant) @reference)
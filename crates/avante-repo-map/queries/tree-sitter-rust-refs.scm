@@ -0,0 +1,56 @@
+;; Capture called functions and macros, method and field accesses, imported
+;; names, and type references
+(call_expression
+  function: (identifier) @reference)
+
+(call_expression
+  function: (scoped_identifier
+    name: (identifier) @reference))
+
+(generic_function
+  function: (identifier) @reference)
+
+(generic_function
+  function: (scoped_identifier
+    name: (identifier) @reference))
+
+(macro_invocation
+  macro: (identifier) @reference)
+
+(field_expression
+  field: (field_identifier) @reference)
+
+(scoped_identifier
+  path: (identifier) @reference)
+
+(use_declaration
+  argument: (scoped_identifier
+    name: (identifier) @reference))
+
+(use_list
+  (identifier) @reference)
+
+(use_list
+  (scoped_identifier
+    name: (identifier) @reference))
+
+(_
+  type: (type_identifier) @reference)
+
+(_
+  return_type: (type_identifier) @reference)
+
+(_
+  trait: (type_identifier) @reference)
+
+(type_arguments
+  (type_identifier) @reference)
+
+(trait_bounds
+  (type_identifier) @reference)
+
+(scoped_type_identifier
+  name: (type_identifier) @reference)
+
+(struct_expression
+  name: (type_identifier) @reference)
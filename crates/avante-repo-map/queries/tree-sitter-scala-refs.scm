@@ -0,0 +1,9 @@
+;; Capture called functions, field accesses (method calls included) and type
+;; references
+(call_expression
+  function: (identifier) @reference)
+
+(field_expression
+  field: (identifier) @reference)
+
+(type_identifier) @reference
@@ -0,0 +1,10 @@
+;; Capture called functions, member accesses (method calls included) and type
+;; references
+(call_expression
+  (simple_identifier) @reference)
+
+(navigation_suffix
+  suffix: (simple_identifier) @reference)
+
+(user_type
+  (type_identifier) @reference)
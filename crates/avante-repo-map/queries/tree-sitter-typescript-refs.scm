@@ -0,0 +1,50 @@
+;; Capture called functions and constructors, property accesses (method calls
+;; included), base classes and interfaces, imported names, JSX components and
+;; type references
+(call_expression
+  function: (identifier) @reference)
+
+(new_expression
+  constructor: (identifier) @reference)
+
+(member_expression
+  property: (property_identifier) @reference)
+
+(extends_clause
+  value: (identifier) @reference)
+
+(implements_clause
+  (type_identifier) @reference)
+
+(extends_type_clause
+  type: (type_identifier) @reference)
+
+(import_specifier
+  name: (identifier) @reference)
+
+(jsx_opening_element
+  name: (identifier) @reference)
+
+(jsx_self_closing_element
+  name: (identifier) @reference)
+
+(type_annotation
+  (type_identifier) @reference)
+
+(generic_type
+  name: (type_identifier) @reference)
+
+(type_arguments
+  (type_identifier) @reference)
+
+(union_type
+  (type_identifier) @reference)
+
+(intersection_type
+  (type_identifier) @reference)
+
+(array_type
+  (type_identifier) @reference)
+
+(nested_type_identifier
+  name: (type_identifier) @reference)
@@ -0,0 +1,6 @@
+;; Capture called functions and field accesses (method calls included)
+(call_expression
+  function: (identifier) @reference)
+
+(field_expression
+  member: (identifier) @reference)
@@ -0,0 +1,872 @@
+use ignore::{WalkBuilder, WalkState};
+use mlua::prelude::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::cache::{self, CachedFile};
+use crate::rank::{self, Symbol};
+use crate::{extract_definitions, extract_references, DocMode};
+
+// Files larger than this are skipped unless `max_file_size` says otherwise
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+// Bytes inspected for NULs to tell binary files apart
+const BINARY_CHECK_LEN: usize = 8000;
+// Names `buffers` may give as a `lang`
+const LANGUAGES: &[&str] = &[
+    "rust",
+    "python",
+    "php",
+    "java",
+    "javascript",
+    "typescript",
+    "go",
+    "c",
+    "cpp",
+    "lua",
+    "ruby",
+    "zig",
+    "scala",
+    "swift",
+    "elixir",
+    "csharp",
+];
+
+// A buffer loaded in the editor: its filetype wins over detection, and its
+// unsaved text over the file on disk
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BufferOverride {
+    pub(crate) lang: Option<String>,
+    pub(crate) content: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RepoMapOptions {
+    pub(crate) extensions: Option<Vec<String>>, // Only files with these extensions
+    pub(crate) languages: Option<Vec<String>>,  // Only files detected as these languages
+    pub(crate) max_file_size: u64,
+    pub(crate) max_files: Option<usize>,
+    pub(crate) hidden: bool,    // Include hidden files and directories
+    pub(crate) gitignore: bool, // Honor .gitignore, .ignore and .git/info/exclude
+    pub(crate) follow_links: bool,
+    pub(crate) threads: usize,              // 0 picks one per CPU
+    pub(crate) cache_dir: Option<PathBuf>,  // Keep per-file definitions here between builds
+    pub(crate) token_budget: Option<usize>, // Rank definitions and keep the best that fit
+    pub(crate) context_files: Vec<String>,  // Files already in context; they seed the ranking
+    pub(crate) docs: DocMode,               // How much of the doc comments goes in `defs`
+    pub(crate) buffers: HashMap<PathBuf, BufferOverride>, // By absolute path
+}
+
+impl Default for RepoMapOptions {
+    fn default() -> Self {
+        Self {
+            extensions: None,
+            languages: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_files: None,
+            hidden: false,
+            gitignore: true,
+            follow_links: false,
+            threads: 0,
+            cache_dir: None,
+            token_budget: None,
+            context_files: Vec::new(),
+            docs: DocMode::None,
+            buffers: HashMap::new(),
+        }
+    }
+}
+
+impl RepoMapOptions {
+    fn language_of(&self, path: &Path) -> Option<&'static str> {
+        let buffer_lang = self
+            .buffers
+            .get(path)
+            .and_then(|buffer| buffer.lang.as_deref());
+        let language = match buffer_lang {
+            // The extensions stand for their languages, the file's own may not say
+            Some(lang) => {
+                let language = LANGUAGES.iter().copied().find(|name| *name == lang)?;
+                if let Some(extensions) = &self.extensions {
+                    if !extensions
+                        .iter()
+                        .any(|ext| language_for_extension(ext) == Some(language))
+                    {
+                        return None;
+                    }
+                }
+                language
+            }
+            None => self.detected_language_of(path)?,
+        };
+        if let Some(languages) = &self.languages {
+            if !languages.iter().any(|lang| lang == language) {
+                return None;
+            }
+        }
+        Some(language)
+    }
+
+    fn detected_language_of(&self, path: &Path) -> Option<&'static str> {
+        if let Some(extensions) = &self.extensions {
+            let extension = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or_default();
+            if !extensions
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(extension))
+            {
+                return None;
+            }
+        }
+        detect_language(path)
+    }
+
+    fn thread_count(&self) -> usize {
+        if self.threads > 0 {
+            return self.threads;
+        }
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    }
+}
+
+impl FromLua for RepoMapOptions {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        let table = match value {
+            LuaValue::Nil => return Ok(Self::default()),
+            LuaValue::Table(table) => table,
+            _ => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: "LuaValue",
+                    to: "RepoMapOptions".to_string(),
+                    message: Some("Expected a table".to_string()),
+                })
+            }
+        };
+
+        let defaults = Self::default();
+        Ok(Self {
+            extensions: table.get("extensions")?,
+            languages: table.get("languages")?,
+            max_file_size: table
+                .get::<Option<u64>>("max_file_size")?
+                .unwrap_or(defaults.max_file_size),
+            max_files: table.get("max_files")?,
+            hidden: table
+                .get::<Option<bool>>("hidden")?
+                .unwrap_or(defaults.hidden),
+            gitignore: table
+                .get::<Option<bool>>("gitignore")?
+                .unwrap_or(defaults.gitignore),
+            follow_links: table
+                .get::<Option<bool>>("follow_links")?
+                .unwrap_or(defaults.follow_links),
+            threads: table
+                .get::<Option<usize>>("threads")?
+                .unwrap_or(defaults.threads),
+            cache_dir: table.get::<Option<String>>("cache_dir")?.map(PathBuf::from),
+            token_budget: table.get("token_budget")?,
+            context_files: table
+                .get::<Option<Vec<String>>>("context_files")?
+                .unwrap_or_default(),
+            docs: table.get::<Option<DocMode>>("docs")?.unwrap_or_default(),
+            buffers: table
+                .get::<Option<Vec<LuaTable>>>("buffers")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|buffer| {
+                    let path = buffer.get::<String>("path")?;
+                    let buffer = BufferOverride {
+                        lang: buffer.get("lang")?,
+                        content: buffer.get("content")?,
+                    };
+                    Ok((PathBuf::from(path), buffer))
+                })
+                .collect::<LuaResult<_>>()?,
+        })
+    }
+}
+
+// One file of the map, as returned to Lua
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct RepoMapEntry {
+    pub(crate) path: String, // Relative to the root
+    pub(crate) lang: String,
+    pub(crate) defs: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rank: Option<f64>, // Best rank of its definitions, with a `token_budget`
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    scanning: AtomicBool,
+    total: AtomicUsize,
+    processed: AtomicUsize,
+    parsed: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct ProgressSnapshot {
+    pub(crate) scanning: bool, // Still walking the tree; `total` is not known yet
+    pub(crate) total: usize,
+    pub(crate) processed: usize,
+    pub(crate) parsed: usize, // Processed files that weren't in the cache
+    pub(crate) done: bool,
+}
+
+impl Progress {
+    fn snapshot(&self, done: bool) -> ProgressSnapshot {
+        ProgressSnapshot {
+            scanning: self.scanning.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            parsed: self.parsed.load(Ordering::Relaxed),
+            done,
+        }
+    }
+}
+
+pub(crate) fn language_for_extension(extension: &str) -> Option<&'static str> {
+    let language = match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "php" => "php",
+        "java" => "java",
+        "js" | "jsx" | "mjs" | "cjs" => "javascript",
+        "ts" | "tsx" | "mts" | "cts" => "typescript",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "c++" | "hh" | "hpp" | "hxx" | "h++" => "cpp",
+        "lua" => "lua",
+        "rb" | "rake" | "gemspec" => "ruby",
+        "zig" => "zig",
+        "scala" | "sc" => "scala",
+        "swift" => "swift",
+        "ex" | "exs" => "elixir",
+        "cs" => "csharp",
+        _ => return None,
+    };
+    Some(language)
+}
+
+// Language of a script without an extension, from its `#!` line
+fn shebang_language(path: &Path) -> Option<&'static str> {
+    let mut head = [0u8; 128];
+    let len = fs::File::open(path).ok()?.read(&mut head).ok()?;
+    let head = String::from_utf8_lossy(&head[..len]);
+    let line = head.lines().next()?.strip_prefix("#!")?;
+
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    // python3.12 -> python
+    let language = match program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "python" => "python",
+        "ruby" => "ruby",
+        "lua" | "luajit" => "lua",
+        "node" | "deno" | "bun" => "javascript",
+        "php" => "php",
+        "elixir" => "elixir",
+        _ => return None,
+    };
+    Some(language)
+}
+
+pub(crate) fn detect_language(path: &Path) -> Option<&'static str> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(extension) => language_for_extension(extension),
+        None => shebang_language(path),
+    }
+}
+
+// Walk `root` in parallel and return the candidate files, sorted by path
+fn collect_files(
+    root: &Path,
+    options: &RepoMapOptions,
+    cancelled: &AtomicBool,
+) -> Vec<(PathBuf, &'static str)> {
+    let files = Mutex::new(Vec::new());
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .hidden(!options.hidden)
+        .git_ignore(options.gitignore)
+        .git_global(options.gitignore)
+        .git_exclude(options.gitignore)
+        .ignore(options.gitignore)
+        .parents(options.gitignore)
+        .require_git(false)
+        .follow_links(options.follow_links)
+        .max_filesize(Some(options.max_file_size))
+        .threads(options.thread_count())
+        .filter_entry(|entry| entry.file_name() != ".git");
+
+    builder.build_parallel().run(|| {
+        let files = &files;
+        Box::new(move |entry: Result<ignore::DirEntry, ignore::Error>| {
+            if cancelled.load(Ordering::Relaxed) {
+                return WalkState::Quit;
+            }
+            let Ok(entry) = entry else {
+                return WalkState::Continue;
+            };
+            if !entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_file())
+            {
+                return WalkState::Continue;
+            }
+            if let Some(language) = options.language_of(entry.path()) {
+                files.lock().unwrap().push((entry.into_path(), language));
+            }
+            WalkState::Continue
+        })
+    });
+
+    // Buffers that were never written have nothing on disk to walk
+    let mut files = files.into_inner().unwrap();
+    for (path, buffer) in &options.buffers {
+        if buffer.content.is_some() && path.starts_with(root) && !path.exists() {
+            if let Some(language) = options.language_of(path) {
+                files.push((path.clone(), language));
+            }
+        }
+    }
+    // Sort before applying max_files so the same files are kept on every run
+    files.sort();
+    if let Some(max_files) = options.max_files {
+        files.truncate(max_files);
+    }
+    files
+}
+
+// Map one file, reusing its cached definitions when the mtime and size, or
+// failing that the contents, are unchanged. `None` if it can't be read.
+fn map_file(
+    path: &Path,
+    language: &str,
+    docs: DocMode,
+    cached: Option<&CachedFile>,
+    progress: &Progress,
+) -> Option<CachedFile> {
+    let metadata = fs::metadata(path).ok()?;
+    // Stringified with other docs, it has to be stringified again
+    let cached = cached.filter(|cached| cached.docs == docs);
+    if let Some(cached) = cached.filter(|cached| cached.is_current(language, &metadata)) {
+        return Some(cached.clone());
+    }
+
+    let bytes = fs::read(path).ok()?;
+    let (mtime, mtime_nanos) = cache::modified(&metadata);
+    Some(CachedFile {
+        mtime,
+        mtime_nanos,
+        size: metadata.len(),
+        ..map_source(&bytes, language, docs, cached, progress)
+    })
+}
+
+// Map the unsaved text of a buffer; only its contents can match the cache
+fn map_buffer(
+    content: &str,
+    language: &str,
+    docs: DocMode,
+    cached: Option<&CachedFile>,
+    progress: &Progress,
+) -> CachedFile {
+    let cached = cached.filter(|cached| cached.docs == docs);
+    map_source(content.as_bytes(), language, docs, cached, progress)
+}
+
+// Definitions and references of `bytes`, or the cached ones if it hashes the
+// same. The mtime and size are left for the caller.
+fn map_source(
+    bytes: &[u8],
+    language: &str,
+    docs: DocMode,
+    cached: Option<&CachedFile>,
+    progress: &Progress,
+) -> CachedFile {
+    let hash = cache::content_hash(bytes);
+    if let Some(cached) = cached.filter(|cached| cached.lang == language && cached.hash == hash) {
+        return cached.clone();
+    }
+
+    progress.parsed.fetch_add(1, Ordering::Relaxed);
+    let (symbols, references) = if bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0) {
+        Default::default()
+    } else {
+        let source = String::from_utf8_lossy(bytes);
+        // A file tree-sitter chokes on shouldn't take the whole map down
+        let symbols =
+            panic::catch_unwind(AssertUnwindSafe(|| extract_definitions(language, &source)))
+                .ok()
+                .and_then(Result::ok)
+                .map(|definitions| {
+                    definitions
+                        .iter()
+                        .map(|definition| Symbol::from_definition(definition, docs))
+                        .collect()
+                })
+                .unwrap_or_default();
+        let references =
+            panic::catch_unwind(AssertUnwindSafe(|| extract_references(language, &source)))
+                .ok()
+                .and_then(Result::ok)
+                .unwrap_or_default();
+        (symbols, references)
+    };
+    CachedFile {
+        mtime: 0,
+        mtime_nanos: 0,
+        size: 0,
+        hash,
+        lang: language.to_string(),
+        docs,
+        symbols,
+        references,
+    }
+}
+
+pub(crate) fn build_repo_map(
+    root: &Path,
+    options: &RepoMapOptions,
+    progress: &Progress,
+    cancelled: &AtomicBool,
+) -> Result<Vec<RepoMapEntry>, String> {
+    if !root.is_dir() {
+        return Err(format!("Not a directory: {}", root.display()));
+    }
+
+    progress.scanning.store(true, Ordering::Relaxed);
+    let files = collect_files(root, options, cancelled);
+    progress.total.store(files.len(), Ordering::Relaxed);
+    progress.scanning.store(false, Ordering::Relaxed);
+
+    let cache = options
+        .cache_dir
+        .as_deref()
+        .map(|dir| cache::open(dir, root));
+    // Workers read a copy, so `invalidate` isn't blocked while they parse
+    let cached = cache
+        .as_ref()
+        .map(|cache| cache.lock().unwrap().files().clone())
+        .unwrap_or_default();
+
+    let next = AtomicUsize::new(0);
+    let mapped = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..options.thread_count().min(files.len()) {
+            scope.spawn(|| {
+                while !cancelled.load(Ordering::Relaxed) {
+                    let Some((path, language)) = files.get(next.fetch_add(1, Ordering::Relaxed))
+                    else {
+                        break;
+                    };
+                    let relative = path
+                        .strip_prefix(root)
+                        .unwrap_or(path)
+                        .to_string_lossy()
+                        .to_string();
+                    let content = options
+                        .buffers
+                        .get(path)
+                        .and_then(|buffer| buffer.content.as_deref());
+                    let file = match content {
+                        Some(content) => Some(map_buffer(
+                            content,
+                            language,
+                            options.docs,
+                            cached.get(&relative),
+                            progress,
+                        )),
+                        None => map_file(
+                            path,
+                            language,
+                            options.docs,
+                            cached.get(&relative),
+                            progress,
+                        ),
+                    };
+                    if let Some(file) = file {
+                        mapped
+                            .lock()
+                            .unwrap()
+                            .push((relative, file, content.is_some()));
+                    }
+                    progress.processed.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+
+    if cancelled.load(Ordering::Relaxed) {
+        return Err("Repo map build cancelled".to_string());
+    }
+    let mut mapped = mapped.into_inner().unwrap();
+    mapped.sort_by(|a, b| a.0.cmp(&b.0));
+    // Unsaved text doesn't belong in the cache, which describes the files on disk
+    let unsaved: HashSet<String> = mapped
+        .iter()
+        .filter(|(_, _, unsaved)| *unsaved)
+        .map(|(path, _, _)| path.clone())
+        .collect();
+    let mapped: Vec<_> = mapped
+        .into_iter()
+        .map(|(path, file, _)| (path, file))
+        .collect();
+    let entries = match options.token_budget {
+        Some(token_budget) => {
+            let context_files: HashSet<String> = options
+                .context_files
+                .iter()
+                .map(|path| {
+                    let path = Path::new(path);
+                    path.strip_prefix(root)
+                        .unwrap_or(path)
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            rank::rank_repo_map(&mapped, &context_files, token_budget)
+        }
+        None => mapped
+            .iter()
+            .filter(|(_, file)| !file.symbols.is_empty())
+            .map(|(path, file)| RepoMapEntry {
+                path: path.clone(),
+                lang: file.lang.clone(),
+                defs: file.defs(),
+                rank: None,
+            })
+            .collect(),
+    };
+
+    if let Some(cache) = cache {
+        let mut cache = cache.lock().unwrap();
+        cache.update(
+            mapped
+                .into_iter()
+                .filter(|(path, _)| !unsaved.contains(path))
+                .collect(),
+        );
+        // The map itself is fine without the cache; the next build just parses more
+        let _ = cache.save();
+    }
+    Ok(entries)
+}
+
+type JobResult = Result<Vec<RepoMapEntry>, String>;
+
+// A build running on a background thread; Lua polls it for progress and the result
+pub(crate) struct RepoMapJob {
+    progress: Arc<Progress>,
+    cancelled: Arc<AtomicBool>,
+    handle: Option<JoinHandle<JobResult>>,
+    result: Option<JobResult>,
+}
+
+impl RepoMapJob {
+    pub(crate) fn spawn(root: PathBuf, options: RepoMapOptions) -> Self {
+        let progress = Arc::new(Progress::default());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let progress = progress.clone();
+            let cancelled = cancelled.clone();
+            std::thread::spawn(move || build_repo_map(&root, &options, &progress, &cancelled))
+        };
+        Self {
+            progress,
+            cancelled,
+            handle: Some(handle),
+            result: None,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.handle.as_ref().map_or(true, JoinHandle::is_finished)
+    }
+
+    fn poll(&mut self) -> Option<&JobResult> {
+        if self.result.is_none() && self.is_done() {
+            let handle = self.handle.take()?;
+            self.result = Some(
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err("Repo map build panicked".to_string())),
+            );
+        }
+        self.result.as_ref()
+    }
+}
+
+impl Drop for RepoMapJob {
+    // Stop the walk once Lua drops the handle
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl LuaUserData for RepoMapJob {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_done", |_, this, ()| Ok(this.is_done()));
+        methods.add_method("progress", |lua, this, ()| {
+            lua.to_value(&this.progress.snapshot(this.is_done()))
+        });
+        // nil while running, the entries once done; raises if the build failed
+        methods.add_method_mut("result", |lua, this, ()| match this.poll() {
+            None => Ok(LuaValue::Nil),
+            Some(Ok(entries)) => lua.to_value(entries),
+            Some(Err(e)) => Err(LuaError::RuntimeError(e.clone())),
+        });
+        methods.add_method("cancel", |_, this, ()| {
+            this.cancelled.store(true, Ordering::Relaxed);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(root: &Path, options: &RepoMapOptions) -> Vec<RepoMapEntry> {
+        build_repo_map(root, options, &Progress::default(), &AtomicBool::new(false)).unwrap()
+    }
+
+    // Number of files the build had to parse
+    fn build_parsed(root: &Path, options: &RepoMapOptions) -> (Vec<RepoMapEntry>, usize) {
+        let progress = Progress::default();
+        let entries = build_repo_map(root, options, &progress, &AtomicBool::new(false)).unwrap();
+        (entries, progress.snapshot(true).parsed)
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("tool");
+        fs::write(&script, "#!/usr/bin/env python3.12\nprint('hi')\n").unwrap();
+        let shell = dir.path().join("run");
+        fs::write(&shell, "#!/bin/sh\necho hi\n").unwrap();
+
+        assert_eq!(detect_language(Path::new("src/main.RS")), Some("rust"));
+        assert_eq!(detect_language(Path::new("App.tsx")), Some("typescript"));
+        assert_eq!(detect_language(Path::new("include/vec.hpp")), Some("cpp"));
+        assert_eq!(detect_language(Path::new("README.md")), None);
+        assert_eq!(detect_language(&script), Some("python"));
+        assert_eq!(detect_language(&shell), None);
+    }
+
+    #[test]
+    fn test_build_repo_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::create_dir_all(root.join(".hidden")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(root.join(".ignore"), "*.gen.py\n").unwrap();
+        fs::write(
+            root.join("src/lib.rs"),
+            "pub fn add(a: u32, b: u32) -> u32 { a + b }\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("src/nested/util.py"),
+            "def helper(x):\n    return x\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("src/nested/skip.gen.py"),
+            "def generated():\n    pass\n",
+        )
+        .unwrap();
+        fs::write(root.join("src/private.rs"), "fn hidden() {}\n").unwrap();
+        fs::write(root.join("target/out.rs"), "pub fn built() {}\n").unwrap();
+        fs::write(root.join(".hidden/secret.rs"), "pub fn secret() {}\n").unwrap();
+        fs::write(
+            root.join("big.rs"),
+            format!("pub fn big() {{}}\n{}", "//\n".repeat(1000)),
+        )
+        .unwrap();
+
+        let options = RepoMapOptions {
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let entries = build(root, &options);
+        let paths: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.path.replace('\\', "/"))
+            .collect();
+        assert_eq!(paths, vec!["src/lib.rs", "src/nested/util.py"]);
+        assert_eq!(entries[0].lang, "rust");
+        assert_eq!(entries[0].defs, "func add(a: u32, b: u32) -> u32;");
+
+        let options = RepoMapOptions {
+            extensions: Some(vec!["py".to_string()]),
+            ..Default::default()
+        };
+        let entries = build(root, &options);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].lang, "python");
+
+        let options = RepoMapOptions {
+            gitignore: false,
+            hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(build(root, &options).len(), 6);
+    }
+
+    #[test]
+    fn test_incremental_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.rs"), "pub fn a() {}\n").unwrap();
+        fs::write(root.join("b.rs"), "pub fn b() {}\n").unwrap();
+        fs::write(root.join("c.rs"), "fn private() {}\n").unwrap();
+        let options = RepoMapOptions {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let (first, parsed) = build_parsed(root, &options);
+        assert_eq!((first.len(), parsed), (2, 3));
+        let (second, parsed) = build_parsed(root, &options);
+        assert_eq!((second, parsed), (first, 0));
+
+        // Rewritten with the same contents: matched by hash, not parsed
+        fs::write(root.join("a.rs"), "pub fn a() {}\n").unwrap();
+        fs::write(root.join("b.rs"), "pub fn b2() {}\n").unwrap();
+        let (entries, parsed) = build_parsed(root, &options);
+        assert_eq!(parsed, 1);
+        assert_eq!(entries[1].defs, "func b2() -> void;");
+
+        assert_eq!(cache::invalidate(&[root.join("a.rs")]), 1);
+        assert_eq!(build_parsed(root, &options).1, 1);
+    }
+
+    #[test]
+    fn test_buffer_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.rs"), "pub fn a() {}\n").unwrap();
+        fs::write(root.join("tasks.conf"), "def build():\n    pass\n").unwrap();
+        let buffer = |lang: &str, content: Option<&str>| BufferOverride {
+            lang: Some(lang.to_string()),
+            content: content.map(str::to_string),
+        };
+        let options = RepoMapOptions {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            buffers: HashMap::from([
+                (root.join("a.rs"), buffer("rust", Some("pub fn a2() {}\n"))),
+                (root.join("tasks.conf"), buffer("python", None)),
+                (
+                    root.join("new.rs"),
+                    buffer("rust", Some("pub fn fresh() {}\n")),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let entries = build(root, &options);
+        let defs: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.path.as_str(),
+                    entry.lang.as_str(),
+                    entry.defs.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            defs,
+            vec![
+                ("a.rs", "rust", "func a2() -> void;"),
+                ("new.rs", "rust", "func fresh() -> void;"),
+                ("tasks.conf", "python", "func build() -> void;"),
+            ]
+        );
+
+        // The extensions stand for their language, a buffer's own may differ
+        let options = RepoMapOptions {
+            extensions: Some(vec!["py".to_string()]),
+            ..options
+        };
+        let entries = build(root, &options);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "tasks.conf");
+
+        // The unsaved text never reached the cache
+        let options = RepoMapOptions {
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let entries = build(root, &options);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].defs, "func a() -> void;");
+    }
+
+    #[test]
+    fn test_local_binding_is_not_a_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("config.rs"), "pub fn config() -> u32 { 1 }\n").unwrap();
+        fs::write(root.join("render.rs"), "pub fn render() {}\n").unwrap();
+        fs::write(
+            root.join("main.rs"),
+            "fn main() {\n    let config = 2;\n    render();\n}\n",
+        )
+        .unwrap();
+        let options = RepoMapOptions {
+            token_budget: Some(1000),
+            context_files: vec!["main.rs".to_string()],
+            ..Default::default()
+        };
+
+        // Only the call links main.rs to another file
+        let entries = build(root, &options);
+        let ranks: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.rank.unwrap()))
+            .collect();
+        assert_eq!(ranks[0].0, "render.rs");
+        assert!(ranks[0].1 > 0.0);
+        assert_eq!(ranks[1], ("config.rs", 0.0));
+    }
+
+    #[test]
+    fn test_background_job() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..20 {
+            fs::write(
+                dir.path().join(format!("mod_{i}.rs")),
+                format!("pub fn f{i}() {{}}\n"),
+            )
+            .unwrap();
+        }
+
+        let mut job = RepoMapJob::spawn(dir.path().to_path_buf(), RepoMapOptions::default());
+        while !job.is_done() {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        let progress = job.progress.snapshot(true);
+        assert_eq!(
+            (progress.total, progress.processed, progress.scanning),
+            (20, 20, false)
+        );
+        assert_eq!(job.poll().unwrap().as_ref().unwrap().len(), 20);
+    }
+}
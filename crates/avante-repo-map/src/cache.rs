@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use crate::rank::Symbol;
+use crate::{DocMode, QUERIES};
+
+// Bump when the cached definitions would come out differently for the same
+// source (e.g. the stringified format changes) without a query changing
+const CACHE_FORMAT: u32 = 5;
+
+// What a file mapped to, and the stamps that tell whether it changed since
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CachedFile {
+    pub(crate) mtime: u64, // Seconds since the epoch
+    pub(crate) mtime_nanos: u32,
+    pub(crate) size: u64,
+    pub(crate) hash: String, // sha256 of the contents
+    pub(crate) lang: String,
+    pub(crate) docs: DocMode, // How much of the doc comments `symbols` were stringified with
+    pub(crate) symbols: Vec<Symbol>, // Empty for files without definitions, so they aren't parsed again
+    pub(crate) references: BTreeMap<String, u32>, // Identifier -> times it's used
+}
+
+impl CachedFile {
+    // Unchanged by mtime and size, without reading the file
+    pub(crate) fn is_current(&self, language: &str, metadata: &Metadata) -> bool {
+        self.lang == language
+            && self.size == metadata.len()
+            && (self.mtime, self.mtime_nanos) == modified(metadata)
+    }
+
+    // The file's stringified definitions, as `stringify_definitions` writes them
+    pub(crate) fn defs(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|symbol| symbol.defs.as_str())
+            .collect()
+    }
+}
+
+pub(crate) fn modified(metadata: &Metadata) -> (u64, u32) {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |since| (since.as_secs(), since.subsec_nanos()))
+}
+
+pub(crate) fn content_hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Cached definitions are only valid for the queries (and crate version) that produced them
+fn query_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(|| {
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        hasher.update(CACHE_FORMAT.to_le_bytes());
+        for query in QUERIES {
+            hasher.update(query);
+        }
+        format!("{:x}", hasher.finalize())
+    })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheContents {
+    version: String,
+    root: PathBuf,
+    files: HashMap<String, CachedFile>, // Keyed by path relative to the root
+}
+
+// Per-file definitions of one project, persisted as JSON in the cache directory
+#[derive(Debug)]
+pub(crate) struct RepoMapCache {
+    path: PathBuf,
+    contents: CacheContents,
+    dirty: bool,
+}
+
+fn cache_path(dir: &Path, root: &Path) -> PathBuf {
+    dir.join(format!(
+        "{}.json",
+        &content_hash(root.to_string_lossy().as_bytes())[..16]
+    ))
+}
+
+impl RepoMapCache {
+    fn load(dir: &Path, root: &Path) -> Self {
+        let path = cache_path(dir, root);
+        // A missing, unreadable or outdated cache starts over empty
+        let contents = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<CacheContents>(&bytes).ok())
+            .filter(|contents| contents.version == query_version() && contents.root == root)
+            .unwrap_or_else(|| CacheContents {
+                version: query_version().to_string(),
+                root: root.to_path_buf(),
+                files: HashMap::new(),
+            });
+        Self {
+            path,
+            contents,
+            dirty: false,
+        }
+    }
+
+    pub(crate) fn files(&self) -> &HashMap<String, CachedFile> {
+        &self.contents.files
+    }
+
+    // Record a build. Files it didn't visit are kept while they exist, since
+    // other builds of the same root may filter on other extensions.
+    pub(crate) fn update(&mut self, files: Vec<(String, CachedFile)>) {
+        let seen: HashSet<&String> = files.iter().map(|(path, _)| path).collect();
+        let root = &self.contents.root;
+        let before = self.contents.files.len();
+        self.contents
+            .files
+            .retain(|path, _| seen.contains(path) || root.join(path).is_file());
+        self.dirty |= self.contents.files.len() != before;
+
+        for (path, file) in files {
+            if self.contents.files.get(&path) != Some(&file) {
+                self.contents.files.insert(path, file);
+                self.dirty = true;
+            }
+        }
+    }
+
+    fn invalidate(&mut self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.contents.root) else {
+            return false;
+        };
+        let removed = self
+            .contents
+            .files
+            .remove(relative.to_string_lossy().as_ref())
+            .is_some();
+        self.dirty |= removed;
+        removed
+    }
+
+    pub(crate) fn save(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write then rename, so a crash never leaves a truncated cache behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.contents)?)?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+type SharedCache = Arc<Mutex<RepoMapCache>>;
+
+// Caches loaded by this process, by cache file, so `invalidate` can reach them
+fn loaded() -> &'static Mutex<HashMap<PathBuf, SharedCache>> {
+    static CACHES: OnceLock<Mutex<HashMap<PathBuf, SharedCache>>> = OnceLock::new();
+    CACHES.get_or_init(Mutex::default)
+}
+
+pub(crate) fn open(dir: &Path, root: &Path) -> SharedCache {
+    loaded()
+        .lock()
+        .unwrap()
+        .entry(cache_path(dir, root))
+        .or_insert_with(|| Arc::new(Mutex::new(RepoMapCache::load(dir, root))))
+        .clone()
+}
+
+// Drop the cached definitions of `paths` (absolute) from every loaded cache,
+// so the next build parses them again. Returns how many entries were dropped.
+pub(crate) fn invalidate(paths: &[PathBuf]) -> usize {
+    let caches: Vec<SharedCache> = loaded().lock().unwrap().values().cloned().collect();
+    let mut dropped = 0;
+    for cache in caches {
+        let mut cache = cache.lock().unwrap();
+        dropped += paths.iter().filter(|path| cache.invalidate(path)).count();
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(defs: &str) -> CachedFile {
+        CachedFile {
+            mtime: 1,
+            mtime_nanos: 0,
+            size: 1,
+            hash: String::new(),
+            lang: "rust".to_string(),
+            docs: DocMode::None,
+            symbols: vec![Symbol {
+                names: Vec::new(),
+                defs: defs.to_string(),
+            }],
+            references: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_persist_and_invalidate() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.rs"), "").unwrap();
+        fs::write(root.path().join("b.rs"), "").unwrap();
+
+        let cache = open(cache_dir.path(), root.path());
+        {
+            let mut cache = cache.lock().unwrap();
+            cache.update(vec![
+                ("a.rs".to_string(), cached("func a();")),
+                ("b.rs".to_string(), cached("func b();")),
+                ("gone.rs".to_string(), cached("func gone();")),
+            ]);
+            cache.save().unwrap();
+        }
+
+        // Entries for files that no longer exist are dropped on the next update
+        let mut reloaded = RepoMapCache::load(cache_dir.path(), root.path());
+        assert_eq!(reloaded.files().len(), 3);
+        reloaded.update(vec![("a.rs".to_string(), cached("func a();"))]);
+        assert_eq!(reloaded.files().len(), 2);
+        assert!(reloaded.files().contains_key("b.rs"));
+
+        assert_eq!(
+            invalidate(&[root.path().join("a.rs"), PathBuf::from("/elsewhere/a.rs")]),
+            1
+        );
+        assert!(!cache.lock().unwrap().files().contains_key("a.rs"));
+    }
+
+    #[test]
+    fn test_outdated_cache_is_discarded() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut cache = RepoMapCache::load(cache_dir.path(), root.path());
+        cache.contents.version = "old".to_string();
+        cache
+            .contents
+            .files
+            .insert("a.rs".to_string(), cached("func a();"));
+        cache.dirty = true;
+        cache.save().unwrap();
+
+        assert!(RepoMapCache::load(cache_dir.path(), root.path())
+            .files()
+            .is_empty());
+    }
+}
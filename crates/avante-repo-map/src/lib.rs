@@ -1,17 +1,71 @@
 #![allow(clippy::unnecessary_map_or)]
 
+mod builder;
+mod cache;
+mod rank;
+
+use builder::{RepoMapJob, RepoMapOptions};
 use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use tree_sitter::{Node, Parser, Query, QueryCursor};
 use tree_sitter_language::LanguageFn;
 
+// Where a definition sits in the source. Lines are 1-based and columns 0-based
+// byte offsets, as `nvim_win_set_cursor` takes them; all zero when unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Range {
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Range {
+    fn of(node: &Node) -> Self {
+        Self {
+            start_line: node.start_position().row + 1,
+            start_column: node.start_position().column,
+            end_line: node.end_position().row + 1,
+            end_column: node.end_position().column,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start_line == 0
+    }
+
+    fn contains(&self, other: &Range) -> bool {
+        (self.start_line, self.start_column) <= (other.start_line, other.start_column)
+            && (other.end_line, other.end_column) <= (self.end_line, self.end_column)
+    }
+
+    // Grow to cover `other` as well
+    fn extend(&mut self, other: Range) {
+        if self.is_empty() {
+            *self = other;
+            return;
+        }
+        if (other.start_line, other.start_column) < (self.start_line, self.start_column) {
+            self.start_line = other.start_line;
+            self.start_column = other.start_column;
+        }
+        if (other.end_line, other.end_column) > (self.end_line, self.end_column) {
+            self.end_line = other.end_line;
+            self.end_column = other.end_column;
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Func {
     pub name: String,
     pub params: String,
     pub return_type: String,
     pub accessibility_modifier: Option<String>,
+    pub doc: Option<String>,
+    pub range: Range,
 }
 
 #[derive(Debug, Clone)]
@@ -21,24 +75,65 @@ pub struct Class {
     pub methods: Vec<Func>,
     pub properties: Vec<Variable>,
     pub visibility_modifier: Option<String>,
+    pub doc: Option<String>,
+    pub range: Range,
 }
 
 #[derive(Debug, Clone)]
 pub struct Enum {
     pub name: String,
     pub items: Vec<Variable>,
+    pub range: Range,
 }
 
 #[derive(Debug, Clone)]
 pub struct Union {
     pub name: String,
     pub items: Vec<Variable>,
+    pub range: Range,
 }
 
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
     pub value_type: String,
+    pub doc: Option<String>,
+    pub range: Range,
+}
+
+// How much of a definition's doc comment goes into the stringified map. Lua
+// and the cache spell it the same: "none", "first_sentence" or "full".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocMode {
+    #[default]
+    None,
+    FirstSentence,
+    Full,
+}
+
+impl FromLua for DocMode {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        match String::from_lua(value, lua)?.as_str() {
+            "none" => Ok(Self::None),
+            "first_sentence" => Ok(Self::FirstSentence),
+            "full" => Ok(Self::Full),
+            other => Err(LuaError::FromLuaConversionError {
+                from: "string",
+                to: "DocMode".to_string(),
+                message: Some(format!(
+                    "Expected \"none\", \"first_sentence\" or \"full\", got {other:?}"
+                )),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    pub name: String,
+    pub definitions: Vec<Definition>,
+    pub range: Range,
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +144,7 @@ pub enum Definition {
     Enum(Enum),
     Variable(Variable),
     Union(Union),
-    // TODO: Namespace support
+    Namespace(Namespace),
 }
 
 fn get_ts_language(language: &str) -> Option<LanguageFn> {
@@ -91,6 +186,59 @@ const SWIFT_QUERY: &str = include_str!("../queries/tree-sitter-swift-defs.scm");
 const ELIXIR_QUERY: &str = include_str!("../queries/tree-sitter-elixir-defs.scm");
 const CSHARP_QUERY: &str = include_str!("../queries/tree-sitter-c-sharp-defs.scm");
 
+const C_REFS_QUERY: &str = include_str!("../queries/tree-sitter-c-refs.scm");
+const CPP_REFS_QUERY: &str = include_str!("../queries/tree-sitter-cpp-refs.scm");
+const GO_REFS_QUERY: &str = include_str!("../queries/tree-sitter-go-refs.scm");
+const JAVA_REFS_QUERY: &str = include_str!("../queries/tree-sitter-java-refs.scm");
+const JAVASCRIPT_REFS_QUERY: &str = include_str!("../queries/tree-sitter-javascript-refs.scm");
+const LUA_REFS_QUERY: &str = include_str!("../queries/tree-sitter-lua-refs.scm");
+const PYTHON_REFS_QUERY: &str = include_str!("../queries/tree-sitter-python-refs.scm");
+const PHP_REFS_QUERY: &str = include_str!("../queries/tree-sitter-php-refs.scm");
+const RUST_REFS_QUERY: &str = include_str!("../queries/tree-sitter-rust-refs.scm");
+const ZIG_REFS_QUERY: &str = include_str!("../queries/tree-sitter-zig-refs.scm");
+const TYPESCRIPT_REFS_QUERY: &str = include_str!("../queries/tree-sitter-typescript-refs.scm");
+const RUBY_REFS_QUERY: &str = include_str!("../queries/tree-sitter-ruby-refs.scm");
+const SCALA_REFS_QUERY: &str = include_str!("../queries/tree-sitter-scala-refs.scm");
+const SWIFT_REFS_QUERY: &str = include_str!("../queries/tree-sitter-swift-refs.scm");
+const ELIXIR_REFS_QUERY: &str = include_str!("../queries/tree-sitter-elixir-refs.scm");
+const CSHARP_REFS_QUERY: &str = include_str!("../queries/tree-sitter-c-sharp-refs.scm");
+
+// Every query, for versioning cached definitions and references
+const QUERIES: [&str; 32] = [
+    C_QUERY,
+    CPP_QUERY,
+    GO_QUERY,
+    JAVA_QUERY,
+    JAVASCRIPT_QUERY,
+    LUA_QUERY,
+    PYTHON_QUERY,
+    PHP_QUERY,
+    RUST_QUERY,
+    ZIG_QUERY,
+    TYPESCRIPT_QUERY,
+    RUBY_QUERY,
+    SCALA_QUERY,
+    SWIFT_QUERY,
+    ELIXIR_QUERY,
+    CSHARP_QUERY,
+    C_REFS_QUERY,
+    CPP_REFS_QUERY,
+    GO_REFS_QUERY,
+    JAVA_REFS_QUERY,
+    JAVASCRIPT_REFS_QUERY,
+    LUA_REFS_QUERY,
+    PYTHON_REFS_QUERY,
+    PHP_REFS_QUERY,
+    RUST_REFS_QUERY,
+    ZIG_REFS_QUERY,
+    TYPESCRIPT_REFS_QUERY,
+    RUBY_REFS_QUERY,
+    SCALA_REFS_QUERY,
+    SWIFT_REFS_QUERY,
+    ELIXIR_REFS_QUERY,
+    CSHARP_REFS_QUERY,
+];
+
 fn get_definitions_query(language: &str) -> Result<Query, String> {
     let ts_language = get_ts_language(language);
     if ts_language.is_none() {
@@ -121,6 +269,32 @@ fn get_definitions_query(language: &str) -> Result<Query, String> {
     Ok(query)
 }
 
+fn get_references_query(language: &str) -> Result<Query, String> {
+    let ts_language =
+        get_ts_language(language).ok_or_else(|| format!("Unsupported language: {language}"))?;
+    let contents = match language {
+        "c" => C_REFS_QUERY,
+        "cpp" => CPP_REFS_QUERY,
+        "go" => GO_REFS_QUERY,
+        "java" => JAVA_REFS_QUERY,
+        "javascript" => JAVASCRIPT_REFS_QUERY,
+        "lua" => LUA_REFS_QUERY,
+        "python" => PYTHON_REFS_QUERY,
+        "php" => PHP_REFS_QUERY,
+        "rust" => RUST_REFS_QUERY,
+        "zig" => ZIG_REFS_QUERY,
+        "typescript" => TYPESCRIPT_REFS_QUERY,
+        "ruby" => RUBY_REFS_QUERY,
+        "scala" => SCALA_REFS_QUERY,
+        "swift" => SWIFT_REFS_QUERY,
+        "elixir" => ELIXIR_REFS_QUERY,
+        "csharp" => CSHARP_REFS_QUERY,
+        _ => return Err(format!("Unsupported language: {language}")),
+    };
+    Query::new(&ts_language.into(), contents)
+        .map_err(|e| format!("Failed to parse references query for {language}: {e}"))
+}
+
 fn get_closest_ancestor_name(node: &Node, source: &str) -> String {
     let mut parent = node.parent();
     while let Some(parent_node) = parent {
@@ -158,6 +332,43 @@ fn find_first_ancestor_by_types<'a>(
     None
 }
 
+fn find_ancestor_by_name<'a>(node: &'a Node, source: &[u8], name: &str) -> Option<Node<'a>> {
+    let mut parent = node.parent();
+    while let Some(parent_node) = parent {
+        if parent_node
+            .child_by_field_name("name")
+            .map_or(false, |n| n.utf8_text(source).unwrap_or_default() == name)
+        {
+            return Some(parent_node);
+        }
+        parent = parent_node.parent();
+    }
+    None
+}
+
+// A container (class, enum, union) spans its own declaration once that's found,
+// and until then the members seen so far, e.g. a Rust `impl` of a type declared
+// in another file. Returns the declaration when this is where it was found.
+fn extend_container_range<'a>(
+    range: &mut Range,
+    declared: &mut BTreeSet<String>,
+    name: &str,
+    member: &'a Node,
+    source: &[u8],
+) -> Option<Node<'a>> {
+    if declared.contains(name) {
+        return None;
+    }
+    let container = find_ancestor_by_name(member, source, short_name(name));
+    if let Some(container) = container {
+        *range = Range::of(&container);
+        declared.insert(name.to_string());
+    } else {
+        range.extend(Range::of(member));
+    }
+    container
+}
+
 fn find_descendant_by_type<'a>(node: &'a Node, child_type: &str) -> Option<Node<'a>> {
     let mut cursor = node.walk();
     for i in 0..node.descendant_count() {
@@ -288,6 +499,176 @@ fn ruby_find_parent_module_declaration_name<'a>(
     }
 }
 
+// Nodes a declaration can be wrapped in, with its doc comment before the wrapper
+// (`export function`, `const x = ...`, Go's `type X struct`)
+const DOC_WRAPPER_KINDS: [&str; 7] = [
+    "export_statement",
+    "lexical_declaration",
+    "variable_declaration",
+    "assignment_statement",
+    "type_declaration",
+    "const_declaration",
+    "var_declaration",
+];
+
+fn is_doc_comment(language: &str, comment: &str) -> bool {
+    match language {
+        "rust" => {
+            (comment.starts_with("///") && !comment.starts_with("////"))
+                || comment.starts_with("/**")
+        }
+        "javascript" | "typescript" | "java" => comment.starts_with("/**"),
+        "go" => comment.starts_with("//"),
+        "lua" => comment.starts_with("---"),
+        "csharp" => comment.starts_with("///"),
+        _ => false,
+    }
+}
+
+fn strip_comment_markers(line: &str) -> &str {
+    let line = line.trim();
+    let line = line.strip_suffix("*/").unwrap_or(line);
+    ["///", "/**", "//", "---", "*"]
+        .iter()
+        .find_map(|marker| line.strip_prefix(marker))
+        .unwrap_or(line)
+        .trim()
+}
+
+// Drop C# XML doc tags (`<summary>`, `<param name="x">`), keeping their text.
+// Self-closing references like `<paramref name="x"/>` or `<see cref="Foo"/>`
+// stand for what they name.
+fn strip_xml_tags(text: &str) -> String {
+    let mut res = String::new();
+    let mut tag: Option<String> = None;
+    for c in text.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (Some(inner), '>') => {
+                if let Some(name) = xml_reference_name(inner) {
+                    res.push_str(name);
+                }
+                tag = None;
+            }
+            (Some(inner), _) => inner.push(c),
+            (None, _) => res.push(c),
+        }
+    }
+    res
+}
+
+// What a self-closing XML doc tag refers to: its `name`, `cref` or `langword`
+fn xml_reference_name(tag: &str) -> Option<&str> {
+    let attributes = tag.strip_suffix('/')?;
+    ["name", "cref", "langword"].iter().find_map(|attribute| {
+        let start = attributes.find(&format!(" {attribute}=\""))? + attribute.len() + 3;
+        let end = attributes[start..].find('"')?;
+        Some(&attributes[start..start + end])
+    })
+}
+
+// Trimmed lines, without the blank ones around them. `None` if nothing is left.
+fn normalize_doc<'a>(lines: impl Iterator<Item = &'a str>) -> Option<String> {
+    let lines: Vec<&str> = lines.map(str::trim).collect();
+    let start = lines.iter().position(|line| !line.is_empty())?;
+    let end = lines.iter().rposition(|line| !line.is_empty())?;
+    Some(lines[start..=end].join("\n"))
+}
+
+// The comments right above `node` (no blank line in between), skipping Rust attributes
+fn get_leading_doc_comment(language: &str, node: &Node, source: &[u8]) -> Option<String> {
+    let mut target = *node;
+    while let Some(parent) = target.parent() {
+        if !DOC_WRAPPER_KINDS.contains(&parent.kind()) || target.prev_named_sibling().is_some() {
+            break;
+        }
+        target = parent;
+    }
+
+    let mut comments = Vec::new();
+    let mut next_row = target.start_position().row;
+    let mut sibling = target.prev_sibling();
+    while let Some(sibling_node) = sibling {
+        if sibling_node.kind() == "attribute_item" {
+            next_row = sibling_node.start_position().row;
+        } else if sibling_node.kind().contains("comment")
+            && sibling_node.end_position().row + 1 >= next_row
+        {
+            let text = get_node_text(&sibling_node, source);
+            if !is_doc_comment(language, &text) {
+                break;
+            }
+            comments.push(text);
+            next_row = sibling_node.start_position().row;
+        } else {
+            break;
+        }
+        sibling = sibling_node.prev_sibling();
+    }
+    comments.reverse();
+
+    let text = comments.join("\n");
+    let doc = normalize_doc(text.lines().map(strip_comment_markers))?;
+    if language == "csharp" {
+        return normalize_doc(strip_xml_tags(&doc).lines());
+    }
+    Some(doc)
+}
+
+// The docstring opening the body of a Python function or class
+fn python_get_docstring(node: &Node, source: &[u8]) -> Option<String> {
+    let body = node.child_by_field_name("body")?;
+    let statement = body.named_child(0)?;
+    if statement.kind() != "expression_statement" {
+        return None;
+    }
+    let string = statement.named_child(0).filter(|n| n.kind() == "string")?;
+    let content = find_child_by_type(&string, "string_content")?;
+    normalize_doc(get_node_text(&content, source).lines())
+}
+
+// The `@doc` (or `@moduledoc`) attribute of an Elixir `def` or `defmodule` call
+fn ex_get_doc_attribute(node: &Node, source: &[u8]) -> Option<String> {
+    let mut call = Some(*node);
+    while let Some(call_node) = call {
+        if call_node.kind() == "call" && get_node_text(&call_node, source).starts_with("def") {
+            break;
+        }
+        call = call_node.parent();
+    }
+    let call = call?;
+    let doc = if get_node_text(&call, source).starts_with("defmodule ") {
+        let block = find_child_by_type(&call, "do_block")?;
+        block
+            .named_children(&mut block.walk())
+            .find(|child| get_node_text(child, source).starts_with("@moduledoc"))
+    } else {
+        // Skip the other attributes (`@spec`, `@impl`) between `@doc` and the function
+        let mut sibling = call.prev_named_sibling();
+        while let Some(sibling_node) = sibling {
+            if sibling_node.kind() != "unary_operator" {
+                sibling = None;
+                break;
+            }
+            if get_node_text(&sibling_node, source).starts_with("@doc ") {
+                break;
+            }
+            sibling = sibling_node.prev_named_sibling();
+        }
+        sibling
+    }?;
+    let content = find_descendant_by_type(&doc, "quoted_content")?;
+    normalize_doc(get_node_text(&content, source).lines())
+}
+
+fn get_doc_comment(language: &str, node: &Node, source: &[u8]) -> Option<String> {
+    match language {
+        "python" => python_get_docstring(node, source),
+        "elixir" => ex_get_doc_attribute(node, source),
+        _ => get_leading_doc_comment(language, node, source),
+    }
+}
+
 fn get_node_text<'a>(node: &'a Node, source: &'a [u8]) -> String {
     node.utf8_text(source).unwrap_or_default().to_string()
 }
@@ -351,6 +732,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     methods: vec![],
                     properties: vec![],
                     visibility_modifier: None,
+                    doc: None,
+                    range: Range::default(),
                 })
             });
         };
@@ -363,6 +746,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                 methods: vec![],
                 properties: vec![],
                 visibility_modifier: None,
+                doc: None,
+                range: Range::default(),
             })
         });
     };
@@ -372,6 +757,7 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
             RefCell::new(Enum {
                 name: name.to_string(),
                 items: vec![],
+                range: Range::default(),
             })
         });
     };
@@ -381,6 +767,7 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
             RefCell::new(Union {
                 name: name.to_string(),
                 items: vec![],
+                range: Range::default(),
             })
         });
     };
@@ -388,6 +775,10 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
     // Sometimes, multiple queries capture the same node with the same capture name.
     // We need to ensure that we only add the node to the definition map once.
     let mut captured_nodes: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    // Containers whose own declaration was found, so members don't widen their range
+    let mut declared_classes = BTreeSet::new();
+    let mut declared_enums = BTreeSet::new();
+    let mut declared_unions = BTreeSet::new();
 
     for (m, _) in captures {
         for capture in m.captures {
@@ -500,11 +891,20 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                             } else {
                                 Some(visibility_modifier.to_string())
                             };
+                        let declaration = get_declaration_node(language, node);
+                        class_def.borrow_mut().range = Range::of(&declaration);
+                        class_def.borrow_mut().doc =
+                            get_doc_comment(language, &declaration, source.as_bytes());
+                        declared_classes.insert(name);
                     }
                 }
                 "module" => {
                     if !name.is_empty() {
                         ensure_module_def(&name, &mut class_def_map);
+                        let mut module_def = class_def_map.get_mut(&name).unwrap().borrow_mut();
+                        module_def.range = Range::of(&node);
+                        module_def.doc = get_doc_comment(language, &node, source.as_bytes());
+                        declared_classes.insert(name);
                     }
                 }
                 "enum_item" => {
@@ -550,8 +950,17 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: name.to_string(),
                         value_type: enum_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     enum_def.borrow_mut().items.push(variable);
+                    extend_container_range(
+                        &mut enum_def.borrow_mut().range,
+                        &mut declared_enums,
+                        &enum_name,
+                        &node,
+                        source.as_bytes(),
+                    );
                 }
                 "union_item" => {
                     if language != "zig" {
@@ -572,8 +981,17 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: name.to_string(),
                         value_type: union_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     union_def.borrow_mut().items.push(variable);
+                    extend_container_range(
+                        &mut union_def.borrow_mut().range,
+                        &mut declared_unions,
+                        &union_name,
+                        &node,
+                        source.as_bytes(),
+                    );
                 }
                 "method" => {
                     // TODO: C++: Skip private/protected class/struct methods
@@ -772,8 +1190,21 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                         } else {
                             Some(accessibility_modifier.to_string())
                         },
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     class_def.borrow_mut().methods.push(func);
+                    let container = extend_container_range(
+                        &mut class_def.borrow_mut().range,
+                        &mut declared_classes,
+                        &class_name,
+                        &node,
+                        source.as_bytes(),
+                    );
+                    if let Some(container) = container {
+                        class_def.borrow_mut().doc =
+                            get_doc_comment(language, &container, source.as_bytes());
+                    }
                 }
                 "class_assignment" => {
                     let visibility_modifier_node =
@@ -824,8 +1255,21 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: left.to_string(),
                         value_type: value_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     class_def.borrow_mut().properties.push(variable);
+                    let container = extend_container_range(
+                        &mut class_def.borrow_mut().range,
+                        &mut declared_classes,
+                        &class_name,
+                        &node,
+                        source.as_bytes(),
+                    );
+                    if let Some(container) = container {
+                        class_def.borrow_mut().doc =
+                            get_doc_comment(language, &container, source.as_bytes());
+                    }
                 }
                 "class_variable" => {
                     // TODO: C++: Skip private/protected class/struct variables
@@ -910,8 +1354,21 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: name.to_string(),
                         value_type: value_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     class_def.borrow_mut().properties.push(variable);
+                    let container = extend_container_range(
+                        &mut class_def.borrow_mut().range,
+                        &mut declared_classes,
+                        &class_name,
+                        &node,
+                        source.as_bytes(),
+                    );
+                    if let Some(container) = container {
+                        class_def.borrow_mut().doc =
+                            get_doc_comment(language, &container, source.as_bytes());
+                    }
                 }
                 "function" | "arrow_function" => {
                     let visibility_modifier_node =
@@ -1013,6 +1470,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                         } else {
                             Some(accessibility_modifier.to_string())
                         },
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     definitions.push(Definition::Func(func));
                 }
@@ -1063,6 +1522,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: left.to_string(),
                         value_type: value_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     definitions.push(Definition::Variable(variable));
                 }
@@ -1131,6 +1592,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                                 params: params.to_string(),
                                 return_type,
                                 accessibility_modifier: None,
+                                doc: get_doc_comment(language, &node, source.as_bytes()),
+                                range: Range::of(&node),
                             };
                             definitions.push(Definition::Func(func));
                             continue;
@@ -1151,6 +1614,8 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
                     let variable = Variable {
                         name: name.to_string(),
                         value_type: value_type.to_string(),
+                        doc: get_doc_comment(language, &node, source.as_bytes()),
+                        range: Range::of(&node),
                     };
                     definitions.push(Definition::Variable(variable));
                 }
@@ -1179,10 +1644,278 @@ fn extract_definitions(language: &str, source: &str) -> Result<Vec<Definition>,
         definitions.push(Definition::Union(def.into_inner()));
     }
 
-    Ok(definitions)
+    let namespaces = find_namespaces(language, &root_node, source.as_bytes());
+    Ok(nest_definitions(definitions, &namespaces))
+}
+
+// A scope other definitions are nested in, as found in the syntax tree
+#[derive(Debug)]
+struct NamespaceScope {
+    name: String,
+    range: Range,
+}
+
+fn ruby_module_has_nested_types(node: &Node) -> bool {
+    node.children(&mut node.walk())
+        .filter(|child| child.kind() == "body_statement")
+        .any(|body| {
+            body.children(&mut body.walk())
+                .any(|child| child.kind() == "class" || child.kind() == "module")
+        })
+}
+
+fn ex_is_module_declaration(node: &Node, source: &[u8]) -> bool {
+    node.kind() == "call" && get_node_text(node, source).starts_with("defmodule ")
+}
+
+fn ex_module_has_nested_modules(node: &Node, source: &[u8]) -> bool {
+    find_child_by_type(node, "do_block").map_or(false, |block| {
+        block
+            .children(&mut block.walk())
+            .any(|child| ex_is_module_declaration(&child, source))
+    })
+}
+
+// The name of the namespace `node` opens, if it opens one. Ruby and Elixir
+// modules only count as namespaces when they nest other modules or classes.
+fn get_namespace_name(language: &str, node: &Node, source: &[u8]) -> Option<String> {
+    let is_namespace = match language {
+        "cpp" | "php" => node.kind() == "namespace_definition",
+        "csharp" => {
+            node.kind() == "namespace_declaration"
+                || node.kind() == "file_scoped_namespace_declaration"
+        }
+        "rust" => node.kind() == "mod_item" && node.child_by_field_name("body").is_some(),
+        "typescript" => node.kind() == "internal_module" || node.kind() == "module",
+        "ruby" => node.kind() == "module" && ruby_module_has_nested_types(node),
+        "elixir" => {
+            ex_is_module_declaration(node, source) && ex_module_has_nested_modules(node, source)
+        }
+        _ => false,
+    };
+    if !is_namespace {
+        return None;
+    }
+    let name_node = if language == "elixir" {
+        find_child_by_type(node, "arguments")
+    } else {
+        node.child_by_field_name("name")
+    };
+    // Anonymous namespaces don't qualify anything, their contents stay where they are
+    name_node
+        .map(|n| get_node_text(&n, source))
+        .filter(|name| !name.is_empty())
+}
+
+// Where a namespace's definitions can be. C# file-scoped namespaces and PHP
+// namespaces without braces run until the next namespace or the end of the file.
+fn get_namespace_range(node: &Node) -> Range {
+    let mut range = Range::of(node);
+    let is_open = node.kind() == "file_scoped_namespace_declaration"
+        || (node.kind() == "namespace_definition" && node.child_by_field_name("body").is_none());
+    if !is_open {
+        return range;
+    }
+    let mut end = node.parent();
+    let mut sibling = node.next_named_sibling();
+    while let Some(sibling_node) = sibling {
+        if sibling_node.kind() == node.kind() {
+            end = None;
+            range.end_line = sibling_node.start_position().row + 1;
+            range.end_column = sibling_node.start_position().column;
+            break;
+        }
+        sibling = sibling_node.next_named_sibling();
+    }
+    if let Some(end) = end {
+        range.end_line = end.end_position().row + 1;
+        range.end_column = end.end_position().column;
+    }
+    range
+}
+
+fn find_namespaces(language: &str, root: &Node, source: &[u8]) -> Vec<NamespaceScope> {
+    let mut namespaces = Vec::new();
+    let mut cursor = root.walk();
+    for i in 0..root.descendant_count() {
+        cursor.goto_descendant(i);
+        let node = cursor.node();
+        if let Some(name) = get_namespace_name(language, &node, source) {
+            namespaces.push(NamespaceScope {
+                name,
+                range: get_namespace_range(&node),
+            });
+        }
+    }
+    namespaces
+}
+
+fn get_definition_range(definition: &Definition) -> Range {
+    match definition {
+        Definition::Func(func) => func.range,
+        Definition::Class(class) | Definition::Module(class) => class.range,
+        Definition::Enum(enum_def) => enum_def.range,
+        Definition::Union(union_def) => union_def.range,
+        Definition::Variable(variable) => variable.range,
+        Definition::Namespace(namespace) => namespace.range,
+    }
+}
+
+// Move definitions into the namespaces (pre-order, outermost first) they are
+// declared in, each namespace taking the place of its first member. Names
+// qualified by the namespace, like Ruby's `A::B::C`, lose the qualifier that the
+// nesting now shows.
+fn nest_definitions(
+    definitions: Vec<Definition>,
+    namespaces: &[NamespaceScope],
+) -> Vec<Definition> {
+    // Definitions with their position, to put the namespaces back in source order
+    let mut remaining: Vec<(usize, Definition)> = definitions.into_iter().enumerate().collect();
+    let mut nested = Vec::new();
+    let mut index = 0;
+    while index < namespaces.len() {
+        let scope = &namespaces[index];
+        let inner_count = namespaces[index + 1..]
+            .iter()
+            .take_while(|inner| scope.range.contains(&inner.range))
+            .count();
+        let inner = &namespaces[index + 1..=index + inner_count];
+        index += inner_count + 1;
+
+        let (inside, outside): (Vec<_>, Vec<_>) = remaining.into_iter().partition(|(_, def)| {
+            let range = get_definition_range(def);
+            !range.is_empty() && scope.range.contains(&range)
+        });
+        remaining = outside;
+        let Some(&(position, _)) = inside.first() else {
+            continue;
+        };
+        let mut inside: Vec<Definition> = inside.into_iter().map(|(_, def)| def).collect();
+
+        // A Ruby or Elixir module that is itself the namespace gives its members to it
+        if let Some(position) = inside.iter().position(|def| {
+            matches!(def, Definition::Class(class) if class.type_name == "module" && class.range == scope.range)
+        }) {
+            if let Definition::Class(module) = inside.remove(position) {
+                let members = module
+                    .methods
+                    .into_iter()
+                    .map(Definition::Func)
+                    .chain(module.properties.into_iter().map(Definition::Variable));
+                inside.splice(0..0, members);
+            }
+        }
+        for def in &mut inside {
+            if let Definition::Class(class) = def {
+                if let Some(name) = class.name.strip_prefix(&format!("{}::", scope.name)) {
+                    class.name = name.to_string();
+                }
+            }
+        }
+
+        let definitions = nest_definitions(inside, inner);
+        if !definitions.is_empty() {
+            nested.push((
+                position,
+                Definition::Namespace(Namespace {
+                    name: scope.name.clone(),
+                    definitions,
+                    range: scope.range,
+                }),
+            ));
+        }
+    }
+    remaining.extend(nested);
+    remaining.sort_by_key(|(position, _)| *position);
+    remaining.into_iter().map(|(_, def)| def).collect()
+}
+
+// Some queries capture the name of a class rather than its declaration
+fn get_declaration_node<'a>(language: &str, node: Node<'a>) -> Node<'a> {
+    if language == "elixir" {
+        let mut parent = node.parent();
+        while let Some(parent_node) = parent {
+            if parent_node.kind() == "call" {
+                return parent_node;
+            }
+            parent = parent_node.parent();
+        }
+        return node;
+    }
+    node.parent()
+        .filter(|parent| parent.child_by_field_name("name") == Some(node))
+        .unwrap_or(node)
+}
+
+// Last segment of a qualified name (`Foo::bar`, `MyApp.Accounts`), as it is
+// usually written where it's used
+fn short_name(name: &str) -> &str {
+    name.rsplit([':', '.']).next().unwrap_or(name)
+}
+
+// Given a language, parse the given source code and count the identifiers it references
+fn extract_references(language: &str, source: &str) -> Result<BTreeMap<String, u32>, String> {
+    let Some(ts_language) = get_ts_language(language) else {
+        return Ok(BTreeMap::new());
+    };
+    let mut parser = Parser::new();
+    parser
+        .set_language(&ts_language.into())
+        .map_err(|e| format!("Failed to set language for {language}: {e}"))?;
+    let tree = parser
+        .parse(source, None)
+        .ok_or_else(|| format!("Failed to parse source code for {language}"))?;
+
+    let query = get_references_query(language)?;
+    let mut query_cursor = QueryCursor::new();
+    let captures = query_cursor.captures(&query, tree.root_node(), source.as_bytes());
+
+    // Helper captures only narrow a pattern down, and a node two patterns match
+    // is still one reference
+    let mut seen = HashSet::new();
+    let mut references = BTreeMap::new();
+    for (m, _) in captures {
+        for capture in m.captures {
+            if query.capture_names()[capture.index as usize] != "reference"
+                || !seen.insert(capture.node.id())
+            {
+                continue;
+            }
+            let text = capture
+                .node
+                .utf8_text(source.as_bytes())
+                .unwrap_or_default();
+            let name = short_name(text);
+            if !name.is_empty() {
+                *references.entry(name.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+    Ok(references)
+}
+
+// The doc comment of a definition as it goes in front of it, per `docs`
+fn stringify_doc(doc: Option<&String>, docs: DocMode) -> String {
+    let Some(doc) = doc else {
+        return String::new();
+    };
+    let text = match docs {
+        DocMode::None => return String::new(),
+        // Up to the end of the first sentence of the first paragraph
+        DocMode::FirstSentence => {
+            let paragraph = doc.split("\n\n").next().unwrap_or(doc);
+            let text = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+            match text.find(". ") {
+                Some(end) => text[..=end].to_string(),
+                None => text,
+            }
+        }
+        DocMode::Full => doc.split_whitespace().collect::<Vec<_>>().join(" "),
+    };
+    format!("/** {} */", text.replace("*/", "* /"))
 }
 
-fn stringify_function(func: &Func) -> String {
+fn stringify_function(func: &Func, docs: DocMode) -> String {
     let mut res = format!("func {}", func.name);
     if func.params.is_empty() {
         res = format!("{res}()");
@@ -1195,15 +1928,15 @@ fn stringify_function(func: &Func) -> String {
     if let Some(modifier) = &func.accessibility_modifier {
         res = format!("{modifier} {res}");
     }
-    format!("{res};")
+    format!("{}{res};", stringify_doc(func.doc.as_ref(), docs))
 }
 
-fn stringify_variable(variable: &Variable) -> String {
+fn stringify_variable(variable: &Variable, docs: DocMode) -> String {
     let mut res = format!("var {}", variable.name);
     if !variable.value_type.is_empty() {
         res = format!("{res}:{}", variable.value_type);
     }
-    format!("{res};")
+    format!("{}{res};", stringify_doc(variable.doc.as_ref(), docs))
 }
 
 fn stringify_enum_item(item: &Variable) -> String {
@@ -1222,14 +1955,19 @@ fn stringify_union_item(item: &Variable) -> String {
     format!("{res};")
 }
 
-fn stringify_class(class: &Class) -> String {
-    let mut res = format!("{} {}{{", class.type_name, class.name);
+fn stringify_class(class: &Class, docs: DocMode) -> String {
+    let mut res = format!(
+        "{}{} {}{{",
+        stringify_doc(class.doc.as_ref(), docs),
+        class.type_name,
+        class.name
+    );
     for method in &class.methods {
-        let method_str = stringify_function(method);
+        let method_str = stringify_function(method, docs);
         res = format!("{res}{method_str}");
     }
     for property in &class.properties {
-        let property_str = stringify_variable(property);
+        let property_str = stringify_variable(property, docs);
         res = format!("{res}{property_str}");
     }
     format!("{res}}};")
@@ -1252,38 +1990,210 @@ fn stringify_union(union_def: &Union) -> String {
     format!("{res}}};")
 }
 
-fn stringify_definitions(definitions: &Vec<Definition>) -> String {
+fn stringify_namespace(namespace: &Namespace, docs: DocMode) -> String {
+    let mut res = format!("namespace {}{{", namespace.name);
+    for definition in &namespace.definitions {
+        res = format!("{res}{}", stringify_definition(definition, docs));
+    }
+    format!("{res}}};")
+}
+
+fn stringify_definition(definition: &Definition, docs: DocMode) -> String {
+    match definition {
+        Definition::Namespace(namespace) => stringify_namespace(namespace, docs),
+        Definition::Class(class) => stringify_class(class, docs),
+        Definition::Module(module) => stringify_class(module, docs),
+        Definition::Enum(enum_def) => stringify_enum(enum_def),
+        Definition::Union(union_def) => stringify_union(union_def),
+        Definition::Func(func) => stringify_function(func, docs),
+        Definition::Variable(variable) => stringify_variable(variable, docs),
+    }
+}
+
+fn stringify_definitions(definitions: &Vec<Definition>, docs: DocMode) -> String {
     let mut res = String::new();
     for definition in definitions {
-        match definition {
-            Definition::Class(class) => res = format!("{res}{}", stringify_class(class)),
-            Definition::Module(module) => res = format!("{res}{}", stringify_class(module)),
-            Definition::Enum(enum_def) => res = format!("{res}{}", stringify_enum(enum_def)),
-            Definition::Union(union_def) => res = format!("{res}{}", stringify_union(union_def)),
-            Definition::Func(func) => res = format!("{res}{}", stringify_function(func)),
-            Definition::Variable(variable) => {
-                let variable_str = stringify_variable(variable);
-                res = format!("{res}{variable_str}");
-            }
-        }
+        res = format!("{res}{}", stringify_definition(definition, docs));
     }
     res
 }
 
-pub fn get_definitions_string(language: &str, source: &str) -> LuaResult<String> {
+pub fn get_definitions_string(language: &str, source: &str, docs: DocMode) -> LuaResult<String> {
     let definitions =
         extract_definitions(language, source).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
-    let stringified = stringify_definitions(&definitions);
+    let stringified = stringify_definitions(&definitions, docs);
     Ok(stringified)
 }
 
+// The fields every definition table shares: kind, name, visibility, parent and range
+fn lua_node(
+    lua: &Lua,
+    kind: &str,
+    name: &str,
+    visibility: Option<&String>,
+    parent: Option<&str>,
+    range: Range,
+) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+    table.set("kind", kind)?;
+    table.set("name", name)?;
+    table.set("visibility", visibility.map(String::as_str))?;
+    table.set("parent", parent)?;
+    table.set("start_line", range.start_line)?;
+    table.set("start_column", range.start_column)?;
+    table.set("end_line", range.end_line)?;
+    table.set("end_column", range.end_column)?;
+    Ok(table)
+}
+
+fn lua_func(lua: &Lua, func: &Func, parent: Option<&str>) -> LuaResult<LuaTable> {
+    let table = lua_node(
+        lua,
+        "func",
+        &func.name,
+        func.accessibility_modifier.as_ref(),
+        parent,
+        func.range,
+    )?;
+    table.set("params", func.params.as_str())?;
+    table.set("return_type", func.return_type.as_str())?;
+    table.set("doc", func.doc.as_deref())?;
+    Ok(table)
+}
+
+fn lua_variable(lua: &Lua, variable: &Variable, parent: Option<&str>) -> LuaResult<LuaTable> {
+    let table = lua_node(
+        lua,
+        "variable",
+        &variable.name,
+        None,
+        parent,
+        variable.range,
+    )?;
+    table.set("value_type", variable.value_type.as_str())?;
+    table.set("doc", variable.doc.as_deref())?;
+    Ok(table)
+}
+
+fn lua_items(lua: &Lua, items: &[Variable], parent: &str) -> LuaResult<LuaTable> {
+    lua.create_sequence_from(
+        items
+            .iter()
+            .map(|item| lua_variable(lua, item, Some(parent)))
+            .collect::<LuaResult<Vec<_>>>()?,
+    )
+}
+
+fn lua_definition(lua: &Lua, definition: &Definition, parent: Option<&str>) -> LuaResult<LuaTable> {
+    match definition {
+        Definition::Func(func) => lua_func(lua, func, parent),
+        Definition::Variable(variable) => lua_variable(lua, variable, parent),
+        Definition::Class(class) | Definition::Module(class) => {
+            let table = lua_node(
+                lua,
+                &class.type_name,
+                &class.name,
+                class.visibility_modifier.as_ref(),
+                parent,
+                class.range,
+            )?;
+            let methods = class
+                .methods
+                .iter()
+                .map(|method| lua_func(lua, method, Some(class.name.as_str())))
+                .collect::<LuaResult<Vec<_>>>()?;
+            table.set("doc", class.doc.as_deref())?;
+            table.set("methods", lua.create_sequence_from(methods)?)?;
+            table.set(
+                "properties",
+                lua_items(lua, &class.properties, &class.name)?,
+            )?;
+            Ok(table)
+        }
+        Definition::Enum(enum_def) => {
+            let table = lua_node(lua, "enum", &enum_def.name, None, parent, enum_def.range)?;
+            table.set("items", lua_items(lua, &enum_def.items, &enum_def.name)?)?;
+            Ok(table)
+        }
+        Definition::Union(union_def) => {
+            let table = lua_node(lua, "union", &union_def.name, None, parent, union_def.range)?;
+            table.set("items", lua_items(lua, &union_def.items, &union_def.name)?)?;
+            Ok(table)
+        }
+        Definition::Namespace(namespace) => {
+            let table = lua_node(
+                lua,
+                "namespace",
+                &namespace.name,
+                None,
+                parent,
+                namespace.range,
+            )?;
+            let definitions = namespace
+                .definitions
+                .iter()
+                .map(|definition| lua_definition(lua, definition, Some(namespace.name.as_str())))
+                .collect::<LuaResult<Vec<_>>>()?;
+            table.set("definitions", lua.create_sequence_from(definitions)?)?;
+            Ok(table)
+        }
+    }
+}
+
 #[mlua::lua_module]
 fn avante_repo_map(lua: &Lua) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
     exports.set(
         "stringify_definitions",
-        lua.create_function(move |_, (language, source): (String, String)| {
-            get_definitions_string(language.as_str(), source.as_str())
+        lua.create_function(
+            move |_, (language, source, docs): (String, String, Option<DocMode>)| {
+                get_definitions_string(language.as_str(), source.as_str(), docs.unwrap_or_default())
+            },
+        )?,
+    )?;
+    exports.set(
+        "extract_definitions",
+        lua.create_function(|lua, (language, source): (String, String)| {
+            let definitions =
+                extract_definitions(&language, &source).map_err(LuaError::RuntimeError)?;
+            let tables = definitions
+                .iter()
+                .map(|definition| lua_definition(lua, definition, None))
+                .collect::<LuaResult<Vec<_>>>()?;
+            lua.create_sequence_from(tables)
+        })?,
+    )?;
+    exports.set(
+        "build_repo_map",
+        lua.create_function(|lua, (root, options): (String, LuaValue)| {
+            let background = match &options {
+                LuaValue::Table(table) => table.get::<Option<bool>>("background")?.unwrap_or(false),
+                _ => false,
+            };
+            let options = RepoMapOptions::from_lua(options, lua)?;
+            if background {
+                return lua
+                    .create_userdata(RepoMapJob::spawn(root.into(), options))
+                    .map(LuaValue::UserData);
+            }
+            let entries = builder::build_repo_map(
+                std::path::Path::new(&root),
+                &options,
+                &builder::Progress::default(),
+                &std::sync::atomic::AtomicBool::new(false),
+            )
+            .map_err(LuaError::RuntimeError)?;
+            lua.to_value(&entries)
+        })?,
+    )?;
+    exports.set(
+        "invalidate",
+        lua.create_function(|_, paths: Vec<String>| {
+            let paths = paths
+                .iter()
+                .map(|path| std::path::absolute(path).map_err(LuaError::external))
+                .collect::<LuaResult<Vec<_>>>()?;
+            Ok(cache::invalidate(&paths))
         })?,
     )?;
     Ok(exports)
@@ -1345,7 +2255,7 @@ mod tests {
         }
         "#;
         let definitions = extract_definitions("rust", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var TEST_CONST:u32;var TEST_STATIC:u32;func test_fn(a: u32, b: u32) -> u32;class TestStruct{func test_method(&self, a: u32, b: u32) -> u32;var test_field:String;};";
         assert_eq!(stringified, expected);
@@ -1416,7 +2326,7 @@ mod tests {
         "#;
 
         let definitions = extract_definitions("zig", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var TEST_CONST:u32;var TEST_VAR:u32;func test_fn() -> void;class TestStruct{func test_method(_: *TestStruct, a: u32, b: u32) -> void;var test_field:[]const u8;var test_field2:u64;};enum TestEnum{TestEnumField1;TestEnumField2;};union TestUnion{TestUnionField1;TestUnionField2;};";
         assert_eq!(stringified, expected);
@@ -1463,7 +2373,7 @@ mod tests {
         }
         "#;
         let definitions = extract_definitions("go", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var TestConst:string;var TestVar:string;func TestFunc(a int, b int) -> (int, error);class TestStruct{func TestMethod(a int, b int) -> (int, error);var TestField:string;};";
         assert_eq!(stringified, expected);
@@ -1494,7 +2404,7 @@ mod tests {
             return a + b
         "#;
         let definitions = extract_definitions("python", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var test_var:str;func test_func(a: int, b: int) -> int;class TestClass{func __init__(self, a, b) -> void;func test_method(self, a: int, b: int) -> int;};";
         assert_eq!(stringified, expected);
@@ -1543,7 +2453,7 @@ mod tests {
         }
         "#;
         let definitions = extract_definitions("typescript", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var testVar:string;func testFunc(a: number, b: number) -> void;func testFunc2(a: number, b: number) -> void;func testFunc3(a: number, b: number) -> number;class TestClass{func constructor(a: number, b: number) -> void;func testMethod(a: number, b: number) -> number;var a:number;var b:number;};"
 ;
@@ -1591,7 +2501,7 @@ mod tests {
         }
         "#;
         let definitions = extract_definitions("javascript", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var testVar;var testFunc;func testFunc2(a, b) -> void;func testFunc3(a, b) -> void;class TestClass{func constructor(a, b) -> void;func testMethod(a, b) -> void;};";
         assert_eq!(stringified, expected);
@@ -1632,7 +2542,7 @@ mod tests {
         end
         "#;
         let definitions = extract_definitions("ruby", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         // FIXME:
         let expected = "var test_var;func test_func(a, b) -> void;class InnerClassInFunc{func initialize(a, b) -> void;func test_method(a, b) -> void;};class TestClass{func initialize(a, b) -> void;func test_method(a, b) -> void;};";
@@ -1688,9 +2598,9 @@ mod tests {
         end
         "#;
         let definitions = extract_definitions("ruby", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
-        let expected = "var top_level_var;func top_level_func() -> void;module A{};module A::B{func module_method() -> void;var @module_var;};class A::B::C{func initialize(a, b) -> void;func bar() -> void;private func baz(request, params) -> void;var TEST_CONST;var @class_var;};";
+        let expected = "var top_level_var;func top_level_func() -> void;namespace A{namespace B{func module_method() -> void;var @module_var;class C{func initialize(a, b) -> void;func bar() -> void;private func baz(request, params) -> void;var TEST_CONST;var @class_var;};};};";
         assert_eq!(stringified, expected);
     }
 
@@ -1708,7 +2618,7 @@ mod tests {
         end
         "#;
         let definitions = extract_definitions("lua", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var test_var;func test_func(a, b) -> void;";
         assert_eq!(stringified, expected);
@@ -1774,9 +2684,9 @@ mod tests {
         enum TestEnum { ENUM_VALUE_1, ENUM_VALUE_2 };
         "#;
         let definitions = extract_definitions("cpp", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{}", stringified);
-        let expected = "var TEST_CONSTEXPR:int;var TEST_CONST:int;var test_var:int;func TestFunc(bool b) -> int;func TestStruct::operator==(const TestStruct &other) -> bool;var TestStruct::c:int;func testFunction(int a, int b) -> int;func InnerClass::innerMethod(int a) -> bool;class InnerClass{func innerMethod(int a) -> bool;};class TestClass{func TestClass() -> TestClass;func operator==(const TestClass &other) -> bool;func testMethod(T x, T y) -> T;func privateMethod() -> void;func TestClass(T a, T b) -> TestClass;var c:T;var a:T;var b:T;};class TestStruct{func TestStruct(int a, int b) -> void;func operator==(const TestStruct &other) -> bool;func testMethod(int x, int y) -> int;var c:int;var a:int;var b:int;};enum TestEnum{ENUM_VALUE_1;ENUM_VALUE_2;};";
+        let expected = "var TEST_CONSTEXPR:int;var TEST_CONST:int;var test_var:int;func TestFunc(bool b) -> int;func TestStruct::operator==(const TestStruct &other) -> bool;var TestStruct::c:int;func testFunction(int a, int b) -> int;namespace TestNamespace{func InnerClass::innerMethod(int a) -> bool;class InnerClass{func innerMethod(int a) -> bool;};};class TestClass{func TestClass() -> TestClass;func operator==(const TestClass &other) -> bool;func testMethod(T x, T y) -> T;func privateMethod() -> void;func TestClass(T a, T b) -> TestClass;var c:T;var a:T;var b:T;};class TestStruct{func TestStruct(int a, int b) -> void;func operator==(const TestStruct &other) -> bool;func testMethod(int x, int y) -> int;var c:int;var a:int;var b:int;};enum TestEnum{ENUM_VALUE_1;ENUM_VALUE_2;};";
         assert_eq!(stringified, expected);
     }
 
@@ -1813,7 +2723,7 @@ mod tests {
         "#;
 
         let definitions = extract_definitions("scala", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var foo:TestClass;class Main{func main(args: Array[String]) -> Unit;};class TestCaseClass{};class TestClass{func testMethod(a: Int, b: Int) -> Int;var testVal:String;var testVar;};class TestTrait{func abstractMethod(x: Int) -> Int;func concreteMethod(y: Int) -> Int;};enum TestEnum{First;Second;Third;};";
         assert_eq!(stringified, expected);
@@ -1852,7 +2762,7 @@ mod tests {
         end
         "#;
         let definitions = extract_definitions("elixir", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected =
             "module AnotherModule{func another_func();};module TestModule{func test_func(a, b);};";
@@ -1907,9 +2817,9 @@ mod tests {
       "#;
 
         let definitions = extract_definitions("csharp", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
-        let expected = "class MyInnerClass{func MyInnerClass(InnerClassDependency m) -> MyInnerClass;};class MyInnerRecord{func MyInnerRecord(int a) -> MyInnerRecord;};class TestClass{func TestClass(TestDependency m) -> TestClass;func TestClass() -> TestClass;func TestMethod(int a, int b) -> void;func TestMethod(int a, int b, int c) -> int;var TestProperty:int;var TestField:string;};class TestRecord{func TestRecord(int a, int b) -> TestRecord;};enum TestEnum{Value1;Value2;};";
+        let expected = "namespace TestNamespace{class MyInnerClass{func MyInnerClass(InnerClassDependency m) -> MyInnerClass;};class MyInnerRecord{func MyInnerRecord(int a) -> MyInnerRecord;};class TestClass{func TestClass(TestDependency m) -> TestClass;func TestClass() -> TestClass;func TestMethod(int a, int b) -> void;func TestMethod(int a, int b, int c) -> int;var TestProperty:int;var TestField:string;};class TestRecord{func TestRecord(int a, int b) -> TestRecord;};enum TestEnum{Value1;Value2;};};";
         assert_eq!(stringified, expected);
     }
 
@@ -1952,7 +2862,7 @@ mod tests {
         "#;
 
         let definitions = extract_definitions("swift", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "var myPublicVariable;class MyClass{func init() -> void;func myPublicMethod() -> void;func myMethod() -> void;var myPublicVariable;};class MyStruct{func myPublicMethod() -> void;var myPublicVariable;};";
         assert_eq!(stringified, expected);
@@ -1980,7 +2890,7 @@ mod tests {
         "#;
 
         let definitions = extract_definitions("php", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "class MyClass{func myPublicMethod($parameter) -> void;func myPrivateMethod($parameter) -> void;func myMethod() -> void;var public $myPublicVariable = 0;;var private $myPrivateVariable = 0;;};";
         assert_eq!(stringified, expected);
@@ -2005,7 +2915,7 @@ mod tests {
         "#;
 
         let definitions = extract_definitions("java", source).unwrap();
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected =
             "class MyClass{func myPublicMethod(String parameter) -> void;func myMethod() -> void;};";
@@ -2017,9 +2927,365 @@ mod tests {
         let source = "print('Hello, world!')";
         let definitions = extract_definitions("unknown", source).unwrap();
 
-        let stringified = stringify_definitions(&definitions);
+        let stringified = stringify_definitions(&definitions, DocMode::None);
         println!("{stringified}");
         let expected = "";
         assert_eq!(stringified, expected);
     }
+
+    #[test]
+    fn test_extract_references() {
+        let source = r#"
+        use crate::config::load_config;
+
+        fn main() {
+            let config = load_config();
+            let server = Server::new(config.port);
+            server.run();
+        }
+        "#;
+
+        let references = extract_references("rust", source).unwrap();
+        assert_eq!(references.get("load_config"), Some(&2));
+        assert_eq!(references.get("Server"), Some(&1));
+        assert_eq!(references.get("port"), Some(&1));
+        assert_eq!(references.get("run"), Some(&1));
+        // Locals and a path's modules aren't references to a definition
+        assert_eq!(references.get("config"), None);
+        assert_eq!(references.get("server"), None);
+
+        let references = extract_references("python", "print(helper(x))").unwrap();
+        assert_eq!(references.keys().collect::<Vec<_>>(), ["helper", "print"]);
+
+        assert!(extract_references("unknown", "whatever")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_definition_ranges() {
+        let source = r"pub fn run() {}
+
+pub struct Config {
+    pub port: u16,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self { port: 0 }
+    }
+}
+";
+
+        let definitions = extract_definitions("rust", source).unwrap();
+        let range = |start_line, start_column, end_line, end_column| Range {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+        };
+        let [Definition::Func(func), Definition::Class(class)] = definitions.as_slice() else {
+            panic!("unexpected definitions {definitions:?}");
+        };
+        assert_eq!(func.range, range(1, 0, 1, 15));
+        // The struct itself, not the span of its `impl`
+        assert_eq!(class.range, range(3, 0, 5, 1));
+        assert_eq!(class.methods[0].range, range(8, 4, 10, 5));
+        assert_eq!(class.properties[0].range, range(4, 4, 4, 17));
+
+        let source = "enum Color {\n  Red,\n  Green,\n};\n";
+        let definitions = extract_definitions("cpp", source).unwrap();
+        let [Definition::Enum(enum_def)] = definitions.as_slice() else {
+            panic!("unexpected definitions {definitions:?}");
+        };
+        assert_eq!(enum_def.range, range(1, 0, 4, 1));
+        assert_eq!(enum_def.items[1].range, range(3, 2, 3, 7));
+
+        // The query captures the class name; the range is still the declaration's
+        let source = "public class Greeter {\n    public void greet() {}\n}\n";
+        let definitions = extract_definitions("java", source).unwrap();
+        let [Definition::Class(class)] = definitions.as_slice() else {
+            panic!("unexpected definitions {definitions:?}");
+        };
+        assert_eq!(class.range, range(1, 0, 3, 1));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let source = r"
+        pub mod outer {
+            pub fn outer_func() {}
+            pub mod inner {
+                pub struct InnerStruct {
+                    pub a: u32,
+                }
+            }
+        }
+        mod private_empty {
+            fn hidden() {}
+        }
+        pub fn top_level() {}
+        ";
+        let definitions = extract_definitions("rust", source).unwrap();
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected = "namespace outer{func outer_func() -> void;namespace inner{class InnerStruct{var a:u32;};};};func top_level() -> void;";
+        assert_eq!(stringified, expected);
+
+        let source = r"
+        export namespace Shapes {
+            export function area(r: number): number {
+                return r * r;
+            }
+            export namespace Polygons {
+                export class Square {
+                    side: number;
+                }
+            }
+        }
+        ";
+        let definitions = extract_definitions("typescript", source).unwrap();
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected = "namespace Shapes{func area(r: number) -> number;namespace Polygons{class Square{var side:number;};};};";
+        assert_eq!(stringified, expected);
+
+        let source = r"
+        namespace a::b {
+        namespace c {
+        int nested(int x) { return x; }
+        }
+        int flat(int x) { return x; }
+        }
+        ";
+        let definitions = extract_definitions("cpp", source).unwrap();
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected =
+            "namespace a::b{namespace c{func nested(int x) -> int;};func flat(int x) -> int;};";
+        assert_eq!(stringified, expected);
+
+        let source = r"
+        defmodule Outer do
+          def outer_func(a), do: a
+
+          defmodule Inner do
+            def inner_func(b), do: b
+          end
+        end
+        ";
+        let definitions = extract_definitions("elixir", source).unwrap();
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected = "namespace Outer{func outer_func(a);module Inner{func inner_func(b);};};";
+        assert_eq!(stringified, expected);
+
+        // Namespaces stay where their first member is
+        let source = r"
+        int before(int x) { return x; }
+        namespace middle {
+        int inside(int x) { return x; }
+        }
+        int after(int x) { return x; }
+        ";
+        let definitions = extract_definitions("cpp", source).unwrap();
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected = "func before(int x) -> int;namespace middle{func inside(int x) -> int;};func after(int x) -> int;";
+        assert_eq!(stringified, expected);
+    }
+
+    #[test]
+    fn test_open_namespaces() {
+        let range = |start_line, start_column, end_line, end_column| Range {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
+        };
+
+        // A file-scoped namespace runs to the end of the file
+        let source = r"
+        using System;
+
+        namespace App.Models;
+
+        public class User
+        {
+            public string Name { get; set; }
+        }
+        ";
+        let definitions = extract_definitions("csharp", source).unwrap();
+        let Some(Definition::Namespace(namespace)) = definitions.first() else {
+            panic!("unexpected definitions {definitions:?}");
+        };
+        assert_eq!(namespace.name, "App.Models");
+        assert_eq!(namespace.range.end_line, source.lines().count());
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected = "namespace App.Models{class User{var Name:string;};};";
+        assert_eq!(stringified, expected);
+
+        // One without braces runs until the next namespace
+        let source = r"<?php
+namespace First;
+
+function one() {}
+
+namespace Second;
+
+function two() {}
+";
+        let definitions = extract_definitions("php", source).unwrap();
+        let ranges: Vec<_> = definitions
+            .iter()
+            .map(|definition| match definition {
+                Definition::Namespace(namespace) => (namespace.name.as_str(), namespace.range),
+                _ => panic!("unexpected definitions {definitions:?}"),
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            [("First", range(2, 0, 6, 0)), ("Second", range(6, 0, 9, 0))]
+        );
+        let stringified = stringify_definitions(&definitions, DocMode::None);
+        let expected =
+            "namespace First{func one() -> void;};namespace Second{func two() -> void;};";
+        assert_eq!(stringified, expected);
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let docs_of = |language: &str, source: &str, docs: DocMode| {
+            let definitions = extract_definitions(language, source).unwrap();
+            stringify_definitions(&definitions, docs)
+        };
+
+        let source = r"
+        /// Adds two numbers. Overflows wrap.
+        ///
+        /// More details here.
+        #[inline]
+        pub fn add(a: u32, b: u32) -> u32 {
+            a.wrapping_add(b)
+        }
+
+        // Not a doc comment
+        pub fn sub(a: u32, b: u32) -> u32 {
+            a - b
+        }
+
+        /// A point.
+        pub struct Point {
+            /// Horizontal.
+            pub x: f64,
+        }
+        ";
+        assert_eq!(
+            docs_of("rust", source, DocMode::FirstSentence),
+            "/** Adds two numbers. */func add(a: u32, b: u32) -> u32;func sub(a: u32, b: u32) -> u32;/** A point. */class Point{/** Horizontal. */var x:f64;};"
+        );
+        assert_eq!(
+            docs_of("rust", source, DocMode::Full),
+            "/** Adds two numbers. Overflows wrap. More details here. */func add(a: u32, b: u32) -> u32;func sub(a: u32, b: u32) -> u32;/** A point. */class Point{/** Horizontal. */var x:f64;};"
+        );
+        assert_eq!(
+            docs_of("rust", source, DocMode::None),
+            "func add(a: u32, b: u32) -> u32;func sub(a: u32, b: u32) -> u32;class Point{var x:f64;};"
+        );
+
+        let source = r#"
+def greet(name: str) -> str:
+    """Return a greeting.
+
+    Longer description.
+    """
+    return "hi " + name
+
+class Greeter:
+    """Greets people."""
+    def hello(self) -> str:
+        """Say hello."""
+        return "hello"
+"#;
+        let definitions = extract_definitions("python", source).unwrap();
+        let Definition::Func(func) = &definitions[0] else {
+            panic!("unexpected definitions {definitions:?}");
+        };
+        assert_eq!(
+            func.doc.as_deref(),
+            Some("Return a greeting.\n\nLonger description.")
+        );
+        assert_eq!(
+            docs_of("python", source, DocMode::FirstSentence),
+            "/** Return a greeting. */func greet(name: str) -> str;/** Greets people. */class Greeter{/** Say hello. */func hello(self) -> str;};"
+        );
+
+        let source = r"
+        /**
+         * Adds numbers.
+         * @param a first
+         */
+        export function add(a: number, b: number): number {
+            return a + b;
+        }
+        /** The answer. */
+        export const answer: number = 42;
+        ";
+        assert_eq!(
+            docs_of("typescript", source, DocMode::Full),
+            "/** Adds numbers. @param a first */func add(a: number, b: number) -> number;/** The answer. */var answer:number;"
+        );
+
+        let source = r"
+        /** A thing. */
+        public class Thing {
+            /** Does it. */
+            public void doIt() {}
+        }
+        ";
+        assert_eq!(
+            docs_of("java", source, DocMode::Full),
+            "/** A thing. */class Thing{/** Does it. */func doIt() -> void;};"
+        );
+
+        let source = r"
+package main
+
+// Add adds two numbers.
+func Add(a int, b int) int {
+	return a + b
+}
+";
+        assert_eq!(
+            docs_of("go", source, DocMode::Full),
+            "/** Add adds two numbers. */func Add(a int, b int) -> int;"
+        );
+
+        let source = r#"
+        /// <summary>A calculator.</summary>
+        public class Calculator
+        {
+            /// <summary>
+            /// Adds <paramref name="a"/> and b.
+            /// </summary>
+            /// <returns><see langword="null"/> on <see cref="Overflow"/>.</returns>
+            public int Add(int a, int b) => a + b;
+        }
+        "#;
+        assert_eq!(
+            docs_of("csharp", source, DocMode::Full),
+            "/** A calculator. */class Calculator{/** Adds a and b. null on Overflow. */func Add(int a, int b) -> int;};"
+        );
+
+        let source = r#"
+        defmodule Math do
+          @moduledoc "Math helpers."
+
+          @doc """
+          Adds two numbers.
+          """
+          @spec add(integer, integer) :: integer
+          def add(a, b), do: a + b
+        end
+        "#;
+        assert_eq!(
+            docs_of("elixir", source, DocMode::Full),
+            "/** Math helpers. */module Math{/** Adds two numbers. */func add(a, b);};"
+        );
+    }
 }
@@ -0,0 +1,355 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::builder::RepoMapEntry;
+use crate::cache::CachedFile;
+use crate::{short_name, stringify_definition, Definition, DocMode};
+
+const DAMPING: f64 = 0.85;
+const MAX_ITERATIONS: usize = 100;
+const TOLERANCE: f64 = 1e-8;
+
+// A top-level definition of a file, as ranked and emitted on its own
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Symbol {
+    pub(crate) names: Vec<String>, // The definition and its methods, as other files refer to them
+    pub(crate) defs: String,       // Stringified definition
+}
+
+// The names other files use for `definition`, and everything nested in it
+fn collect_names<'a>(definition: &'a Definition, names: &mut BTreeSet<&'a str>) {
+    match definition {
+        Definition::Func(func) => {
+            names.insert(short_name(&func.name));
+        }
+        Definition::Class(class) | Definition::Module(class) => {
+            names.insert(short_name(&class.name));
+            names.extend(class.methods.iter().map(|method| short_name(&method.name)));
+        }
+        Definition::Enum(enum_def) => {
+            names.insert(short_name(&enum_def.name));
+        }
+        Definition::Union(union_def) => {
+            names.insert(short_name(&union_def.name));
+        }
+        Definition::Variable(variable) => {
+            names.insert(short_name(&variable.name));
+        }
+        Definition::Namespace(namespace) => {
+            names.insert(short_name(&namespace.name));
+            for definition in &namespace.definitions {
+                collect_names(definition, names);
+            }
+        }
+    }
+}
+
+impl Symbol {
+    pub(crate) fn from_definition(definition: &Definition, docs: DocMode) -> Self {
+        let mut names = BTreeSet::new();
+        collect_names(definition, &mut names);
+        names.remove("");
+        Self {
+            names: names.into_iter().map(str::to_string).collect(),
+            defs: stringify_definition(definition, docs),
+        }
+    }
+}
+
+// Rough token count of text sent to the model: about four bytes per token
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+// How much a shared identifier says about two files being related, as in aider:
+// long descriptive names count more, private and widely defined ones less
+fn identifier_weight(name: &str, definers: usize) -> f64 {
+    let mut weight = 1.0;
+    let is_snake = name.contains('_') && name.chars().any(char::is_alphabetic);
+    let is_camel = name.chars().any(char::is_uppercase) && name.chars().any(char::is_lowercase);
+    if (is_snake || is_camel) && name.len() >= 8 {
+        weight *= 10.0;
+    }
+    if name.starts_with('_') {
+        weight *= 0.1;
+    }
+    if definers > 5 {
+        weight *= 0.1;
+    }
+    weight
+}
+
+// Personalized PageRank over weighted edges (`edges[from]` = `(to, weight)`).
+// Teleports, and the rank of nodes without edges, follow `personalization`.
+pub(crate) fn pagerank(edges: &[Vec<(usize, f64)>], personalization: &[f64]) -> Vec<f64> {
+    let out_weights: Vec<f64> = edges
+        .iter()
+        .map(|out| out.iter().map(|(_, weight)| weight).sum())
+        .collect();
+    let mut rank = personalization.to_vec();
+    for _ in 0..MAX_ITERATIONS {
+        let dangling: f64 = rank
+            .iter()
+            .zip(&out_weights)
+            .filter(|(_, &out_weight)| out_weight <= 0.0)
+            .map(|(rank, _)| rank)
+            .sum();
+        let mut next: Vec<f64> = personalization
+            .iter()
+            .map(|share| (1.0 - DAMPING + DAMPING * dangling) * share)
+            .collect();
+        for (from, out) in edges.iter().enumerate() {
+            if out_weights[from] <= 0.0 {
+                continue;
+            }
+            for &(to, weight) in out {
+                next[to] += DAMPING * rank[from] * weight / out_weights[from];
+            }
+        }
+        let change: f64 = next.iter().zip(&rank).map(|(a, b)| (a - b).abs()).sum();
+        rank = next;
+        if change < TOLERANCE {
+            break;
+        }
+    }
+    rank
+}
+
+// Rank every definition by how much the rest of the repository relies on it,
+// seeded by the files already in context, and keep the best ones that fit in
+// `token_budget`. Files are ordered by their best definition; definitions keep
+// their source order within a file. Files in context are left out, since the
+// model already sees them.
+pub(crate) fn rank_repo_map(
+    files: &[(String, CachedFile)],
+    context_files: &HashSet<String>,
+    token_budget: usize,
+) -> Vec<RepoMapEntry> {
+    // Which files define and which reference each identifier
+    let mut definers: BTreeMap<&str, BTreeSet<usize>> = BTreeMap::new();
+    for (index, (_, file)) in files.iter().enumerate() {
+        for symbol in &file.symbols {
+            for name in &symbol.names {
+                definers.entry(name).or_default().insert(index);
+            }
+        }
+    }
+    let mut referencers: HashMap<&str, Vec<(usize, u32)>> = HashMap::new();
+    for (index, (_, file)) in files.iter().enumerate() {
+        for (name, &count) in &file.references {
+            if definers.contains_key(name.as_str()) {
+                referencers.entry(name).or_default().push((index, count));
+            }
+        }
+    }
+
+    // Referencing file -> defining file, once per shared identifier
+    let in_context: Vec<bool> = files
+        .iter()
+        .map(|(path, _)| context_files.contains(path))
+        .collect();
+    let mut edges: Vec<Vec<(usize, f64, &str)>> = vec![Vec::new(); files.len()];
+    for (&name, defined_in) in &definers {
+        let weight = identifier_weight(name, defined_in.len());
+        let mut referenced = false;
+        for &(referencer, count) in referencers.get(name).map_or(&[][..], Vec::as_slice) {
+            let boost = if in_context[referencer] { 50.0 } else { 1.0 };
+            for &definer in defined_in {
+                if definer != referencer {
+                    edges[referencer].push((
+                        definer,
+                        weight * boost * f64::from(count).sqrt(),
+                        name,
+                    ));
+                    referenced = true;
+                }
+            }
+        }
+        // Still give definitions nobody else uses a small share of their file's rank
+        if !referenced {
+            for &definer in defined_in {
+                edges[definer].push((definer, 0.1, name));
+            }
+        }
+    }
+
+    // Teleport to the files in context, or anywhere without any
+    let seeds: Vec<bool> = if in_context.contains(&true) {
+        in_context.clone()
+    } else {
+        vec![true; files.len()]
+    };
+    let share = 1.0
+        / f64::from(u32::try_from(seeds.iter().filter(|&&seed| seed).count()).unwrap_or(u32::MAX));
+    let personalization: Vec<f64> = seeds
+        .iter()
+        .map(|&seed| if seed { share } else { 0.0 })
+        .collect();
+    let file_ranks = pagerank(
+        &edges
+            .iter()
+            .map(|out| out.iter().map(|&(to, weight, _)| (to, weight)).collect())
+            .collect::<Vec<_>>(),
+        &personalization,
+    );
+
+    // Split each file's rank over the identifiers it points at
+    let mut name_ranks: HashMap<(usize, &str), f64> = HashMap::new();
+    for (from, out) in edges.iter().enumerate() {
+        let total: f64 = out.iter().map(|&(_, weight, _)| weight).sum();
+        for &(to, weight, name) in out {
+            *name_ranks.entry((to, name)).or_default() += file_ranks[from] * weight / total;
+        }
+    }
+
+    let mut candidates: Vec<(usize, usize, f64)> = Vec::new();
+    for (file_index, (_, file)) in files.iter().enumerate() {
+        if in_context[file_index] {
+            continue;
+        }
+        for (symbol_index, symbol) in file.symbols.iter().enumerate() {
+            let rank = symbol
+                .names
+                .iter()
+                .filter_map(|name| name_ranks.get(&(file_index, name.as_str())))
+                .sum();
+            candidates.push((file_index, symbol_index, rank));
+        }
+    }
+    candidates.sort_by(|a, b| {
+        b.2.total_cmp(&a.2)
+            .then_with(|| files[a.0].0.cmp(&files[b.0].0))
+            .then(a.1.cmp(&b.1))
+    });
+
+    // Take definitions best first while they fit; one that doesn't is skipped,
+    // smaller ones after it may still fit
+    let mut used = 0;
+    let mut order: Vec<usize> = Vec::new();
+    let mut chosen: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+    for (file_index, symbol_index, rank) in candidates {
+        let (path, file) = &files[file_index];
+        let mut cost = estimate_tokens(&file.symbols[symbol_index].defs);
+        if !chosen.contains_key(&file_index) {
+            cost += estimate_tokens(path) + estimate_tokens(&file.lang);
+        }
+        if used + cost > token_budget {
+            continue;
+        }
+        used += cost;
+        chosen
+            .entry(file_index)
+            .or_insert_with(|| {
+                order.push(file_index);
+                Vec::new()
+            })
+            .push((symbol_index, rank));
+    }
+
+    order
+        .into_iter()
+        .map(|file_index| {
+            let (path, file) = &files[file_index];
+            let mut symbols = chosen.remove(&file_index).unwrap_or_default();
+            let rank = symbols.first().map_or(0.0, |&(_, rank)| rank);
+            symbols.sort_by_key(|&(symbol_index, _)| symbol_index);
+            RepoMapEntry {
+                path: path.clone(),
+                lang: file.lang.clone(),
+                defs: symbols
+                    .iter()
+                    .map(|&(symbol_index, _)| file.symbols[symbol_index].defs.as_str())
+                    .collect(),
+                rank: Some(rank),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(lang: &str, symbols: &[(&str, &str)], references: &[(&str, u32)]) -> CachedFile {
+        CachedFile {
+            mtime: 0,
+            mtime_nanos: 0,
+            size: 0,
+            hash: String::new(),
+            lang: lang.to_string(),
+            docs: DocMode::None,
+            symbols: symbols
+                .iter()
+                .map(|(name, defs)| Symbol {
+                    names: vec![(*name).to_string()],
+                    defs: (*defs).to_string(),
+                })
+                .collect(),
+            references: references
+                .iter()
+                .map(|(name, count)| ((*name).to_string(), *count))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_pagerank() {
+        // 0 -> 1 -> 2 -> 1: rank pools in the 1 <-> 2 cycle
+        let edges = vec![vec![(1, 1.0)], vec![(2, 1.0)], vec![(1, 1.0)]];
+        let rank = pagerank(&edges, &[1.0 / 3.0; 3]);
+        assert!((rank.iter().sum::<f64>() - 1.0).abs() < 1e-6);
+        assert!(rank[1] > rank[0] && rank[2] > rank[0]);
+
+        // All teleports land on node 0
+        let rank = pagerank(&edges, &[1.0, 0.0, 0.0]);
+        assert!(rank[0] > 0.1);
+    }
+
+    #[test]
+    fn test_rank_repo_map() {
+        let files = vec![
+            (
+                "app.rs".to_string(),
+                file(
+                    "rust",
+                    &[("main", "func main();")],
+                    &[("load_config", 3), ("render_page", 1)],
+                ),
+            ),
+            (
+                "config.rs".to_string(),
+                file(
+                    "rust",
+                    &[("load_config", "func load_config() -> Config;")],
+                    &[],
+                ),
+            ),
+            (
+                "render.rs".to_string(),
+                file(
+                    "rust",
+                    &[
+                        ("render_page", "func render_page();"),
+                        ("unused_helper", "func unused_helper();"),
+                    ],
+                    &[("load_config", 1)],
+                ),
+            ),
+        ];
+
+        let entries = rank_repo_map(&files, &HashSet::new(), 1000);
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths[0], "config.rs");
+        assert_eq!(entries.len(), 3);
+
+        // With app.rs in context it's left out, and the budget only fits the top definition
+        let context = HashSet::from(["app.rs".to_string()]);
+        let budget = estimate_tokens("config.rs")
+            + estimate_tokens("rust")
+            + estimate_tokens("func load_config() -> Config;");
+        let entries = rank_repo_map(&files, &context, budget);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "config.rs");
+        assert_eq!(entries[0].defs, "func load_config() -> Config;");
+    }
+}
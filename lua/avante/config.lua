@@ -512,6 +512,12 @@ M._defaults = {
   repo_map = {
     ignore_patterns = { "%.git", "%.worktree", "__pycache__", "node_modules" }, -- ignore files matching these
     negate_patterns = {}, -- negate ignore files matching these.
+    ---@type integer | nil
+    token_budget = nil, -- rank definitions against the selected files and keep the best that fit, nil maps everything
+    -- How much of each definition's doc comment goes into the map: "none" leaves
+    -- them out, "first_sentence" keeps the summary, "full" keeps all of it
+    ---@type "none" | "first_sentence" | "full"
+    docs = "none",
   },
   --- @class AvanteFileSelectorConfig
   file_selector = {
@@ -14,6 +14,36 @@ local RequestState = {
   Acknowledged = "Acknowledged" -- Request completion was acknowledged by client
 }
 
+---@class AvanteCurlProviderError
+---@field type string|nil
+---@field code string|nil
+---@field message string|nil
+
+---Structured error reported by the Rust backend in `get_status().error`
+---@class AvanteCurlError
+---@field kind string e.g. "timeout", "connect", "cancelled", "rate_limited", "auth", "http_status"
+---@field message string
+---@field status integer|nil HTTP status, if the error came from a response
+---@field retryable boolean
+---@field retry_after number|nil seconds to wait before retrying
+---@field body any|nil error body, decoded from JSON where possible
+---@field provider AvanteCurlProviderError|nil
+---@field line integer|nil position of a JSON parse error (response_type = "json")
+---@field column integer|nil
+
+---Request state and response as `get_status` reports them
+---@class AvanteCurlResponse
+---@field id string
+---@field state string
+---@field status integer|nil
+---@field headers table<string, string>|nil lowercase name -> value, repeated headers comma-joined
+---@field header_list string[][]|nil every { name, value } pair, so repeated headers (set-cookie) stay apart
+---@field url string|nil final URL, after redirects
+---@field redirects string[]|nil URLs visited while following redirects
+---@field body string|nil
+---@field json any|nil decoded body, with response_type = "json"
+---@field error AvanteCurlError|nil
+
 -- Lazy load the avante-curl module
 local avante_curl = nil
 local function load_avante_curl()
@@ -23,6 +53,15 @@ local function load_avante_curl()
   if not ok then error("Failed to load avante_curl module. Make sure the Rust crate is built: " .. tostring(curl)) end
 
   avante_curl = curl
+
+  -- Don't let open streams hold up :qa
+  if curl.shutdown then
+    vim.api.nvim_create_autocmd("VimLeavePre", {
+      group = vim.api.nvim_create_augroup("avante_curl_shutdown", { clear = true }),
+      callback = function() curl.shutdown(500) end,
+    })
+  end
+
   return curl
 end
 
@@ -56,21 +95,90 @@ end
 ---@field request_map table<string, table>
 ---@field polling_interval number
 ---@field polling_timer table
+---@field event_poll table
+---@field pending_threads thread[] coroutines waiting in an async backend call
+---@field before_request fun(options: table): table|false|nil
 local AvanteCurlClient = {}
 
-function AvanteCurlClient.new()
+---@class AvanteCurlTlsOptions
+---@field ca_certs string[]|nil extra root certificates (PEM files)
+---@field client_cert string|nil client certificate, PEM or PKCS#12 (.p12/.pfx)
+---@field client_key string|nil PKCS#8 PEM key for a PEM client certificate
+---@field client_cert_password string|nil password of a PKCS#12 client certificate
+---@field min_version "1.0"|"1.1"|"1.2"|"1.3"|nil
+---@field pinned_pubkeys string[]|nil curl-style "sha256//<base64>" public key pins, checked during the handshake (PEM client certificates only)
+
+---@class AvanteCurlProxyOptions
+---@field system boolean|nil fill unset fields from HTTP_PROXY/HTTPS_PROXY/ALL_PROXY/NO_PROXY
+---@field all string|nil proxy for every scheme (http://, https://, socks5://, socks5h://)
+---@field http string|nil proxy for http:// URLs
+---@field https string|nil proxy for https:// URLs
+---@field username string|nil
+---@field password string|nil
+---@field no_proxy string|string[]|nil hosts, domain suffixes and CIDR ranges to reach directly
+
+---@class AvanteCurlMiddleware
+---@field type "headers"|"rewrite"|"body_limit"|"usage"|"redact"
+---@field headers table<string, string>|string[]|nil headers to add ("headers") or to mask ("redact")
+---@field override boolean|nil "headers": replace values the request already sets
+---@field from string|nil "rewrite": URL prefix to replace
+---@field to string|nil "rewrite": replacement prefix
+---@field query table<string, string>|string[]|nil query parameters to add ("rewrite") or to mask ("redact")
+---@field max_bytes integer|nil "body_limit": largest request body to send
+
+---@class AvanteCurlCacheOptions
+---@field dir string directory holding the cached responses
+---@field max_bytes integer|nil evict least recently used entries past this size (default 100 MiB)
+
+---@class AvanteCurlModelPrice
+---@field input number price per million input tokens
+---@field output number price per million output tokens
+---@field cache_read number|nil defaults to the input price
+---@field cache_write number|nil defaults to the input price
+
+---@class AvanteCurlTokenUsage
+---@field input_tokens integer uncached input
+---@field output_tokens integer
+---@field cache_read_tokens integer
+---@field cache_write_tokens integer
+---@field requests integer|nil requests counted (session totals only)
+---@field model string|nil
+---@field cost number|nil estimated from the session pricing table
+
+---@class AvanteCurlSessionOptions
+---@field unix_socket string|nil connect through this Unix domain socket
+---@field resolve string[]|nil curl-style "HOST:PORT:ADDR" entries
+---@field tls AvanteCurlTlsOptions|nil
+---@field proxy string|"system"|AvanteCurlProxyOptions|nil
+---@field middleware AvanteCurlMiddleware[]|nil applied in order to every request
+---@field cache string|AvanteCurlCacheOptions|nil disk cache for GET responses (a directory or options), keyed per credential
+---@field max_concurrent integer|nil requests in flight at once; the rest wait, highest `priority` first
+---@field pricing table<string, AvanteCurlModelPrice>|nil model name (or prefix) -> prices, for usage cost estimates
+---@field preempt boolean|nil at the limit, cancel and requeue the lowest-priority running request for a higher one (its status then reports `restarts`)
+---@field before_request fun(options: table): table|false|nil runs on the main thread before dispatch; may edit or return new options, false drops the request
+
+---@param session_opts AvanteCurlSessionOptions|nil defaults applied to every request
+function AvanteCurlClient.new(session_opts)
   local curl = load_avante_curl()
-  local session_id = curl.create_session()
+  session_opts = session_opts or {}
+  local before_request = session_opts.before_request
+  -- The hook stays on the Lua side
+  local backend_opts = vim.tbl_extend("force", {}, session_opts)
+  backend_opts.before_request = nil
+  local session_id = curl.create_session(backend_opts)
 
   local self = setmetatable({
     session_id = session_id,
+    before_request = before_request,
     request_map = {},
     polling_interval = 100, -- milliseconds
     polling_timer = nil,
+    event_poll = nil,
+    pending_threads = {},
   }, { __index = AvanteCurlClient })
 
-  -- Start the polling timer
-  self:start_polling()
+  -- Wake up on backend events when possible, otherwise poll on a timer
+  if not self:start_event_watch() then self:start_polling() end
 
   return self
 end
@@ -82,10 +190,17 @@ function AvanteCurlClient:destroy()
     self.polling_timer = nil
   end
 
+  if self.event_poll then
+    self.event_poll:stop()
+    self.event_poll:close()
+    self.event_poll = nil
+  end
+
   local curl = load_avante_curl()
   curl.destroy_session(self.session_id)
   self.session_id = nil
   self.request_map = {}
+  self.pending_threads = {}
 end
 
 function AvanteCurlClient:start_polling()
@@ -98,9 +213,36 @@ function AvanteCurlClient:start_polling()
   self.polling_timer:start(0, self.polling_interval, vim.schedule_wrap(function() self:poll_requests() end))
 end
 
+-- Watch the session's event fd so we only poll when the backend has news
+function AvanteCurlClient:start_event_watch()
+  local curl = load_avante_curl()
+  if not curl.event_fd then return false end
+
+  local fd = curl.event_fd(self.session_id)
+  if not fd then return false end
+
+  local poll = uv.new_poll(fd)
+  if not poll then return false end
+
+  self.event_poll = poll
+  poll:start("r", function(err)
+    if err or not self.session_id then return end
+    -- Drain right away so the fd stops being readable before we reschedule
+    curl.drain_events(self.session_id)
+    vim.schedule(function()
+      if self.session_id then self:poll_requests() end
+    end)
+  end)
+
+  return true
+end
+
 function AvanteCurlClient:poll_requests()
   local curl = load_avante_curl()
 
+  -- Give coroutines blocked in await_request/next_chunk a chance to continue
+  self:resume_pending()
+
   -- Only poll for status updates, not for callback handling
   for request_id, request_info in pairs(self.request_map) do
     local status = curl.get_status(self.session_id, request_id)
@@ -110,6 +252,20 @@ function AvanteCurlClient:poll_requests()
       request_info.state = status.state
     end
 
+    -- Deliver WebSocket messages received since the last poll
+    if status.pending_messages and request_info.on_message then
+      for _, message in ipairs(curl.take_messages(self.session_id, request_id)) do
+        safe_callback(request_info.on_message, message)
+      end
+    end
+
+    -- Deliver events framed from the body, for requests with `framing`
+    if status.pending_events and request_info.on_event then
+      for _, event in ipairs(curl.take_events(self.session_id, request_id)) do
+        safe_callback(request_info.on_event, event)
+      end
+    end
+
     -- Check if the request is in a terminal state
     local is_terminal_state = status.state == RequestState.Complete
       or status.state == RequestState.Error
@@ -117,40 +273,96 @@ function AvanteCurlClient:poll_requests()
       or status.state == RequestState.Cancelled
       or status.state == RequestState.Idle
 
+    -- Messages and events queued before the request finished were delivered
+    -- above, so forget it and let the backend free it
     if is_terminal_state then
-      -- Remove from the request map only if not using callbacks
-      -- Callbacks will be handled directly by the Rust backend now
-      if not (request_info.on_complete or request_info.on_error or request_info.on_chunk) then
-        self.request_map[request_id] = nil
-      end
+      self.request_map[request_id] = nil
+      curl.acknowledge_request(self.session_id, request_id)
     end
   end
 end
 
--- Generic request method
-function AvanteCurlClient:request(options)
+-- Resume a coroutine, parking it again if the backend is still waiting
+function AvanteCurlClient:step(thread, ...)
   local curl = load_avante_curl()
 
+  local ok, result = coroutine.resume(thread, ...)
+  if not ok then
+    vim.schedule(function() vim.notify("Error in coroutine: " .. tostring(result), vim.log.levels.ERROR) end)
+    return
+  end
+
+  if coroutine.status(thread) == "suspended" and result == curl.poll_pending then
+    table.insert(self.pending_threads, thread)
+  end
+end
+
+function AvanteCurlClient:resume_pending()
+  if #self.pending_threads == 0 then return end
+
+  local threads = self.pending_threads
+  self.pending_threads = {}
+  for _, thread in ipairs(threads) do
+    self:step(thread)
+  end
+end
+
+-- Run a function as a coroutine that may call await_request and iterate chunks.
+-- It is resumed whenever the backend reports new events.
+---@param fn fun(...)
+function AvanteCurlClient:run(fn, ...)
+  self:step(coroutine.create(fn), ...)
+end
+
+-- Run the before_request hook. Returns the options to send, or nil if the hook dropped the request.
+function AvanteCurlClient:before_dispatch(options)
+  options = options or {}
+  if not self.before_request then return options end
+
+  local ok, result = pcall(self.before_request, options)
+  if not ok then error("before_request hook failed: " .. tostring(result)) end
+  if result == false then return nil end
+  return result or options
+end
+
+-- Convert request options to the shape the backend expects
+local function build_request(options)
   local opts = vim.tbl_deep_extend("force", {
     url = "",
     method = "GET",
     headers = {},
     body = nil,
+    body_file = nil,
+    body_parts = nil,
+    compress = nil,
     query = nil,
     form = nil,
     auth = nil,
     timeout = 60,
     insecure = false,
     proxy = nil,
+    unix_socket = nil,
+    resolve = nil,
+    tls = nil,
+    token_provider = nil,
+    response_type = nil,
+    json_null = nil,
+    max_response_bytes = nil,
+    max_buffered_bytes = nil,
+    on_limit = nil,
+    cache = nil,
+    priority = nil,
+    usage_format = nil,
+    model = nil,
+    framing = nil,
+    retries = nil,
     stream = nil,
     on_complete = nil,
     on_error = nil,
     on_chunk = nil,
+    on_event = nil,
   }, options or {})
 
-  -- Generate a unique request ID
-  local request_id = vim.fn.sha256(opts.url .. tostring(vim.fn.localtime()) .. vim.fn.rand())
-
   local lua_opts = {
     _options = {
       url = opts.url,
@@ -159,6 +371,34 @@ function AvanteCurlClient:request(options)
       timeout = opts.timeout,
       insecure = opts.insecure,
       proxy = opts.proxy,
+      unix_socket = opts.unix_socket,
+      resolve = opts.resolve,
+      tls = opts.tls,
+      token_provider = opts.token_provider,
+      -- "json" decodes the body in the backend; get_status then returns `json` instead of `body`
+      response_type = opts.response_type,
+      -- "nil" to drop JSON nulls instead of decoding them as vim.NIL
+      json_null = opts.json_null,
+      -- Size limits; on_limit = "truncate" completes with `truncated = true` instead of failing.
+      -- max_response_bytes counts wire bytes, like Content-Length and get_status().received_bytes
+      max_response_bytes = opts.max_response_bytes,
+      max_buffered_bytes = opts.max_buffered_bytes,
+      on_limit = opts.on_limit,
+      -- With a session cache: "default", "force" (serve stale entries) or "bypass"
+      cache = opts.cache,
+      -- Higher goes first once the session is at max_concurrent, e.g. chat above suggestions
+      priority = opts.priority,
+      -- "auto", "openai", "anthropic" or "gemini": count tokens into the session usage
+      usage_format = opts.usage_format,
+      -- Model to price the usage with, when the body or URL doesn't name it
+      model = opts.model,
+      -- "gzip" or "zstd": compress the body and send Content-Encoding
+      compress = opts.compress,
+      -- "auto" (from Content-Type), "sse", "ndjson" or "raw": split the body into events for on_event
+      framing = opts.framing,
+      -- Retry retryable failures (429, 5xx, connection errors) this many times with backoff.
+      -- Chunks of a failed attempt may already have been delivered; get_status().retry tells
+      retries = opts.retries,
     },
     _callbacks = {
       -- Pass callback functions directly to Rust
@@ -168,8 +408,13 @@ function AvanteCurlClient:request(options)
     },
   }
 
-  -- Add body if present
-  if opts.body then
+  -- Add body if present. body_file and body_parts are streamed by the backend
+  -- instead of being built into one string: parts are strings or { file = path }.
+  if opts.body_parts then
+    lua_opts._options.body = { Parts = opts.body_parts }
+  elseif opts.body_file then
+    lua_opts._options.body = { File = opts.body_file }
+  elseif opts.body then
     if type(opts.body) == "table" then
       lua_opts._options.body = { Json = vim.json.encode(opts.body) }
     elseif type(opts.body) == "string" and vim.fn.filereadable(opts.body) == 1 then
@@ -188,12 +433,26 @@ function AvanteCurlClient:request(options)
   -- Add auth info if present
   if opts.auth then lua_opts._options.auth = opts.auth end
 
+  return opts, lua_opts
+end
+
+-- Generic request method
+function AvanteCurlClient:request(options)
+  local curl = load_avante_curl()
+  options = self:before_dispatch(options)
+  if not options then return nil, "request dropped by before_request" end
+  local opts, lua_opts = build_request(options)
+
+  -- Generate a unique request ID
+  local request_id = vim.fn.sha256(opts.url .. tostring(vim.fn.localtime()) .. vim.fn.rand())
+
   -- Store request info for status tracking
   self.request_map[request_id] = {
     id = request_id,
     on_complete = opts.on_complete,
     on_error = opts.on_error,
     on_chunk = opts.on_chunk,
+    on_event = opts.on_event,
     state = RequestState.Init, -- Initialize with Init state
   }
 
@@ -252,6 +511,153 @@ function AvanteCurlClient:patch(url, opts)
   return self:request(options)
 end
 
+-- Start a request and wait for its headers. Must be called from `run`.
+-- Returns the response (status, headers, id, ...) or nil and an error.
+---@return table|nil response
+---@return AvanteCurlError|nil error
+function AvanteCurlClient:await_request(options)
+  local curl = load_avante_curl()
+  options = self:before_dispatch(options)
+  if not options then
+    return nil, { kind = "cancelled", message = "request dropped by before_request", retryable = false }
+  end
+  local _, lua_opts = build_request(options)
+  if options.id then lua_opts.id = options.id end
+  return curl.await_request(self.session_id, lua_opts)
+end
+
+-- Iterate over the body chunks of a response from await_request. Must be
+-- called from `run`; check get_status(request_id).error after the loop.
+---@param request_id string
+---@return fun(): string|nil
+function AvanteCurlClient:chunks(request_id)
+  local curl = load_avante_curl()
+  return function() return curl.next_chunk(self.session_id, request_id) end
+end
+
+function AvanteCurlClient:get_status(request_id)
+  local curl = load_avante_curl()
+  return curl.get_status(self.session_id, request_id)
+end
+
+-- Token usage of the session so far, with a `models` breakdown
+---@return AvanteCurlTokenUsage
+function AvanteCurlClient:usage()
+  local curl = load_avante_curl()
+  return curl.usage(self.session_id)
+end
+
+---@class AvanteCurlBatchOptions
+---@field concurrency integer|nil sub-requests in flight at once, defaults to all
+---@field fail_fast boolean|nil cancel the rest of the batch on the first failure
+
+-- Start several requests together. Returns the batch id; sub-requests are
+-- reported in order by batch_status as "<batch_id>:<n>".
+---@param requests table[] request options, as for `request`
+---@param opts AvanteCurlBatchOptions|nil
+---@return string
+function AvanteCurlClient:request_batch(requests, opts)
+  local curl = load_avante_curl()
+  local batch = vim.tbl_map(function(options)
+    options = self:before_dispatch(options)
+    if not options then error("batch request dropped by before_request") end
+    local _, lua_opts = build_request(options)
+    return lua_opts
+  end, requests)
+  return curl.request_batch(self.session_id, batch, opts)
+end
+
+-- Aggregate state ("Running", "Complete", "Failed", "Cancelled") and per-request status.
+-- A finished batch is reported once and then forgotten; asking again raises an error.
+function AvanteCurlClient:batch_status(batch_id)
+  local curl = load_avante_curl()
+  return curl.get_batch_status(self.session_id, batch_id)
+end
+
+function AvanteCurlClient:cancel_batch(batch_id)
+  local curl = load_avante_curl()
+  return curl.cancel_batch(self.session_id, batch_id)
+end
+
+---@class AvanteCurlTokenProviderOptions
+---@field url string|nil token endpoint returning JSON ({access_token|token, expires_at|expires_in})
+---@field method string|nil
+---@field headers table<string, string>|nil
+---@field body string|nil
+---@field command string|string[]|nil command printing the token, e.g. { "gcloud", "auth", "print-access-token" }
+---@field json boolean|nil parse the command output as JSON
+---@field timeout integer|nil seconds before the command is killed and the request fails with kind "token" (default 30)
+---@field token_field string|nil
+---@field ttl integer|nil token lifetime in seconds when the source doesn't report one
+---@field skew integer|nil refresh this many seconds before expiry
+---@field header string|nil header to inject, defaults to "Authorization"
+---@field prefix string|nil header value prefix, defaults to "Bearer "
+
+-- Register a token provider; requests reference it with `token_provider = name`
+---@param name string
+---@param opts AvanteCurlTokenProviderOptions
+function AvanteCurlClient:register_token_provider(name, opts)
+  local curl = load_avante_curl()
+  return curl.register_token_provider(self.session_id, name, opts)
+end
+
+function AvanteCurlClient:invalidate_token(name)
+  local curl = load_avante_curl()
+  return curl.invalidate_token(self.session_id, name)
+end
+
+---@class AvanteCurlWsMessage
+---@field type "text"|"binary"|"close"
+---@field data string|nil payload of text and binary messages
+---@field code integer|nil close code
+---@field reason string|nil close reason
+
+---@class AvanteCurlWsOptions
+---@field headers table<string, string>|nil
+---@field protocols string[]|nil Sec-WebSocket-Protocol values
+---@field ping_interval integer|nil seconds between keepalive pings, 0 to disable (default 30)
+---@field pong_timeout integer|nil seconds without a pong before the connection fails
+---@field on_message fun(message: AvanteCurlWsMessage)|nil
+
+-- Open a WebSocket connection, returning its id for ws_send/ws_close
+---@param url string ws:// or wss:// URL
+---@param opts AvanteCurlWsOptions|nil
+function AvanteCurlClient:ws_connect(url, opts)
+  local curl = load_avante_curl()
+  opts = opts or {}
+
+  local connection_id = curl.ws_connect(self.session_id, url, {
+    headers = opts.headers,
+    protocols = opts.protocols,
+    ping_interval = opts.ping_interval,
+    pong_timeout = opts.pong_timeout,
+  })
+
+  self.request_map[connection_id] = {
+    id = connection_id,
+    on_message = opts.on_message,
+    state = RequestState.Init,
+  }
+
+  return connection_id
+end
+
+---@param connection_id string
+---@param data string
+---@param binary boolean|nil send as a binary message
+function AvanteCurlClient:ws_send(connection_id, data, binary)
+  local curl = load_avante_curl()
+  return curl.ws_send(self.session_id, connection_id, data, binary)
+end
+
+---@param connection_id string
+---@param code integer|nil close code, defaults to 1000
+---@param reason string|nil
+function AvanteCurlClient:ws_close(connection_id, code, reason)
+  local curl = load_avante_curl()
+  return curl.ws_close(self.session_id, connection_id, code, reason)
+end
+
 function AvanteCurlClient:cancel(request_id)
   local curl = load_avante_curl()
 
@@ -268,7 +674,20 @@ end
 local singleton_client = nil
 
 local M = {
-  create = function() return AvanteCurlClient.new() end,
+  create = function(session_opts) return AvanteCurlClient.new(session_opts) end,
+
+  -- Runtime settings ({ worker_threads, cleanup_interval }), applied when the
+  -- runtime next starts. Returns false if it's already running.
+  configure = function(opts) return load_avante_curl().configure(opts) end,
+
+  -- Cancel all requests, drop every session and stop the runtime
+  shutdown = function(timeout_ms)
+    if singleton_client then
+      singleton_client:destroy()
+      singleton_client = nil
+    end
+    return load_avante_curl().shutdown(timeout_ms)
+  end,
 
   -- Get the singleton client
   get_client = function()
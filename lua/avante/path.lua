@@ -277,6 +277,10 @@ function RepoMap.load(project_root, ext)
   return nil
 end
 
+-- Directory where avante_repo_map keeps per-file definitions between builds
+---@return string
+function RepoMap.cache_dir() return P.cache_path:joinpath("repo_map"):absolute() end
+
 P.repo_map = RepoMap
 
 ---@return AvanteTemplates|nil
@@ -1,5 +1,6 @@
 local Popup = require("nui.popup")
 local Utils = require("avante.utils")
+local Config = require("avante.config")
 local event = require("nui.utils.autocmd").event
 
 local filetype_map = {
@@ -8,8 +9,69 @@ local filetype_map = {
   ["cs"] = "csharp",
 }
 
+---@class AvanteRepoMapEntry
+---@field path string relative to the project root
+---@field lang string
+---@field defs string
+---@field rank number|nil best rank of its definitions, only with a `token_budget`
+
+---@class AvanteRepoMapOptions
+---@field extensions string[]|nil only map files with these extensions
+---@field languages string[]|nil only map files detected as these languages
+---@field max_file_size integer|nil skip larger files (default 1 MiB)
+---@field max_files integer|nil
+---@field hidden boolean|nil include hidden files and directories
+---@field gitignore boolean|nil honor .gitignore/.ignore (default true)
+---@field follow_links boolean|nil
+---@field threads integer|nil defaults to one per CPU
+---@field cache_dir string|nil keep per-file definitions here, so rebuilds only parse changed files
+---@field background boolean|nil return an AvanteRepoMapJob instead of blocking
+---@field token_budget integer|nil rank definitions and keep the best that fit, files ordered by rank
+---@field context_files string[]|nil files already in context: they seed the ranking and are left out of the map
+---@field docs "none"|"first_sentence"|"full"|nil how much of each doc comment goes into `defs` (default "none")
+---@field buffers AvanteRepoMapBuffer[]|nil loaded buffers, mapped as the editor sees them
+
+---@class AvanteRepoMapBuffer
+---@field path string absolute
+---@field lang string|nil its filetype as a repo map language, over detection by extension
+---@field content string|nil unsaved text, over the file on disk
+
+---@class AvanteRepoMapProgress
+---@field scanning boolean still walking the tree, `total` is not known yet
+---@field total integer
+---@field processed integer
+---@field parsed integer processed files that had to be parsed (not cached)
+---@field done boolean
+
+---@class AvanteRepoMapJob
+---@field is_done fun(self: AvanteRepoMapJob): boolean
+---@field progress fun(self: AvanteRepoMapJob): AvanteRepoMapProgress
+---@field result fun(self: AvanteRepoMapJob): AvanteRepoMapEntry[]|nil nil while running, raises if the build failed
+---@field cancel fun(self: AvanteRepoMapJob)
+
+---@class AvanteDefinition
+---@field kind string "func", "variable", "class", "module", "enum", "union" or "namespace"
+---@field name string
+---@field visibility string|nil
+---@field parent string|nil name of the enclosing definition
+---@field doc string|nil doc comment or docstring of a func, variable, class or module
+---@field start_line integer 1-based
+---@field start_column integer 0-based byte offset
+---@field end_line integer
+---@field end_column integer
+---@field params string|nil func
+---@field return_type string|nil func
+---@field value_type string|nil variable
+---@field methods AvanteDefinition[]|nil class, module
+---@field properties AvanteDefinition[]|nil class, module
+---@field items AvanteDefinition[]|nil enum, union
+---@field definitions AvanteDefinition[]|nil namespace
+
 ---@class AvanteRepoMap
----@field stringify_definitions fun(lang: string, source: string): string
+---@field stringify_definitions fun(lang: string, source: string, docs: "none"|"first_sentence"|"full"|nil): string
+---@field extract_definitions fun(lang: string, source: string): AvanteDefinition[]
+---@field build_repo_map fun(root: string, opts: AvanteRepoMapOptions|nil): AvanteRepoMapEntry[]|AvanteRepoMapJob
+---@field invalidate fun(paths: string[]): integer drop cached definitions so the next build parses the files again
 local repo_map_lib = nil
 
 local RepoMap = {}
@@ -25,50 +87,174 @@ function RepoMap._init_repo_map_lib()
   return repo_map_lib
 end
 
-function RepoMap.setup() vim.defer_fn(RepoMap._init_repo_map_lib, 1000) end
+function RepoMap.setup()
+  vim.defer_fn(RepoMap._init_repo_map_lib, 1000)
+
+  vim.api.nvim_create_autocmd("BufWritePost", {
+    group = vim.api.nvim_create_augroup("avante_repo_map", { clear = true }),
+    callback = function(ev)
+      if not repo_map_lib then return end
+      local ok, filepath = pcall(vim.api.nvim_buf_get_name, ev.buf)
+      if not ok or not filepath or filepath == "" then return end
+      repo_map_lib.invalidate({ filepath })
+    end,
+  })
+end
 
 function RepoMap.get_ts_lang(filepath)
   local filetype = Utils.get_filetype(filepath)
   return filetype_map[filetype] or filetype
 end
 
-function RepoMap._build_repo_map(project_root, file_ext)
-  local output = {}
+---@param file_ext string
+---@return string[]
+local function same_file_exts(file_ext)
+  if file_ext == "ts" or file_ext == "tsx" then return { "ts", "tsx" } end
+  if file_ext == "js" or file_ext == "jsx" then return { "js", "jsx" } end
+  return { file_ext }
+end
 
-  local filepaths = Utils.scan_directory({
-    directory = project_root,
-  })
-  if filepaths and not RepoMap._init_repo_map_lib() then
+-- Loaded buffers under the root, with the filetype the editor gave them
+-- (overrides and modelines included) and their text when it isn't saved
+---@param project_root string
+---@return AvanteRepoMapBuffer[]
+local function loaded_buffers(project_root)
+  local buffers = {}
+  for _, bufnr in ipairs(vim.api.nvim_list_bufs()) do
+    local path = vim.api.nvim_buf_get_name(bufnr)
+    if vim.api.nvim_buf_is_loaded(bufnr) and vim.bo[bufnr].buftype == "" and vim.startswith(path, project_root) then
+      local filetype = vim.bo[bufnr].filetype
+      local buffer = { path = path, lang = filetype ~= "" and (filetype_map[filetype] or filetype) or nil }
+      if vim.bo[bufnr].modified then
+        local lines = Utils.read_file_from_buf_or_disk(path)
+        buffer.content = table.concat(lines or {}, "\n")
+      end
+      table.insert(buffers, buffer)
+    end
+  end
+  return buffers
+end
+
+---@param project_root string
+---@param file_ext string
+---@return AvanteRepoMapOptions
+local function build_options(project_root, file_ext)
+  local Path = require("avante.path")
+  return {
+    extensions = same_file_exts(file_ext),
+    cache_dir = Path.repo_map.cache_dir(),
+    docs = Config.repo_map.docs,
+    buffers = loaded_buffers(project_root),
+  }
+end
+
+---@param extra_opts AvanteRepoMapOptions|nil
+function RepoMap._build_repo_map(project_root, file_ext, extra_opts)
+  if not RepoMap._init_repo_map_lib() then
     -- or just throw an error if we don't want to execute request without codebase
     Utils.error("Failed to load avante_repo_map")
     return
   end
-  vim.iter(filepaths):each(function(filepath)
-    if not Utils.is_same_file_ext(file_ext, filepath) then return end
-    local filetype = RepoMap.get_ts_lang(filepath)
-    local lines = Utils.read_file_from_buf_or_disk(filepath)
-    local content = lines and table.concat(lines, "\n") or ""
-    local definitions = filetype and repo_map_lib.stringify_definitions(filetype, content) or ""
-    if definitions == "" then return end
-    table.insert(output, {
-      path = Utils.relative_path(filepath),
-      lang = Utils.get_filetype(filepath),
-      defs = definitions,
-    })
-  end)
+  -- The backend walks and parses the tree on its own threads
+  local opts = vim.tbl_extend("force", build_options(project_root, file_ext), extra_opts or {})
+  local ok, output = pcall(repo_map_lib.build_repo_map, project_root, opts)
+  if not ok then
+    Utils.error("Failed to build repo map: " .. tostring(output))
+    return
+  end
   return output
 end
 
+-- Build the repo map in the background, polling the job until it finishes.
+-- `on_done` gets nil if the build failed.
+---@param on_done fun(repo_map: AvanteRepoMapEntry[]|nil)
+---@param extra_opts AvanteRepoMapOptions|nil
+function RepoMap._build_repo_map_async(project_root, file_ext, on_done, extra_opts)
+  if not RepoMap._init_repo_map_lib() then return on_done(nil) end
+  local opts = vim.tbl_extend("force", build_options(project_root, file_ext), extra_opts or {}, { background = true })
+  local ok, job = pcall(repo_map_lib.build_repo_map, project_root, opts)
+  if not ok then
+    Utils.warn("Failed to build repo map: " .. tostring(job))
+    return on_done(nil)
+  end
+  local timer = vim.loop.new_timer()
+  if not timer then
+    job:cancel()
+    return on_done(nil)
+  end
+  timer:start(
+    100,
+    100,
+    vim.schedule_wrap(function()
+      if not job:is_done() then return end
+      timer:stop()
+      timer:close()
+      local ok, result = pcall(job.result, job)
+      if not ok then
+        Utils.warn("Failed to build repo map: " .. tostring(result))
+        on_done(nil)
+        return
+      end
+      local progress = job:progress()
+      Utils.debug("repo map built:", progress.processed, "files,", progress.parsed, "parsed")
+      on_done(result)
+    end)
+  )
+end
+
 local cache = {}
 
-function RepoMap.get_repo_map(file_ext)
+local ranked_cache = {}
+
+-- The ranked map depends on the context files and the tree. The first request
+-- for a set of context files waits for a ranked build so the budget applies to
+-- it; later ones get the last map ranked for them and refresh it in the background
+---@param context_files string[]
+function RepoMap._get_ranked_repo_map(file_ext, context_files)
+  if not RepoMap._init_repo_map_lib() then
+    Utils.error("Failed to load avante_repo_map")
+    return
+  end
+  local project_root = Utils.root.get()
+  local sorted_files = vim.deepcopy(context_files)
+  table.sort(sorted_files)
+  local cache_key = project_root .. "." .. file_ext .. "\0" .. table.concat(sorted_files, "\0")
+  local ranked = ranked_cache[cache_key]
+  if not ranked then
+    ranked = {}
+    ranked_cache[cache_key] = ranked
+  end
+
+  local opts = { token_budget = Config.repo_map.token_budget, context_files = context_files }
+  if not ranked.repo_map then
+    ranked.repo_map = RepoMap._build_repo_map(project_root, file_ext, opts)
+    return ranked.repo_map
+  end
+
+  if not ranked.building then
+    ranked.building = true
+    RepoMap._build_repo_map_async(project_root, file_ext, function(result)
+      ranked.building = false
+      if result then ranked.repo_map = result end
+    end, opts)
+  end
+  return ranked.repo_map
+end
+
+---@param context_files string[]|nil files already in context, used to rank the map when `repo_map.token_budget` is set
+function RepoMap.get_repo_map(file_ext, context_files)
   -- Add safety check for file_ext
   if not file_ext then
     Utils.warn("No file extension available - please open a file first")
     return {}
   end
 
-  local repo_map = RepoMap._get_repo_map(file_ext) or {}
+  local repo_map
+  if Config.repo_map.token_budget then
+    repo_map = RepoMap._get_ranked_repo_map(file_ext, context_files or {}) or {}
+  else
+    repo_map = RepoMap._get_repo_map(file_ext) or {}
+  end
   if not repo_map or next(repo_map) == nil then
     Utils.warn("The repo map is empty. Maybe do not support this language: " .. file_ext)
   end
@@ -106,18 +292,12 @@ function RepoMap._get_repo_map(file_ext)
     build_and_save()
     if not repo_map then return end
   else
-    local timer = vim.loop.new_timer()
-
-    if timer then
-      timer:start(
-        0,
-        0,
-        vim.schedule_wrap(function()
-          build_and_save()
-          timer:close()
-        end)
-      )
-    end
+    RepoMap._build_repo_map_async(project_root, file_ext, function(result)
+      if not result then return end
+      repo_map = result
+      cache[cache_key] = repo_map
+      Path.repo_map.save(project_root, file_ext, repo_map)
+    end)
   end
 
   local update_repo_map = vim.schedule_wrap(function(rel_filepath)
@@ -125,7 +305,8 @@ function RepoMap._get_repo_map(file_ext)
       local abs_filepath = PPath:new(project_root):joinpath(rel_filepath):absolute()
       local lines = Utils.read_file_from_buf_or_disk(abs_filepath)
       local content = lines and table.concat(lines, "\n") or ""
-      local definitions = repo_map_lib.stringify_definitions(RepoMap.get_ts_lang(abs_filepath), content)
+      local definitions =
+        repo_map_lib.stringify_definitions(RepoMap.get_ts_lang(abs_filepath), content, Config.repo_map.docs)
       if definitions == "" then return end
       local found = false
       for _, m in ipairs(repo_map) do
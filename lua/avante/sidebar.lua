@@ -2176,7 +2176,10 @@ function Sidebar:get_generate_prompts_options(request, cb)
   local mentions = Utils.extract_mentions(request)
   request = mentions.new_content
 
-  local project_context = mentions.enable_project_context and file_ext and RepoMap.get_repo_map(file_ext) or nil
+  local project_context = mentions.enable_project_context
+      and file_ext
+      and RepoMap.get_repo_map(file_ext, self.file_selector:get_selected_filepaths())
+    or nil
 
   local diagnostics = nil
   if mentions.enable_diagnostics then
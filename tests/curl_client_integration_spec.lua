@@ -76,6 +76,34 @@ a.describe("curl_client integration", function()
     end)
   end)
 
+  a.it("should decode JSON nulls as vim.NIL", function()
+    local client = curl_client.create()
+
+    local done = false
+    local request_id = client:post("https://httpbin.org/anything", {
+      timeout = 10,
+      body = { a = vim.NIL, b = { 1, vim.NIL } },
+      response_type = "json",
+      on_complete = function() done = true end,
+      on_error = function() done = true end,
+    })
+
+    local timeout = os.time() + 15
+    while not done and os.time() < timeout do
+      client:poll_requests()
+      async.util.sleep(100)
+    end
+
+    -- httpbin echoes the request body back under `json`
+    local status = client:get_status(request_id)
+    assert.is_not_nil(status.json)
+    assert.equals(vim.NIL, status.json.json.a)
+    assert.equals(vim.NIL, status.json.json.b[2])
+    assert.equals(2, #status.json.json.b)
+
+    client:destroy()
+  end)
+
   a.it("should handle streaming responses", function()
     -- async.run(function()
     local client = curl_client.create()
@@ -0,0 +1,98 @@
+-- This is an integration test that requires the avante_repo_map module to be built
+-- Run this test only after building the Rust crate
+local assert = require("luassert")
+local RepoMap = require("avante.repo_map")
+
+local function range(definition)
+  return { definition.start_line, definition.start_column, definition.end_line, definition.end_column }
+end
+
+describe("repo_map extract_definitions", function()
+  local repo_map_lib
+
+  before_each(function()
+    repo_map_lib = RepoMap._init_repo_map_lib()
+    assert.is_not_nil(repo_map_lib, "avante_repo_map is not built")
+  end)
+
+  it("should report classes with their methods and properties", function()
+    local source = table.concat({
+      "pub struct Config {",
+      "    pub port: u16,",
+      "}",
+      "",
+      "impl Config {",
+      "    pub fn new() -> Self {",
+      "        Self { port: 0 }",
+      "    }",
+      "}",
+    }, "\n")
+
+    local definitions = repo_map_lib.extract_definitions("rust", source)
+    assert.equals(1, #definitions)
+
+    local class = definitions[1]
+    assert.equals("class", class.kind)
+    assert.equals("Config", class.name)
+    assert.equals("pub", class.visibility)
+    assert.is_nil(class.parent)
+    assert.same({ 1, 0, 3, 1 }, range(class))
+
+    assert.equals(1, #class.methods)
+    local method = class.methods[1]
+    assert.equals("func", method.kind)
+    assert.equals("new", method.name)
+    assert.equals("Config", method.parent)
+    assert.equals("()", method.params)
+    assert.equals("Self", method.return_type)
+    assert.same({ 6, 4, 8, 5 }, range(method))
+
+    assert.equals(1, #class.properties)
+    local property = class.properties[1]
+    assert.equals("variable", property.kind)
+    assert.equals("port", property.name)
+    assert.equals("Config", property.parent)
+    assert.equals("u16", property.value_type)
+    assert.same({ 2, 4, 2, 17 }, range(property))
+  end)
+
+  it("should nest namespace members and enum items under their parent", function()
+    local source = table.concat({
+      "namespace net {",
+      "int connect(int port) {",
+      "  return 0;",
+      "}",
+      "enum Color {",
+      "  Red,",
+      "  Green,",
+      "};",
+      "}",
+    }, "\n")
+
+    local definitions = repo_map_lib.extract_definitions("cpp", source)
+    assert.equals(1, #definitions)
+
+    local namespace = definitions[1]
+    assert.equals("namespace", namespace.kind)
+    assert.equals("net", namespace.name)
+    assert.is_nil(namespace.parent)
+    assert.same({ 1, 0, 9, 1 }, range(namespace))
+    assert.equals(2, #namespace.definitions)
+
+    local func = namespace.definitions[1]
+    assert.equals("func", func.kind)
+    assert.equals("connect", func.name)
+    assert.equals("net", func.parent)
+    assert.is_nil(func.visibility)
+    assert.equals("(int port)", func.params)
+    assert.same({ 2, 0, 4, 1 }, range(func))
+
+    local enum = namespace.definitions[2]
+    assert.equals("enum", enum.kind)
+    assert.equals("Color", enum.name)
+    assert.equals("net", enum.parent)
+    assert.same({ "Red", "Green" }, vim.tbl_map(function(item) return item.name end, enum.items))
+    assert.equals("Color", enum.items[2].parent)
+    assert.same({ 7, 2, 7, 7 }, range(enum.items[2]))
+  end)
+end)